@@ -0,0 +1,65 @@
+//! Sets up a bitswap behaviour using nothing but this crate and its re-exports, so the
+//! versions of libp2p, libipld and prometheus can't drift apart from the ones bitswap
+//! was built against. The behaviour is then added to a `deps::libp2p::Swarm` like any
+//! other.
+//!
+//! ```sh
+//! cargo run --example deps_only --features default-params -- <cid> <peer id> <address>
+//! ```
+
+use libp2p_bitswap::deps::{oneshot, Block, Cid, Multiaddr, PeerId, Registry, Result};
+use libp2p_bitswap::{BitswapConfig, BitswapStore, DefaultBitswap, DiscoveredProviders};
+use std::collections::HashMap;
+
+/// Keeps blocks in memory and walks their links to find the missing ones.
+#[derive(Default)]
+struct MemStore(HashMap<Cid, Vec<u8>>);
+
+impl BitswapStore for MemStore {
+    type Params = libp2p_bitswap::deps::DefaultParams;
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        Ok(self.0.contains_key(cid))
+    }
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.get(cid).cloned())
+    }
+    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        self.0.insert(*block.cid(), block.data().to_vec());
+        Ok(())
+    }
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        let mut stack = vec![*cid];
+        let mut missing = vec![];
+        while let Some(cid) = stack.pop() {
+            if let Some(data) = self.0.get(&cid) {
+                let block = Block::<Self::Params>::new_unchecked(cid, data.clone());
+                block.references(&mut stack)?;
+            } else {
+                missing.push(cid);
+            }
+        }
+        Ok(missing)
+    }
+}
+
+fn main() -> Result<()> {
+    let usage = "usage: deps_only <cid> <peer id> <address>";
+    let mut args = std::env::args().skip(1);
+    let cid: Cid = args.next().expect(usage).parse()?;
+    let peer: PeerId = args.next().expect(usage).parse()?;
+    let addr: Multiaddr = args.next().expect(usage).parse()?;
+
+    let mut bitswap = DefaultBitswap::new(BitswapConfig::new(), MemStore::default());
+    bitswap.add_address(&peer, addr)?;
+    bitswap.set_provider_discovery(|_cid: Cid| {
+        let (tx, rx) = oneshot::channel();
+        tx.send(DiscoveredProviders::default()).ok();
+        rx
+    });
+    let registry = Registry::new();
+    bitswap.register_metrics(&registry)?;
+
+    let id = bitswap.sync(cid, vec![peer], std::iter::once(cid))?;
+    println!("started sync {}", id);
+    Ok(())
+}
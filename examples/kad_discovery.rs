@@ -0,0 +1,152 @@
+//! Fetches a block, asking a Kademlia dht running in the same swarm for its providers
+//! whenever the get runs out of them.
+//!
+//! ```sh
+//! cargo run --example kad_discovery -- <cid> <bootstrap peer id> <bootstrap address>
+//! ```
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use libipld::store::DefaultParams;
+use libipld::{Block, Cid, Result};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::Boxed;
+use libp2p::kad::record::store::MemoryStore;
+use libp2p::kad::record::Key;
+use libp2p::kad::{GetProvidersOk, Kademlia, KademliaEvent, QueryId, QueryResult};
+use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
+use libp2p::swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent};
+use libp2p::tcp::{self, async_io};
+use libp2p::yamux::YamuxConfig;
+use libp2p::{identity, Multiaddr, PeerId, Transport};
+use libp2p_bitswap::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore, DiscoveredProviders};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event")]
+struct Behaviour {
+    bitswap: Bitswap<DefaultParams>,
+    kad: Kademlia<MemoryStore>,
+}
+
+#[derive(Debug)]
+enum Event {
+    Bitswap(BitswapEvent),
+    Kad(KademliaEvent),
+}
+
+impl From<BitswapEvent> for Event {
+    fn from(event: BitswapEvent) -> Self {
+        Self::Bitswap(event)
+    }
+}
+
+impl From<KademliaEvent> for Event {
+    fn from(event: KademliaEvent) -> Self {
+        Self::Kad(event)
+    }
+}
+
+/// Keeps blocks in memory. Doesn't know about links, so it only supports gets.
+#[derive(Default)]
+struct MemStore(HashMap<Cid, Vec<u8>>);
+
+impl BitswapStore for MemStore {
+    type Params = DefaultParams;
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        Ok(self.0.contains_key(cid))
+    }
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.get(cid).cloned())
+    }
+    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        self.0.insert(*block.cid(), block.data().to_vec());
+        Ok(())
+    }
+    fn missing_blocks(&mut self, _cid: &Cid) -> Result<Vec<Cid>> {
+        Ok(vec![])
+    }
+}
+
+fn transport(key: &identity::Keypair) -> Boxed<(PeerId, StreamMuxerBox)> {
+    let dh_key = Keypair::<X25519Spec>::new().into_authentic(key).unwrap();
+    async_io::Transport::new(tcp::Config::new().nodelay(true))
+        .upgrade(libp2p::core::upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(dh_key).into_authenticated())
+        .multiplex(YamuxConfig::default())
+        .timeout(Duration::from_secs(20))
+        .boxed()
+}
+
+#[async_std::main]
+async fn main() -> Result<()> {
+    let usage = "usage: kad_discovery <cid> <bootstrap peer id> <bootstrap address>";
+    let mut args = std::env::args().skip(1);
+    let cid: Cid = args.next().expect(usage).parse()?;
+    let bootstrap: PeerId = args.next().expect(usage).parse()?;
+    let addr: Multiaddr = args.next().expect(usage).parse()?;
+
+    let key = identity::Keypair::generate_ed25519();
+    let peer_id = key.public().to_peer_id();
+    let mut kad = Kademlia::new(peer_id, MemoryStore::new(peer_id));
+    kad.add_address(&bootstrap, addr);
+
+    // the bitswap behaviour can't reach the dht, so lookups go through a channel that
+    // is drained by the swarm loop below
+    let (lookups_tx, mut lookups) = mpsc::unbounded();
+    let mut bitswap = Bitswap::new(BitswapConfig::new(), MemStore::default());
+    bitswap.set_provider_discovery(move |cid: Cid| {
+        let (tx, rx) = oneshot::channel::<DiscoveredProviders>();
+        lookups_tx.unbounded_send((cid, tx)).ok();
+        rx
+    });
+
+    let behaviour = Behaviour { bitswap, kad };
+    let mut swarm =
+        SwarmBuilder::with_async_std_executor(transport(&key), behaviour, peer_id).build();
+    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+
+    let id = swarm.behaviour_mut().bitswap.get(cid, std::iter::empty())?;
+    let mut pending: HashMap<QueryId, (oneshot::Sender<_>, DiscoveredProviders)> = HashMap::new();
+    loop {
+        futures::select! {
+            (cid, tx) = lookups.select_next_some() => {
+                let key = Key::new(&cid.to_bytes());
+                let query = swarm.behaviour_mut().kad.get_providers(key);
+                pending.insert(query, (tx, vec![]));
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(Event::Kad(KademliaEvent::OutboundQueryProgressed {
+                    id: query,
+                    result: QueryResult::GetProviders(result),
+                    step,
+                    ..
+                })) => {
+                    if let (Some((_, found)), Ok(GetProvidersOk::FoundProviders { providers, .. })) =
+                        (pending.get_mut(&query), result)
+                    {
+                        // the dht knows the addresses of the peers it returns
+                        found.extend(providers.into_iter().map(|peer| (peer, vec![])));
+                    }
+                    if step.last {
+                        if let Some((tx, found)) = pending.remove(&query) {
+                            tx.send(found).ok();
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(Event::Bitswap(BitswapEvent::Complete {
+                    id: id2,
+                    result,
+                    ..
+                })) if id2 == id => {
+                    match result {
+                        Ok(()) => println!("fetched {}", cid),
+                        Err(err) => println!("{}", err),
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+}
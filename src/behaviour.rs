@@ -1,4 +1,3 @@
-
 //! Handles the `/ipfs/bitswap/1.0.0` and `/ipfs/bitswap/1.1.0` protocols. This
 //! allows exchanging IPFS blocks.
 //!
@@ -6,50 +5,240 @@
 //!
 //! The `Bitswap` struct implements the `NetworkBehaviour` trait. When used, it
 //! will allow providing and reciving IPFS blocks.
-#[cfg(feature = "compat")]
-use crate::compat::{CompatMessage, CompatProtocol, InboundMessage};
+use crate::bootstrap::Bootstrap;
+use crate::breaker::{CircuitBreaker, CircuitState, Circuits};
+use crate::cache::HaveCache;
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, FaultAction, FaultInjector};
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+use crate::compat::{
+    self, CompatMessage, CompatProtocol, CompatVersion, InboundMessage, OutboundMessage,
+};
+#[cfg(feature = "compression")]
+use crate::compression::Compression;
+use crate::discovery::{DiscoveredProviders, ProviderDiscovery};
+use crate::error::{AddrError, Error, InvalidReferences, QueryRejected};
+use crate::estimator::{AdaptiveTimeout, PeerEstimate};
+use crate::hot::HotCids;
+use crate::ledger::PeerScore;
+use crate::limiter::{RateLimit, RateLimiter, Throttle};
+use crate::policy::{Ledger, PolicyDecision, ServerPolicy};
 use crate::protocol::{
-    BitswapCodec, BitswapProtocol, BitswapRequest, BitswapResponse, RequestType,
+    Batch, BitswapCodec, BitswapProtocol, BitswapRequest, BitswapResponse, Capabilities,
+    Negotiated, RequestType, DEFAULT_CHUNK_SIZE, DEFAULT_CODEC_BUFFER_SIZE,
+    DEFAULT_CODEC_POOL_SIZE, MAX_BATCH_SIZE,
 };
-use crate::query::{QueryEvent, QueryId, QueryManager, Request, Response};
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+use crate::query::CompatFallbacks;
+use crate::query::{
+    is_inline, ActiveQuery, QueryEvent, QueryId, QueryInfo, QueryManager, QueryStats, Request,
+    Response, SyncOptions, DEFAULT_PROVIDER_BATCH,
+};
+use crate::record::Recorder;
+use crate::selector::Selector;
+use crate::session::{SessionId, Sessions};
+#[cfg(feature = "spill")]
+use crate::spill::{Spill, SpillStore};
 use crate::stats::*;
-use fnv::FnvHashMap;
-#[cfg(feature = "compat")]
-use fnv::FnvHashSet;
+use crate::wantlist::{PeerWantlists, WantList, WantListHandle, WantMessage};
+use async_trait::async_trait;
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::{
     channel::mpsc,
+    future::FutureExt,
     stream::{Stream, StreamExt},
     task::{Context, Poll},
 };
-use libipld::{error::BlockNotFound, store::StoreParams, Block, Cid, Result};
-#[cfg(feature = "compat")]
+use futures::{future::BoxFuture, lock::Mutex, stream::FuturesUnordered};
+use futures_timer::Delay;
+use instant::{Instant, SystemTime};
+use libipld::codec::{Decode, References};
+use libipld::{store::StoreParams, Block, Cid, Ipld, Result};
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
 use libp2p::core::either::EitherOutput;
-use libp2p::core::{connection::ConnectionId, Multiaddr, PeerId};
+use libp2p::core::{connection::ConnectionId, multiaddr::Protocol, Multiaddr, PeerId};
 use libp2p::swarm::derive_prelude::{ConnectionClosed, DialFailure, FromSwarm, ListenFailure};
-#[cfg(feature = "compat")]
-use libp2p::swarm::{ConnectionHandlerSelect, NotifyHandler, OneShotHandler};
+use libp2p::swarm::dial_opts::DialOpts;
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+use libp2p::swarm::{
+    ConnectionHandlerSelect, NotifyHandler, OneShotHandler, OneShotHandlerConfig, SubstreamProtocol,
+};
 use libp2p::{
     request_response::{
         InboundFailure, OutboundFailure, ProtocolSupport, RequestId, RequestResponse,
         RequestResponseConfig, RequestResponseEvent, RequestResponseMessage, ResponseChannel,
     },
-    swarm::{ConnectionHandler, NetworkBehaviour, NetworkBehaviourAction, PollParameters},
+    swarm::{
+        ConnectionHandler, DialError, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+    },
 };
 use prometheus::Registry;
-use std::{pin::Pin, time::Duration};
+use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    time::Duration,
+};
+
+/// Bitswap response channel. Opaque so that the request response transport can
+/// change without breaking users.
+#[derive(Debug)]
+pub struct Channel(ResponseChannel<Negotiated<Batch<BitswapResponse>>>);
 
-/// Bitswap response channel.
-pub type Channel = ResponseChannel<BitswapResponse>;
+/// Bitswap behaviour with the default store params of libipld.
+#[cfg(feature = "default-params")]
+pub type DefaultBitswap = Bitswap<libipld::store::DefaultParams>;
 
 /// Event emitted by the bitswap behaviour.
+///
+/// New variants and fields may be added in minor releases. Use the constructors to
+/// create events, e.g. in tests.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum BitswapEvent {
-    /// Received a block from a peer. Includes the number of known missing blocks for a
-    /// sync query. When a block is received and missing blocks is not empty the counter
-    /// is increased. If missing blocks is empty the counter is decremented.
-    Progress(QueryId, usize),
+    /// Progress of a sync query. Emitted after the links of a received block were
+    /// looked up and once more right before the sync completes. The counters never
+    /// decrease, so they can drive a progress bar.
+    #[non_exhaustive]
+    Progress {
+        /// Sync query.
+        id: QueryId,
+        /// Number of blocks being fetched.
+        missing: usize,
+        /// Number of blocks discovered so far, including the ones the sync started with.
+        discovered: u64,
+        /// Number of blocks fetched so far. Equals `discovered` when the sync completes
+        /// without `QueryStats::partial`.
+        fetched: u64,
+        /// Estimated time until the sync completes. `None` until enough blocks were
+        /// received to estimate the throughput.
+        eta: Option<Duration>,
+        /// Bytes of the blocks received so far, including duplicates.
+        bytes: u64,
+        /// Number of blocks received after another peer had sent them.
+        duplicates: u64,
+        /// Number of peers that sent blocks.
+        peers: usize,
+    },
     /// A get or sync query completed.
-    Complete(QueryId, Result<()>),
+    #[non_exhaustive]
+    Complete {
+        /// Get or sync query.
+        id: QueryId,
+        /// Outcome of the query.
+        result: std::result::Result<(), Error>,
+        /// Time, blocks, bytes, peers and retries the query took, and the blocks a
+        /// partial sync couldn't fetch.
+        stats: QueryStats,
+    },
+    /// Received a validated block for a get or sync query. Only emitted when
+    /// `BitswapConfig::emit_blocks_in_events` is set. For get queries it is emitted
+    /// before the query completes.
+    #[non_exhaustive]
+    Block {
+        /// Get or sync query.
+        id: QueryId,
+        /// Cid of the block.
+        cid: Cid,
+        /// Data of the block.
+        data: Bytes,
+    },
+    /// The next block of a sync with `SyncOptions::ordered_window` set is in the store.
+    /// Blocks are reported in depth first order of the dag.
+    #[non_exhaustive]
+    SyncBlock {
+        /// Sync query.
+        id: QueryId,
+        /// Cid of the block.
+        cid: Cid,
+    },
+    /// A peer asked for a block. Only emitted when `BitswapConfig::emit_want_events`
+    /// is set. Emitted before the server policy and the store see the request, so it
+    /// doesn't tell whether the block is served.
+    #[non_exhaustive]
+    Want {
+        /// Peer that sent the request.
+        peer: PeerId,
+        /// Cid of the wanted block.
+        cid: Cid,
+        /// Whether the peer asked for the block or only whether we have it.
+        ty: RequestType,
+    },
+    /// Dropped a response for a query because it came from a peer the request wasn't
+    /// sent to. Only emitted for the first mismatch, all of them are counted by the
+    /// `bitswap_mismatched_responses_total` metric.
+    #[non_exhaustive]
+    MismatchedResponse {
+        /// Query the request was sent for.
+        id: QueryId,
+        /// Peer that sent the response.
+        peer: PeerId,
+    },
+}
+
+impl BitswapEvent {
+    /// Creates a `Progress` event.
+    pub fn progress(id: QueryId, info: QueryInfo) -> Self {
+        Self::Progress {
+            id,
+            missing: info.missing,
+            discovered: info.discovered,
+            fetched: info.fetched,
+            eta: info.eta,
+            bytes: info.bytes,
+            duplicates: info.duplicates,
+            peers: info.peers,
+        }
+    }
+
+    /// Creates a `Complete` event with empty stats.
+    pub fn complete(id: QueryId, result: std::result::Result<(), Error>) -> Self {
+        Self::complete_with_stats(id, result, QueryStats::default())
+    }
+
+    /// Creates a `Complete` event.
+    pub fn complete_with_stats(
+        id: QueryId,
+        result: std::result::Result<(), Error>,
+        stats: QueryStats,
+    ) -> Self {
+        Self::Complete { id, result, stats }
+    }
+
+    /// Creates a `Block` event.
+    pub fn block(id: QueryId, cid: Cid, data: Bytes) -> Self {
+        Self::Block { id, cid, data }
+    }
+
+    /// Creates a `SyncBlock` event.
+    pub fn sync_block(id: QueryId, cid: Cid) -> Self {
+        Self::SyncBlock { id, cid }
+    }
+
+    /// Creates a `Want` event.
+    pub fn want(peer: PeerId, cid: Cid, ty: RequestType) -> Self {
+        Self::Want { peer, cid, ty }
+    }
+
+    /// Creates a `MismatchedResponse` event.
+    pub fn mismatched_response(id: QueryId, peer: PeerId) -> Self {
+        Self::MismatchedResponse { id, peer }
+    }
+}
+
+/// Where a received block belongs, so that a store can keep the blocks of a dag
+/// together although they arrive in the order their requests complete.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct InsertHint {
+    /// Get or sync query that fetched the block.
+    pub root: QueryId,
+    /// Block whose missing blocks listed the block, usually the block linking to it.
+    /// `None` if the block wasn't discovered by a sync query.
+    pub parent: Option<Cid>,
 }
 
 /// Trait implemented by a block store.
@@ -62,17 +251,330 @@ pub trait BitswapStore: Send + Sync + 'static {
     fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>>;
     /// A block response needs to insert the block into the store.
     fn insert(&mut self, block: &Block<Self::Params>) -> Result<()>;
+    /// Inserts a block received by a query together with a hint where it belongs.
+    /// Inserts the block ignoring the hint by default. Blocks that were spilled are
+    /// inserted with `insert`.
+    fn insert_with_hint(&mut self, block: &Block<Self::Params>, _hint: InsertHint) -> Result<()> {
+        self.insert(block)
+    }
     /// A sync query needs a list of missing blocks to make progress.
     fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>>;
+    /// Decides whether a block may be served to other peers. Have and block requests
+    /// for blocks that can't be served are answered as if the store didn't contain
+    /// them, and errors are treated like `false`. Serves every block by default.
+    ///
+    /// This only keeps honest peers from receiving the block from us. It is not access
+    /// control: anyone who knows the data can compute its cid, and other peers holding
+    /// the block may still serve it. With `BitswapConfig::have_cache`, blocks that were
+    /// served and are no longer servable need to be uncached, see
+    /// [`Bitswap::uncache_have`].
+    fn can_serve(&mut self, _cid: &Cid) -> Result<bool> {
+        Ok(true)
+    }
+    /// Returns another handle to the store, e.g. with its own connection to the
+    /// database, for an additional db worker, see `BitswapConfig::db_workers`. Stores
+    /// that can't be accessed concurrently return `None`, the default.
+    fn try_clone(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// Trait implemented by a block store with an async api, e.g. one backed by a remote
+/// database. See [`Bitswap::new_async`].
+///
+/// The methods are the ones of [`BitswapStore`]. Each db worker still services its
+/// requests one at a time in the order of its queue.
+#[async_trait]
+pub trait AsyncBitswapStore: Send + Sync + 'static {
+    /// The store params.
+    type Params: StoreParams;
+    /// A have query needs to know if the block store contains the block.
+    async fn contains(&mut self, cid: &Cid) -> Result<bool>;
+    /// A block query needs to retrieve the block from the store.
+    async fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>>;
+    /// A block response needs to insert the block into the store.
+    async fn insert(&mut self, block: &Block<Self::Params>) -> Result<()>;
+    /// Inserts a block received by a query together with a hint where it belongs.
+    /// Inserts the block ignoring the hint by default.
+    async fn insert_with_hint(
+        &mut self,
+        block: &Block<Self::Params>,
+        _hint: InsertHint,
+    ) -> Result<()> {
+        self.insert(block).await
+    }
+    /// A sync query needs a list of missing blocks to make progress.
+    async fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>>;
+    /// Decides whether a block may be served to other peers, see
+    /// [`BitswapStore::can_serve`]. Serves every block by default.
+    async fn can_serve(&mut self, _cid: &Cid) -> Result<bool> {
+        Ok(true)
+    }
+    /// Returns another handle to the store, see [`BitswapStore::try_clone`].
+    fn try_clone(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// Drives a `BitswapStore` like an async one. The calls complete without yielding.
+struct SyncStore<S>(S);
+
+#[async_trait]
+impl<S: BitswapStore> AsyncBitswapStore for SyncStore<S> {
+    type Params = S::Params;
+    async fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.0.contains(cid)
+    }
+    async fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.0.get(cid)
+    }
+    async fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        self.0.insert(block)
+    }
+    async fn insert_with_hint(
+        &mut self,
+        block: &Block<Self::Params>,
+        hint: InsertHint,
+    ) -> Result<()> {
+        self.0.insert_with_hint(block, hint)
+    }
+    async fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.0.missing_blocks(cid)
+    }
+    async fn can_serve(&mut self, cid: &Cid) -> Result<bool> {
+        self.0.can_serve(cid)
+    }
+    fn try_clone(&self) -> Option<Self> {
+        self.0.try_clone().map(SyncStore)
+    }
 }
 
 /// Bitswap configuration.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct BitswapConfig {
     /// Timeout of a request.
     pub request_timeout: Duration,
+    /// Derives the timeout of each request from the latency and bandwidth observed from
+    /// the peer. Requests expiring early are rerouted to other providers while
+    /// `request_timeout` stays the upper bound. Disabled by default.
+    pub adaptive_timeout: Option<AdaptiveTimeout>,
+    /// Number of providers probed in parallel before a block is requested when a get
+    /// starts without being connected to any of its providers. Providers that don't
+    /// answer within `probe_timeout` are only tried after the responsive ones. `0`
+    /// disables probing.
+    pub provider_probes: usize,
+    /// Time a provider has to answer a probe.
+    pub probe_timeout: Duration,
+    /// Probes every connected peer that isn't known not to support bitswap when a get
+    /// starts without providers, like go-bitswap broadcasts its wants, and requests the
+    /// block from the peers that have it. The providers are only discovered if none of
+    /// the peers has the block. Disabled by default.
+    pub broadcast_wants: bool,
+    /// Number of times a request is retried after failing to dial the provider. The
+    /// backoff doubles with every retry. `0` disables retries. Once the retries are
+    /// exhausted the circuit of the provider opens if `circuit_breaker` is set.
+    pub max_dial_retries: u32,
+    /// Backoff before the first dial retry.
+    pub dial_retry_backoff: Duration,
+    /// Number of times a request is resent to the same provider after it timed out,
+    /// so that a dropped packet or momentary congestion doesn't fail the request. The
+    /// backoff doubles with every retry. `0` disables retries.
+    pub max_timeout_retries: u32,
+    /// Backoff before the first timeout retry.
+    pub timeout_retry_backoff: Duration,
+    /// Number of requests that may be in flight to a peer at a time, a batch counting
+    /// as one. Further requests to the peer are queued and sent in the order they were
+    /// made as responses arrive, so that slow peers aren't flooded. Unlimited by default.
+    pub max_requests_per_peer: Option<usize>,
+    /// Number of requests dialing at the same time after their dial was rejected by the
+    /// connection limits of the swarm. Such requests are queued without penalizing the
+    /// provider and resent whenever a connection is established or closed or another
+    /// dial fails.
+    pub pending_dial_budget: usize,
     /// Time a connection is kept alive.
     pub connection_keep_alive: Duration,
+    /// Maximum time an inbound request may wait before it is served. Older requests are
+    /// skipped since the remote has most likely timed out and would discard the response.
+    pub inbound_response_budget: Duration,
+    /// Time a request deferred by the server policy is held before we answer that we
+    /// don't have the block.
+    pub deferred_request_ttl: Duration,
+    /// Debt ratio above which block requests of a peer are deferred like with
+    /// `PolicyDecision::Defer`, until the peer sends us blocks or its watermark moves,
+    /// so peers that only take and never give are throttled. See `Ledger::debt_ratio`.
+    /// Applies to requests the server policy decided to serve. Disabled by default.
+    pub max_debt_ratio: Option<f64>,
+    /// Bytes served to a peer since its watermark before `max_debt_ratio` applies, so
+    /// that new peers can get started.
+    pub debt_allowance: u64,
+    /// Limits the rate of inbound requests of each peer, so that a peer can't keep the
+    /// store busy. Checked before the server policy sees a request. Disabled by default.
+    pub inbound_rate_limit: Option<RateLimit>,
+    /// Bytes per second of blocks sent to peers. Block responses are queued while the
+    /// rate is exceeded, so that serving blocks doesn't starve other protocols sharing
+    /// the uplink. Have responses aren't queued. Disabled by default.
+    pub max_upload_rate: Option<u64>,
+    /// How wants for blocks we are fetching ourselves are answered.
+    pub fetching_wants: FetchingWants,
+    /// Bytes of received blocks waiting to be inserted into the store above which blocks
+    /// are put into the spill store, if one is set. Blocks of strict sync queries are
+    /// always queued since their references are checked on insertion.
+    #[cfg(feature = "spill")]
+    pub max_pending_insert_bytes: usize,
+    /// Buffer capacity each codec retains between messages. Larger messages grow the
+    /// buffer up to the maximum message size, after which it shrinks back.
+    pub codec_buffer_size: usize,
+    /// Capacity of unused buffers kept for reuse by the pool that the codecs of all
+    /// connections read and write responses larger than `codec_buffer_size` with.
+    pub codec_pool_size: usize,
+    /// Bytes of a response sent per frame to peers that advertised
+    /// `Capabilities::CHUNKING`, if it is in `capabilities` too. Larger responses are
+    /// split into chunks and reassembled by the peer before the block is verified.
+    pub chunk_size: usize,
+    /// Emits a `BitswapEvent::Block` with the block data for every received block.
+    /// Unless `skip_store_insert` applies, the data is copied once since the block is
+    /// also inserted into the store, so every received block is held in memory until
+    /// the event is consumed.
+    pub emit_blocks_in_events: bool,
+    /// Emits a `BitswapEvent::Want` for every inbound have or block request, e.g. to
+    /// prefetch blocks peers ask for.
+    pub emit_want_events: bool,
+    /// Doesn't insert blocks received by get queries into the store. Only useful in
+    /// combination with `emit_blocks_in_events` for ephemeral fetches. Blocks of sync
+    /// queries are always inserted since traversing the dag reads them from the store.
+    pub skip_store_insert: bool,
+    /// Fails [`BitswapConfig::validate`] with `Error::CompatBlockSize` if
+    /// `StoreParams::MAX_BLOCK_SIZE` exceeds the block size compat peers accept, which
+    /// `Bitswap::new` only logs a warning for. Blocks above the limit are never served
+    /// to compat peers.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub strict_compat_block_size: bool,
+    /// Speaks `/ipfs/bitswap/1.2.0` with peers that don't support
+    /// `/ipfs-embed/bitswap`. When disabled the protocol isn't advertised, inbound
+    /// compat streams are rejected and requests aren't retried over compat, so the
+    /// behaviour matches a build without the compat feature.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub enable_compat: bool,
+    /// Protocol our requests are sent over while compat is enabled. Defaults to
+    /// `CompatMode::Fallback`.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub compat_mode: CompatMode,
+    /// Number of inbound wants kept in the want history. `0` disables the history.
+    pub want_history_size: usize,
+    /// Peers whose blocks are accepted without verifying their hash.
+    ///
+    /// WARNING: a trusted peer can make us store arbitrary data under any cid we
+    /// request from it. Only add peers that are authenticated and operated by you.
+    pub trusted_peers: FnvHashSet<PeerId>,
+    /// Peers that are neither served nor asked for blocks, see [`Bitswap::block_peer`].
+    pub blocked_peers: FnvHashSet<PeerId>,
+    /// If set, only these peers are served and asked for blocks, see
+    /// [`Bitswap::allow_peers`]. Blocked peers are refused even if they are allowed.
+    pub allowed_peers: Option<FnvHashSet<PeerId>>,
+    /// Wire features advertised to peers speaking `/ipfs-embed/bitswap/2.0.0`. A
+    /// feature is only used with peers advertising it too. With
+    /// `Capabilities::BATCHING`, requests to a peer that advertised batching are sent
+    /// together with the other pending requests to the same peer.
+    pub capabilities: Capabilities,
+    /// Compresses the blocks sent to peers that advertised `Capabilities::COMPRESSION`,
+    /// which is added to `capabilities` while compression is set. Batching, chunking
+    /// and priorities are negotiated as usual on compressed streams. Disabled by
+    /// default.
+    #[cfg(feature = "compression")]
+    pub compression: Option<Compression>,
+    /// Tracks the bytes served per cid and keeps the given number of the hottest cids
+    /// for [`Bitswap::hot_cids`]. Counts are approximate but never underestimated and
+    /// memory stays bounded regardless of how many cids are served. Disabled by default.
+    pub hot_cid_tracking: Option<usize>,
+    /// Remembers the given number of recently served cids, so that repeated have
+    /// requests for them are answered without asking the store, neither
+    /// `BitswapStore::contains` nor `BitswapStore::can_serve`. Only cids the store had
+    /// and served are remembered: misses aren't, since blocks inserted into the store
+    /// directly rather than through bitswap couldn't invalidate them. Call
+    /// [`Bitswap::uncache_have`] once a block is removed from the store or `can_serve`
+    /// no longer serves it, or [`Bitswap::clear_have_cache`]. Disabled by default.
+    pub have_cache: Option<usize>,
+    /// Counts inbound and outbound failures per peer in `bitswap_peer_failures_total`,
+    /// labelled by the tail of the peer id, for up to the given number of peers. Beyond
+    /// that the peer with the fewest failures is folded into the `other` label, so the
+    /// cardinality of the metric stays bounded. Disabled by default, the metric is only
+    /// registered if enabled.
+    pub per_peer_failure_metrics: Option<usize>,
+    /// How long the outcome of protocol negotiation with a peer is remembered. Get
+    /// queries only ask peers known not to support bitswap if no other provider has the
+    /// block, so this bounds how long it takes to notice that a peer was upgraded.
+    pub protocol_support_ttl: Duration,
+    /// Peers dialed on startup and kept connected. Once their protocol support is
+    /// detected they are added to the providers of queries started with fewer than
+    /// `min_providers` peers. Dropped peers are dialed again with a backoff starting
+    /// at `dial_retry_backoff`.
+    pub bootstrap_peers: Vec<(PeerId, Multiaddr)>,
+    /// Number of providers below which bootstrap peers are added to a query.
+    pub min_providers: usize,
+    /// Accepts addresses that fail validation unchanged instead of rejecting them, for
+    /// callers feeding addresses straight from a dht on a best effort basis.
+    pub lenient_addresses: bool,
+    /// Number of providers a get query pulls from its provider iterator at a time. The
+    /// next batch is only pulled once every pulled provider failed, so a dht query can
+    /// be passed without collecting it first.
+    pub provider_batch: usize,
+    /// Parks peers whose requests keep failing across queries, so that new queries only
+    /// ask them if no other provider has the block. Disabled by default.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Lets the db thread handle our own store requests, e.g. the missing blocks of a
+    /// sync and the inserts completing a query, before serving other peers. One in four
+    /// requests is still a serve while both are pending, so serves keep making progress.
+    /// Without it, requests are handled in the order they were queued.
+    pub local_priority: bool,
+    /// Lets the db thread serve other peers before handling our own store requests, so
+    /// a large sync doesn't keep us from answering their queries. One in four requests
+    /// is still our own while both are pending. Takes precedence over `local_priority`.
+    ///
+    /// With either priority, have requests are served before block requests, and the
+    /// missing blocks of a sync are listed after the inserts queued before them.
+    pub serve_priority: bool,
+    /// Number of times a get query that ran out of providers asks the
+    /// [`ProviderDiscovery`] installed with [`Bitswap::set_provider_discovery`] for more
+    /// before it fails with `Error::BlockNotFound`. Discovered providers the query asked
+    /// before are skipped. Without a discovery, gets fail right away. A get started without
+    /// providers always asks the discovery once.
+    pub discovery_rounds: usize,
+    /// How requests to the `BitswapStore` are serviced.
+    pub store_driver: StoreDriver,
+    /// Number of workers servicing requests to the store. The first one handles our
+    /// own requests in order, since missing blocks and insert acknowledgments read the
+    /// blocks inserted before them, while the others take turns serving other peers, so
+    /// a slow `missing_blocks` doesn't stall serving. The additional workers get their
+    /// handles from `BitswapStore::try_clone`; without them, or with
+    /// `StoreDriver::SyncInline`, a single worker handles all requests. The other workers
+    /// take serves from a queue shared between them, which orders serves like the queue
+    /// of the first worker does.
+    pub db_workers: usize,
+    /// Number of get and sync queries that may be in progress at a time, not counting
+    /// the gets started by a sync. Further queries are rejected with
+    /// `QueryRejected::TooManyQueries`.
+    pub max_active_queries: usize,
+    /// Number of gets started by syncs that may be in progress at a time, across all
+    /// syncs. The remaining blocks of a large dag are queued and fetched as earlier gets
+    /// complete, so memory and connections stay bounded. Unlimited by default.
+    pub max_sync_gets: Option<usize>,
+    /// Time after which get and sync queries complete with `Error::Timeout`, unless
+    /// they completed before. Can be set per query with `Bitswap::set_query_timeout`.
+    pub query_timeout: Option<Duration>,
+    /// Decodes the references of each block a sync receives as soon as it is
+    /// validated and starts gets for them right away, instead of waiting for the block
+    /// to be inserted and the store to list its missing blocks. The block having been
+    /// missing makes its links plausibly missing too, so links already in the store may
+    /// be fetched again. The missing blocks of the block skip the prefetched links.
+    /// Trades decoding every block twice and some redundant requests for a round trip
+    /// to the store per level of the dag.
+    pub speculative_prefetch: bool,
 }
 
 impl BitswapConfig {
@@ -80,8 +582,81 @@ impl BitswapConfig {
     pub fn new() -> Self {
         Self {
             request_timeout: Duration::from_secs(10),
+            adaptive_timeout: None,
+            provider_probes: 0,
+            probe_timeout: Duration::from_secs(2),
+            broadcast_wants: false,
+            max_dial_retries: 0,
+            dial_retry_backoff: Duration::from_secs(1),
+            max_timeout_retries: 0,
+            timeout_retry_backoff: Duration::from_secs(1),
+            max_requests_per_peer: None,
+            pending_dial_budget: 1,
             connection_keep_alive: Duration::from_secs(10),
+            inbound_response_budget: Duration::from_secs(10),
+            deferred_request_ttl: Duration::from_secs(5),
+            max_debt_ratio: None,
+            debt_allowance: 1024 * 1024,
+            inbound_rate_limit: None,
+            max_upload_rate: None,
+            fetching_wants: FetchingWants::DontHave,
+            #[cfg(feature = "spill")]
+            max_pending_insert_bytes: 64 * 1024 * 1024,
+            codec_buffer_size: DEFAULT_CODEC_BUFFER_SIZE,
+            codec_pool_size: DEFAULT_CODEC_POOL_SIZE,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            emit_blocks_in_events: false,
+            emit_want_events: false,
+            skip_store_insert: false,
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            strict_compat_block_size: false,
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            enable_compat: true,
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_mode: CompatMode::Fallback,
+            want_history_size: 0,
+            trusted_peers: Default::default(),
+            blocked_peers: Default::default(),
+            allowed_peers: None,
+            capabilities: Capabilities::empty(),
+            #[cfg(feature = "compression")]
+            compression: None,
+            hot_cid_tracking: None,
+            have_cache: None,
+            per_peer_failure_metrics: None,
+            protocol_support_ttl: Duration::from_secs(600),
+            bootstrap_peers: Vec::new(),
+            min_providers: 1,
+            lenient_addresses: false,
+            provider_batch: DEFAULT_PROVIDER_BATCH,
+            circuit_breaker: None,
+            local_priority: true,
+            serve_priority: false,
+            discovery_rounds: 3,
+            store_driver: StoreDriver::Thread,
+            db_workers: 1,
+            max_active_queries: 10_000,
+            max_sync_gets: None,
+            query_timeout: None,
+            speculative_prefetch: false,
+        }
+    }
+
+    /// Checks the config against the store params of the behaviour before it is
+    /// created. Fails with `Error::CompatBlockSize` if `strict_compat_block_size` is set
+    /// and `StoreParams::MAX_BLOCK_SIZE` exceeds the block size compat peers accept.
+    pub fn validate<P: StoreParams>(&self) -> std::result::Result<(), Error> {
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        if self.enable_compat
+            && self.strict_compat_block_size
+            && P::MAX_BLOCK_SIZE > compat::MAX_BLOCK_SIZE
+        {
+            return Err(Error::CompatBlockSize {
+                max_block_size: P::MAX_BLOCK_SIZE,
+                limit: compat::MAX_BLOCK_SIZE,
+            });
         }
+        Ok(())
     }
 }
 
@@ -91,665 +666,4682 @@ impl Default for BitswapConfig {
     }
 }
 
+/// How inbound wants for blocks that a get or sync query is fetching are answered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FetchingWants {
+    /// Answers that we don't have the block.
+    DontHave,
+    /// Holds the request until the block arrives, the query ends or the inbound response
+    /// budget runs out.
+    Delay,
+    /// Answers that we don't have the block and announces it to the peer once it
+    /// arrives. Announcements are sent as unsolicited block presences over the compat
+    /// protocol; requests of other peers are delayed instead.
+    Announce,
+}
+
+/// Protocol our requests are sent over, see `BitswapConfig::compat_mode`. Inbound
+/// requests are served over both protocols regardless.
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CompatMode {
+    /// Requests are sent over `/ipfs-embed/bitswap` and retried over compat if the peer
+    /// doesn't support it.
+    #[default]
+    Fallback,
+    /// Requests are sent over compat unless the peer is known to support
+    /// `/ipfs-embed/bitswap`, which saves go-ipfs peers the failed negotiation. Peers
+    /// without compat support are only reached once they answered a native request,
+    /// e.g. a bootstrap probe.
+    Prefer,
+    /// Requests are only sent over compat. Peers that aren't connected are dialed with
+    /// the compat requests queued until the connection is established.
+    Only,
+}
+
+/// Runs the store on the runtime of the host with `StoreDriver::Thread` instead of
+/// dedicated threads, so that it shows up in its metrics and tracing, see
+/// [`Bitswap::new_with_spawner`]. The futures of an [`AsyncBitswapStore`] are spawned as
+/// tasks. A blocking [`BitswapStore`] would stall the async threads of the runtime, so
+/// it only uses the runtime if the spawner can also run blocking closures, see
+/// [`Spawner::with_blocking`], and keeps its dedicated threads otherwise. The futures and
+/// closures complete once the behaviour is dropped.
+///
+/// ```
+/// # use libp2p_bitswap::Spawner;
+/// let spawner = Spawner::new(|future| {
+///     async_std::task::spawn(future);
+/// })
+/// .with_blocking(|f| {
+///     async_std::task::spawn_blocking(f);
+/// });
+/// ```
+#[derive(Clone)]
+pub struct Spawner {
+    spawn: Arc<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>,
+    blocking: Option<Arc<dyn Fn(Box<dyn FnOnce() + Send>) + Send + Sync>>,
+}
+
+impl Spawner {
+    /// Creates a spawner from a function spawning a future on the runtime.
+    pub fn new(spawn: impl Fn(BoxFuture<'static, ()>) + Send + Sync + 'static) -> Self {
+        Self {
+            spawn: Arc::new(spawn),
+            blocking: None,
+        }
+    }
+
+    /// Sets a function running a blocking closure on the runtime, e.g. with
+    /// `spawn_blocking`, which services a blocking store. Each closure runs until the
+    /// behaviour is dropped.
+    pub fn with_blocking(
+        mut self,
+        spawn_blocking: impl Fn(Box<dyn FnOnce() + Send>) + Send + Sync + 'static,
+    ) -> Self {
+        self.blocking = Some(Arc::new(spawn_blocking));
+        self
+    }
+
+    /// Runs the futures servicing the store, on the runtime if possible and otherwise
+    /// on dedicated threads. Returns them as a task polled by the behaviour without
+    /// threads on wasm32.
+    fn run(
+        spawner: Option<&Self>,
+        dbs: Vec<BoxFuture<'static, ()>>,
+        blocking: bool,
+    ) -> Option<BoxFuture<'static, ()>> {
+        match spawner {
+            Some(spawner) if !blocking => {
+                for db in dbs {
+                    (spawner.spawn)(db);
+                }
+                return None;
+            }
+            Some(Self {
+                blocking: Some(spawn_blocking),
+                ..
+            }) => {
+                for db in dbs {
+                    spawn_blocking(Box::new(move || futures::executor::block_on(db)));
+                }
+                return None;
+            }
+            _ => {}
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            for db in dbs {
+                std::thread::spawn(move || futures::executor::block_on(db));
+            }
+            None
+        }
+        #[cfg(target_arch = "wasm32")]
+        Some(futures::future::join_all(dbs).map(drop).boxed())
+    }
+}
+
+impl std::fmt::Debug for Spawner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Spawner")
+    }
+}
+
+/// How requests to the `BitswapStore` are serviced.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StoreDriver {
+    /// A dedicated thread services the requests, so a slow store never blocks the swarm.
+    /// The futures of an [`AsyncBitswapStore`] are blocked on. With a [`Spawner`], the
+    /// runtime of the host runs them instead. Without threads on wasm32, the requests
+    /// are otherwise serviced like with `StoreDriver::Task`.
+    #[default]
+    Thread,
+    /// The requests are serviced by a task polled together with the behaviour, so the
+    /// futures of an [`AsyncBitswapStore`] run on the executor of the swarm, e.g. when
+    /// they need its runtime. A blocking store stalls the swarm.
+    Task,
+    /// The requests are serviced synchronously whenever the behaviour is polled, in the
+    /// order they were queued. Since the store never races the behaviour, tests driving
+    /// a swarm become deterministic. Requires the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    SyncInline,
+}
+
+/// Whether a peer serves our bitswap requests, as learned from protocol negotiation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PeerSupport {
+    /// No request was negotiated with the peer recently.
+    Unknown,
+    /// The peer answers requests over `/ipfs-embed/bitswap`.
+    Native,
+    /// The peer doesn't support `/ipfs-embed/bitswap` but answers requests over the
+    /// compat protocol, which is used for it directly.
+    Compat,
+    /// The peer doesn't answer bitswap requests, e.g. because it only runs a client.
+    Unsupported,
+}
+
+/// Inbound want recorded in the want history.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WantRecord {
+    /// Peer that sent the want.
+    pub peer: PeerId,
+    /// Wanted cid.
+    pub cid: Cid,
+    /// Type of the want.
+    pub ty: RequestType,
+    /// Time the want was answered.
+    pub timestamp: SystemTime,
+    /// Whether we had the block and were willing to serve it.
+    pub served: bool,
+}
+
+/// Inbound request deferred by the server policy or held until a block we are fetching
+/// arrives.
+struct Deferred {
+    channel: BitswapChannel,
+    request: BitswapRequest,
+    received: Instant,
+}
+
+/// Outbound request awaiting a response.
+#[derive(Debug)]
+struct Inflight {
+    peer_id: PeerId,
+    sent: Instant,
+    deadline: Option<Instant>,
+    expired: bool,
+    probe: bool,
+}
+
+/// Inbound batch collecting the responses of its entries.
+struct InboundBatch {
+    peer_id: PeerId,
+    channel: Channel,
+    entries: Vec<(Cid, Option<BitswapResponse>)>,
+    remaining: usize,
+}
+
+/// Inbound compat message collecting the responses to its wantlist entries, which are
+/// sent back bundled once all of them are answered.
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+struct CompatBatch {
+    peer_id: PeerId,
+    responses: Vec<CompatMessage>,
+    remaining: usize,
+    /// Answers a want the peer sent before, so dont haves are left out.
+    notification: bool,
+    /// Whether the peer speaks a compat version with block presences. Haves and dont
+    /// haves are left out otherwise.
+    presences: bool,
+}
+
+/// Request held back by a fault injector.
+#[cfg(feature = "chaos")]
+enum Delayed {
+    /// Sends a delayed request.
+    Send(QueryId, PeerId, BitswapRequest),
+    /// Fails a dropped request.
+    Timeout(QueryId, PeerId),
+}
+
+/// Strips a trailing `/p2p` component matching `peer_id` and checks that the address has
+/// a transport to dial.
+fn normalize_addr(
+    peer_id: &PeerId,
+    mut addr: Multiaddr,
+) -> std::result::Result<Multiaddr, AddrError> {
+    let last = match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => Some(hash),
+        _ => None,
+    };
+    if let Some(hash) = last {
+        match PeerId::from_multihash(hash) {
+            Ok(found) if found == *peer_id => {
+                addr.pop();
+            }
+            Ok(found) => {
+                return Err(AddrError::PeerIdMismatch {
+                    addr,
+                    expected: *peer_id,
+                    found,
+                })
+            }
+            Err(_) => return Err(AddrError::InvalidPeerId(addr)),
+        }
+    }
+    let dialable = addr.iter().any(|protocol| {
+        matches!(
+            protocol,
+            Protocol::Tcp(_)
+                | Protocol::Udp(_)
+                | Protocol::Memory(_)
+                | Protocol::Unix(_)
+                | Protocol::Dnsaddr(_)
+                | Protocol::P2pCircuit
+        )
+    });
+    if !dialable {
+        return Err(AddrError::NoTransport(addr));
+    }
+    Ok(addr)
+}
+
+/// Outbound request of a query.
+#[derive(Clone, Copy, Debug)]
+struct PendingRequest {
+    query: QueryId,
+    /// Get or sync query the request belongs to.
+    root: QueryId,
+    peer_id: PeerId,
+    cid: Cid,
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 enum BitswapId {
     Bitswap(RequestId),
-    #[cfg(feature = "compat")]
+    /// Entry of a batched request after the first one, which uses `Bitswap`.
+    Batch(RequestId, usize),
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
     Compat(Cid),
 }
 
+/// Multicodec of dag-pb.
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+const DAG_PB: u64 = 0x70;
+
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+impl BitswapId {
+    /// Returns the id of a compat request. Dag-pb cids are keyed by their v0 form since
+    /// compat peers may answer a v0 want with a v1 cid or vice versa.
+    fn compat(cid: Cid) -> Self {
+        if cid.codec() == DAG_PB {
+            if let Ok(cid) = Cid::new_v0(*cid.hash()) {
+                return Self::Compat(cid);
+            }
+        }
+        Self::Compat(cid)
+    }
+}
+
 enum BitswapChannel {
-    Bitswap(Channel),
-    #[cfg(feature = "compat")]
-    Compat(PeerId, Cid),
+    Bitswap(PeerId, Cid, Channel),
+    /// Entry of an inbound batch.
+    Batch(PeerId, Cid, u64, usize),
+    /// Wantlist entry of an inbound compat message.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    Compat(PeerId, Cid, u64),
+}
+
+impl BitswapChannel {
+    /// Returns `true` if the request was received over the compat protocol.
+    fn is_compat(&self) -> bool {
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        return matches!(self, Self::Compat(_, _, _));
+        #[cfg(not(any(feature = "compat", feature = "compat-lite")))]
+        false
+    }
+
+    /// Returns the requesting peer and the requested cid.
+    fn peer_cid(&self) -> (PeerId, Cid) {
+        match self {
+            Self::Bitswap(peer_id, cid, _) | Self::Batch(peer_id, cid, _, _) => (*peer_id, *cid),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            Self::Compat(peer_id, cid, _) => (*peer_id, *cid),
+        }
+    }
 }
 
 /// Network behaviour that handles sending and receiving blocks.
 pub struct Bitswap<P: StoreParams> {
+    /// Configuration.
+    config: BitswapConfig,
     /// Inner behaviour.
     inner: RequestResponse<BitswapCodec<P>>,
     /// Query manager.
     query_manager: QueryManager,
+    /// Sessions of related gets.
+    sessions: Sessions,
     /// Requests.
-    requests: FnvHashMap<BitswapId, QueryId>,
+    requests: FnvHashMap<BitswapId, PendingRequest>,
+    /// Whether a mismatched response was reported.
+    mismatch_reported: bool,
+    /// Outbound requests awaiting a response.
+    inflight: FnvHashMap<RequestId, Inflight>,
+    /// Number of outbound requests in flight to each peer.
+    peer_inflight: FnvHashMap<PeerId, usize>,
+    /// Requests waiting for a peer to have fewer than `max_requests_per_peer` requests
+    /// in flight.
+    peer_queues: FnvHashMap<PeerId, VecDeque<QueryId>>,
+    /// Inbound batches waiting for the responses of their entries.
+    inbound_batches: FnvHashMap<u64, InboundBatch>,
+    /// Id of the next inbound batch.
+    next_batch: u64,
+    /// Outbound requests ordered by their adaptive or probe deadline.
+    deadlines: BTreeMap<Instant, Vec<RequestId>>,
+    /// Fires when the earliest adaptive deadline passes, with its deadline.
+    timer: Option<(Instant, Delay)>,
+    /// Fires when the earliest query deadline passes.
+    query_timer: Option<Delay>,
+    /// Block counters waiting to be added to the prometheus metrics.
+    counters: LocalCounters,
+    /// Fires when the block counters are flushed.
+    counters_timer: Option<Delay>,
+    /// Number of dial retries of each query and provider.
+    dial_retries: FnvHashMap<(QueryId, PeerId), u32>,
+    /// Number of timeout retries of each query and provider.
+    timeout_retries: FnvHashMap<(QueryId, PeerId), u32>,
+    /// Requests waiting for their dial or timeout retry backoff.
+    retries: FuturesUnordered<BoxFuture<'static, (QueryId, PeerId)>>,
+    /// Peers whose last dial was rejected by the connection limits.
+    limited_dials: FnvHashSet<PeerId>,
+    /// Requests waiting for a slot of the pending dial budget.
+    dial_queue: VecDeque<(QueryId, PeerId)>,
+    /// Peers dialed for queued requests that didn't connect yet.
+    queued_dials: FnvHashSet<PeerId>,
+    /// Decides which inbound requests are served.
+    policy: Option<Box<dyn ServerPolicy>>,
+    /// Looks up providers of get queries that ran out of them.
+    discovery: Option<Box<dyn ProviderDiscovery>>,
+    /// Provider lookups in progress.
+    discoveries: FuturesUnordered<BoxFuture<'static, (QueryId, DiscoveredProviders)>>,
+    /// Bytes served to each peer.
+    ledgers: FnvHashMap<PeerId, Ledger>,
+    /// Token buckets of the peers that sent requests, see `inbound_rate_limit`.
+    rate_limiter: RateLimiter,
+    /// Inbound requests deferred by the server policy.
+    deferred: FnvHashMap<PeerId, VecDeque<Deferred>>,
+    /// Fires when the earliest deferred request expires.
+    deferred_timer: Option<Delay>,
+    /// Inbound requests held until the block we are fetching arrives.
+    waiting: FnvHashMap<Cid, Vec<Deferred>>,
+    /// Wants registered through a `WantListHandle`, resolved like held requests when
+    /// their block arrives.
+    wantlist: WantList,
+    wantlist_handle: WantListHandle,
+    wantlist_rx: mpsc::UnboundedReceiver<WantMessage>,
+    /// Held requests ordered by when they run out of budget. Entries of requests that
+    /// were answered before are skipped once they expire.
+    waiting_deadlines: BTreeSet<(Instant, Cid)>,
+    /// Fires when the earliest held request runs out of budget, with its deadline.
+    waiting_timer: Option<(Instant, Delay)>,
+    /// Compat peers to announce a block to once it arrives.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    announcements: FnvHashMap<Cid, FnvHashSet<PeerId>>,
+    /// Responses produced without consulting the store.
+    responses: VecDeque<DbResponse>,
+    /// Block responses waiting for the budget of `max_upload_rate`.
+    uploads: Option<Throttle<DbResponse>>,
+    /// Insert backlog and spilled blocks.
+    #[cfg(feature = "spill")]
+    spill: Arc<Spill>,
     /// Db request channel.
-    db_tx: mpsc::UnboundedSender<DbRequest<P>>,
+    db_tx: DbSender<P>,
     /// Db response channel.
     db_rx: mpsc::UnboundedReceiver<DbResponse>,
+    /// Services the db requests unless they are serviced by a thread.
+    db_task: Option<BoxFuture<'static, ()>>,
+    /// Pending events.
+    events: VecDeque<BitswapEvent>,
+    /// Recently answered inbound wants.
+    want_history: VecDeque<WantRecord>,
+    /// Unanswered inbound wants of each peer.
+    peer_wants: PeerWantlists,
+    /// Established connections of each peer with the time of their last activity.
+    connections: FnvHashMap<PeerId, FnvHashMap<ConnectionId, Instant>>,
+    /// Capabilities of connected peers we exchanged a message with. Empty for peers
+    /// speaking `/ipfs-embed/bitswap/1.0.0`.
+    capabilities: FnvHashMap<PeerId, Capabilities>,
+    /// Outcome of the last protocol negotiation for our requests with each peer.
+    support: FnvHashMap<PeerId, (PeerSupport, Instant)>,
+    /// Bootstrap peers.
+    bootstrap: Bootstrap,
+    /// Bootstrap peers to dial.
+    bootstrap_dials: VecDeque<PeerId>,
+    /// Bootstrap peers waiting for their redial backoff.
+    bootstrap_redials: FuturesUnordered<BoxFuture<'static, PeerId>>,
+    /// Requests detecting the protocol support of bootstrap peers.
+    bootstrap_probes: FnvHashMap<RequestId, PeerId>,
+    /// Circuit breakers of peers with failed requests.
+    circuits: Circuits,
+    /// Peers whose circuit is open until their cooldown expires.
+    circuit_cooldowns: FuturesUnordered<BoxFuture<'static, PeerId>>,
+    /// Requests probing peers with a half open circuit.
+    circuit_probes: FnvHashMap<RequestId, PeerId>,
+    /// Bytes served per cid.
+    hot_cids: Option<HotCids>,
+    /// Recently served cids, see `BitswapConfig::have_cache`.
+    have_cache: Option<HaveCache>,
+    /// Failures counted per peer.
+    peer_failures: Option<PeerFailures>,
+    /// Decodes blocks with the codecs of the store, set once a sync was started.
+    decoder: Option<Decoder<P>>,
+    /// Selectors applying to the blocks of the syncs started by `sync_selected`.
+    selectors: FnvHashMap<QueryId, FnvHashMap<Cid, Selector>>,
     /// Compat peers.
-    #[cfg(feature = "compat")]
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
     compat: FnvHashSet<PeerId>,
+    /// Compat messages handed to a connection that haven't been sent yet, in the order
+    /// they were handed to it.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_pending: FnvHashMap<ConnectionId, VecDeque<OutboundMessage>>,
+    /// Id of the next compat message handed to a connection.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    next_compat_id: u64,
+    /// Compat messages waiting to be handed to a connection.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_queue: VecDeque<(PeerId, OutboundMessage)>,
+    /// Bytes of the blocks in `compat_queue` for each peer.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_queued_bytes: FnvHashMap<PeerId, usize>,
+    /// Inbound compat messages waiting for the responses to their wantlist entries.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_batches: FnvHashMap<u64, CompatBatch>,
+    /// Number of unanswered inbound compat wants of each peer and cid. Responses to
+    /// wants the peer cancelled are dropped.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_wants: FnvHashMap<(PeerId, Cid), usize>,
+    /// Compat version last negotiated with each connected compat peer.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_versions: FnvHashMap<PeerId, CompatVersion>,
+    /// Compat requests waiting for the dial of their peer.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_dials: FnvHashMap<PeerId, Vec<CompatMessage>>,
+    /// Peers to dial for their compat requests.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_dial_queue: VecDeque<PeerId>,
+    /// Compat requests retried after the peer didn't support bitswap.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fallbacks: FnvHashSet<BitswapId>,
+    /// Compat fallbacks of each peer, forgotten together with its protocol support.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    compat_fallbacks: FnvHashMap<PeerId, CompatFallbacks>,
+    /// Fault injector.
+    #[cfg(feature = "chaos")]
+    faults: Option<Box<dyn FaultInjector>>,
+    /// Requests delayed or dropped by the fault injector.
+    #[cfg(feature = "chaos")]
+    delayed: FuturesUnordered<BoxFuture<'static, Delayed>>,
+    /// Requests whose response gets corrupted.
+    #[cfg(feature = "chaos")]
+    corrupt: FnvHashSet<RequestId>,
 }
 
 impl<P: StoreParams> Bitswap<P> {
     /// Creates a new `Bitswap` behaviour.
     pub fn new<S: BitswapStore<Params = P>>(config: BitswapConfig, store: S) -> Self {
+        Self::build(config, SyncStore(store), None, true)
+    }
+
+    /// Creates a new `Bitswap` behaviour whose store runs on the runtime of the host with
+    /// `StoreDriver::Thread`. The store is blocking, so it keeps its dedicated threads
+    /// unless the spawner can run blocking closures, see [`Spawner`].
+    pub fn new_with_spawner<S: BitswapStore<Params = P>>(
+        config: BitswapConfig,
+        store: S,
+        spawner: Spawner,
+    ) -> Self {
+        Self::build(config, SyncStore(store), Some(spawner), true)
+    }
+
+    /// Creates a new `Bitswap` behaviour with an async block store. Use
+    /// `StoreDriver::Task` if the futures of the store need the runtime of the swarm.
+    pub fn new_async<S: AsyncBitswapStore<Params = P>>(config: BitswapConfig, store: S) -> Self {
+        Self::build(config, store, None, false)
+    }
+
+    /// Creates a new `Bitswap` behaviour with an async block store whose futures are
+    /// spawned on the runtime of the host with `StoreDriver::Thread`.
+    pub fn new_async_with_spawner<S: AsyncBitswapStore<Params = P>>(
+        config: BitswapConfig,
+        store: S,
+        spawner: Spawner,
+    ) -> Self {
+        Self::build(config, store, Some(spawner), false)
+    }
+
+    /// Creates the behaviour, `blocking` if the futures of the store block.
+    fn build<S: AsyncBitswapStore<Params = P>>(
+        config: BitswapConfig,
+        store: S,
+        spawner: Option<Spawner>,
+        blocking: bool,
+    ) -> Self {
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        if config.enable_compat && P::MAX_BLOCK_SIZE > compat::MAX_BLOCK_SIZE {
+            tracing::warn!(
+                "MAX_BLOCK_SIZE of {} bytes exceeds the compat limit of {} bytes, larger \
+                 blocks can't be exchanged with compat peers",
+                P::MAX_BLOCK_SIZE,
+                compat::MAX_BLOCK_SIZE
+            );
+        }
         let mut rr_config = RequestResponseConfig::default();
         rr_config.set_connection_keep_alive(config.connection_keep_alive);
         rr_config.set_request_timeout(config.request_timeout);
-        let protocols = std::iter::once((BitswapProtocol, ProtocolSupport::Full));
-        let inner = RequestResponse::new(BitswapCodec::<P>::default(), protocols, rr_config);
-        let (db_tx, db_rx) = start_db_thread(store);
-        Self {
+        // compressed blocks are only read with the compression feature
+        #[cfg(feature = "compression")]
+        let capabilities = if config.compression.is_some() {
+            config.capabilities | Capabilities::COMPRESSION
+        } else {
+            config.capabilities
+        };
+        #[cfg(not(feature = "compression"))]
+        let capabilities =
+            Capabilities::from_bits(config.capabilities.bits() & !Capabilities::COMPRESSION.bits());
+        let config = BitswapConfig {
+            capabilities,
+            ..config
+        };
+        let protocols = vec![BitswapProtocol::V2, BitswapProtocol::V1];
+        let protocols = protocols
+            .into_iter()
+            .map(|protocol| (protocol, ProtocolSupport::Full));
+        let codec = BitswapCodec::<P>::new(config.codec_buffer_size)
+            .with_pool(config.codec_pool_size)
+            .with_chunk_size(config.chunk_size)
+            .with_capabilities(config.capabilities);
+        #[cfg(feature = "compression")]
+        let codec = codec.with_compression(config.compression);
+        let inner = RequestResponse::new(codec, protocols, rr_config);
+        #[cfg(feature = "spill")]
+        let (spill, spill_writer) = Spill::new();
+        #[cfg(any(test, feature = "test-util"))]
+        let inline = config.store_driver == StoreDriver::SyncInline;
+        #[cfg(not(any(test, feature = "test-util")))]
+        let inline = false;
+        // inline requests are serviced by a single worker in the order they were queued
+        let priority = if inline {
+            DbPriority::Fifo
+        } else if config.serve_priority {
+            DbPriority::Serves
+        } else if config.local_priority {
+            DbPriority::Local
+        } else {
+            DbPriority::Fifo
+        };
+        let db_workers = if inline { 1 } else { config.db_workers };
+        let workers: Vec<S> = (1..db_workers).map_while(|_| store.try_clone()).collect();
+        if workers.len() + 1 < db_workers {
+            tracing::warn!(
+                "store can't be cloned, using {} of {} db workers",
+                workers.len() + 1,
+                db_workers
+            );
+        }
+        let (db_tx, db_rx, dbs) = start_db(
+            store,
+            workers,
+            config.inbound_response_budget,
+            priority,
+            #[cfg(feature = "spill")]
+            spill.clone(),
+        );
+        // spilled blocks are written next to the db workers, off the swarm task
+        #[cfg(feature = "spill")]
+        let dbs: Vec<_> = dbs.into_iter().chain(Some(spill_writer)).collect();
+        let db_task = match config.store_driver {
+            StoreDriver::Thread => Spawner::run(spawner.as_ref(), dbs, blocking),
+            _ => Some(futures::future::join_all(dbs).map(drop).boxed()),
+        };
+        let hot_cids = config.hot_cid_tracking.map(HotCids::new);
+        let have_cache = config.have_cache.map(HaveCache::new);
+        let peer_failures = config.per_peer_failure_metrics.map(PeerFailures::new);
+        let uploads = config.max_upload_rate.map(Throttle::new);
+        let (wantlist_handle, wantlist_rx) = WantListHandle::new();
+        let bootstrap_peers = config.bootstrap_peers.clone();
+        let mut query_manager = QueryManager::default();
+        query_manager.set_provider_batch(config.provider_batch);
+        if let Some(max) = config.max_sync_gets {
+            query_manager.set_max_sync_gets(max);
+        }
+        let mut bitswap = Self {
+            config,
             inner,
-            query_manager: Default::default(),
+            query_manager,
+            sessions: Default::default(),
             requests: Default::default(),
+            mismatch_reported: false,
+            inflight: Default::default(),
+            peer_inflight: Default::default(),
+            peer_queues: Default::default(),
+            inbound_batches: Default::default(),
+            next_batch: 0,
+            deadlines: Default::default(),
+            timer: None,
+            query_timer: None,
+            counters: Default::default(),
+            counters_timer: None,
+            dial_retries: Default::default(),
+            timeout_retries: Default::default(),
+            retries: Default::default(),
+            limited_dials: Default::default(),
+            dial_queue: Default::default(),
+            queued_dials: Default::default(),
+            policy: None,
+            discovery: None,
+            discoveries: Default::default(),
+            ledgers: Default::default(),
+            rate_limiter: Default::default(),
+            deferred: Default::default(),
+            deferred_timer: None,
+            waiting: Default::default(),
+            wantlist: Default::default(),
+            wantlist_handle,
+            wantlist_rx,
+            waiting_deadlines: Default::default(),
+            waiting_timer: None,
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            announcements: Default::default(),
+            responses: Default::default(),
+            uploads,
+            #[cfg(feature = "spill")]
+            spill,
             db_tx,
             db_rx,
-            #[cfg(feature = "compat")]
+            db_task,
+            events: Default::default(),
+            want_history: Default::default(),
+            peer_wants: Default::default(),
+            connections: Default::default(),
+            capabilities: Default::default(),
+            support: Default::default(),
+            bootstrap: Default::default(),
+            bootstrap_dials: Default::default(),
+            bootstrap_redials: Default::default(),
+            bootstrap_probes: Default::default(),
+            circuits: Default::default(),
+            circuit_cooldowns: Default::default(),
+            circuit_probes: Default::default(),
+            hot_cids,
+            have_cache,
+            peer_failures,
+            decoder: None,
+            selectors: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
             compat: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_pending: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            next_compat_id: 0,
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_queue: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_queued_bytes: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_batches: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_wants: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_versions: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_dials: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_dial_queue: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            fallbacks: Default::default(),
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            compat_fallbacks: Default::default(),
+            #[cfg(feature = "chaos")]
+            faults: None,
+            #[cfg(feature = "chaos")]
+            delayed: Default::default(),
+            #[cfg(feature = "chaos")]
+            corrupt: Default::default(),
+        };
+        for (peer_id, addr) in bootstrap_peers {
+            if let Err(err) = bitswap.add_bootstrap_peer(peer_id, addr) {
+                tracing::warn!("ignoring bootstrap peer: {}", err);
+            }
         }
+        bitswap
+    }
+
+    /// Sets the store received blocks are spilled to when the insert backlog exceeds
+    /// `max_pending_insert_bytes`.
+    #[cfg(feature = "spill")]
+    pub fn set_spill_store(&mut self, store: impl SpillStore) {
+        self.spill.set_store(Box::new(store));
     }
 
-    /// Adds an address for a peer.
-    pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
+    /// Adds an address for a peer. A trailing `/p2p` component is stripped if it matches
+    /// `peer_id`. Addresses of another peer or without a transport are rejected unless
+    /// `BitswapConfig::lenient_addresses` is set.
+    pub fn add_address(
+        &mut self,
+        peer_id: &PeerId,
+        addr: Multiaddr,
+    ) -> std::result::Result<(), AddrError> {
+        let addr = self.validate_addr(peer_id, addr)?;
         self.inner.add_address(peer_id, addr);
+        Ok(())
     }
 
     /// Removes an address for a peer.
     pub fn remove_address(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
-        self.inner.remove_address(peer_id, addr);
+        let addr = normalize_addr(peer_id, addr.clone()).unwrap_or_else(|_| addr.clone());
+        self.inner.remove_address(peer_id, &addr);
+    }
+
+    /// Adds a bootstrap peer and dials it. The address is validated like in
+    /// [`Bitswap::add_address`].
+    ///
+    /// See [`BitswapConfig::bootstrap_peers`].
+    pub fn add_bootstrap_peer(
+        &mut self,
+        peer_id: PeerId,
+        addr: Multiaddr,
+    ) -> std::result::Result<(), AddrError> {
+        let addr = self.validate_addr(&peer_id, addr)?;
+        self.inner.add_address(&peer_id, addr.clone());
+        if self.bootstrap.insert(peer_id, addr) {
+            self.bootstrap_dials.push_back(peer_id);
+        }
+        Ok(())
+    }
+
+    /// Normalizes an address, keeping invalid ones unchanged in lenient mode.
+    fn validate_addr(
+        &self,
+        peer_id: &PeerId,
+        addr: Multiaddr,
+    ) -> std::result::Result<Multiaddr, AddrError> {
+        match normalize_addr(peer_id, addr.clone()) {
+            Err(err) if self.config.lenient_addresses => {
+                tracing::debug!("accepting invalid address of {}: {}", peer_id, err);
+                Ok(addr)
+            }
+            res => res,
+        }
+    }
+
+    /// Returns the connected peers a want is broadcast to, leaving out refused ones and
+    /// the ones known not to support bitswap.
+    fn broadcast_peers(&self) -> Vec<PeerId> {
+        self.connections
+            .keys()
+            .filter(|peer_id| self.peer_support(peer_id) != PeerSupport::Unsupported)
+            .filter(|peer_id| !self.refuses_peer(peer_id))
+            .copied()
+            .collect()
+    }
+
+    /// Adds the ready bootstrap peers to the providers of a query if there are fewer
+    /// than `min_providers`.
+    fn with_bootstrap_peers(&self, mut peers: Vec<PeerId>) -> Vec<PeerId> {
+        if peers.len() < self.config.min_providers {
+            for peer_id in self.bootstrap.ready() {
+                if !peers.contains(&peer_id) && !self.refuses_peer(&peer_id) {
+                    peers.push(peer_id);
+                }
+            }
+        }
+        peers
+    }
+
+    /// Rejects a new query if `BitswapConfig::max_active_queries` are in progress.
+    fn admit_query(&self) -> std::result::Result<(), QueryRejected> {
+        let max = self.config.max_active_queries;
+        if self.query_manager.active_queries() >= max {
+            QUERIES_REJECTED.inc();
+            tracing::debug!("rejecting query, {} queries are in progress", max);
+            return Err(QueryRejected::TooManyQueries(max));
+        }
+        Ok(())
+    }
+
+    /// Starts a get query with an initial guess of providers. Without providers the
+    /// connected peers are probed if `BitswapConfig::broadcast_wants` is set, then the
+    /// [`ProviderDiscovery`] is asked for some, if none is installed the query fails
+    /// with `Error::BlockNotFound`.
+    ///
+    /// Only the first `BitswapConfig::provider_batch` providers are pulled right away,
+    /// the rest is pulled in batches once every pulled provider failed. The iterator may
+    /// be unbounded, e.g. a stream of dht results. Fails without starting the query if
+    /// `BitswapConfig::max_active_queries` are in progress.
+    ///
+    /// A get of a block another get is already fetching joins that get instead of
+    /// fetching the block again. Both complete with their own id, and canceling one
+    /// doesn't affect the other.
+    pub fn get(
+        &mut self,
+        cid: Cid,
+        mut peers: impl Iterator<Item = PeerId> + Send + 'static,
+    ) -> std::result::Result<QueryId, QueryRejected> {
+        self.admit_query()?;
+        self.prune_support();
+        let first = peers
+            .by_ref()
+            .take(self.config.provider_batch.max(1))
+            .collect::<Vec<_>>();
+        let first = self.with_bootstrap_peers(first);
+        let (first, probes) = if first.is_empty() && self.config.broadcast_wants {
+            let connected = self.broadcast_peers();
+            BROADCAST_GETS.inc();
+            tracing::debug!("broadcasting want of {} to {} peers", cid, connected.len());
+            let probes = connected.len();
+            (connected, probes)
+        } else if first.len() > 1 && !first.iter().any(|peer| self.connections.contains_key(peer)) {
+            (first, self.config.provider_probes)
+        } else {
+            (first, 0)
+        };
+        let id = self
+            .query_manager
+            .get_probing(None, cid, first.into_iter().chain(peers), probes);
+        if let Some(timeout) = self.config.query_timeout {
+            self.set_query_timeout(id, timeout);
+        }
+        Ok(id)
+    }
+
+    /// Opens a session of related gets with an initial set of peers.
+    pub fn new_session(&mut self, peers: impl IntoIterator<Item = PeerId>) -> SessionId {
+        self.sessions.open(peers)
+    }
+
+    /// Closes a session. Gets started through it keep running. Returns `false` if the
+    /// session wasn't open.
+    pub fn close_session(&mut self, session: SessionId) -> bool {
+        self.sessions.close(session)
     }
 
-    /// Starts a get query with an initial guess of providers.
-    pub fn get(&mut self, cid: Cid, peers: impl Iterator<Item = PeerId>) -> QueryId {
-        self.query_manager.get(None, cid, peers)
+    /// Adds a peer to a session. Returns `false` if the session isn't open or already
+    /// has the peer.
+    pub fn add_session_peer(&mut self, session: SessionId, peer_id: PeerId) -> bool {
+        self.sessions.add_peer(session, peer_id)
     }
 
-    /// Starts a sync query with an the initial set of missing blocks.
+    /// Returns the peers of a session, the ones that delivered the most blocks first.
+    pub fn session_peers(&self, session: SessionId) -> Option<Vec<PeerId>> {
+        self.sessions.peers(session)
+    }
+
+    /// Starts a get query with the peers of a session. Once peers of the session
+    /// delivered blocks, only they are asked and the other peers are parked until they
+    /// failed. The peer the block is received from is credited to the session. Fails
+    /// without starting the query if the session isn't open or
+    /// `BitswapConfig::max_active_queries` are in progress.
+    pub fn session_get(
+        &mut self,
+        session: SessionId,
+        cid: Cid,
+    ) -> std::result::Result<QueryId, QueryRejected> {
+        let (useful, rest) = self
+            .sessions
+            .providers(session)
+            .ok_or(QueryRejected::UnknownSession(session))?;
+        let id = if useful.is_empty() {
+            self.get(cid, rest.into_iter())?
+        } else {
+            let id = self.get(cid, useful.into_iter())?;
+            self.query_manager.park(id, rest);
+            id
+        };
+        self.sessions.insert_query(id, session);
+        Ok(id)
+    }
+
+    /// Starts a sync query with an the initial set of missing blocks. Fails without
+    /// starting the query if `BitswapConfig::max_active_queries` are in progress.
+    ///
+    /// Strict and selective syncs and speculative prefetching decode received blocks,
+    /// so syncs need `Ipld` to implement `References` and `Decode` for the codecs of the
+    /// store params, as it does for `IpldCodec`.
     pub fn sync(
         &mut self,
         cid: Cid,
         peers: Vec<PeerId>,
         missing: impl Iterator<Item = Cid>,
-    ) -> QueryId {
-        self.query_manager.sync(cid, peers, missing)
+    ) -> std::result::Result<QueryId, QueryRejected>
+    where
+        Ipld: References<P::Codecs> + Decode<P::Codecs>,
+    {
+        self.sync_with_options(cid, peers, missing, SyncOptions::default())
+    }
+
+    /// Starts a sync query with an the initial set of missing blocks and custom options.
+    /// Fails without starting the query if `BitswapConfig::max_active_queries` are in
+    /// progress.
+    pub fn sync_with_options(
+        &mut self,
+        cid: Cid,
+        peers: Vec<PeerId>,
+        missing: impl Iterator<Item = Cid>,
+        options: SyncOptions,
+    ) -> std::result::Result<QueryId, QueryRejected>
+    where
+        Ipld: References<P::Codecs> + Decode<P::Codecs>,
+    {
+        self.admit_query()?;
+        self.decoder = Some(Decoder::new());
+        self.prune_support();
+        let peers = self.with_bootstrap_peers(peers);
+        let id = self.query_manager.sync(cid, peers, missing, options);
+        if let Some(timeout) = self.config.query_timeout {
+            self.set_query_timeout(id, timeout);
+        }
+        Ok(id)
+    }
+
+    /// Starts a sync query fetching the part of a dag `selector` selects. The blocks
+    /// the selector follows are decoded from the store, the missing blocks of subdags
+    /// it selects entirely are listed by `BitswapStore::missing_blocks`. Speculative
+    /// prefetching is disabled for the sync. Fails without starting the query if
+    /// `BitswapConfig::max_active_queries` are in progress.
+    pub fn sync_selected(
+        &mut self,
+        cid: Cid,
+        peers: Vec<PeerId>,
+        selector: Selector,
+        options: SyncOptions,
+    ) -> std::result::Result<QueryId, QueryRejected>
+    where
+        Ipld: References<P::Codecs> + Decode<P::Codecs>,
+    {
+        // the root is listed as missing if it isn't in the store
+        let id = self.sync_with_options(cid, peers, std::iter::empty(), options)?;
+        self.selectors.entry(id).or_default().insert(cid, selector);
+        Ok(id)
+    }
+
+    /// Sets the time after which an in progress get or sync query completes with
+    /// `Error::Timeout`, counted from now. Returns `false` if the query isn't in
+    /// progress or was started by a sync.
+    pub fn set_query_timeout(&mut self, id: QueryId, timeout: Duration) -> bool {
+        let res = self
+            .query_manager
+            .set_deadline(id, Instant::now() + timeout);
+        // the timer is armed for the new deadline on the next poll
+        self.query_timer = None;
+        res
+    }
+
+    /// Sets the priority of a get or sync query, so that an interactive get overtakes a
+    /// background sync. Requests of queries with a higher priority are sent first, the
+    /// default priority is zero. Set it right after starting the query to have its first
+    /// requests sent in order too. Returns `false` if the query isn't in progress or was
+    /// started by a sync.
+    pub fn set_query_priority(&mut self, id: QueryId, priority: i32) -> bool {
+        self.query_manager.set_priority(id, priority)
     }
 
-    /// Cancels an in progress query. Returns true if a query was cancelled.
+    /// Returns the progress of a get or sync query, or `None` if it completed.
+    pub fn query_info(&self, id: QueryId) -> Option<QueryInfo> {
+        self.query_manager.info(id)
+    }
+
+    /// Cancels an in progress query. Returns true if a query was cancelled, it then
+    /// completes with `Error::Cancelled`.
     pub fn cancel(&mut self, id: QueryId) -> bool {
+        self.sessions.remove_query(id);
+        self.selectors.remove(&id);
+        let stats = self.query_manager.stats(id).unwrap_or_default();
         let res = self.query_manager.cancel(id);
         if res {
             REQUESTS_CANCELED.inc();
+            let event = BitswapEvent::complete_with_stats(id, Err(Error::Cancelled), stats);
+            self.events.push_back(event);
         }
         res
     }
 
-    /// Registers prometheus metrics.
-    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
-        registry.register(Box::new(REQUESTS_TOTAL.clone()))?;
-        registry.register(Box::new(REQUEST_DURATION_SECONDS.clone()))?;
-        registry.register(Box::new(REQUESTS_CANCELED.clone()))?;
-        registry.register(Box::new(BLOCK_NOT_FOUND.clone()))?;
-        registry.register(Box::new(PROVIDERS_TOTAL.clone()))?;
-        registry.register(Box::new(MISSING_BLOCKS_TOTAL.clone()))?;
-        registry.register(Box::new(RECEIVED_BLOCK_BYTES.clone()))?;
-        registry.register(Box::new(RECEIVED_INVALID_BLOCK_BYTES.clone()))?;
-        registry.register(Box::new(SENT_BLOCK_BYTES.clone()))?;
-        registry.register(Box::new(RESPONSES_TOTAL.clone()))?;
-        registry.register(Box::new(THROTTLED_INBOUND.clone()))?;
-        registry.register(Box::new(THROTTLED_OUTBOUND.clone()))?;
-        registry.register(Box::new(OUTBOUND_FAILURE.clone()))?;
-        registry.register(Box::new(INBOUND_FAILURE.clone()))?;
-        Ok(())
+    /// Accepts blocks from `peer_id` without verifying their hash.
+    ///
+    /// See [`BitswapConfig::trusted_peers`].
+    pub fn add_trusted_peer(&mut self, peer_id: PeerId) {
+        self.config.trusted_peers.insert(peer_id);
     }
-}
 
-enum DbRequest<P: StoreParams> {
-    Bitswap(BitswapChannel, BitswapRequest),
-    Insert(Block<P>),
-    MissingBlocks(QueryId, Cid),
-}
+    /// Verifies blocks from `peer_id` again.
+    pub fn remove_trusted_peer(&mut self, peer_id: &PeerId) {
+        self.config.trusted_peers.remove(peer_id);
+    }
 
-enum DbResponse {
-    Bitswap(BitswapChannel, BitswapResponse),
-    MissingBlocks(QueryId, Result<Vec<Cid>>),
-}
+    /// Refuses `peer_id`: its requests are answered that we don't have the block and
+    /// queries no longer ask it, as if it failed. Requests already sent to it aren't
+    /// canceled.
+    ///
+    /// See [`BitswapConfig::blocked_peers`].
+    pub fn block_peer(&mut self, peer_id: PeerId) {
+        self.config.blocked_peers.insert(peer_id);
+    }
 
-fn start_db_thread<S: BitswapStore>(
-    mut store: S,
-) -> (
-    mpsc::UnboundedSender<DbRequest<S::Params>>,
-    mpsc::UnboundedReceiver<DbResponse>,
-) {
-    let (tx, requests) = mpsc::unbounded();
-    let (responses, rx) = mpsc::unbounded();
-    std::thread::spawn(move || {
-        let mut requests: mpsc::UnboundedReceiver<DbRequest<S::Params>> = requests;
-        while let Some(request) = futures::executor::block_on(requests.next()) {
-            match request {
-                DbRequest::Bitswap(channel, request) => {
-                    let response = match request.ty {
-                        RequestType::Have => {
-                            let have = store.contains(&request.cid).ok().unwrap_or_default();
-                            if have {
-                                RESPONSES_TOTAL.with_label_values(&["have"]).inc();
-                            } else {
-                                RESPONSES_TOTAL.with_label_values(&["dont_have"]).inc();
-                            }
-                            tracing::trace!("have {}", have);
-                            BitswapResponse::Have(have)
-                        }
-                        RequestType::Block => {
-                            let block = store.get(&request.cid).ok().unwrap_or_default();
-                            if let Some(data) = block {
-                                RESPONSES_TOTAL.with_label_values(&["block"]).inc();
-                                SENT_BLOCK_BYTES.inc_by(data.len() as u64);
-                                tracing::trace!("block {}", data.len());
-                                BitswapResponse::Block(data)
-                            } else {
-                                RESPONSES_TOTAL.with_label_values(&["dont_have"]).inc();
-                                tracing::trace!("have false");
-                                BitswapResponse::Have(false)
-                            }
-                        }
-                    };
-                    responses
-                        .unbounded_send(DbResponse::Bitswap(channel, response))
-                        .ok();
-                }
-                DbRequest::Insert(block) => {
-                    if let Err(err) = store.insert(&block) {
-                        tracing::error!("error inserting blocks {}", err);
-                    }
-                }
-                DbRequest::MissingBlocks(id, cid) => {
-                    let res = store.missing_blocks(&cid);
-                    responses
-                        .unbounded_send(DbResponse::MissingBlocks(id, res))
-                        .ok();
-                }
-            }
-        }
-    });
-    (tx, rx)
-}
+    /// Stops refusing `peer_id` unless it isn't allowed.
+    pub fn unblock_peer(&mut self, peer_id: &PeerId) {
+        self.config.blocked_peers.remove(peer_id);
+    }
 
-impl<P: StoreParams> Bitswap<P> {
-    /// Processes an incoming bitswap request.
-    fn inject_request(&mut self, channel: BitswapChannel, request: BitswapRequest) {
-        self.db_tx
-            .unbounded_send(DbRequest::Bitswap(channel, request))
-            .ok();
+    /// Refuses every peer except `peers` like a blocked one, replacing the previously
+    /// allowed peers.
+    ///
+    /// See [`BitswapConfig::allowed_peers`].
+    pub fn allow_peers(&mut self, peers: impl IntoIterator<Item = PeerId>) {
+        self.config.allowed_peers = Some(peers.into_iter().collect());
     }
 
-    /// Processes an incoming bitswap response.
-    fn inject_response(&mut self, id: BitswapId, peer: PeerId, response: BitswapResponse) {
-        if let Some(id) = self.requests.remove(&id) {
-            match response {
-                BitswapResponse::Have(have) => {
-                    self.query_manager
-                        .inject_response(id, Response::Have(peer, have));
-                }
-                BitswapResponse::Block(data) => {
-                    if let Some(info) = self.query_manager.query_info(id) {
-                        let len = data.len();
-                        if let Ok(block) = Block::new(info.cid, data) {
-                            RECEIVED_BLOCK_BYTES.inc_by(len as u64);
-                            self.db_tx.unbounded_send(DbRequest::Insert(block)).ok();
-                            self.query_manager
-                                .inject_response(id, Response::Block(peer, true));
-                        } else {
-                            tracing::error!("received invalid block");
-                            RECEIVED_INVALID_BLOCK_BYTES.inc_by(len as u64);
-                            self.query_manager
-                                .inject_response(id, Response::Block(peer, false));
-                        }
-                    }
+    /// Stops refusing peers that weren't allowed, only blocked peers are refused.
+    pub fn allow_all_peers(&mut self) {
+        self.config.allowed_peers = None;
+    }
+
+    /// Returns `true` if a peer is blocked or not allowed.
+    fn refuses_peer(&self, peer_id: &PeerId) -> bool {
+        self.config.blocked_peers.contains(peer_id)
+            || matches!(&self.config.allowed_peers, Some(allowed) if !allowed.contains(peer_id))
+    }
+
+    /// Installs a fault injector that decides how each outbound request is tampered
+    /// with. Only meant for testing.
+    #[cfg(feature = "chaos")]
+    pub fn set_fault_injector(&mut self, injector: impl FaultInjector) {
+        self.faults = Some(Box::new(injector));
+    }
+
+    /// Installs a policy deciding which inbound requests are served.
+    pub fn set_server_policy(&mut self, policy: impl ServerPolicy) {
+        self.policy = Some(Box::new(policy));
+    }
+
+    /// Installs a provider discovery asked for more providers by get queries that ran
+    /// out of them, see [`BitswapConfig::discovery_rounds`]. Gets started without
+    /// providers ask it once even if no rounds are configured.
+    pub fn set_provider_discovery(&mut self, discovery: impl ProviderDiscovery) {
+        self.discovery = Some(Box::new(discovery));
+        self.query_manager
+            .set_discovery_rounds(self.config.discovery_rounds);
+    }
+
+    /// Starts looking up providers of a get query.
+    fn discover(&mut self, id: QueryId, cid: Cid) {
+        if let Some(discovery) = self.discovery.as_mut() {
+            let providers = discovery.discover(cid);
+            self.discoveries.push(Box::pin(async move {
+                (id, providers.await.unwrap_or_default())
+            }));
+        } else {
+            self.query_manager
+                .inject_response(id, Response::Providers(vec![]));
+        }
+    }
+
+    /// Adds the addresses of discovered providers and hands the providers to their
+    /// query.
+    fn receive_providers(&mut self, id: QueryId, discovered: DiscoveredProviders) {
+        let label = if discovered.is_empty() {
+            "empty"
+        } else {
+            "found"
+        };
+        PROVIDER_DISCOVERIES.with_label_values(&[label]).inc();
+        let mut providers = Vec::with_capacity(discovered.len());
+        for (peer_id, addrs) in discovered {
+            for addr in addrs {
+                if let Err(err) = self.add_address(&peer_id, addr) {
+                    tracing::debug!("dropping discovered address: {}", err);
                 }
             }
+            providers.push(peer_id);
         }
+        self.query_manager
+            .inject_response(id, Response::Providers(providers));
     }
 
-    fn inject_outbound_failure(
-        &mut self,
-        peer: &PeerId,
-        request_id: RequestId,
-        error: &OutboundFailure,
-    ) {
-        tracing::debug!(
-            "bitswap outbound failure {} {} {:?}",
-            peer,
-            request_id,
-            error
-        );
-        match error {
-            OutboundFailure::DialFailure => {
-                OUTBOUND_FAILURE.with_label_values(&["dial_failure"]).inc();
-            }
-            OutboundFailure::Timeout => {
-                OUTBOUND_FAILURE.with_label_values(&["timeout"]).inc();
-            }
-            OutboundFailure::ConnectionClosed => {
-                OUTBOUND_FAILURE
-                    .with_label_values(&["connection_closed"])
-                    .inc();
-            }
-            OutboundFailure::UnsupportedProtocols => {
-                OUTBOUND_FAILURE
-                    .with_label_values(&["unsupported_protocols"])
-                    .inc();
+    /// Appends every input of the query manager and every event it emits to `writer`,
+    /// so that a session can be fed back through `replay` with the `test-util` feature.
+    /// Block data isn't recorded, only whether a block was valid. Each record is written
+    /// with a single `write_all` from the swarm task; recording stops if a write fails.
+    pub fn record_queries(&mut self, writer: impl std::io::Write + Send + 'static) {
+        self.query_manager.set_recorder(Recorder::new(writer));
+    }
+
+    /// Returns the bytes exchanged with a peer.
+    pub fn ledger(&self, peer_id: &PeerId) -> Option<&Ledger> {
+        self.ledgers.get(peer_id)
+    }
+
+    /// Moves the watermark of a peer to `bytes` served bytes, for example after the
+    /// peer paid for them, and reevaluates the requests deferred for the peer.
+    pub fn set_peer_watermark(&mut self, peer_id: PeerId, bytes: u64) {
+        self.ledgers.entry(peer_id).or_default().watermark = bytes;
+        self.reevaluate_deferred(&peer_id);
+    }
+
+    /// Reevaluates the requests deferred for a peer after its ledger changed.
+    fn reevaluate_deferred(&mut self, peer_id: &PeerId) {
+        if let Some(deferred) = self.deferred.remove(peer_id) {
+            for deferred in deferred {
+                self.evaluate_request(deferred);
             }
         }
     }
 
-    fn inject_inbound_failure(
-        &mut self,
-        peer: &PeerId,
-        request_id: RequestId,
-        error: &InboundFailure,
-    ) {
-        tracing::error!(
-            "bitswap inbound failure {} {} {:?}",
-            peer,
-            request_id,
-            error
-        );
-        match error {
-            InboundFailure::Timeout => {
-                INBOUND_FAILURE.with_label_values(&["timeout"]).inc();
-            }
-            InboundFailure::ConnectionClosed => {
-                INBOUND_FAILURE
-                    .with_label_values(&["connection_closed"])
-                    .inc();
-            }
-            InboundFailure::UnsupportedProtocols => {
-                INBOUND_FAILURE
-                    .with_label_values(&["unsupported_protocols"])
-                    .inc();
-            }
-            InboundFailure::ResponseOmission => {
-                INBOUND_FAILURE
-                    .with_label_values(&["response_omission"])
-                    .inc();
-            }
+    /// Returns the capabilities a connected peer advertised, or `None` if no message
+    /// was exchanged with it yet. Peers speaking `/ipfs-embed/bitswap/1.0.0` have no
+    /// capabilities.
+    pub fn peer_capabilities(&self, peer_id: &PeerId) -> Option<Capabilities> {
+        self.capabilities.get(peer_id).copied()
+    }
+
+    /// Returns the capabilities both we and a connected peer support.
+    pub fn negotiated_capabilities(&self, peer_id: &PeerId) -> Capabilities {
+        self.peer_capabilities(peer_id)
+            .map(|caps| caps.intersection(self.config.capabilities))
+            .unwrap_or_default()
+    }
+
+    /// Returns whether a peer serves our requests. Peers that are known not to support
+    /// bitswap are only asked for a block if no other provider has it.
+    pub fn peer_support(&self, peer_id: &PeerId) -> PeerSupport {
+        match self.support.get(peer_id) {
+            Some((support, at)) if at.elapsed() < self.config.protocol_support_ttl => *support,
+            _ => PeerSupport::Unknown,
+        }
+    }
+
+    /// Returns the state of the circuit breaker of a peer. Always closed unless
+    /// `BitswapConfig::circuit_breaker` is set.
+    pub fn peer_circuit(&self, peer_id: &PeerId) -> CircuitState {
+        self.circuits.state(peer_id)
+    }
+
+    /// Returns how often requests to a peer fell back to the compat protocol because it
+    /// didn't support bitswap and how the fallbacks ended.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub fn compat_fallbacks(&self, peer_id: &PeerId) -> CompatFallbacks {
+        self.compat_fallbacks
+            .get(peer_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the compat version last negotiated with a connected peer, `None` if no
+    /// compat message was exchanged with it.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub fn compat_version(&self, peer_id: &PeerId) -> Option<CompatVersion> {
+        self.compat_versions.get(peer_id).copied()
+    }
+
+    /// Returns up to `top_n` cids with the most bytes served, hottest first. Empty unless
+    /// `BitswapConfig::hot_cid_tracking` is set.
+    pub fn hot_cids(&self, top_n: usize) -> Vec<(Cid, u64)> {
+        self.hot_cids
+            .as_ref()
+            .map(|hot_cids| hot_cids.top(top_n))
+            .unwrap_or_default()
+    }
+
+    /// Forgets that a block was served, so that the next have request for it asks the
+    /// store again, see `BitswapConfig::have_cache`.
+    pub fn uncache_have(&mut self, cid: &Cid) {
+        if let Some(cache) = self.have_cache.as_mut() {
+            cache.remove(cid);
+        }
+    }
+
+    /// Forgets all served blocks, e.g. once `BitswapStore::can_serve` changed for many
+    /// of them, see `BitswapConfig::have_cache`.
+    pub fn clear_have_cache(&mut self) {
+        if let Some(cache) = self.have_cache.as_mut() {
+            cache.clear();
+        }
+    }
+
+    /// Returns a handle for registering wants, for example from prefetch logic that
+    /// wants to wait for blocks bitswap is already fetching.
+    pub fn wantlist_handle(&self) -> WantListHandle {
+        self.wantlist_handle.clone()
+    }
+
+    /// Notifies the behaviour that a block was put into the store by someone else.
+    /// Answers requests held for the block and resolves the wants registered for it.
+    pub fn notify_block_stored(&mut self, cid: &Cid) {
+        self.block_arrived(cid);
+    }
+
+    /// Notifies the behaviour that blocks were put into the store by someone else, like
+    /// `notify_block_stored`. Compat peers that asked for one of the blocks before we
+    /// had it and didn't cancel the want are sent the block, or a have if they only
+    /// asked whether we have it. The server policy applies as to any request.
+    pub fn notify_new_blocks(&mut self, cids: impl Iterator<Item = Cid>) {
+        for cid in cids {
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            self.notify_compat_wants(cid);
+            self.block_arrived(&cid);
         }
     }
+
+    /// Returns the latency and bandwidth estimate of a peer.
+    pub fn peer_estimate(&self, peer_id: &PeerId) -> Option<&PeerEstimate> {
+        self.query_manager.peer_estimate(peer_id)
+    }
+
+    /// Returns the hit rate of a peer. Gets request blocks from the providers with the
+    /// lowest expected latency, see `peer_estimate`, relative to their hit rate first.
+    pub fn peer_score(&self, peer_id: &PeerId) -> Option<&PeerScore> {
+        self.query_manager.peer_score(peer_id)
+    }
+
+    /// Returns the blocks a connected peer currently wants from us, in no particular
+    /// order. Wants of native peers end with our response. Compat peers keep wanting a
+    /// block we don't have until they cancel it or we send it. At most 1024 wants are
+    /// tracked per peer.
+    pub fn peer_wantlist(&self, peer_id: &PeerId) -> Vec<(Cid, RequestType)> {
+        self.peer_wants.wantlist(peer_id)
+    }
+
+    /// Returns the get and sync queries in progress, ordered by id, for example to find
+    /// out which syncs are stuck.
+    pub fn queries(&self) -> impl Iterator<Item = ActiveQuery> {
+        self.query_manager.queries().into_iter()
+    }
+
+    /// Returns the blocks we currently want from other peers with the get or sync
+    /// queries they are fetched for, ordered by cid. A block is listed while a have or
+    /// block request for it is outstanding.
+    pub fn local_wantlist(&self) -> Vec<(Cid, QueryId)> {
+        self.query_manager.wantlist()
+    }
+
+    /// Returns the recently answered inbound wants, oldest first.
+    pub fn want_history(&self) -> impl Iterator<Item = &WantRecord> + '_ {
+        self.want_history.iter()
+    }
+
+    /// Returns the recently answered inbound wants for a cid, oldest first.
+    pub fn want_history_for_cid<'a>(
+        &'a self,
+        cid: &'a Cid,
+    ) -> impl Iterator<Item = &'a WantRecord> + 'a {
+        self.want_history().filter(move |record| record.cid == *cid)
+    }
+
+    /// Returns the recently answered inbound wants of a peer, oldest first.
+    pub fn want_history_for_peer<'a>(
+        &'a self,
+        peer_id: &'a PeerId,
+    ) -> impl Iterator<Item = &'a WantRecord> + 'a {
+        self.want_history()
+            .filter(move |record| record.peer == *peer_id)
+    }
+
+    /// Registers prometheus metrics. If registration fails partway through, the metrics
+    /// registered so far are unregistered again so the call can be retried.
+    pub fn register_metrics(&self, registry: &Registry) -> std::result::Result<(), Error> {
+        crate::stats::register(registry, self.peer_failures.is_some())?;
+        Ok(())
+    }
+
+    /// Unregisters the prometheus metrics, e.g. before registering another `Bitswap`
+    /// with the same registry.
+    pub fn unregister_metrics(&self, registry: &Registry) {
+        crate::stats::unregister(registry);
+    }
 }
 
-impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
-    #[cfg(not(feature = "compat"))]
-    type ConnectionHandler =
-        <RequestResponse<BitswapCodec<P>> as NetworkBehaviour>::ConnectionHandler;
+enum DbRequest<P: StoreParams> {
+    Bitswap(BitswapChannel, BitswapRequest, Instant),
+    /// Serves a request held until the insert of its block was queued. It is queued
+    /// with our own requests, so that it is handled after that insert.
+    Released(BitswapChannel, BitswapRequest, Instant),
+    Insert(Block<P>, InsertHint),
+    /// Inserts the block of a strict sync and checks its references.
+    InsertStrict(
+        Block<P>,
+        InsertHint,
+        fn(&Block<P>) -> std::result::Result<(), InvalidReferences>,
+    ),
+    MissingBlocks(QueryId, Cid),
+    /// Lists the missing blocks a selector selects, decoding the blocks it follows.
+    SelectBlocks(QueryId, Cid, Selector, fn(&Block<P>) -> Result<Ipld>),
+    Available(Cid),
+    /// Acknowledges the insert of the block of a get query once it is in the store. The
+    /// peer is `None` for inline blocks.
+    InsertDone(QueryId, Option<PeerId>, Cid),
+}
 
-    #[cfg(feature = "compat")]
-    #[allow(clippy::type_complexity)]
-    type ConnectionHandler = ConnectionHandlerSelect<
-        <RequestResponse<BitswapCodec<P>> as NetworkBehaviour>::ConnectionHandler,
-        OneShotHandler<CompatProtocol, CompatMessage, InboundMessage>,
-    >;
-    type OutEvent = BitswapEvent;
+impl<P: StoreParams> DbRequest<P> {
+    /// Returns `true` unless the request is a serve the other workers may handle.
+    fn is_local(&self) -> bool {
+        !matches!(self, Self::Bitswap(_, _, _))
+    }
 
-    fn new_handler(&mut self) -> Self::ConnectionHandler {
-        #[cfg(not(feature = "compat"))]
-        return self.inner.new_handler();
-        #[cfg(feature = "compat")]
-        ConnectionHandler::select(self.inner.new_handler(), OneShotHandler::default())
+    /// Lane of the request in the db queue.
+    fn lane(&self) -> DbLane {
+        match self {
+            Self::Bitswap(_, request, _) => match request.ty {
+                RequestType::Have => DbLane::Have,
+                RequestType::Block => DbLane::Block,
+            },
+            Self::MissingBlocks(_, _) | Self::SelectBlocks(_, _, _, _) => DbLane::Traversal,
+            // the serves of the other lanes may be handled before our inserts
+            _ => DbLane::Local,
+        }
     }
 
-    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
-        self.inner.addresses_of_peer(peer_id)
+    /// Priority of a serve, `None` for our own requests, which keep their order.
+    fn priority(&self) -> Option<i32> {
+        match self {
+            Self::Bitswap(_, request, _) => Some(request.priority),
+            _ => None,
+        }
     }
 
-    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
-        match event {
-            FromSwarm::ConnectionEstablished(ev) => self
-                .inner
-                .on_swarm_event(FromSwarm::ConnectionEstablished(ev)),
-            FromSwarm::ConnectionClosed(ConnectionClosed {
-                peer_id,
-                connection_id,
-                endpoint,
-                handler,
-                remaining_established,
-            }) => {
-                #[cfg(feature = "compat")]
-                if remaining_established == 0 {
-                    self.compat.remove(&peer_id);
-                }
-                #[cfg(feature = "compat")]
-                let (handler, _oneshot) = handler.into_inner();
-                self.inner
-                    .on_swarm_event(FromSwarm::ConnectionClosed(ConnectionClosed {
-                        peer_id,
-                        connection_id,
-                        endpoint,
-                        handler,
-                        remaining_established,
-                    }));
-            }
-            FromSwarm::DialFailure(DialFailure {
-                peer_id,
-                handler,
-                error,
-            }) => {
-                #[cfg(feature = "compat")]
-                let (handler, _oneshot) = handler.into_inner();
-                self.inner
-                    .on_swarm_event(FromSwarm::DialFailure(DialFailure {
-                        peer_id,
-                        handler,
-                        error,
-                    }));
-            }
-            FromSwarm::AddressChange(ev) => self.inner.on_swarm_event(FromSwarm::AddressChange(ev)),
-            FromSwarm::ListenFailure(ListenFailure {
-                local_addr,
-                send_back_addr,
-                handler,
-            }) => {
-                #[cfg(feature = "compat")]
-                let (handler, _oneshot) = handler.into_inner();
-                self.inner
-                    .on_swarm_event(FromSwarm::ListenFailure(ListenFailure {
-                        local_addr,
-                        send_back_addr,
-                        handler,
-                    }));
-            }
-            FromSwarm::NewListener(ev) => self.inner.on_swarm_event(FromSwarm::NewListener(ev)),
-            FromSwarm::NewListenAddr(ev) => self.inner.on_swarm_event(FromSwarm::NewListenAddr(ev)),
-            FromSwarm::ExpiredListenAddr(ev) => {
-                self.inner.on_swarm_event(FromSwarm::ExpiredListenAddr(ev))
-            }
-            FromSwarm::ListenerError(ev) => self.inner.on_swarm_event(FromSwarm::ListenerError(ev)),
-            FromSwarm::ListenerClosed(ev) => {
-                self.inner.on_swarm_event(FromSwarm::ListenerClosed(ev))
-            }
-            FromSwarm::NewExternalAddr(ev) => {
-                self.inner.on_swarm_event(FromSwarm::NewExternalAddr(ev))
-            }
-            FromSwarm::ExpiredExternalAddr(ev) => self
-                .inner
-                .on_swarm_event(FromSwarm::ExpiredExternalAddr(ev)),
+    /// Label of the request in the db metrics.
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Bitswap(_, _, _) | Self::Released(_, _, _) => "serve",
+            Self::Insert(_, _) | Self::InsertStrict(_, _, _) => "insert",
+            Self::MissingBlocks(_, _) | Self::SelectBlocks(_, _, _, _) => "missing_blocks",
+            Self::Available(_) => "available",
+            Self::InsertDone(_, _, _) => "insert_done",
         }
     }
+}
 
-    fn on_connection_handler_event(
-        &mut self,
-        peer_id: PeerId,
-        conn: ConnectionId,
-        event: <Self::ConnectionHandler as ConnectionHandler>::OutEvent,
-    ) {
-        tracing::trace!(?event, "on_connection_handler_event");
-        #[cfg(not(feature = "compat"))]
-        return self.inner.on_connection_handler_event(peer_id, conn, event);
-        #[cfg(feature = "compat")]
-        match event {
-            EitherOutput::First(event) => {
-                self.inner.on_connection_handler_event(peer_id, conn, event)
-            }
-            EitherOutput::Second(msg) => {
-                for msg in msg.0 {
-                    match msg {
-                        CompatMessage::Request(req) => {
-                            tracing::trace!("received compat request");
-                            self.inject_request(BitswapChannel::Compat(peer_id, req.cid), req);
-                        }
-                        CompatMessage::Response(cid, res) => {
-                            tracing::trace!("received compat response");
-                            self.inject_response(BitswapId::Compat(cid), peer_id, res);
-                        }
-                    }
-                }
-            }
+/// Number of requests of the preferred lanes the db thread handles in a row while the
+/// others are pending.
+const BURST: usize = 3;
+
+/// Lane of a request in the db queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DbLane {
+    /// Have requests of other peers.
+    Have,
+    /// Block requests of other peers.
+    Block,
+    /// Our own inserts and the requests reading the blocks inserted before them.
+    Local,
+    /// Missing blocks of syncs, listed after the inserts queued before them.
+    Traversal,
+}
+
+/// Which requests the db thread handles first.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DbPriority {
+    /// In the order they were queued.
+    Fifo,
+    /// Our own requests.
+    Local,
+    /// Serves of other peers.
+    Serves,
+}
+
+/// Backlog of the db thread.
+///
+/// Unless requests are handled in order, each lane is queued separately. The lanes of
+/// our own requests keep them in order, since missing blocks and insert
+/// acknowledgments read the blocks inserted before them. Serves with a priority are
+/// queued ahead of the serves with a lower one.
+struct DbQueue<T> {
+    priority: DbPriority,
+    lanes: [VecDeque<(Option<i32>, T)>; 4],
+    burst: usize,
+}
+
+impl<T> DbQueue<T> {
+    fn new(priority: DbPriority) -> Self {
+        Self {
+            priority,
+            lanes: Default::default(),
+            burst: 0,
         }
     }
 
-    fn poll(
-        &mut self,
-        cx: &mut Context,
-        pp: &mut impl PollParameters,
-    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
-        let mut exit = false;
-        while !exit {
-            exit = true;
-            while let Poll::Ready(Some(response)) = Pin::new(&mut self.db_rx).poll_next(cx) {
-                exit = false;
-                match response {
-                    DbResponse::Bitswap(channel, response) => match channel {
-                        BitswapChannel::Bitswap(channel) => {
-                            self.inner.send_response(channel, response).ok();
-                        }
-                        #[cfg(feature = "compat")]
-                        BitswapChannel::Compat(peer_id, cid) => {
-                            let compat = CompatMessage::Response(cid, response);
-                            return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
-                                peer_id,
-                                handler: NotifyHandler::Any,
-                                event: EitherOutput::Second(compat),
-                            });
-                        }
-                    },
-                    DbResponse::MissingBlocks(id, res) => match res {
-                        Ok(missing) => {
-                            MISSING_BLOCKS_TOTAL.inc_by(missing.len() as u64);
-                            self.query_manager
-                                .inject_response(id, Response::MissingBlocks(missing));
-                        }
-                        Err(err) => {
-                            self.query_manager.cancel(id);
-                            let event = BitswapEvent::Complete(id, Err(err));
-                            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
-                        }
-                    },
+    fn is_empty(&self) -> bool {
+        self.lanes.iter().all(VecDeque::is_empty)
+    }
+
+    /// Queues a request behind the requests of its lane with the same or a higher
+    /// priority. Requests without a priority are queued at the end of their lane.
+    fn push(&mut self, request: T, lane: DbLane, priority: Option<i32>) {
+        let lane = match self.priority {
+            DbPriority::Fifo => DbLane::Have,
+            _ => lane,
+        };
+        let lane = &mut self.lanes[lane as usize];
+        let index = priority
+            .and_then(|priority| {
+                lane.iter()
+                    .position(|(queued, _)| matches!(queued, Some(queued) if *queued < priority))
+            })
+            .unwrap_or(lane.len());
+        lane.insert(index, (priority, request));
+    }
+
+    fn is_pending(&self, lanes: [DbLane; 2]) -> bool {
+        lanes
+            .iter()
+            .any(|lane| !self.lanes[*lane as usize].is_empty())
+    }
+
+    /// Pops the first request of the first lane that isn't empty.
+    fn pop_lanes(&mut self, lanes: [DbLane; 2]) -> Option<T> {
+        lanes
+            .iter()
+            .find_map(|lane| self.lanes[*lane as usize].pop_front())
+            .map(|(_, request)| request)
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let local = [DbLane::Local, DbLane::Traversal];
+        let serves = [DbLane::Have, DbLane::Block];
+        let (preferred, other) = match self.priority {
+            DbPriority::Serves => (serves, local),
+            _ => (local, serves),
+        };
+        if self.is_pending(preferred) && (self.burst < BURST || !self.is_pending(other)) {
+            self.burst += 1;
+            self.pop_lanes(preferred)
+        } else {
+            self.burst = 0;
+            self.pop_lanes(other)
+        }
+    }
+}
+
+/// Request to the db together with the time it was queued.
+type Queued<P> = (DbRequest<P>, Instant);
+
+/// Serves shared by the workers other than the first, queued by lane and priority like
+/// the requests of the first worker.
+struct ServeQueue<T> {
+    requests: mpsc::UnboundedReceiver<T>,
+    queue: DbQueue<T>,
+}
+
+impl<T> ServeQueue<T> {
+    /// Queues the serves received so far. Returns `false` once the channel is closed.
+    fn receive(&mut self, key: impl Fn(&T) -> (DbLane, Option<i32>)) -> bool {
+        loop {
+            match self.requests.try_next() {
+                Ok(Some(request)) => {
+                    let (lane, priority) = key(&request);
+                    self.queue.push(request, lane, priority);
                 }
+                Ok(None) => return false,
+                Err(_) => return true,
             }
-            while let Some(query) = self.query_manager.next() {
-                exit = false;
-                match query {
-                    QueryEvent::Request(id, req) => match req {
-                        Request::Have(peer_id, cid) => {
-                            let req = BitswapRequest {
-                                ty: RequestType::Have,
-                                cid,
-                            };
-                            let rid = self.inner.send_request(&peer_id, req);
-                            self.requests.insert(BitswapId::Bitswap(rid), id);
-                        }
-                        Request::Block(peer_id, cid) => {
-                            let req = BitswapRequest {
-                                ty: RequestType::Block,
-                                cid,
-                            };
-                            let rid = self.inner.send_request(&peer_id, req);
-                            self.requests.insert(BitswapId::Bitswap(rid), id);
-                        }
-                        Request::MissingBlocks(cid) => {
-                            self.db_tx
-                                .unbounded_send(DbRequest::MissingBlocks(id, cid))
-                                .ok();
-                        }
-                    },
-                    QueryEvent::Progress(id, missing) => {
-                        let event = BitswapEvent::Progress(id, missing);
-                        return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
-                    }
-                    QueryEvent::Complete(id, res) => {
-                        if res.is_err() {
-                            BLOCK_NOT_FOUND.inc();
-                        }
-                        let event = BitswapEvent::Complete(
-                            id,
-                            res.map_err(|cid| BlockNotFound(cid).into()),
-                        );
-                        return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+    }
+}
+
+/// Sends requests to the db workers.
+struct DbSender<P: StoreParams> {
+    /// Queue of the first worker.
+    local: mpsc::UnboundedSender<Queued<P>>,
+    /// Queue shared by the other workers, if there are any.
+    serves: Option<mpsc::UnboundedSender<Queued<P>>>,
+}
+
+impl<P: StoreParams> DbSender<P> {
+    /// Queues a request. Serves go to the other workers unless `local` is set.
+    fn send(&self, request: DbRequest<P>, local: bool) {
+        let tx = match &self.serves {
+            Some(serves) if !local && !request.is_local() => serves,
+            _ => &self.local,
+        };
+        tx.unbounded_send((request, Instant::now())).ok();
+    }
+}
+
+enum DbResponse {
+    Bitswap(BitswapChannel, RequestType, BitswapResponse, Instant),
+    MissingBlocks(QueryId, Result<Vec<Cid>>),
+    SelectedBlocks(QueryId, Result<Vec<(Cid, Selector)>>),
+    InvalidReferences(QueryId, InvalidReferences),
+    Available(Cid),
+    InsertDone(QueryId, Option<PeerId>, bool),
+}
+
+/// Returns the response to a block query, or to an inline query without a peer.
+fn block_response(peer_id: Option<PeerId>, block: bool) -> Response {
+    match peer_id {
+        Some(peer_id) => Response::Block(peer_id, block),
+        None => Response::Inline(block),
+    }
+}
+
+/// Returns the type of the requests of a subquery.
+fn request_type(label: &str) -> RequestType {
+    match label {
+        "have" | "probe" => RequestType::Have,
+        "block" => RequestType::Block,
+        _ => unreachable!(),
+    }
+}
+
+/// Answers that we don't have a block that is too large for a compat message, instead
+/// of failing to send it.
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+fn fit_compat(cid: &Cid, response: BitswapResponse) -> BitswapResponse {
+    match response {
+        BitswapResponse::Block(data) if data.len() > compat::MAX_BLOCK_SIZE => {
+            tracing::debug!(
+                "block {} of {} bytes is too large for compat peers",
+                cid,
+                data.len()
+            );
+            COMPAT_UNSERVEABLE_BLOCKS.inc();
+            BitswapResponse::Have(false)
+        }
+        response => response,
+    }
+}
+
+/// Returns the metric label of a response.
+fn response_type(response: &BitswapResponse) -> &'static str {
+    match response {
+        BitswapResponse::Have(_) => "have",
+        BitswapResponse::Block(_) => "block",
+    }
+}
+
+/// Returns the links of a block, or none if they don't decode.
+fn block_references<P: StoreParams>(block: &Block<P>) -> Vec<Cid>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut links = vec![];
+    if block.references(&mut links).is_err() {
+        // the store rejects the block once it is inserted
+        links.clear();
+    }
+    links
+}
+
+/// Checks that the references of a block decode and use supported codecs and hashes.
+fn check_references<P: StoreParams>(block: &Block<P>) -> std::result::Result<(), InvalidReferences>
+where
+    Ipld: References<P::Codecs>,
+{
+    let invalid = |link, reason| InvalidReferences {
+        cid: *block.cid(),
+        link,
+        reason,
+    };
+    let mut links = vec![];
+    block
+        .references(&mut links)
+        .map_err(|err| invalid(None, err.to_string()))?;
+    for link in links {
+        if P::Codecs::try_from(link.codec()).is_err() {
+            let reason = format!("link {} has unsupported codec {:#x}", link, link.codec());
+            return Err(invalid(Some(link), reason));
+        }
+        if P::Hashes::try_from(link.hash().code()).is_err() {
+            let reason = format!(
+                "link {} has unsupported multihash {:#x}",
+                link,
+                link.hash().code()
+            );
+            return Err(invalid(Some(link), reason));
+        }
+    }
+    Ok(())
+}
+
+/// Decodes blocks with the codecs of the store params. Only the sync entry points need
+/// the bounds, so the behaviour can be created for codecs that `Ipld` can't decode.
+struct Decoder<P: StoreParams> {
+    /// Returns the links of a block for speculative prefetching.
+    references: fn(&Block<P>) -> Vec<Cid>,
+    /// Checks the references of a block of a strict sync.
+    check: fn(&Block<P>) -> std::result::Result<(), InvalidReferences>,
+    /// Decodes the blocks a selector follows.
+    ipld: fn(&Block<P>) -> Result<Ipld>,
+}
+
+impl<P: StoreParams> Decoder<P> {
+    fn new() -> Self
+    where
+        Ipld: References<P::Codecs> + Decode<P::Codecs>,
+    {
+        Self {
+            references: block_references::<P>,
+            check: check_references::<P>,
+            ipld: Block::<P>::ipld,
+        }
+    }
+}
+
+impl<P: StoreParams> Clone for Decoder<P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P: StoreParams> Copy for Decoder<P> {}
+
+/// Bytes of spilled blocks read from the spill store at a time.
+#[cfg(feature = "spill")]
+const SPILL_DRAIN_BYTES: usize = 1 << 20;
+
+/// Inserts the spilled blocks into the store, verifying them again. The blocks are read
+/// in batches of `SPILL_DRAIN_BYTES`, since the spill store can't be held across an
+/// await and shouldn't be read into memory at once.
+#[cfg(feature = "spill")]
+async fn drain_spill<S: AsyncBitswapStore>(spill: &Spill, store: &mut S) {
+    loop {
+        let blocks = spill.drain(SPILL_DRAIN_BYTES);
+        if blocks.is_empty() {
+            break;
+        }
+        for (cid, data) in blocks {
+            match Block::<S::Params>::new(cid, data) {
+                Ok(block) => {
+                    if let Err(err) = store.insert(&block).await {
+                        tracing::error!("error inserting blocks {}", err);
                     }
                 }
+                Err(err) => tracing::warn!("dropping invalid spilled block: {}", err),
             }
-            while let Poll::Ready(event) = self.inner.poll(cx, pp) {
-                exit = false;
-                let event = match event {
-                    NetworkBehaviourAction::GenerateEvent(event) => event,
-                    NetworkBehaviourAction::Dial { opts, handler } => {
-                        #[cfg(feature = "compat")]
-                        let handler = ConnectionHandler::select(handler, Default::default());
-                        return Poll::Ready(NetworkBehaviourAction::Dial { opts, handler });
-                    }
-                    NetworkBehaviourAction::NotifyHandler {
-                        peer_id,
-                        handler,
-                        event,
-                    } => {
-                        return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
-                            peer_id,
-                            handler,
-                            #[cfg(not(feature = "compat"))]
-                            event,
-                            #[cfg(feature = "compat")]
-                            event: EitherOutput::First(event),
-                        });
-                    }
-                    NetworkBehaviourAction::ReportObservedAddr { address, score } => {
-                        return Poll::Ready(NetworkBehaviourAction::ReportObservedAddr {
-                            address,
-                            score,
-                        });
-                    }
-                    NetworkBehaviourAction::CloseConnection {
-                        peer_id,
-                        connection,
-                    } => {
-                        return Poll::Ready(NetworkBehaviourAction::CloseConnection {
-                            peer_id,
-                            connection,
-                        });
-                    }
-                };
-                match event {
-                    RequestResponseEvent::Message { peer, message } => match message {
-                        RequestResponseMessage::Request {
-                            request_id: _,
-                            request,
-                            channel,
-                        } => self.inject_request(BitswapChannel::Bitswap(channel), request),
-                        RequestResponseMessage::Response {
-                            request_id,
-                            response,
-                        } => self.inject_response(BitswapId::Bitswap(request_id), peer, response),
-                    },
-                    RequestResponseEvent::ResponseSent { .. } => {}
-                    RequestResponseEvent::OutboundFailure {
-                        peer,
-                        request_id,
-                        error,
-                    } => {
-                        self.inject_outbound_failure(&peer, request_id, &error);
-                        #[cfg(feature = "compat")]
-                        if let OutboundFailure::UnsupportedProtocols = error {
-                            if let Some(id) = self.requests.remove(&BitswapId::Bitswap(request_id))
-                            {
-                                if let Some(info) = self.query_manager.query_info(id) {
-                                    let ty = match info.label {
-                                        "have" => RequestType::Have,
-                                        "block" => RequestType::Block,
-                                        _ => unreachable!(),
-                                    };
-                                    let request = BitswapRequest { ty, cid: info.cid };
-                                    self.requests.insert(BitswapId::Compat(info.cid), id);
-                                    tracing::trace!("adding compat peer {}", peer);
-                                    self.compat.insert(peer);
-                                    return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
-                                        peer_id: peer,
-                                        handler: NotifyHandler::Any,
-                                        event: EitherOutput::Second(CompatMessage::Request(
-                                            request,
-                                        )),
-                                    });
-                                }
-                            }
-                        }
-                        if let Some(id) = self.requests.remove(&BitswapId::Bitswap(request_id)) {
-                            self.query_manager
-                                .inject_response(id, Response::Have(peer, false));
-                        }
-                    }
-                    RequestResponseEvent::InboundFailure {
-                        peer,
-                        request_id,
-                        error,
-                    } => {
-                        self.inject_inbound_failure(&peer, request_id, &error);
-                    }
-                }
+        }
+    }
+}
+
+/// Answers a request of another peer. Requests that waited in the queue for longer
+/// than `budget` are skipped, since the peer gave up on them.
+async fn serve_request<S: AsyncBitswapStore>(
+    store: &mut S,
+    channel: BitswapChannel,
+    request: BitswapRequest,
+    enqueued: Instant,
+    budget: Duration,
+    counters: &mut LocalCounters,
+) -> Option<DbResponse> {
+    if enqueued.elapsed() > budget {
+        let ty = match request.ty {
+            RequestType::Have => "have",
+            RequestType::Block => "block",
+        };
+        STALE_REQUESTS_SKIPPED.with_label_values(&[ty]).inc();
+        tracing::debug!("skipping stale {} request for {}", ty, request.cid);
+        return None;
+    }
+    let inline = is_inline(&request.cid);
+    let servable = inline || store.can_serve(&request.cid).await.unwrap_or_default();
+    let response = match request.ty {
+        // the data of an inline block is in its cid, so no store is asked
+        RequestType::Have if inline => {
+            counters.sent_have(true);
+            BitswapResponse::Have(true)
+        }
+        RequestType::Block if inline => {
+            let data = request.cid.hash().digest().to_vec();
+            counters.sent_block(data.len());
+            BitswapResponse::Block(data)
+        }
+        _ if !servable => {
+            POLICY_BLOCKED_SERVES.inc();
+            counters.sent_have(false);
+            tracing::trace!("policy blocks serving {}", request.cid);
+            BitswapResponse::Have(false)
+        }
+        RequestType::Have => {
+            let have = store.contains(&request.cid).await.ok().unwrap_or_default();
+            counters.sent_have(have);
+            tracing::trace!("have {}", have);
+            BitswapResponse::Have(have)
+        }
+        RequestType::Block => {
+            let block = store.get(&request.cid).await.ok().unwrap_or_default();
+            if let Some(data) = block {
+                counters.sent_block(data.len());
+                tracing::trace!("block {}", data.len());
+                BitswapResponse::Block(data)
+            } else {
+                counters.sent_have(false);
+                tracing::trace!("have false");
+                BitswapResponse::Have(false)
             }
         }
-        Poll::Pending
+    };
+    if counters.is_due() {
+        counters.flush();
     }
+    Some(DbResponse::Bitswap(channel, request.ty, response, enqueued))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use async_std::task;
-    use futures::prelude::*;
-    use libipld::block::Block;
-    use libipld::cbor::DagCborCodec;
-    use libipld::ipld;
-    use libipld::ipld::Ipld;
-    use libipld::multihash::Code;
-    use libipld::store::DefaultParams;
-    use libp2p::core::muxing::StreamMuxerBox;
-    use libp2p::core::transport::Boxed;
-    use libp2p::identity;
-    use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
-    use libp2p::swarm::SwarmEvent;
-    use libp2p::tcp::{self, async_io};
-    use libp2p::yamux::YamuxConfig;
-    use libp2p::{PeerId, Swarm, Transport};
-    use std::sync::{Arc, Mutex};
-    use std::time::Duration;
-    use tracing_subscriber::fmt::TestWriter;
+/// Services a db request. Returns the response to hand to the behaviour, if any.
+async fn handle_db_request<S: AsyncBitswapStore>(
+    store: &mut S,
+    request: DbRequest<S::Params>,
+    budget: Duration,
+    counters: &mut LocalCounters,
+    #[cfg(feature = "spill")] spill: &Spill,
+) -> Option<DbResponse> {
+    match request {
+        DbRequest::Bitswap(channel, request, enqueued)
+        | DbRequest::Released(channel, request, enqueued) => {
+            #[cfg(feature = "spill")]
+            if !is_inline(&request.cid)
+                && spill.is_spilled()
+                && !store.contains(&request.cid).await.unwrap_or_default()
+            {
+                drain_spill(spill, store).await;
+            }
+            serve_request(store, channel, request, enqueued, budget, counters).await
+        }
+        DbRequest::Insert(block, hint) => {
+            if let Err(err) = store.insert_with_hint(&block, hint).await {
+                tracing::error!("error inserting blocks {}", err);
+            }
+            #[cfg(feature = "spill")]
+            spill.inserted(block.data().len());
+            None
+        }
+        DbRequest::InsertStrict(block, hint, check) => {
+            if let Err(err) = store.insert_with_hint(&block, hint).await {
+                tracing::error!("error inserting blocks {}", err);
+            }
+            #[cfg(feature = "spill")]
+            spill.inserted(block.data().len());
+            check(&block).err().map(|err| {
+                tracing::error!("{}", err);
+                DbResponse::InvalidReferences(hint.root, err)
+            })
+        }
+        DbRequest::MissingBlocks(id, cid) => {
+            #[cfg(feature = "spill")]
+            drain_spill(spill, store).await;
+            Some(DbResponse::MissingBlocks(
+                id,
+                store.missing_blocks(&cid).await,
+            ))
+        }
+        DbRequest::SelectBlocks(id, cid, selector, decode) => {
+            #[cfg(feature = "spill")]
+            drain_spill(spill, store).await;
+            let res = select_blocks(store, cid, selector, decode).await;
+            Some(DbResponse::SelectedBlocks(id, res))
+        }
+        DbRequest::Available(cid) => {
+            #[cfg(feature = "spill")]
+            if spill.is_spilled() && !store.contains(&cid).await.unwrap_or_default() {
+                drain_spill(spill, store).await;
+            }
+            if store.contains(&cid).await.ok().unwrap_or_default() {
+                Some(DbResponse::Available(cid))
+            } else {
+                None
+            }
+        }
+        DbRequest::InsertDone(id, peer_id, cid) => {
+            #[cfg(feature = "spill")]
+            if spill.is_spilled() && !store.contains(&cid).await.unwrap_or_default() {
+                drain_spill(spill, store).await;
+            }
+            let stored = store.contains(&cid).await.ok().unwrap_or_default();
+            Some(DbResponse::InsertDone(id, peer_id, stored))
+        }
+    }
+}
 
-    fn tracing_try_init() {
-        tracing_subscriber::fmt()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .with_writer(TestWriter::new())
-            .try_init()
-            .ok();
+/// Returns the missing blocks of the part of a dag below `cid` that a selector selects,
+/// each with the selector applying to its block, in the order of the links.
+async fn select_blocks<S: AsyncBitswapStore>(
+    store: &mut S,
+    cid: Cid,
+    selector: Selector,
+    decode: fn(&Block<S::Params>) -> Result<Ipld>,
+) -> Result<Vec<(Cid, Selector)>> {
+    let mut stack = vec![(cid, selector)];
+    let mut missing = vec![];
+    while let Some((cid, selector)) = stack.pop() {
+        if selector.is_all() {
+            let blocks = store.missing_blocks(&cid).await?;
+            missing.extend(blocks.into_iter().map(|cid| (cid, Selector::default())));
+        } else if let Some(data) = store.get(&cid).await? {
+            let ipld = decode(&Block::new_unchecked(cid, data))?;
+            stack.extend(selector.links(&ipld).into_iter().rev());
+        } else {
+            missing.push((cid, selector));
+        }
     }
+    Ok(missing)
+}
 
-    fn mk_transport() -> (PeerId, Boxed<(PeerId, StreamMuxerBox)>) {
-        let id_key = identity::Keypair::generate_ed25519();
-        let peer_id = id_key.public().to_peer_id();
-        let dh_key = Keypair::<X25519Spec>::new()
-            .into_authentic(&id_key)
-            .unwrap();
-        let noise = NoiseConfig::xx(dh_key).into_authenticated();
+/// Serves other peers with one of the additional db workers, taking turns with the
+/// others on their shared queue.
+async fn serve_worker<S: AsyncBitswapStore>(
+    mut store: S,
+    serves: Arc<Mutex<ServeQueue<Queued<S::Params>>>>,
+    responses: mpsc::UnboundedSender<DbResponse>,
+    budget: Duration,
+) {
+    let key = |request: &Queued<S::Params>| (request.0.lane(), request.0.priority());
+    let mut counters = LocalCounters::default();
+    loop {
+        let next = {
+            let mut serves = serves.lock().await;
+            let open = serves.receive(key);
+            if serves.queue.is_empty() {
+                counters.flush();
+                if !open {
+                    break;
+                }
+                match serves.requests.next().await {
+                    Some(request) => {
+                        let (lane, priority) = key(&request);
+                        serves.queue.push(request, lane, priority);
+                    }
+                    None => break,
+                }
+            }
+            serves.queue.pop()
+        };
+        let (request, queued) = match next {
+            Some(next) => next,
+            None => continue,
+        };
+        DB_QUEUE_WAIT_SECONDS
+            .with_label_values(&[request.kind()])
+            .observe(queued.elapsed().as_secs_f64());
+        if let DbRequest::Bitswap(channel, request, enqueued) = request {
+            let response = serve_request(
+                &mut store,
+                channel,
+                request,
+                enqueued,
+                budget,
+                &mut counters,
+            )
+            .await;
+            if let Some(response) = response {
+                responses.unbounded_send(response).ok();
+            }
+        }
+    }
+    counters.flush();
+}
 
-        let transport = async_io::Transport::new(tcp::Config::new().nodelay(true))
-            .upgrade(libp2p::core::upgrade::Version::V1)
-            .authenticate(noise)
-            .multiplex(YamuxConfig::default())
-            .timeout(Duration::from_secs(20))
-            .boxed();
-        (peer_id, transport)
+/// Returns the channels to the db together with the futures of its workers, the first
+/// one servicing our own requests with `store`. The futures complete once the request
+/// channels are closed.
+fn start_db<S: AsyncBitswapStore>(
+    mut store: S,
+    workers: Vec<S>,
+    budget: Duration,
+    priority: DbPriority,
+    #[cfg(feature = "spill")] spill: Arc<Spill>,
+) -> (
+    DbSender<S::Params>,
+    mpsc::UnboundedReceiver<DbResponse>,
+    Vec<BoxFuture<'static, ()>>,
+) {
+    let (tx, mut requests) = mpsc::unbounded::<Queued<S::Params>>();
+    let (responses, rx) = mpsc::unbounded();
+    let mut dbs = vec![];
+    let mut serves = None;
+    if !workers.is_empty() {
+        let (serves_tx, serves_rx) = mpsc::unbounded();
+        let serves_rx = Arc::new(Mutex::new(ServeQueue {
+            requests: serves_rx,
+            queue: DbQueue::new(priority),
+        }));
+        for store in workers {
+            dbs.push(serve_worker(store, serves_rx.clone(), responses.clone(), budget).boxed());
+        }
+        serves = Some(serves_tx);
     }
+    let db = async move {
+        let mut queue = DbQueue::new(priority);
+        let mut counters = LocalCounters::default();
+        let mut queue_waits = FnvHashMap::default();
+        loop {
+            while let Ok(Some(request)) = requests.try_next() {
+                let (lane, priority) = (request.0.lane(), request.0.priority());
+                queue.push(request, lane, priority);
+            }
+            if queue.is_empty() {
+                counters.flush();
+                // the backlog is empty, catch up on the spilled blocks
+                #[cfg(feature = "spill")]
+                drain_spill(&spill, &mut store).await;
+                match requests.next().await {
+                    Some(request) => {
+                        let (lane, priority) = (request.0.lane(), request.0.priority());
+                        queue.push(request, lane, priority);
+                    }
+                    None => break,
+                }
+            }
+            let (request, queued) = match queue.pop() {
+                Some(request) => request,
+                None => continue,
+            };
+            queue_waits
+                .entry(request.kind())
+                .or_insert_with(|| DB_QUEUE_WAIT_SECONDS.with_label_values(&[request.kind()]))
+                .observe(queued.elapsed().as_secs_f64());
+            let response = handle_db_request(
+                &mut store,
+                request,
+                budget,
+                &mut counters,
+                #[cfg(feature = "spill")]
+                &spill,
+            )
+            .await;
+            if let Some(response) = response {
+                responses.unbounded_send(response).ok();
+            }
+        }
+        // truncate the spill store on shutdown
+        #[cfg(feature = "spill")]
+        drain_spill(&spill, &mut store).await;
+    };
+    dbs.insert(0, db.boxed());
+    let tx = DbSender { local: tx, serves };
+    (tx, rx, dbs)
+}
 
-    fn create_block(ipld: Ipld) -> Block<DefaultParams> {
-        Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
+impl<P: StoreParams> Bitswap<P> {
+    /// Records activity on a connection.
+    fn touch(&mut self, peer_id: PeerId, conn: ConnectionId) {
+        if let Some(conns) = self.connections.get_mut(&peer_id) {
+            if let Some(last_active) = conns.get_mut(&conn) {
+                *last_active = Instant::now();
+            }
+        }
     }
 
-    #[derive(Clone, Default)]
-    struct Store(Arc<Mutex<FnvHashMap<Cid, Vec<u8>>>>);
+    /// Returns the connection to a peer with the most recent activity.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn active_connection(&self, peer_id: &PeerId) -> Option<ConnectionId> {
+        self.connections
+            .get(peer_id)?
+            .iter()
+            .max_by_key(|(_, last_active)| **last_active)
+            .map(|(conn, _)| *conn)
+    }
 
-    impl BitswapStore for Store {
-        type Params = DefaultParams;
-        fn contains(&mut self, cid: &Cid) -> Result<bool> {
-            Ok(self.0.lock().unwrap().contains_key(cid))
+    /// Hands a compat message to the most recently active connection of a peer,
+    /// remembering it until it was sent so it can be rerouted if the connection closes.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn notify_compat(
+        &mut self,
+        peer_id: PeerId,
+        mut msg: OutboundMessage,
+    ) -> NetworkBehaviourAction<BitswapEvent, <Self as NetworkBehaviour>::ConnectionHandler> {
+        msg.id = self.next_compat_id;
+        self.next_compat_id += 1;
+        for part in &msg.parts {
+            if let CompatMessage::Request(req) = part {
+                if let Some(request) = self.requests.get(&BitswapId::compat(req.cid)) {
+                    self.query_manager.request_sent(request.query);
+                }
+            }
         }
-        fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-            Ok(self.0.lock().unwrap().get(cid).cloned())
+        let handler = if let Some(conn) = self.active_connection(&peer_id) {
+            self.compat_pending
+                .entry(conn)
+                .or_default()
+                .push_back(msg.clone());
+            NotifyHandler::One(conn)
+        } else {
+            NotifyHandler::Any
+        };
+        NetworkBehaviourAction::NotifyHandler {
+            peer_id,
+            handler,
+            event: EitherOutput::Second(msg),
         }
-        fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
-            self.0
-                .lock()
-                .unwrap()
-                .insert(*block.cid(), block.data().to_vec());
-            Ok(())
+    }
+
+    /// Forgets a compat message handed to a connection once it was sent or failed.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn take_compat_pending(&mut self, conn: ConnectionId, id: u64) -> Option<OutboundMessage> {
+        let pending = self.compat_pending.get_mut(&conn)?;
+        let msg = pending
+            .iter()
+            .position(|msg| msg.id == id)
+            .and_then(|index| pending.remove(index));
+        if pending.is_empty() {
+            self.compat_pending.remove(&conn);
         }
-        fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        msg
+    }
+
+    /// Drops a compat message that couldn't be written, failing the requests it carried.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn drop_compat_message(&mut self, peer_id: PeerId, msg: OutboundMessage) {
+        for part in msg.parts {
+            match part {
+                CompatMessage::Request(req) => {
+                    let id = BitswapId::compat(req.cid);
+                    let query = self
+                        .requests
+                        .get(&id)
+                        .filter(|request| request.peer_id == peer_id)
+                        .map(|request| request.query);
+                    if let Some(query) = query {
+                        self.fail_compat_request(id, query, peer_id);
+                    }
+                }
+                CompatMessage::Response(cid, response) => {
+                    let ty = response_type(&response);
+                    RESPONSE_DELIVERY_FAILED.with_label_values(&[ty]).inc();
+                    tracing::debug!("dropping {} response to {} for {}", ty, peer_id, cid);
+                }
+                CompatMessage::Cancel(_) => {}
+            }
+        }
+    }
+
+    /// Returns `true` if compat is compiled in and enabled by the config.
+    fn compat_enabled(&self) -> bool {
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        return self.config.enable_compat;
+        #[cfg(not(any(feature = "compat", feature = "compat-lite")))]
+        false
+    }
+
+    /// Retries a request of a query to a peer that doesn't support bitswap over compat.
+    /// Returns `None` if compat is disabled or the query is gone.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn compat_fallback(
+        &mut self,
+        id: QueryId,
+        peer_id: PeerId,
+    ) -> Option<NetworkBehaviourAction<BitswapEvent, <Self as NetworkBehaviour>::ConnectionHandler>>
+    {
+        if !self.config.enable_compat {
+            return None;
+        }
+        let info = self.query_manager.query_info(id)?;
+        let ty = request_type(info.label);
+        let cid = info.cid;
+        let request = self.pending_request(id, peer_id, cid);
+        self.insert_request(BitswapId::compat(cid), request);
+        self.fallbacks.insert(BitswapId::compat(cid));
+        self.count_compat_fallback(id, peer_id, "started");
+        tracing::trace!("adding compat peer {}", peer_id);
+        self.compat.insert(peer_id);
+        let compat = CompatMessage::Request(BitswapRequest::new(ty, cid));
+        Some(self.notify_compat(peer_id, compat.into()))
+    }
+
+    /// Appends an answered inbound want to the want history.
+    fn record_want(&mut self, channel: &BitswapChannel, ty: RequestType, res: &BitswapResponse) {
+        if self.config.want_history_size == 0 {
+            return;
+        }
+        if self.want_history.len() >= self.config.want_history_size {
+            self.want_history.pop_front();
+        }
+        let (peer, cid) = channel.peer_cid();
+        self.want_history.push_back(WantRecord {
+            peer,
+            cid,
+            ty,
+            timestamp: SystemTime::now(),
+            served: !matches!(res, BitswapResponse::Have(false)),
+        });
+    }
+
+    /// Removes an inbound want from the wantlist of the peer once it is answered with
+    /// `response`, or dropped without an answer if `None`.
+    fn answer_want(
+        &mut self,
+        channel: &BitswapChannel,
+        ty: RequestType,
+        response: Option<&BitswapResponse>,
+    ) {
+        let (peer_id, cid) = channel.peer_cid();
+        let answered = if !channel.is_compat() {
+            Some(ty)
+        } else {
+            // compat peers keep wanting a block until they cancel the want
+            match response {
+                Some(BitswapResponse::Block(_)) => Some(RequestType::Block),
+                Some(BitswapResponse::Have(true)) => Some(RequestType::Have),
+                _ => None,
+            }
+        };
+        if let Some(answered) = answered {
+            self.peer_wants.answered(peer_id, cid, answered);
+        }
+    }
+
+    /// Sends a request of a query to a peer, applying the fault injector if one is
+    /// installed.
+    fn send_request(&mut self, id: QueryId, peer_id: PeerId, req: BitswapRequest) {
+        if self.refuses_peer(&peer_id) {
+            tracing::debug!("not sending {} request to refused peer {}", req.ty, peer_id);
+            REFUSED_PEER_REQUESTS.with_label_values(&["outbound"]).inc();
+            self.query_manager
+                .inject_response(id, Response::Failed(peer_id));
+            return;
+        }
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        if self.compat_first(&peer_id) {
+            let request = self.pending_request(id, peer_id, req.cid);
+            self.insert_request(BitswapId::compat(req.cid), request);
+            self.compat.insert(peer_id);
+            let msg = CompatMessage::Request(req);
+            if self.connections.contains_key(&peer_id) {
+                self.compat_queue.push_back((peer_id, msg.into()));
+            } else {
+                self.dial_compat(peer_id, msg);
+            }
+            return;
+        }
+        if self.peer_saturated(&peer_id) {
+            tracing::trace!("queueing {} request to {}", req.ty, peer_id);
+            REQUESTS_QUEUED.inc();
+            self.peer_queues.entry(peer_id).or_default().push_back(id);
+            return;
+        }
+        #[cfg(feature = "chaos")]
+        if let Some(faults) = self.faults.as_mut() {
+            match faults.on_outbound_request(&peer_id, &req.cid) {
+                FaultAction::Deliver => {}
+                FaultAction::Delay(delay) => {
+                    tracing::debug!("delaying request to {} by {:?}", peer_id, delay);
+                    self.delayed.push(Box::pin(async move {
+                        Delay::new(delay).await;
+                        Delayed::Send(id, peer_id, req)
+                    }));
+                    return;
+                }
+                FaultAction::DropRequest => {
+                    tracing::debug!("dropping request to {}", peer_id);
+                    let timeout = self.config.request_timeout;
+                    self.delayed.push(Box::pin(async move {
+                        Delay::new(timeout).await;
+                        Delayed::Timeout(id, peer_id)
+                    }));
+                    return;
+                }
+                FaultAction::CorruptResponse => {
+                    tracing::debug!("corrupting response of {}", peer_id);
+                    let request_id = self.dispatch_request(id, peer_id, req);
+                    self.corrupt.insert(request_id);
+                    return;
+                }
+            }
+        }
+        if self.can_batch(id, &peer_id) {
+            let rest = self.query_manager.coalesce(peer_id, MAX_BATCH_SIZE - 1);
+            if !rest.is_empty() {
+                self.dispatch_batch(peer_id, (id, req), rest);
+                return;
+            }
+        }
+        self.dispatch_request(id, peer_id, req);
+    }
+
+    /// Returns `true` if requests to a peer are sent over compat right away instead of
+    /// falling back to it, see `CompatMode`.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn compat_first(&self, peer_id: &PeerId) -> bool {
+        if !self.config.enable_compat {
+            return false;
+        }
+        let support = self.peer_support(peer_id);
+        match self.config.compat_mode {
+            CompatMode::Fallback => {
+                support == PeerSupport::Compat && self.connections.contains_key(peer_id)
+            }
+            CompatMode::Prefer => support != PeerSupport::Native,
+            CompatMode::Only => true,
+        }
+    }
+
+    /// Queues a compat message until a peer that isn't connected is dialed.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn dial_compat(&mut self, peer_id: PeerId, msg: CompatMessage) {
+        let queued = self.compat_dials.entry(peer_id).or_default();
+        if queued.is_empty() {
+            tracing::trace!("dialing {} for compat requests", peer_id);
+            self.compat_dial_queue.push_back(peer_id);
+        }
+        queued.push(msg);
+    }
+
+    /// Returns `true` if a request of a query may be sent together with the other
+    /// pending requests to the peer. Probes keep their own deadline.
+    fn can_batch(&self, id: QueryId, peer_id: &PeerId) -> bool {
+        self.negotiated_capabilities(peer_id)
+            .contains(Capabilities::BATCHING)
+            && self.query_manager.query_info(id).map(|info| info.label) != Some("probe")
+    }
+
+    /// Records the outcome of protocol negotiation for a request to a peer.
+    fn record_support(&mut self, peer_id: PeerId, support: PeerSupport) {
+        let prev = self.support.insert(peer_id, (support, Instant::now()));
+        if prev.map(|(prev, _)| prev) != Some(support) {
+            tracing::debug!("peer {} support is {:?}", peer_id, support);
+        }
+        self.query_manager
+            .set_unsupported(peer_id, support == PeerSupport::Unsupported);
+    }
+
+    /// Forgets protocol negotiation outcomes older than `protocol_support_ttl`, so that
+    /// upgraded peers are asked again.
+    fn prune_support(&mut self) {
+        let ttl = self.config.protocol_support_ttl;
+        let query_manager = &mut self.query_manager;
+        self.support.retain(|peer_id, (_, at)| {
+            let keep = at.elapsed() < ttl;
+            if !keep {
+                query_manager.set_unsupported(*peer_id, false);
+            }
+            keep
+        });
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        {
+            let support = &self.support;
+            self.compat_fallbacks
+                .retain(|peer_id, _| support.contains_key(peer_id));
+        }
+    }
+
+    /// Counts the outcome of a compat fallback of a query to a peer.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn count_compat_fallback(&mut self, id: QueryId, peer_id: PeerId, result: &'static str) {
+        COMPAT_FALLBACKS.with_label_values(&[result]).inc();
+        let count = |fallbacks: &mut CompatFallbacks| match result {
+            "started" => fallbacks.started += 1,
+            "succeeded" => fallbacks.succeeded += 1,
+            _ => fallbacks.failed += 1,
+        };
+        count(self.compat_fallbacks.entry(peer_id).or_default());
+        if let Some(fallbacks) = self.query_manager.compat_fallbacks(id) {
+            count(fallbacks);
+        }
+    }
+
+    /// Fails the compat requests to a peer that disconnected, since their responses
+    /// can't arrive anymore.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn fail_compat_requests(&mut self, peer_id: PeerId) {
+        let failed = self
+            .requests
+            .iter()
+            .filter(|(id, request)| {
+                matches!(id, BitswapId::Compat(_)) && request.peer_id == peer_id
+            })
+            .map(|(id, request)| (*id, request.query))
+            .collect::<Vec<_>>();
+        for (id, query) in failed {
+            self.fail_compat_request(id, query, peer_id);
+        }
+    }
+
+    /// Fails a compat request whose response can't arrive.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn fail_compat_request(&mut self, id: BitswapId, query: QueryId, peer_id: PeerId) {
+        tracing::debug!("compat request {:?} to {} failed", id, peer_id);
+        self.requests.remove(&id);
+        if self.fallbacks.remove(&id) {
+            self.count_compat_fallback(query, peer_id, "failed");
+        }
+        self.query_manager
+            .inject_response(query, Response::Failed(peer_id));
+    }
+
+    /// Caches the capabilities a peer advertised on a stream.
+    fn update_capabilities(&mut self, peer_id: PeerId, caps: Option<Capabilities>) {
+        let caps = caps.unwrap_or_default();
+        if self.capabilities.insert(peer_id, caps) != Some(caps) {
+            tracing::debug!("peer {} has capabilities {:?}", peer_id, caps);
+        }
+    }
+
+    /// Hands a request of a query to the inner behaviour.
+    fn dispatch_request(&mut self, id: QueryId, peer_id: PeerId, req: BitswapRequest) -> RequestId {
+        let (ty, cid) = (req.ty, req.cid);
+        let probe = self.query_manager.query_info(id).map(|info| info.label) == Some("probe");
+        let request_id = self.inner.send_request(&peer_id, req.into());
+        self.query_manager.request_sent(id);
+        self.track_request(request_id, peer_id, ty, probe);
+        let request = self.pending_request(id, peer_id, cid);
+        self.insert_request(BitswapId::Bitswap(request_id), request);
+        request_id
+    }
+
+    /// Hands a batch of requests to a peer to the inner behaviour. The first entry is
+    /// tracked like a single request, the others by their index in the batch.
+    fn dispatch_batch(
+        &mut self,
+        peer_id: PeerId,
+        first: (QueryId, BitswapRequest),
+        rest: Vec<(QueryId, Request)>,
+    ) {
+        let mut entries = vec![first];
+        entries.extend(rest.into_iter().filter_map(|(id, req)| {
+            let (ty, cid) = match req {
+                Request::Have(_, cid) => (RequestType::Have, cid),
+                Request::Block(_, cid) => (RequestType::Block, cid),
+                _ => return None,
+            };
+            Some((id, BitswapRequest::new(ty, cid)))
+        }));
+        // the deadline of the batch accounts for a block if any entry asks for one
+        let ty = entries
+            .iter()
+            .map(|(_, req)| req.ty)
+            .find(|ty| *ty == RequestType::Block)
+            .unwrap_or(RequestType::Have);
+        let wants: Vec<_> = entries.iter().map(|(_, req)| *req).collect();
+        let request_id = self
+            .inner
+            .send_request(&peer_id, Negotiated::from(Batch::Many(wants)));
+        REQUEST_BATCH_ENTRIES.observe(entries.len() as f64);
+        self.track_request(request_id, peer_id, ty, false);
+        for (index, (id, req)) in entries.into_iter().enumerate() {
+            self.query_manager.request_sent(id);
+            let request = self.pending_request(id, peer_id, req.cid);
+            let id = if index == 0 {
+                BitswapId::Bitswap(request_id)
+            } else {
+                BitswapId::Batch(request_id, index)
+            };
+            self.insert_request(id, request);
+        }
+    }
+
+    /// Removes the entries of a batched request starting at `from`, which is at least
+    /// one, returning their queries.
+    fn take_batch(&mut self, request_id: RequestId, from: usize, peer_id: &PeerId) -> Vec<QueryId> {
+        let mut queries = vec![];
+        for index in from..MAX_BATCH_SIZE {
+            let id = BitswapId::Batch(request_id, index);
+            if !self.requests.contains_key(&id) {
+                break;
+            }
+            queries.extend(self.take_request(&id, peer_id));
+        }
+        queries
+    }
+
+    /// Schedules a retry of a request whose provider couldn't be dialed. Returns `false`
+    /// if the retries are exhausted.
+    fn retry_dial(&mut self, id: QueryId, peer_id: PeerId) -> bool {
+        if self.config.max_dial_retries == 0 {
+            return false;
+        }
+        let attempts = self.dial_retries.entry((id, peer_id)).or_default();
+        if *attempts >= self.config.max_dial_retries {
+            tracing::debug!("giving up dialing {} after {} retries", peer_id, attempts);
+            DIAL_RETRIES.with_label_values(&["exhausted"]).inc();
+            self.dial_retries.remove(&(id, peer_id));
+            self.circuit_dead(peer_id);
+            return false;
+        }
+        let backoff = self.config.dial_retry_backoff * 2u32.saturating_pow(*attempts);
+        *attempts += 1;
+        tracing::debug!("retrying to dial {} in {:?}", peer_id, backoff);
+        DIAL_RETRIES.with_label_values(&["scheduled"]).inc();
+        self.schedule_retry(id, peer_id, backoff);
+        true
+    }
+
+    /// Schedules a retry of a request that timed out. Returns `false` if the retries
+    /// are exhausted.
+    fn retry_timeout(&mut self, id: QueryId, peer_id: PeerId) -> bool {
+        if self.config.max_timeout_retries == 0 {
+            return false;
+        }
+        let attempts = self.timeout_retries.entry((id, peer_id)).or_default();
+        if *attempts >= self.config.max_timeout_retries {
+            tracing::debug!("giving up on {} after {} timeouts", peer_id, *attempts + 1);
+            TIMEOUT_RETRIES.with_label_values(&["exhausted"]).inc();
+            self.timeout_retries.remove(&(id, peer_id));
+            return false;
+        }
+        let backoff = self.config.timeout_retry_backoff * 2u32.saturating_pow(*attempts);
+        *attempts += 1;
+        tracing::debug!(
+            "request to {} timed out, retrying in {:?}",
+            peer_id,
+            backoff
+        );
+        TIMEOUT_RETRIES.with_label_values(&["scheduled"]).inc();
+        self.schedule_retry(id, peer_id, backoff);
+        true
+    }
+
+    /// Resends a request whose connection closed while its peer is still connected
+    /// through another connection. The inner behaviour picks one of the remaining
+    /// connections.
+    fn resend_closed(&mut self, id: QueryId, peer_id: PeerId) {
+        tracing::debug!("connection to {} closed, resending the request", peer_id);
+        self.query_manager.observe_retry(id);
+        self.resend_request(id, peer_id);
+    }
+
+    fn schedule_retry(&mut self, id: QueryId, peer_id: PeerId, backoff: Duration) {
+        self.query_manager.observe_retry(id);
+        self.retries.push(Box::pin(async move {
+            Delay::new(backoff).await;
+            (id, peer_id)
+        }));
+    }
+
+    /// Resends a request after its retry backoff if the query is still alive.
+    /// Returns `false` if the query completed in the meantime.
+    fn resend_request(&mut self, id: QueryId, peer_id: PeerId) -> bool {
+        let alive = self.query_manager.query_info(id).and_then(|info| {
+            self.query_manager.query_info(info.root)?;
+            Some(BitswapRequest::new(request_type(info.label), info.cid))
+        });
+        if let Some(req) = alive {
+            self.send_request(id, peer_id, req);
+            true
+        } else {
+            self.dial_retries.remove(&(id, peer_id));
+            self.timeout_retries.remove(&(id, peer_id));
+            false
+        }
+    }
+
+    /// Returns `true` if requests to the peer have to wait for one of its in flight
+    /// requests to complete.
+    fn peer_saturated(&self, peer_id: &PeerId) -> bool {
+        let max = if let Some(max) = self.config.max_requests_per_peer {
+            max.max(1)
+        } else {
+            return false;
+        };
+        self.peer_queues.contains_key(peer_id)
+            || self.peer_inflight.get(peer_id).copied().unwrap_or_default() >= max
+    }
+
+    /// Sends the queued requests of peers that have fewer than `max_requests_per_peer`
+    /// requests in flight. Returns `true` if a request was sent.
+    fn send_queued_requests(&mut self) -> bool {
+        let max = self
+            .config
+            .max_requests_per_peer
+            .unwrap_or(usize::MAX)
+            .max(1);
+        let peer_inflight = &self.peer_inflight;
+        let ready: Vec<_> = self
+            .peer_queues
+            .keys()
+            .filter(|peer_id| peer_inflight.get(peer_id).copied().unwrap_or_default() < max)
+            .copied()
+            .collect();
+        let mut sent = false;
+        for peer_id in ready {
+            // the queue is taken out so that the resent requests aren't queued again
+            let mut queue = self.peer_queues.remove(&peer_id).unwrap_or_default();
+            while self
+                .peer_inflight
+                .get(&peer_id)
+                .copied()
+                .unwrap_or_default()
+                < max
+            {
+                if let Some(id) = queue.pop_front() {
+                    sent |= self.resend_request(id, peer_id);
+                } else {
+                    break;
+                }
+            }
+            if let Some(queued) = self.peer_queues.remove(&peer_id) {
+                queue.extend(queued);
+            }
+            if !queue.is_empty() {
+                self.peer_queues.insert(peer_id, queue);
+            }
+        }
+        sent
+    }
+
+    /// Resends the requests whose dial was rejected by the connection limits while fewer
+    /// than `pending_dial_budget` of them are dialing.
+    fn send_queued_dials(&mut self) {
+        let budget = self.config.pending_dial_budget.max(1);
+        while self.queued_dials.len() < budget {
+            let (id, peer_id) = if let Some(request) = self.dial_queue.pop_front() {
+                request
+            } else {
+                break;
+            };
+            if self.resend_request(id, peer_id) && !self.connections.contains_key(&peer_id) {
+                self.queued_dials.insert(peer_id);
+            }
+        }
+    }
+
+    /// Dials a bootstrap peer again after a backoff, unless a redial is scheduled
+    /// already.
+    fn redial_bootstrap(&mut self, peer_id: PeerId, backoff: Duration) {
+        if !self.bootstrap.schedule_redial(&peer_id) {
+            return;
+        }
+        tracing::debug!("dialing bootstrap peer {} in {:?}", peer_id, backoff);
+        self.bootstrap_redials.push(Box::pin(async move {
+            Delay::new(backoff).await;
+            peer_id
+        }));
+    }
+
+    /// Asks a peer whether it has the default cid, which any bitswap peer answers.
+    fn send_probe(&mut self, peer_id: PeerId) -> RequestId {
+        let req = BitswapRequest::new(RequestType::Have, Cid::default());
+        self.inner.send_request(&peer_id, req.into())
+    }
+
+    /// Detects whether a bootstrap peer supports bitswap by asking it for a block.
+    fn detect_support(&mut self, peer_id: PeerId) {
+        let request_id = self.send_probe(peer_id);
+        self.bootstrap_probes.insert(request_id, peer_id);
+    }
+
+    /// Counts a failed request against the circuit breaker of a peer and parks the peer
+    /// for the cooldown if its circuit opens.
+    fn circuit_failure(&mut self, peer_id: PeerId) {
+        let config = if let Some(config) = self.config.circuit_breaker {
+            config
+        } else {
+            return;
+        };
+        let prev = self.circuits.state(&peer_id);
+        if self.circuits.failure(peer_id, &config, Instant::now()) {
+            self.circuit_opened(peer_id, prev, config);
+        }
+    }
+
+    /// Opens the circuit of a peer that couldn't be dialed after all retries, so that
+    /// later queries only ask it if no other provider has a block.
+    fn circuit_dead(&mut self, peer_id: PeerId) {
+        let config = if let Some(config) = self.config.circuit_breaker {
+            config
+        } else {
+            return;
+        };
+        let prev = self.circuits.state(&peer_id);
+        if self.circuits.open(peer_id, Instant::now()) {
+            self.circuit_opened(peer_id, prev, config);
+        }
+    }
+
+    /// Parks a peer whose circuit opened for the cooldown.
+    fn circuit_opened(&mut self, peer_id: PeerId, prev: CircuitState, config: CircuitBreaker) {
+        tracing::debug!("circuit of {} opens for {:?}", peer_id, config.cooldown);
+        if prev == CircuitState::Closed {
+            CIRCUIT_BREAKERS_OPEN.inc();
+            self.query_manager.set_circuit_open(peer_id, true);
+        }
+        self.circuit_cooldowns.push(Box::pin(async move {
+            Delay::new(config.cooldown).await;
+            peer_id
+        }));
+    }
+
+    /// Closes the circuit of a peer that answered a request.
+    fn circuit_success(&mut self, peer_id: PeerId) {
+        if self.circuits.success(&peer_id) != CircuitState::Closed {
+            tracing::debug!("circuit of {} closes", peer_id);
+            CIRCUIT_BREAKERS_OPEN.dec();
+            self.query_manager.set_circuit_open(peer_id, false);
+        }
+    }
+
+    /// Returns the pending request of a query for a block.
+    fn pending_request(&self, query: QueryId, peer_id: PeerId, cid: Cid) -> PendingRequest {
+        let root = self
+            .query_manager
+            .query_info(query)
+            .map(|info| info.root)
+            .unwrap_or(query);
+        PendingRequest {
+            query,
+            root,
+            peer_id,
+            cid,
+        }
+    }
+
+    /// Remembers the query an outbound request belongs to.
+    fn insert_request(&mut self, id: BitswapId, request: PendingRequest) {
+        let prev = self.requests.insert(id, request);
+        // Compat requests are keyed by cid, so concurrent wants of the same block from
+        // compat peers collide.
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        if let (BitswapId::Compat(_), Some(prev)) = (id, prev) {
+            tracing::debug!("compat request {:?} replaced {:?}", id, prev);
+            return;
+        }
+        debug_assert!(prev.is_none(), "request id {:?} reused by {:?}", id, prev);
+    }
+
+    /// Returns `true` if a response belongs to a request that was sent to another peer.
+    /// Mismatched responses are counted and the first one is reported in an event.
+    fn mismatched(&mut self, id: &BitswapId, peer_id: &PeerId) -> bool {
+        let request = match self.requests.get(id) {
+            Some(request) if request.peer_id != *peer_id => *request,
+            _ => return false,
+        };
+        MISMATCHED_RESPONSES.inc();
+        tracing::warn!(
+            "dropping response of {} to a request for {} sent to {}",
+            peer_id,
+            request.cid,
+            request.peer_id
+        );
+        if !self.mismatch_reported {
+            self.mismatch_reported = true;
+            let event = BitswapEvent::mismatched_response(request.query, *peer_id);
+            self.events.push_back(event);
+        }
+        true
+    }
+
+    /// Removes the request a response or failure of a peer belongs to.
+    fn take_request(&mut self, id: &BitswapId, peer_id: &PeerId) -> Option<QueryId> {
+        if self.mismatched(id, peer_id) {
+            return None;
+        }
+        self.requests.remove(id).map(|request| request.query)
+    }
+
+    /// Tracks an outbound request, computing its adaptive or probe deadline.
+    fn track_request(
+        &mut self,
+        request_id: RequestId,
+        peer_id: PeerId,
+        ty: RequestType,
+        probe: bool,
+    ) {
+        let now = Instant::now();
+        let estimator = self.query_manager.estimator();
+        let adaptive = self.config.adaptive_timeout.as_ref().and_then(|config| {
+            let size = match ty {
+                RequestType::Have => 0,
+                RequestType::Block => estimator.block_size().unwrap_or(P::MAX_BLOCK_SIZE),
+            };
+            estimator
+                .timeout(&peer_id, size, config)
+                .filter(|timeout| *timeout < self.config.request_timeout)
+                .map(|timeout| now + timeout)
+        });
+        let deadline = if probe {
+            Some(now + self.config.probe_timeout)
+        } else {
+            adaptive
+        };
+        if let Some(deadline) = deadline {
+            self.deadlines.entry(deadline).or_default().push(request_id);
+        }
+        let inflight = Inflight {
+            peer_id,
+            sent: now,
+            deadline,
+            expired: false,
+            probe,
+        };
+        self.inflight.insert(request_id, inflight);
+        *self.peer_inflight.entry(peer_id).or_default() += 1;
+    }
+
+    /// Stops tracking an outbound request once it was answered or failed.
+    fn untrack_request(&mut self, request_id: RequestId) -> Option<Inflight> {
+        let inflight = self.inflight.remove(&request_id)?;
+        if let Some(deadline) = inflight.deadline {
+            if let Some(ids) = self.deadlines.get_mut(&deadline) {
+                ids.retain(|id| *id != request_id);
+                if ids.is_empty() {
+                    self.deadlines.remove(&deadline);
+                }
+            }
+        }
+        if let Some(count) = self.peer_inflight.get_mut(&inflight.peer_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.peer_inflight.remove(&inflight.peer_id);
+            }
+        }
+        Some(inflight)
+    }
+
+    /// Expires requests whose adaptive deadline passed and arms the timer for the next
+    /// one. Returns `true` if a request expired.
+    fn poll_deadlines(&mut self, cx: &mut Context) -> bool {
+        let now = Instant::now();
+        let mut expired = vec![];
+        while let Some(entry) = self.deadlines.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            for request_id in entry.remove() {
+                if let Some(inflight) = self.inflight.get_mut(&request_id) {
+                    inflight.deadline = None;
+                    inflight.expired = true;
+                    expired.push((request_id, inflight.peer_id, inflight.probe));
+                }
+            }
+        }
+        for (request_id, peer_id, probe) in &expired {
+            if let Some(request) = self.requests.remove(&BitswapId::Bitswap(*request_id)) {
+                let id = request.query;
+                tracing::debug!(
+                    "request {} to {} exceeded its deadline",
+                    request_id,
+                    peer_id
+                );
+                let response = if *probe {
+                    PROVIDER_PROBES.with_label_values(&["slow"]).inc();
+                    Response::Unresponsive(*peer_id)
+                } else {
+                    REQUEST_EXPIRATIONS.with_label_values(&["adaptive"]).inc();
+                    Response::Expired(*peer_id)
+                };
+                self.query_manager.inject_response(id, response);
+            }
+            for id in self.take_batch(*request_id, 1, peer_id) {
+                self.query_manager
+                    .inject_response(id, Response::Expired(*peer_id));
+            }
+        }
+        if let Some(next) = self.deadlines.keys().next().copied() {
+            if !matches!(&self.timer, Some((armed, _)) if *armed == next) {
+                self.timer = Some((next, Delay::new(next - now)));
+            }
+            if let Some((_, timer)) = self.timer.as_mut() {
+                if Pin::new(timer).poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+            }
+        } else {
+            self.timer = None;
+        }
+        !expired.is_empty()
+    }
+
+    /// Times out the queries whose deadline passed and arms the timer for the next one.
+    fn poll_query_deadlines(&mut self, cx: &mut Context) {
+        let now = Instant::now();
+        self.query_manager.expire(now);
+        if let Some(next) = self.query_manager.next_deadline() {
+            let timer = self
+                .query_timer
+                .get_or_insert_with(|| Delay::new(next - now));
+            timer.reset(next - now);
+            if Pin::new(timer).poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        } else {
+            self.query_timer = None;
+        }
+    }
+
+    /// Flushes the block counters once enough updates accumulated or
+    /// `FLUSH_INTERVAL` passed since the first of them.
+    fn poll_counters(&mut self, cx: &mut Context) {
+        if self.counters.is_empty() {
+            return;
+        }
+        let timer = self
+            .counters_timer
+            .get_or_insert_with(|| Delay::new(FLUSH_INTERVAL));
+        if self.counters.is_due() || Pin::new(timer).poll(cx).is_ready() {
+            self.counters.flush();
+            self.counters_timer = None;
+        }
+    }
+
+    /// Processes an incoming bitswap request.
+    fn inject_request(&mut self, channel: BitswapChannel, request: BitswapRequest) {
+        let (peer, _) = channel.peer_cid();
+        let compat = channel.is_compat();
+        self.peer_wants
+            .insert(peer, request.cid, request.ty, compat);
+        if self.config.emit_want_events {
+            let event = BitswapEvent::want(peer, request.cid, request.ty);
+            self.events.push_back(event);
+        }
+        let req = Deferred {
+            channel,
+            request,
+            received: Instant::now(),
+        };
+        let limit = if let Some(limit) = self.config.inbound_rate_limit {
+            limit
+        } else {
+            return self.evaluate_request(req);
+        };
+        if self.rate_limiter.acquire(peer, &limit, req.received) {
+            return self.evaluate_request(req);
+        }
+        tracing::debug!("{} exceeded its rate limit", peer);
+        THROTTLED_INBOUND.inc();
+        match limit.action {
+            PolicyDecision::Serve => self.evaluate_request(req),
+            PolicyDecision::Deny => self.deny_request(req),
+            PolicyDecision::Defer => {
+                self.deferred.entry(peer).or_default().push_back(req);
+            }
+            PolicyDecision::Ignore => self.ignore_request(req),
+        }
+    }
+
+    /// Processes an incoming batch of requests. Each entry goes through the server policy
+    /// and the store like a single request, the batch is answered once all of them are.
+    fn inject_batch(&mut self, peer_id: PeerId, channel: Channel, requests: Vec<BitswapRequest>) {
+        if requests.is_empty() {
+            self.send_batch_response(peer_id, channel, vec![]);
+            return;
+        }
+        let batch = self.next_batch;
+        self.next_batch += 1;
+        let entries = requests.iter().map(|request| (request.cid, None)).collect();
+        let inbound = InboundBatch {
+            peer_id,
+            channel,
+            entries,
+            remaining: requests.len(),
+        };
+        self.inbound_batches.insert(batch, inbound);
+        for (index, request) in requests.into_iter().enumerate() {
+            let channel = BitswapChannel::Batch(peer_id, request.cid, batch, index);
+            self.inject_request(channel, request);
+        }
+    }
+
+    /// Records the response of an entry of an inbound batch, sending the batch once
+    /// every entry is answered.
+    fn batch_response(&mut self, batch: u64, index: usize, response: BitswapResponse) {
+        let inbound = if let Some(inbound) = self.inbound_batches.get_mut(&batch) {
+            inbound
+        } else {
+            return;
+        };
+        if inbound.entries[index].1.replace(response).is_none() {
+            inbound.remaining -= 1;
+        }
+        if inbound.remaining > 0 {
+            return;
+        }
+        let InboundBatch {
+            peer_id,
+            channel,
+            entries,
+            ..
+        } = self.inbound_batches.remove(&batch).unwrap();
+        // the blocks share the size limit of a single block. blocks that don't fit are
+        // answered with a have, so that the peer asks for them again.
+        let mut budget = P::MAX_BLOCK_SIZE;
+        let mut responses = Vec::with_capacity(entries.len());
+        for (cid, response) in entries {
+            let mut response = response.unwrap_or(BitswapResponse::Have(false));
+            let len = match &response {
+                BitswapResponse::Block(data) => data.len(),
+                BitswapResponse::Have(_) => 0,
+            };
+            if len > budget {
+                response = BitswapResponse::Have(true);
+            } else {
+                budget -= len;
+            }
+            self.charge(peer_id, &cid, &response);
+            responses.push((cid, response));
+        }
+        self.send_batch_response(peer_id, channel, responses);
+    }
+
+    /// Processes an inbound compat message. Its wantlist entries are evaluated like a
+    /// batch, so that the responses to all of them are sent back together. A `full`
+    /// wantlist cancels the earlier wants of the peer it doesn't list and skips the ones
+    /// still being answered.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn inject_compat_message(&mut self, peer_id: PeerId, msgs: Vec<CompatMessage>, full: bool) {
+        if full {
+            let listed = msgs
+                .iter()
+                .filter_map(|msg| match msg {
+                    CompatMessage::Request(req) => Some(req.cid),
+                    _ => None,
+                })
+                .collect::<FnvHashSet<_>>();
+            let mut wanted = self.peer_wants.compat_wantlist(&peer_id);
+            wanted.extend(
+                self.compat_wants
+                    .keys()
+                    .filter(|(peer_id2, _)| *peer_id2 == peer_id)
+                    .map(|(_, cid)| *cid),
+            );
+            for cid in wanted {
+                if !listed.contains(&cid) {
+                    self.cancel_compat_want(peer_id, cid);
+                }
+            }
+        }
+        let mut requests = vec![];
+        for msg in msgs {
+            match msg {
+                CompatMessage::Request(req)
+                    if full && self.compat_wants.contains_key(&(peer_id, req.cid)) => {}
+                CompatMessage::Request(req) => requests.push(req),
+                CompatMessage::Cancel(cid) => self.cancel_compat_want(peer_id, cid),
+                CompatMessage::Response(cid, res) => {
+                    tracing::trace!("received compat response");
+                    self.record_support(peer_id, PeerSupport::Compat);
+                    let id = self.compat_response_id(peer_id, cid);
+                    let res = self.compat_presence(id, peer_id, res);
+                    self.inject_response(id, peer_id, res);
+                }
+            }
+        }
+        if requests.is_empty() {
+            return;
+        }
+        tracing::trace!("received {} compat requests", requests.len());
+        let batch = self.compat_batch(peer_id, &requests, false);
+        for req in requests {
+            self.inject_request(BitswapChannel::Compat(peer_id, req.cid, batch), req);
+        }
+    }
+
+    /// Remembers the compat version negotiated with a peer.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn set_compat_version(&mut self, peer_id: PeerId, version: CompatVersion) {
+        if self.compat_versions.insert(peer_id, version) != Some(version) {
+            tracing::debug!("{} speaks compat {:?}", peer_id, version);
+        }
+    }
+
+    /// Returns the id of the request a compat response answers. Peers speaking 1.0.0
+    /// send blocks without their cid, which is decoded as a v0 cid, so their blocks are
+    /// matched to a pending request for the same multihash.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn compat_response_id(&self, peer_id: PeerId, cid: Cid) -> BitswapId {
+        let id = BitswapId::compat(cid);
+        if self.compat_versions.get(&peer_id) != Some(&CompatVersion::V1_0_0)
+            || self.requests.contains_key(&id)
+        {
+            return id;
+        }
+        self.requests
+            .iter()
+            .find(|(id, request)| {
+                request.peer_id == peer_id
+                    && matches!(id, BitswapId::Compat(pending) if pending.hash() == cid.hash())
+            })
+            .map(|(id, _)| *id)
+            .unwrap_or(id)
+    }
+
+    /// Peers speaking a compat version without block presences were asked for the
+    /// block instead of a have, so the block they answer with counts as a have.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn compat_presence(
+        &self,
+        id: BitswapId,
+        peer_id: PeerId,
+        response: BitswapResponse,
+    ) -> BitswapResponse {
+        let presences = match self.compat_versions.get(&peer_id) {
+            Some(version) => version.has_presences(),
+            None => true,
+        };
+        let have = match (&response, self.requests.get(&id)) {
+            (BitswapResponse::Block(_), Some(request)) if !presences => self
+                .query_manager
+                .query_info(request.query)
+                .map(|info| request_type(info.label) == RequestType::Have)
+                .unwrap_or_default(),
+            _ => false,
+        };
+        if have {
+            BitswapResponse::Have(true)
+        } else {
+            response
+        }
+    }
+
+    /// Starts collecting the responses to compat requests of a peer. Returns the id of
+    /// the batch.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn compat_batch(
+        &mut self,
+        peer_id: PeerId,
+        requests: &[BitswapRequest],
+        notification: bool,
+    ) -> u64 {
+        let batch = self.next_batch;
+        self.next_batch += 1;
+        let presences = !matches!(
+            self.compat_versions.get(&peer_id),
+            Some(version) if !version.has_presences()
+        );
+        let inbound = CompatBatch {
+            peer_id,
+            responses: Vec::with_capacity(requests.len()),
+            remaining: requests.len(),
+            notification,
+            presences,
+        };
+        self.compat_batches.insert(batch, inbound);
+        for req in requests {
+            *self.compat_wants.entry((peer_id, req.cid)).or_default() += 1;
+        }
+        batch
+    }
+
+    /// Answers the compat peers that still want a block we didn't have when they asked
+    /// for it, unless an earlier want of theirs is still being answered. The requests go
+    /// through the server policy and the store like new ones.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn notify_compat_wants(&mut self, cid: Cid) {
+        for (peer_id, ty) in self.peer_wants.compat_wants(&cid) {
+            let announced =
+                matches!(self.announcements.get(&cid), Some(peers) if peers.contains(&peer_id));
+            if announced || self.compat_wants.contains_key(&(peer_id, cid)) {
+                continue;
+            }
+            tracing::debug!("notifying {} of {}", peer_id, cid);
+            COMPAT_NOTIFIED_WANTS.inc();
+            let request = BitswapRequest::new(ty, cid);
+            let batch = self.compat_batch(peer_id, &[request], true);
+            self.evaluate_request(Deferred {
+                channel: BitswapChannel::Compat(peer_id, cid, batch),
+                request,
+                received: Instant::now(),
+            });
+        }
+    }
+
+    /// Records the response to a wantlist entry of an inbound compat message, `None` if
+    /// the entry isn't answered. Once every entry is, the responses are queued in as few
+    /// messages as the message size limit allows.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn compat_response(&mut self, batch: u64, response: Option<CompatMessage>) {
+        let inbound = if let Some(inbound) = self.compat_batches.get_mut(&batch) {
+            inbound
+        } else {
+            return;
+        };
+        let (notification, presences) = (inbound.notification, inbound.presences);
+        inbound
+            .responses
+            .extend(response.filter(|response| match response {
+                CompatMessage::Response(_, BitswapResponse::Have(have)) => {
+                    presences && (*have || !notification)
+                }
+                _ => true,
+            }));
+        inbound.remaining -= 1;
+        if inbound.remaining > 0 {
+            return;
+        }
+        let CompatBatch {
+            peer_id, responses, ..
+        } = self.compat_batches.remove(&batch).unwrap();
+        if responses.is_empty() {
+            return;
+        }
+        let bundles = OutboundMessage::bundle(responses);
+        COMPAT_RESPONSE_MESSAGES.inc_by(bundles.len() as u64);
+        self.queue_compat(peer_id, bundles);
+    }
+
+    /// Queues compat messages to a peer, counting the bytes of their blocks so that
+    /// each message tells the peer how many more are coming.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn queue_compat(&mut self, peer_id: PeerId, msgs: impl IntoIterator<Item = OutboundMessage>) {
+        for msg in msgs {
+            let bytes = msg.block_bytes();
+            if bytes > 0 {
+                *self.compat_queued_bytes.entry(peer_id).or_default() += bytes;
+            }
+            self.compat_queue.push_back((peer_id, msg));
+        }
+    }
+
+    /// Takes the next compat message off the queue, setting the bytes of the blocks
+    /// still queued for its peer.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn next_compat(&mut self) -> Option<(PeerId, OutboundMessage)> {
+        let (peer_id, mut msg) = self.compat_queue.pop_front()?;
+        let queued = self
+            .compat_queued_bytes
+            .remove(&peer_id)
+            .unwrap_or_default()
+            .saturating_sub(msg.block_bytes());
+        if queued > 0 {
+            self.compat_queued_bytes.insert(peer_id, queued);
+        }
+        msg.pending_bytes = queued;
+        Some((peer_id, msg))
+    }
+
+    /// Marks an inbound compat want as answered. Returns `false` if the peer cancelled
+    /// it, in which case the response is dropped.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn take_compat_want(&mut self, peer_id: PeerId, cid: Cid) -> bool {
+        match self.compat_wants.get_mut(&(peer_id, cid)) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                self.compat_wants.remove(&(peer_id, cid));
+            }
+            None => return false,
+        }
+        true
+    }
+
+    /// Drops the unanswered compat wants of a peer for a cid. Wants that are deferred or
+    /// held until we fetched the block are dropped right away, those the store is
+    /// looking up once their response is ready.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn cancel_compat_want(&mut self, peer_id: PeerId, cid: Cid) {
+        if let Some(peers) = self.announcements.get_mut(&cid) {
+            peers.remove(&peer_id);
+        }
+        self.peer_wants.cancel(peer_id, cid);
+        if self.compat_wants.remove(&(peer_id, cid)).is_none() {
+            return;
+        }
+        tracing::debug!("{} cancelled its compat want for {}", peer_id, cid);
+        COMPAT_CANCELLED_WANTS.inc();
+        let is_want = |req: &Deferred| {
+            matches!(req.channel, BitswapChannel::Compat(peer_id2, cid2, _)
+                if peer_id2 == peer_id && cid2 == cid)
+        };
+        let mut cancelled = vec![];
+        if let Some(deferred) = self.deferred.remove(&peer_id) {
+            let (wants, deferred): (Vec<_>, VecDeque<_>) = deferred.into_iter().partition(is_want);
+            cancelled.extend(wants);
+            if !deferred.is_empty() {
+                self.deferred.insert(peer_id, deferred);
+            }
+        }
+        if let Some(waiting) = self.waiting.remove(&cid) {
+            let (wants, waiting): (Vec<_>, Vec<_>) = waiting.into_iter().partition(is_want);
+            cancelled.extend(wants);
+            if !waiting.is_empty() {
+                self.waiting.insert(cid, waiting);
+            }
+        }
+        for req in cancelled {
+            if let BitswapChannel::Compat(_, _, batch) = req.channel {
+                self.compat_response(batch, None);
+            }
+        }
+    }
+
+    /// Sends the responses to an inbound batch. If the channel closed, the responses are
+    /// retried over compat for connected compat peers.
+    fn send_batch_response(
+        &mut self,
+        peer_id: PeerId,
+        Channel(channel): Channel,
+        entries: Vec<(Cid, BitswapResponse)>,
+    ) {
+        let (cids, responses): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+        let responses = if channel.is_open() {
+            match self
+                .inner
+                .send_response(channel, Negotiated::from(Batch::Many(responses)))
+            {
+                Ok(()) => return,
+                Err(Negotiated { msg, .. }) => msg,
+            }
+        } else {
+            Batch::Many(responses)
+        };
+        let entries = match responses {
+            Batch::Many(responses) => cids.into_iter().zip(responses).collect::<Vec<_>>(),
+            _ => return,
+        };
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        if !entries.is_empty()
+            && self.compat.contains(&peer_id)
+            && self.inner.is_connected(&peer_id)
+        {
+            tracing::debug!(
+                "retrying a batch of {} responses to {} over compat",
+                entries.len(),
+                peer_id
+            );
+            let msgs = entries
+                .into_iter()
+                .map(|(cid, response)| CompatMessage::Response(cid, fit_compat(&cid, response)));
+            let bundles = OutboundMessage::bundle(msgs);
+            self.queue_compat(peer_id, bundles);
+            return;
+        }
+        for (_, response) in &entries {
+            let ty = response_type(response);
+            RESPONSE_DELIVERY_FAILED.with_label_values(&[ty]).inc();
+        }
+        tracing::debug!(
+            "failed to deliver a batch of {} responses to {}",
+            entries.len(),
+            peer_id
+        );
+    }
+
+    /// Processes the responses to a batched request, which answer its entries in order.
+    /// Entries the peer didn't answer are treated as not having the block.
+    fn inject_batch_response(
+        &mut self,
+        request_id: RequestId,
+        peer: PeerId,
+        responses: Vec<BitswapResponse>,
+    ) {
+        let answered = responses.len();
+        let mut responses = responses.into_iter();
+        if let Some(response) = responses.next() {
+            self.inject_response(BitswapId::Bitswap(request_id), peer, response);
+        } else {
+            self.untrack_request(request_id);
+            if let Some(id) = self.take_request(&BitswapId::Bitswap(request_id), &peer) {
+                self.query_manager
+                    .inject_response(id, Response::Have(peer, false));
+            }
+        }
+        for (index, response) in (1..MAX_BATCH_SIZE).zip(responses) {
+            self.inject_response(BitswapId::Batch(request_id, index), peer, response);
+        }
+        for id in self.take_batch(request_id, answered.max(1), &peer) {
+            self.query_manager
+                .inject_response(id, Response::Have(peer, false));
+        }
+    }
+
+    /// Serves, denies or defers an inbound request according to the server policy.
+    /// Requests of refused peers are denied without asking the policy.
+    fn evaluate_request(&mut self, req: Deferred) {
+        let (peer_id, cid) = req.channel.peer_cid();
+        if self.refuses_peer(&peer_id) {
+            tracing::debug!("denying request of refused peer {} for {}", peer_id, cid);
+            REFUSED_PEER_REQUESTS.with_label_values(&["inbound"]).inc();
+            return self.deny_request(req);
+        }
+        let ledger = self.ledgers.get(&peer_id).copied().unwrap_or_default();
+        let mut decision = if let Some(policy) = self.policy.as_mut() {
+            policy.on_request(&peer_id, &cid, req.request.ty, &ledger)
+        } else {
+            PolicyDecision::Serve
+        };
+        if decision == PolicyDecision::Serve
+            && req.request.ty == RequestType::Block
+            && self.in_debt(&ledger)
+        {
+            tracing::debug!(
+                "deferring block request of {} for {} with a debt ratio of {:.2}",
+                peer_id,
+                cid,
+                ledger.debt_ratio()
+            );
+            DEBT_THROTTLED_REQUESTS.inc();
+            decision = PolicyDecision::Defer;
+        }
+        match decision {
+            PolicyDecision::Serve if self.cached_have(&req.request) => {
+                tracing::trace!("have {} cached", cid);
+                HAVE_CACHE_HITS.inc();
+                self.counters.sent_have(true);
+                let response = DbResponse::Bitswap(
+                    req.channel,
+                    req.request.ty,
+                    BitswapResponse::Have(true),
+                    req.received,
+                );
+                self.responses.push_back(response);
+            }
+            PolicyDecision::Serve => {
+                let request = DbRequest::Bitswap(req.channel, req.request, req.received);
+                self.send_db(request);
+            }
+            PolicyDecision::Deny => self.deny_request(req),
+            PolicyDecision::Defer => {
+                self.deferred.entry(peer_id).or_default().push_back(req);
+            }
+            PolicyDecision::Ignore => {
+                POLICY_IGNORED_REQUESTS.inc();
+                self.ignore_request(req);
+            }
+        }
+    }
+
+    /// Returns whether a have request can be answered from the have cache.
+    fn cached_have(&mut self, request: &BitswapRequest) -> bool {
+        match self.have_cache.as_mut() {
+            Some(cache) if request.ty == RequestType::Have => cache.contains(&request.cid),
+            _ => false,
+        }
+    }
+
+    /// Returns whether a peer took more than `max_debt_ratio` allows.
+    fn in_debt(&self, ledger: &Ledger) -> bool {
+        match self.config.max_debt_ratio {
+            Some(max) => {
+                ledger.since_watermark() > self.config.debt_allowance && ledger.debt_ratio() > max
+            }
+            None => false,
+        }
+    }
+
+    /// Drops a request without answering it.
+    fn ignore_request(&mut self, req: Deferred) {
+        self.answer_want(&req.channel, req.request.ty, None);
+        let (peer_id, cid) = req.channel.peer_cid();
+        tracing::debug!(
+            "ignoring {} request of {} for {}",
+            req.request.ty,
+            peer_id,
+            cid
+        );
+        match req.channel {
+            BitswapChannel::Batch(_, _, batch, index) => {
+                self.batch_response(batch, index, BitswapResponse::Have(false));
+            }
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            BitswapChannel::Compat(peer_id, cid, batch) => {
+                self.take_compat_want(peer_id, cid);
+                self.compat_response(batch, None);
+            }
+            BitswapChannel::Bitswap(_, _, _) => {}
+        }
+    }
+
+    /// Answers that we don't have the block.
+    fn deny_request(&mut self, req: Deferred) {
+        self.counters.sent_have(false);
+        let response = DbResponse::Bitswap(
+            req.channel,
+            req.request.ty,
+            BitswapResponse::Have(false),
+            req.received,
+        );
+        self.responses.push_back(response);
+    }
+
+    /// Denies deferred requests that outlived their ttl and arms the timer for the next
+    /// one. Returns `true` if a request expired.
+    fn poll_deferred(&mut self, cx: &mut Context) -> bool {
+        let now = Instant::now();
+        let ttl = self.config.deferred_request_ttl;
+        let mut next: Option<Instant> = None;
+        let mut expired = vec![];
+        for deferred in self.deferred.values_mut() {
+            while let Some(req) = deferred.front() {
+                let expires = req.received + ttl;
+                if expires <= now {
+                    expired.extend(deferred.pop_front());
+                } else {
+                    next = Some(next.map_or(expires, |next| next.min(expires)));
+                    break;
+                }
+            }
+        }
+        self.deferred.retain(|_, deferred| !deferred.is_empty());
+        let expired_any = !expired.is_empty();
+        for req in expired {
+            self.deny_request(req);
+        }
+        if let Some(next) = next {
+            let timer = self
+                .deferred_timer
+                .get_or_insert_with(|| Delay::new(next - now));
+            timer.reset(next - now);
+            if Pin::new(timer).poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        } else {
+            self.deferred_timer = None;
+        }
+        expired_any
+    }
+
+    /// Returns the next response to send, preferring those produced without the store.
+    fn next_response(&mut self, cx: &mut Context) -> Option<DbResponse> {
+        if let Some(db) = self.db_task.as_mut() {
+            if db.poll_unpin(cx).is_ready() {
+                self.db_task = None;
+            }
+        }
+        if let Some(response) = self
+            .uploads
+            .as_mut()
+            .and_then(|uploads| uploads.poll_pop(cx))
+        {
+            return Some(response);
+        }
+        while let Some(response) = self.responses.pop_front() {
+            if let Some(response) = self.throttle_response(cx, response) {
+                return Some(response);
+            }
+        }
+        while let Poll::Ready(response) = Pin::new(&mut self.db_rx).poll_next(cx) {
+            if let Some(response) = self.hold_response(response?) {
+                if let Some(response) = self.throttle_response(cx, response) {
+                    return Some(response);
+                }
+            }
+        }
+        None
+    }
+
+    /// Queues a block response behind the ones waiting for the budget of
+    /// `max_upload_rate`. Returns the next response that may be sent.
+    fn throttle_response(&mut self, cx: &mut Context, response: DbResponse) -> Option<DbResponse> {
+        let uploads = if let Some(uploads) = self.uploads.as_mut() {
+            uploads
+        } else {
+            return Some(response);
+        };
+        let bytes = match &response {
+            DbResponse::Bitswap(_, _, BitswapResponse::Block(data), _) => data.len(),
+            _ => return Some(response),
+        };
+        uploads.push(bytes, response);
+        uploads.poll_pop(cx)
+    }
+
+    /// Holds a response saying we don't have a block we are currently fetching, or
+    /// registers the peer for an announcement. Returns the response if it is to be sent.
+    fn hold_response(&mut self, response: DbResponse) -> Option<DbResponse> {
+        let (channel, ty, received) = match response {
+            DbResponse::Bitswap(channel, ty, BitswapResponse::Have(false), received)
+                if self.config.fetching_wants != FetchingWants::DontHave =>
+            {
+                (channel, ty, received)
+            }
+            response => return Some(response),
+        };
+        let (peer_id, cid) = channel.peer_cid();
+        if !self.query_manager.is_fetching(&cid) {
+            return Some(DbResponse::Bitswap(
+                channel,
+                ty,
+                BitswapResponse::Have(false),
+                received,
+            ));
+        }
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        if let (FetchingWants::Announce, BitswapChannel::Compat(_, _, _)) =
+            (self.config.fetching_wants, &channel)
+        {
+            tracing::debug!("announcing {} to {} once it arrives", cid, peer_id);
+            FETCHING_WANTS.with_label_values(&["announce"]).inc();
+            self.announcements.entry(cid).or_default().insert(peer_id);
+            return Some(DbResponse::Bitswap(
+                channel,
+                ty,
+                BitswapResponse::Have(false),
+                received,
+            ));
+        }
+        tracing::debug!(
+            "holding request of {} for {} until it arrives",
+            peer_id,
+            cid
+        );
+        FETCHING_WANTS.with_label_values(&["delay"]).inc();
+        let deadline = received + self.config.inbound_response_budget;
+        self.waiting_deadlines.insert((deadline, cid));
+        self.waiting.entry(cid).or_default().push(Deferred {
+            channel,
+            request: BitswapRequest::new(ty, cid),
+            received,
+        });
+        None
+    }
+
+    /// Queues a received block for insertion into the store, or spills it if the
+    /// backlog is too large.
+    fn insert_block(&mut self, request: DbRequest<P>) {
+        #[cfg(feature = "spill")]
+        match &request {
+            DbRequest::Insert(block, _) => {
+                let limit = self.config.max_pending_insert_bytes;
+                if self.spill.put(block.cid(), block.data(), limit) {
+                    return;
+                }
+                self.spill.queued(block.data().len());
+            }
+            DbRequest::InsertStrict(block, _, _) => self.spill.queued(block.data().len()),
+            _ => {}
+        }
+        self.send_db(request);
+    }
+
+    /// Queues a request for the db workers. Serves stay with the first worker while
+    /// blocks are spilled, since only it drains the spill store.
+    fn send_db(&self, request: DbRequest<P>) {
+        #[cfg(feature = "spill")]
+        let local = self.spill.is_spilled();
+        #[cfg(not(feature = "spill"))]
+        let local = false;
+        self.db_tx.send(request, local);
+    }
+
+    /// Serves the requests held for a block that was queued for insertion into the
+    /// store, resolves the wants registered for it and announces it to the registered
+    /// compat peers.
+    fn block_arrived(&mut self, cid: &Cid) {
+        if self.wantlist.contains(cid) {
+            // resolved once the store has the block
+            self.send_db(DbRequest::Available(*cid));
+        }
+        if let Some(waiting) = self.waiting.remove(cid) {
+            for req in waiting {
+                FETCHING_WANTS.with_label_values(&["served"]).inc();
+                let request = DbRequest::Released(req.channel, req.request, req.received);
+                self.send_db(request);
+            }
+        }
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        if let Some(peers) = self.announcements.remove(cid) {
+            for peer_id in peers {
+                if self.connections.contains_key(&peer_id) {
+                    let msg = CompatMessage::Response(*cid, BitswapResponse::Have(true));
+                    self.compat_queue.push_back((peer_id, msg.into()));
+                }
+            }
+        }
+    }
+
+    /// Answers held requests whose block didn't arrive within the inbound response budget
+    /// or is no longer fetched, and arms the timer for the next one. Returns `true` if a
+    /// request was answered.
+    fn poll_waiting(&mut self, cx: &mut Context) -> bool {
+        let mut expired = vec![];
+        for cid in self.query_manager.take_unfetched() {
+            if self.query_manager.is_fetching(&cid) {
+                continue;
+            }
+            if let Some(waiting) = self.waiting.remove(&cid) {
+                expired.extend(waiting);
+            }
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            self.announcements.remove(&cid);
+        }
+        let now = Instant::now();
+        let budget = self.config.inbound_response_budget;
+        while let Some(&(deadline, cid)) = self.waiting_deadlines.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.waiting_deadlines.remove(&(deadline, cid));
+            if let Some(waiting) = self.waiting.get_mut(&cid) {
+                let mut i = 0;
+                while i < waiting.len() {
+                    if waiting[i].received + budget <= now {
+                        expired.push(waiting.swap_remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+                if waiting.is_empty() {
+                    self.waiting.remove(&cid);
+                }
+            }
+        }
+        let expired_any = !expired.is_empty();
+        for req in expired {
+            FETCHING_WANTS.with_label_values(&["expired"]).inc();
+            let response = DbResponse::Bitswap(
+                req.channel,
+                req.request.ty,
+                BitswapResponse::Have(false),
+                req.received,
+            );
+            self.responses.push_back(response);
+        }
+        if let Some(&(next, _)) = self.waiting_deadlines.iter().next() {
+            // the timer is only replaced when the earliest deadline changed
+            if !matches!(&self.waiting_timer, Some((armed, _)) if *armed == next) {
+                self.waiting_timer = Some((next, Delay::new(next - now)));
+            }
+            if let Some((_, timer)) = self.waiting_timer.as_mut() {
+                if Pin::new(timer).poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+            }
+        } else {
+            self.waiting_timer = None;
+        }
+        expired_any
+    }
+
+    /// Accounts a block handed to the network in the ledger of a peer.
+    fn charge(&mut self, peer_id: PeerId, cid: &Cid, response: &BitswapResponse) {
+        if let BitswapResponse::Block(data) = response {
+            self.ledgers.entry(peer_id).or_default().bytes_served += data.len() as u64;
+            if let Some(hot_cids) = self.hot_cids.as_mut() {
+                hot_cids.record(cid, data.len() as u64);
+            }
+        }
+    }
+
+    /// Accounts a valid block received from a peer in its ledger, which may settle its
+    /// debt.
+    fn credit(&mut self, peer_id: PeerId, len: usize) {
+        self.ledgers.entry(peer_id).or_default().bytes_received += len as u64;
+        self.reevaluate_deferred(&peer_id);
+    }
+
+    /// Processes an incoming bitswap response.
+    fn inject_response(&mut self, id: BitswapId, peer: PeerId, response: BitswapResponse) {
+        #[cfg(feature = "chaos")]
+        let response = match (id, response) {
+            (BitswapId::Bitswap(request_id), BitswapResponse::Block(mut data))
+                if self.corrupt.remove(&request_id) =>
+            {
+                chaos::corrupt(&mut data);
+                BitswapResponse::Block(data)
+            }
+            (_, response) => response,
+        };
+        if self.mismatched(&id, &peer) {
+            return;
+        }
+        if let BitswapId::Bitswap(request_id) = id {
+            if let Some(inflight) = self.untrack_request(request_id) {
+                let bytes = match &response {
+                    BitswapResponse::Have(_) => 0,
+                    BitswapResponse::Block(data) => data.len(),
+                };
+                let elapsed = inflight.sent.elapsed();
+                self.query_manager.observe_latency(peer, elapsed, bytes);
+                if inflight.probe && !inflight.expired {
+                    PROVIDER_PROBES.with_label_values(&["alive"]).inc();
+                } else if inflight.expired && !inflight.probe {
+                    REQUEST_EXPIRATIONS
+                        .with_label_values(&["adaptive_late_response"])
+                        .inc();
+                }
+            }
+        }
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        let fallback = self.fallbacks.remove(&id);
+        if let Some(request) = self.requests.remove(&id) {
+            let id = request.query;
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            if fallback {
+                self.count_compat_fallback(id, peer, "succeeded");
+            }
+            if self.dial_retries.remove(&(id, peer)).is_some() {
+                DIAL_RETRIES.with_label_values(&["success"]).inc();
+            }
+            if self.timeout_retries.remove(&(id, peer)).is_some() {
+                TIMEOUT_RETRIES.with_label_values(&["success"]).inc();
+            }
+            match response {
+                BitswapResponse::Have(have) => {
+                    self.query_manager
+                        .inject_response(id, Response::Have(peer, have));
+                }
+                BitswapResponse::Block(data) => {
+                    if let Some(info) = self.query_manager.query_info(id) {
+                        let len = data.len();
+                        let root = info.root;
+                        let verified = !self.config.trusted_peers.contains(&peer);
+                        let block = if verified {
+                            Block::new(info.cid, data)
+                        } else {
+                            Ok(Block::new_unchecked(info.cid, data))
+                        };
+                        if let Ok(block) = block {
+                            self.counters.received_block(len, verified);
+                            self.credit(peer, len);
+                            self.query_manager.observe_block(root, len);
+                            self.query_manager.observe_transfer(root, peer, len, false);
+                            self.receive_block(id, root, Some(peer), block);
+                        } else {
+                            tracing::error!("received invalid block");
+                            self.counters.received_invalid_block(len);
+                            self.query_manager
+                                .inject_response(id, Response::Block(peer, false));
+                        }
+                    } else {
+                        // another peer sent the block first
+                        DUPLICATE_BLOCKS.inc();
+                        self.query_manager
+                            .observe_transfer(request.root, peer, data.len(), true);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Emits a `Block` event for each query waiting for the block.
+    fn emit_block(&mut self, queries: &[QueryId], cid: Cid, data: Bytes) {
+        for id in queries {
+            let event = BitswapEvent::block(*id, cid, data.clone());
+            self.events.push_back(event);
+        }
+    }
+
+    /// Inserts the block of a get or sync query into the store, emitting it if
+    /// configured, and answers the query. `peer` is `None` for inline blocks.
+    fn receive_block(&mut self, id: QueryId, root: QueryId, peer: Option<PeerId>, block: Block<P>) {
+        let answered = self.query_manager.answered(root);
+        if let Some(peer_id) = peer {
+            for query in &answered {
+                self.sessions.block_received(*query, peer_id);
+            }
+        }
+        let options = self.query_manager.sync_options(root).copied();
+        // decoded before the block moves to the store
+        let decoder = self.decoder;
+        let links = match decoder {
+            Some(decoder)
+                if options.is_some()
+                    && self.config.speculative_prefetch
+                    && !self.selectors.contains_key(&root) =>
+            {
+                (decoder.references)(&block)
+            }
+            _ => vec![],
+        };
+        if options.is_some() || !self.config.skip_store_insert {
+            if self.config.emit_blocks_in_events {
+                let data = Bytes::copy_from_slice(block.data());
+                self.emit_block(&answered, *block.cid(), data);
+            }
+            let cid = *block.cid();
+            let hint = InsertHint {
+                root,
+                parent: self.query_manager.parent_block(id),
+            };
+            let request = match (options, decoder) {
+                (Some(options), Some(decoder)) if options.strict => {
+                    DbRequest::InsertStrict(block, hint, decoder.check)
+                }
+                _ => DbRequest::Insert(block, hint),
+            };
+            self.insert_block(request);
+            self.block_arrived(&cid);
+            if options.is_none() {
+                // a get completes once its block is in the store. a sync reads its
+                // blocks from the store before it completes, which orders it after the
+                // insert.
+                self.send_db(DbRequest::InsertDone(id, peer, cid));
+                return;
+            }
+        } else if self.config.emit_blocks_in_events {
+            let (cid, data) = block.into_inner();
+            self.emit_block(&answered, cid, data.into());
+        }
+        if !links.is_empty() {
+            self.query_manager.prefetch(id, links);
+        }
+        self.query_manager
+            .inject_response(id, block_response(peer, true));
+    }
+
+    /// Answers an inline query with the block whose data is the digest of its cid,
+    /// without asking a peer.
+    fn receive_inline(&mut self, id: QueryId, cid: Cid) {
+        let root = if let Some(info) = self.query_manager.query_info(id) {
+            info.root
+        } else {
+            return;
+        };
+        // the digest is the data, so there is no hash to verify
+        let block = Block::new_unchecked(cid, cid.hash().digest().to_vec());
+        self.receive_block(id, root, None, block);
+    }
+
+    fn inject_outbound_failure(
+        &mut self,
+        peer: &PeerId,
+        request_id: RequestId,
+        error: &OutboundFailure,
+    ) {
+        tracing::debug!(
+            "bitswap outbound failure {} {} {:?}",
+            peer,
+            request_id,
+            error
+        );
+        if let Some(inflight) = self.untrack_request(request_id) {
+            if inflight.probe && !inflight.expired {
+                PROVIDER_PROBES.with_label_values(&["dead"]).inc();
+            }
+        }
+        #[cfg(feature = "chaos")]
+        self.corrupt.remove(&request_id);
+        let ty = match error {
+            OutboundFailure::DialFailure => "dial_failure",
+            OutboundFailure::Timeout => {
+                if self.requests.contains_key(&BitswapId::Bitswap(request_id)) {
+                    REQUEST_EXPIRATIONS.with_label_values(&["fixed"]).inc();
+                }
+                "timeout"
+            }
+            OutboundFailure::ConnectionClosed => "connection_closed",
+            OutboundFailure::UnsupportedProtocols => {
+                self.record_support(*peer, PeerSupport::Unsupported);
+                "unsupported_protocols"
+            }
+        };
+        OUTBOUND_FAILURE.with_label_values(&[ty]).inc();
+        if let Some(peer_failures) = self.peer_failures.as_mut() {
+            peer_failures.record(peer, "outbound", ty);
+        }
+    }
+
+    fn inject_inbound_failure(
+        &mut self,
+        peer: &PeerId,
+        request_id: RequestId,
+        error: &InboundFailure,
+    ) {
+        tracing::error!(
+            "bitswap inbound failure {} {} {:?}",
+            peer,
+            request_id,
+            error
+        );
+        let ty = match error {
+            InboundFailure::Timeout => "timeout",
+            InboundFailure::ConnectionClosed => "connection_closed",
+            InboundFailure::UnsupportedProtocols => "unsupported_protocols",
+            InboundFailure::ResponseOmission => "response_omission",
+        };
+        INBOUND_FAILURE.with_label_values(&[ty]).inc();
+        if let Some(peer_failures) = self.peer_failures.as_mut() {
+            peer_failures.record(peer, "inbound", ty);
+        }
+    }
+}
+
+impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
+    #[cfg(not(any(feature = "compat", feature = "compat-lite")))]
+    type ConnectionHandler =
+        <RequestResponse<BitswapCodec<P>> as NetworkBehaviour>::ConnectionHandler;
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[allow(clippy::type_complexity)]
+    type ConnectionHandler = ConnectionHandlerSelect<
+        <RequestResponse<BitswapCodec<P>> as NetworkBehaviour>::ConnectionHandler,
+        OneShotHandler<CompatProtocol, OutboundMessage, InboundMessage>,
+    >;
+    type OutEvent = BitswapEvent;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        #[cfg(not(any(feature = "compat", feature = "compat-lite")))]
+        return self.inner.new_handler();
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        {
+            let protocol = CompatProtocol::new(self.config.enable_compat);
+            let compat = OneShotHandler::new(
+                SubstreamProtocol::new(protocol, ()),
+                OneShotHandlerConfig::default(),
+            );
+            ConnectionHandler::select(self.inner.new_handler(), compat)
+        }
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionEstablished(ev) => {
+                self.connections
+                    .entry(ev.peer_id)
+                    .or_default()
+                    .insert(ev.connection_id, Instant::now());
+                let peer_id = ev.peer_id;
+                self.inner
+                    .on_swarm_event(FromSwarm::ConnectionEstablished(ev));
+                if self.bootstrap.connected(&peer_id) {
+                    self.detect_support(peer_id);
+                }
+                #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                if let Some(msgs) = self.compat_dials.remove(&peer_id) {
+                    // the requests waiting for the dial are all we want from the peer
+                    let mut bundles = OutboundMessage::bundle(msgs);
+                    bundles[0].full = true;
+                    self.compat_queue
+                        .extend(bundles.into_iter().map(|msg| (peer_id, msg)));
+                }
+                self.limited_dials.remove(&peer_id);
+                self.queued_dials.remove(&peer_id);
+                self.send_queued_dials();
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                handler,
+                remaining_established,
+            }) => {
+                if let Some(conns) = self.connections.get_mut(&peer_id) {
+                    conns.remove(&connection_id);
+                    if conns.is_empty() {
+                        self.connections.remove(&peer_id);
+                        self.capabilities.remove(&peer_id);
+                        self.query_manager.forget_estimate(&peer_id);
+                        self.peer_wants.remove_peer(&peer_id);
+                        if let Some(deferred) = self.deferred.remove(&peer_id) {
+                            tracing::debug!(
+                                "dropping {} deferred requests of {}",
+                                deferred.len(),
+                                peer_id
+                            );
+                        }
+                        if self.ledgers.get(&peer_id).map(Ledger::since_watermark) == Some(0) {
+                            self.ledgers.remove(&peer_id);
+                        }
+                        let base = self.config.dial_retry_backoff;
+                        if let Some(backoff) = self.bootstrap.disconnected(&peer_id, base) {
+                            self.redial_bootstrap(peer_id, backoff);
+                        }
+                    }
+                }
+                #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                if let Some(pending) = self.compat_pending.remove(&connection_id) {
+                    if self.connections.contains_key(&peer_id) {
+                        tracing::debug!(
+                            "rerouting {} compat messages to {}",
+                            pending.len(),
+                            peer_id
+                        );
+                        self.queue_compat(peer_id, pending);
+                    } else {
+                        tracing::debug!(
+                            "dropping {} compat messages to {}",
+                            pending.len(),
+                            peer_id
+                        );
+                    }
+                }
+                #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                if remaining_established == 0 {
+                    self.compat.remove(&peer_id);
+                    self.compat_versions.remove(&peer_id);
+                    self.fail_compat_requests(peer_id);
+                    self.compat_batches
+                        .retain(|_, batch| batch.peer_id != peer_id);
+                    self.compat_wants
+                        .retain(|(peer_id2, _), _| *peer_id2 != peer_id);
+                }
+                #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                let (handler, _oneshot) = handler.into_inner();
+                self.inner
+                    .on_swarm_event(FromSwarm::ConnectionClosed(ConnectionClosed {
+                        peer_id,
+                        connection_id,
+                        endpoint,
+                        handler,
+                        remaining_established,
+                    }));
+                self.send_queued_dials();
+            }
+            FromSwarm::DialFailure(DialFailure {
+                peer_id,
+                handler,
+                error,
+            }) => {
+                let limited = matches!(error, DialError::ConnectionLimit(_));
+                if let Some(peer_id) = peer_id {
+                    let base = self.config.dial_retry_backoff;
+                    self.queued_dials.remove(&peer_id);
+                    if limited {
+                        // the peer is fine, we are out of connections
+                        tracing::debug!("dial of {} rejected by the connection limits", peer_id);
+                        DIALS_LIMITED.inc();
+                        self.limited_dials.insert(peer_id);
+                        if self.bootstrap.dial_limited(&peer_id) {
+                            self.redial_bootstrap(peer_id, base);
+                        }
+                    } else {
+                        self.limited_dials.remove(&peer_id);
+                        if let Some(backoff) = self.bootstrap.dial_failed(&peer_id, base) {
+                            self.redial_bootstrap(peer_id, backoff);
+                        }
+                    }
+                    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                    if self.compat_dials.remove(&peer_id).is_some() {
+                        self.fail_compat_requests(peer_id);
+                    }
+                }
+                #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                let (handler, _oneshot) = handler.into_inner();
+                self.inner
+                    .on_swarm_event(FromSwarm::DialFailure(DialFailure {
+                        peer_id,
+                        handler,
+                        error,
+                    }));
+                if !limited {
+                    self.send_queued_dials();
+                }
+            }
+            FromSwarm::AddressChange(ev) => self.inner.on_swarm_event(FromSwarm::AddressChange(ev)),
+            FromSwarm::ListenFailure(ListenFailure {
+                local_addr,
+                send_back_addr,
+                handler,
+            }) => {
+                #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                let (handler, _oneshot) = handler.into_inner();
+                self.inner
+                    .on_swarm_event(FromSwarm::ListenFailure(ListenFailure {
+                        local_addr,
+                        send_back_addr,
+                        handler,
+                    }));
+            }
+            FromSwarm::NewListener(ev) => self.inner.on_swarm_event(FromSwarm::NewListener(ev)),
+            FromSwarm::NewListenAddr(ev) => self.inner.on_swarm_event(FromSwarm::NewListenAddr(ev)),
+            FromSwarm::ExpiredListenAddr(ev) => {
+                self.inner.on_swarm_event(FromSwarm::ExpiredListenAddr(ev))
+            }
+            FromSwarm::ListenerError(ev) => self.inner.on_swarm_event(FromSwarm::ListenerError(ev)),
+            FromSwarm::ListenerClosed(ev) => {
+                self.inner.on_swarm_event(FromSwarm::ListenerClosed(ev))
+            }
+            FromSwarm::NewExternalAddr(ev) => {
+                self.inner.on_swarm_event(FromSwarm::NewExternalAddr(ev))
+            }
+            FromSwarm::ExpiredExternalAddr(ev) => self
+                .inner
+                .on_swarm_event(FromSwarm::ExpiredExternalAddr(ev)),
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        conn: ConnectionId,
+        event: <Self::ConnectionHandler as ConnectionHandler>::OutEvent,
+    ) {
+        tracing::trace!(?event, "on_connection_handler_event");
+        #[cfg(not(any(feature = "compat", feature = "compat-lite")))]
+        {
+            self.touch(peer_id, conn);
+            self.inner.on_connection_handler_event(peer_id, conn, event);
+        }
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        match event {
+            EitherOutput::First(event) => {
+                self.touch(peer_id, conn);
+                self.inner.on_connection_handler_event(peer_id, conn, event);
+            }
+            EitherOutput::Second(InboundMessage::Sent { id, version }) => {
+                self.touch(peer_id, conn);
+                self.set_compat_version(peer_id, version);
+                self.take_compat_pending(conn, id);
+            }
+            EitherOutput::Second(InboundMessage::Failed { id, error }) => {
+                // not touched, so later messages prefer the other connections of the peer
+                tracing::debug!("failed to send compat message to {}: {}", peer_id, error);
+                if let Some(msg) = self.take_compat_pending(conn, id) {
+                    self.drop_compat_message(peer_id, msg);
+                }
+            }
+            EitherOutput::Second(InboundMessage::Received {
+                parts,
+                version,
+                full,
+            }) => {
+                self.touch(peer_id, conn);
+                self.set_compat_version(peer_id, version);
+                self.inject_compat_message(peer_id, parts, full)
+            }
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        pp: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        self.poll_counters(cx);
+        let mut exit = false;
+        while !exit {
+            exit = !self.poll_deadlines(cx);
+            self.poll_query_deadlines(cx);
+            if let Some(event) = self.events.pop_front() {
+                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+            }
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            if let Some((peer_id, msg)) = self.next_compat() {
+                return Poll::Ready(self.notify_compat(peer_id, msg));
+            }
+            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+            if let Some(peer_id) = self.compat_dial_queue.pop_front() {
+                let opts = DialOpts::peer_id(peer_id).build();
+                let handler = self.new_handler();
+                return Poll::Ready(NetworkBehaviourAction::Dial { opts, handler });
+            }
+            #[cfg(feature = "chaos")]
+            while let Poll::Ready(Some(delayed)) = self.delayed.poll_next_unpin(cx) {
+                exit = false;
+                match delayed {
+                    Delayed::Send(id, peer_id, req) => {
+                        if self.query_manager.query_info(id).is_some() {
+                            self.dispatch_request(id, peer_id, req);
+                        }
+                    }
+                    Delayed::Timeout(id, peer_id) => {
+                        if !self.retry_timeout(id, peer_id) {
+                            self.query_manager
+                                .inject_response(id, Response::Failed(peer_id));
+                        }
+                    }
+                }
+            }
+            if self.poll_deferred(cx) {
+                exit = false;
+            }
+            if self.poll_waiting(cx) {
+                exit = false;
+            }
+            while let Poll::Ready(Some(msg)) = self.wantlist_rx.poll_next_unpin(cx) {
+                match msg {
+                    WantMessage::Register(want) => {
+                        let cid = want.cid;
+                        if self.wantlist.insert(want) {
+                            self.send_db(DbRequest::Available(cid));
+                        }
+                    }
+                    WantMessage::Unregister(cid) => self.wantlist.unregister(&cid),
+                }
+            }
+            while let Poll::Ready(Some((id, peer_id))) = self.retries.poll_next_unpin(cx) {
+                exit = false;
+                self.resend_request(id, peer_id);
+            }
+            if !self.peer_queues.is_empty() && self.send_queued_requests() {
+                exit = false;
+            }
+            while let Poll::Ready(Some((id, providers))) = self.discoveries.poll_next_unpin(cx) {
+                exit = false;
+                self.receive_providers(id, providers);
+            }
+            while let Poll::Ready(Some(peer_id)) = self.bootstrap_redials.poll_next_unpin(cx) {
+                self.bootstrap.redial_due(&peer_id);
+                self.bootstrap_dials.push_back(peer_id);
+            }
+            while let Poll::Ready(Some(peer_id)) = self.circuit_cooldowns.poll_next_unpin(cx) {
+                if self.circuits.half_open(&peer_id) {
+                    tracing::debug!("probing {} with a half open circuit", peer_id);
+                    let request_id = self.send_probe(peer_id);
+                    self.circuit_probes.insert(request_id, peer_id);
+                    exit = false;
+                }
+            }
+            while let Some(peer_id) = self.bootstrap_dials.pop_front() {
+                if let Some(addr) = self.bootstrap.start_dial(&peer_id) {
+                    let opts = DialOpts::peer_id(peer_id).addresses(vec![addr]).build();
+                    let handler = self.new_handler();
+                    return Poll::Ready(NetworkBehaviourAction::Dial { opts, handler });
+                }
+            }
+            while let Some(response) = self.next_response(cx) {
+                exit = false;
+                match response {
+                    DbResponse::Bitswap(channel, ty, response, _) => {
+                        if let Some(cache) = self.have_cache.as_mut() {
+                            if !matches!(response, BitswapResponse::Have(false)) {
+                                cache.insert(channel.peer_cid().1);
+                            }
+                        }
+                        self.record_want(&channel, ty, &response);
+                        self.answer_want(&channel, ty, Some(&response));
+                        match channel {
+                            BitswapChannel::Bitswap(peer_id, cid, Channel(channel)) => {
+                                let open = channel.is_open();
+                                let response = if open {
+                                    self.charge(peer_id, &cid, &response);
+                                    match self.inner.send_response(channel, response.into()) {
+                                        Err(Negotiated {
+                                            msg: Batch::One(response),
+                                            ..
+                                        }) => response,
+                                        _ => continue,
+                                    }
+                                } else {
+                                    response
+                                };
+                                #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                                if self.compat.contains(&peer_id)
+                                    && self.inner.is_connected(&peer_id)
+                                {
+                                    tracing::debug!(
+                                        "retrying response to {} for {} over compat",
+                                        peer_id,
+                                        cid
+                                    );
+                                    let response = fit_compat(&cid, response);
+                                    if !open {
+                                        self.charge(peer_id, &cid, &response);
+                                    }
+                                    let compat = CompatMessage::Response(cid, response);
+                                    return Poll::Ready(self.notify_compat(peer_id, compat.into()));
+                                }
+                                let ty = response_type(&response);
+                                RESPONSE_DELIVERY_FAILED.with_label_values(&[ty]).inc();
+                                if open {
+                                    tracing::warn!(
+                                        "failed to deliver {} response to {} for {}",
+                                        ty,
+                                        peer_id,
+                                        cid
+                                    );
+                                } else {
+                                    tracing::debug!(
+                                        "dropping {} response to {} for {}, channel closed",
+                                        ty,
+                                        peer_id,
+                                        cid
+                                    );
+                                }
+                            }
+                            BitswapChannel::Batch(_, _, batch, index) => {
+                                self.batch_response(batch, index, response);
+                            }
+                            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                            BitswapChannel::Compat(peer_id, cid, batch) => {
+                                if !self.take_compat_want(peer_id, cid) {
+                                    tracing::debug!(
+                                        "dropping response to {} for {}, want cancelled",
+                                        peer_id,
+                                        cid
+                                    );
+                                    self.compat_response(batch, None);
+                                    continue;
+                                }
+                                let response = fit_compat(&cid, response);
+                                self.charge(peer_id, &cid, &response);
+                                let compat = CompatMessage::Response(cid, response);
+                                self.compat_response(batch, Some(compat));
+                            }
+                        }
+                    }
+                    DbResponse::MissingBlocks(id, Ok(missing)) => {
+                        MISSING_BLOCKS_TOTAL.inc_by(missing.len() as u64);
+                        self.query_manager
+                            .inject_response(id, Response::MissingBlocks(missing));
+                    }
+                    DbResponse::SelectedBlocks(id, Ok(selected)) => {
+                        let root = self.query_manager.query_info(id).map(|query| query.root);
+                        let mut selectors = root.and_then(|root| self.selectors.get_mut(&root));
+                        let mut missing = Vec::with_capacity(selected.len());
+                        for (cid, selector) in selected {
+                            if let Some(selectors) = selectors.as_mut() {
+                                selectors.insert(cid, selector);
+                            }
+                            missing.push(cid);
+                        }
+                        MISSING_BLOCKS_TOTAL.inc_by(missing.len() as u64);
+                        self.query_manager
+                            .inject_response(id, Response::MissingBlocks(missing));
+                    }
+                    DbResponse::MissingBlocks(id, Err(err))
+                    | DbResponse::SelectedBlocks(id, Err(err)) => {
+                        // the error fails the sync whose subquery listed the missing blocks
+                        let root = match self.query_manager.query_info(id) {
+                            Some(query) => query.root,
+                            None => continue,
+                        };
+                        self.sessions.remove_query(root);
+                        self.selectors.remove(&root);
+                        let stats = self.query_manager.stats(root).unwrap_or_default();
+                        if self.query_manager.cancel(root) {
+                            let event =
+                                BitswapEvent::complete_with_stats(root, Err(err.into()), stats);
+                            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                        }
+                    }
+                    DbResponse::InvalidReferences(root, err) => {
+                        let stats = self.query_manager.stats(root).unwrap_or_default();
+                        if self.query_manager.cancel(root) {
+                            let event =
+                                BitswapEvent::complete_with_stats(root, Err(err.into()), stats);
+                            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                        }
+                    }
+                    DbResponse::Available(cid) => self.wantlist.resolve(&cid),
+                    DbResponse::InsertDone(id, peer_id, stored) => {
+                        if !stored {
+                            tracing::error!("block of {} wasn't stored", id);
+                        }
+                        self.query_manager
+                            .inject_response(id, block_response(peer_id, stored));
+                    }
+                }
+            }
+            while let Some(query) = self.query_manager.next() {
+                exit = false;
+                match query {
+                    QueryEvent::Request(id, req) => match req {
+                        Request::Have(peer_id, cid) => {
+                            let req = BitswapRequest::new(RequestType::Have, cid);
+                            self.send_request(id, peer_id, req);
+                        }
+                        Request::Block(peer_id, cid) => {
+                            let req = BitswapRequest::new(RequestType::Block, cid);
+                            self.send_request(id, peer_id, req);
+                        }
+                        Request::Probe(peer_id, cid) => {
+                            let req = BitswapRequest::new(RequestType::Have, cid);
+                            self.send_request(id, peer_id, req);
+                        }
+                        Request::MissingBlocks(cid) => {
+                            let selector = self
+                                .query_manager
+                                .query_info(id)
+                                .and_then(|query| self.selectors.get(&query.root))
+                                .and_then(|selectors| selectors.get(&cid))
+                                .cloned();
+                            let request = match (selector, self.decoder) {
+                                (Some(selector), Some(decoder)) => {
+                                    DbRequest::SelectBlocks(id, cid, selector, decoder.ipld)
+                                }
+                                _ => DbRequest::MissingBlocks(id, cid),
+                            };
+                            self.send_db(request);
+                        }
+                        Request::Inline(cid) => self.receive_inline(id, cid),
+                        Request::Providers(cid) => self.discover(id, cid),
+                    },
+                    QueryEvent::Progress(id, info) => {
+                        let event = BitswapEvent::progress(id, info);
+                        return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                    }
+                    QueryEvent::SyncBlock(id, cid) => {
+                        let event = BitswapEvent::sync_block(id, cid);
+                        return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                    }
+                    QueryEvent::Complete(id, res, stats) => {
+                        self.sessions.remove_query(id);
+                        self.selectors.remove(&id);
+                        if res.is_err() {
+                            BLOCK_NOT_FOUND.inc();
+                        }
+                        if let Some(partial) = &stats.partial {
+                            BLOCK_NOT_FOUND.inc_by(partial.missing.len() as u64);
+                        }
+                        let res = res.map_err(|cid| {
+                            if stats.answers == 0 && stats.failures > 0 {
+                                Error::AllPeersFailed(cid)
+                            } else {
+                                Error::BlockNotFound(cid)
+                            }
+                        });
+                        let event = BitswapEvent::complete_with_stats(id, res, stats);
+                        return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                    }
+                    QueryEvent::Timeout(id, stats) => {
+                        self.sessions.remove_query(id);
+                        self.selectors.remove(&id);
+                        let event =
+                            BitswapEvent::complete_with_stats(id, Err(Error::Timeout), stats);
+                        return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                    }
+                }
+            }
+            while let Poll::Ready(event) = self.inner.poll(cx, pp) {
+                exit = false;
+                let event = match event {
+                    NetworkBehaviourAction::GenerateEvent(event) => event,
+                    NetworkBehaviourAction::Dial { opts, handler } => {
+                        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                        let handler = ConnectionHandler::select(handler, Default::default());
+                        return Poll::Ready(NetworkBehaviourAction::Dial { opts, handler });
+                    }
+                    NetworkBehaviourAction::NotifyHandler {
+                        peer_id,
+                        handler,
+                        event,
+                    } => {
+                        return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                            peer_id,
+                            handler,
+                            #[cfg(not(any(feature = "compat", feature = "compat-lite")))]
+                            event,
+                            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                            event: EitherOutput::First(event),
+                        });
+                    }
+                    NetworkBehaviourAction::ReportObservedAddr { address, score } => {
+                        return Poll::Ready(NetworkBehaviourAction::ReportObservedAddr {
+                            address,
+                            score,
+                        });
+                    }
+                    NetworkBehaviourAction::CloseConnection {
+                        peer_id,
+                        connection,
+                    } => {
+                        return Poll::Ready(NetworkBehaviourAction::CloseConnection {
+                            peer_id,
+                            connection,
+                        });
+                    }
+                };
+                match event {
+                    RequestResponseEvent::Message { peer, message } => match message {
+                        RequestResponseMessage::Request {
+                            request_id: _,
+                            request,
+                            channel,
+                        } => {
+                            self.update_capabilities(peer, request.caps);
+                            match request.msg {
+                                Batch::One(request) => self.inject_request(
+                                    BitswapChannel::Bitswap(peer, request.cid, Channel(channel)),
+                                    request,
+                                ),
+                                Batch::Many(requests) => {
+                                    self.inject_batch(peer, Channel(channel), requests)
+                                }
+                            }
+                        }
+                        RequestResponseMessage::Response {
+                            request_id,
+                            response,
+                        } => {
+                            self.update_capabilities(peer, response.caps);
+                            self.record_support(peer, PeerSupport::Native);
+                            self.circuit_success(peer);
+                            if self.circuit_probes.remove(&request_id).is_some() {
+                                continue;
+                            }
+                            if self.bootstrap_probes.remove(&request_id).is_some() {
+                                tracing::debug!("bootstrap peer {} is ready", peer);
+                                self.bootstrap.set_ready(&peer, true);
+                                continue;
+                            }
+                            match response.msg {
+                                Batch::One(response) => self.inject_response(
+                                    BitswapId::Bitswap(request_id),
+                                    peer,
+                                    response,
+                                ),
+                                Batch::Many(responses) => {
+                                    self.inject_batch_response(request_id, peer, responses)
+                                }
+                            }
+                        }
+                    },
+                    RequestResponseEvent::ResponseSent { .. } => {}
+                    RequestResponseEvent::OutboundFailure {
+                        peer,
+                        request_id,
+                        error,
+                    } => {
+                        self.inject_outbound_failure(&peer, request_id, &error);
+                        let limited = matches!(error, OutboundFailure::DialFailure)
+                            && self.limited_dials.contains(&peer);
+                        // the peer is still connected if another connection survived
+                        let closed = matches!(error, OutboundFailure::ConnectionClosed)
+                            && self.connections.contains_key(&peer);
+                        if !limited
+                            && !closed
+                            && !matches!(error, OutboundFailure::UnsupportedProtocols)
+                        {
+                            self.circuit_failure(peer);
+                        }
+                        if self.circuit_probes.remove(&request_id).is_some() {
+                            continue;
+                        }
+                        if self.bootstrap_probes.remove(&request_id).is_some() {
+                            // Compat peers are reached through the compat fallback once
+                            // a query asks them.
+                            let ready = self.compat_enabled()
+                                && matches!(error, OutboundFailure::UnsupportedProtocols);
+                            tracing::debug!("bootstrap peer {} ready: {}", peer, ready);
+                            self.bootstrap.set_ready(&peer, ready);
+                            continue;
+                        }
+                        // batches only go to peers we exchanged messages with, so the
+                        // other entries fail right away instead of retrying the dial
+                        for id in self.take_batch(request_id, 1, &peer) {
+                            if closed {
+                                self.resend_closed(id, peer);
+                                continue;
+                            }
+                            if matches!(error, OutboundFailure::Timeout)
+                                && self.retry_timeout(id, peer)
+                            {
+                                continue;
+                            }
+                            self.query_manager
+                                .inject_response(id, Response::Failed(peer));
+                        }
+                        if let Some(id) = self.take_request(&BitswapId::Bitswap(request_id), &peer)
+                        {
+                            #[cfg(any(feature = "compat", feature = "compat-lite"))]
+                            if let OutboundFailure::UnsupportedProtocols = error {
+                                if let Some(action) = self.compat_fallback(id, peer) {
+                                    return Poll::Ready(action);
+                                }
+                            }
+                            if limited {
+                                tracing::debug!("queueing the request to {}", peer);
+                                self.dial_queue.push_back((id, peer));
+                                continue;
+                            }
+                            if closed {
+                                self.resend_closed(id, peer);
+                                continue;
+                            }
+                            if let OutboundFailure::DialFailure = error {
+                                if self.retry_dial(id, peer) {
+                                    continue;
+                                }
+                            } else {
+                                self.dial_retries.remove(&(id, peer));
+                            }
+                            if let OutboundFailure::Timeout = error {
+                                if self.retry_timeout(id, peer) {
+                                    continue;
+                                }
+                            }
+                            self.query_manager
+                                .inject_response(id, Response::Failed(peer));
+                        }
+                    }
+                    RequestResponseEvent::InboundFailure {
+                        peer,
+                        request_id,
+                        error,
+                    } => {
+                        self.inject_inbound_failure(&peer, request_id, &error);
+                    }
+                }
+            }
+        }
+        self.poll_counters(cx);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wantlist::{WantListClosed, WantToken};
+    use async_std::task;
+    use futures::prelude::*;
+    use libipld::block::Block;
+    use libipld::cbor::DagCborCodec;
+    use libipld::ipld;
+    use libipld::ipld::Ipld;
+    use libipld::multihash::{Code, Multihash, MultihashDigest};
+    use libipld::store::DefaultParams;
+    use libp2p::core::muxing::StreamMuxerBox;
+    use libp2p::core::transport::Boxed;
+    use libp2p::identity;
+    use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
+    use libp2p::swarm::{ConnectionLimits, SwarmBuilder, SwarmEvent};
+    use libp2p::tcp::{self, async_io};
+    use libp2p::yamux::YamuxConfig;
+    use libp2p::{PeerId, Swarm, Transport};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing_subscriber::fmt::TestWriter;
+
+    fn tracing_try_init() {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_writer(TestWriter::new())
+            .try_init()
+            .ok();
+    }
+
+    fn mk_transport() -> (PeerId, Boxed<(PeerId, StreamMuxerBox)>) {
+        let id_key = identity::Keypair::generate_ed25519();
+        let peer_id = id_key.public().to_peer_id();
+        let dh_key = Keypair::<X25519Spec>::new()
+            .into_authentic(&id_key)
+            .unwrap();
+        let noise = NoiseConfig::xx(dh_key).into_authenticated();
+
+        let transport = async_io::Transport::new(tcp::Config::new().nodelay(true))
+            .upgrade(libp2p::core::upgrade::Version::V1)
+            .authenticate(noise)
+            .multiplex(YamuxConfig::default())
+            .timeout(Duration::from_secs(20))
+            .boxed();
+        (peer_id, transport)
+    }
+
+    fn create_block(ipld: Ipld) -> Block<DefaultParams> {
+        Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
+    }
+
+    /// In memory store. Inserts take the configured delay to simulate a slow store.
+    #[derive(Clone, Default)]
+    struct Store(
+        Arc<Mutex<FnvHashMap<Cid, Vec<u8>>>>,
+        Arc<Mutex<Duration>>,
+        Arc<Mutex<FnvHashSet<Cid>>>,
+        Arc<Mutex<Vec<(Cid, InsertHint)>>>,
+    );
+
+    impl BitswapStore for Store {
+        type Params = DefaultParams;
+        fn contains(&mut self, cid: &Cid) -> Result<bool> {
+            Ok(self.0.lock().unwrap().contains_key(cid))
+        }
+        fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(cid).cloned())
+        }
+        fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+            std::thread::sleep(*self.1.lock().unwrap());
+            self.0
+                .lock()
+                .unwrap()
+                .insert(*block.cid(), block.data().to_vec());
+            Ok(())
+        }
+        fn insert_with_hint(
+            &mut self,
+            block: &Block<Self::Params>,
+            hint: InsertHint,
+        ) -> Result<()> {
+            self.3.lock().unwrap().push((*block.cid(), hint));
+            self.insert(block)
+        }
+        fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
             let mut stack = vec![*cid];
             let mut missing = vec![];
             while let Some(cid) = stack.pop() {
@@ -757,137 +5349,2802 @@ mod tests {
                     let block = Block::<Self::Params>::new_unchecked(cid, data);
                     block.references(&mut stack)?;
                 } else {
-                    missing.push(cid);
+                    missing.push(cid);
+                }
+            }
+            Ok(missing)
+        }
+        fn can_serve(&mut self, cid: &Cid) -> Result<bool> {
+            Ok(!self.2.lock().unwrap().contains(cid))
+        }
+        fn try_clone(&self) -> Option<Self> {
+            Some(self.clone())
+        }
+    }
+
+    /// Async view of a `Store` that yields to the executor before each call.
+    struct AsyncStore(Store);
+
+    #[async_trait]
+    impl AsyncBitswapStore for AsyncStore {
+        type Params = DefaultParams;
+        async fn contains(&mut self, cid: &Cid) -> Result<bool> {
+            task::yield_now().await;
+            self.0.contains(cid)
+        }
+        async fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            task::yield_now().await;
+            self.0.get(cid)
+        }
+        async fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+            task::yield_now().await;
+            self.0.insert(block)
+        }
+        async fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+            task::yield_now().await;
+            self.0.missing_blocks(cid)
+        }
+    }
+
+    struct Peer {
+        peer_id: PeerId,
+        addr: Multiaddr,
+        store: Store,
+        swarm: Swarm<Bitswap<DefaultParams>>,
+    }
+
+    impl Peer {
+        fn new() -> Self {
+            Self::with_config(BitswapConfig::new())
+        }
+
+        fn with_config(config: BitswapConfig) -> Self {
+            Self::with_limits(config, ConnectionLimits::default())
+        }
+
+        /// Creates a peer whose store requests are serviced whenever its swarm is
+        /// polled, so that the store never races the behaviour.
+        fn with_inline_store(mut config: BitswapConfig) -> Self {
+            config.store_driver = StoreDriver::SyncInline;
+            Self::with_config(config)
+        }
+
+        /// Creates a peer with an async store serviced by a task of its swarm.
+        fn with_async_store(mut config: BitswapConfig) -> Self {
+            config.store_driver = StoreDriver::Task;
+            let store = Store::default();
+            let behaviour = Bitswap::new_async(config, AsyncStore(store.clone()));
+            Self::with_behaviour(store, behaviour, ConnectionLimits::default())
+        }
+
+        fn with_limits(config: BitswapConfig, limits: ConnectionLimits) -> Self {
+            let store = Store::default();
+            let behaviour = Bitswap::new(config, store.clone());
+            Self::with_behaviour(store, behaviour, limits)
+        }
+
+        fn with_behaviour(
+            store: Store,
+            behaviour: Bitswap<DefaultParams>,
+            limits: ConnectionLimits,
+        ) -> Self {
+            let (peer_id, trans) = mk_transport();
+            let mut swarm = SwarmBuilder::with_async_std_executor(trans, behaviour, peer_id)
+                .connection_limits(limits)
+                .build();
+            Swarm::listen_on(&mut swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+            while swarm.next().now_or_never().is_some() {}
+            let addr = Swarm::listeners(&swarm).next().unwrap().clone();
+            Self {
+                peer_id,
+                addr,
+                store,
+                swarm,
+            }
+        }
+
+        fn add_address(&mut self, peer: &Peer) {
+            self.swarm
+                .behaviour_mut()
+                .add_address(&peer.peer_id, peer.addr.clone())
+                .unwrap();
+        }
+
+        fn store(&mut self) -> impl std::ops::DerefMut<Target = FnvHashMap<Cid, Vec<u8>>> + '_ {
+            self.store.0.lock().unwrap()
+        }
+
+        fn swarm(&mut self) -> &mut Swarm<Bitswap<DefaultParams>> {
+            &mut self.swarm
+        }
+
+        fn set_insert_delay(&mut self, delay: Duration) {
+            *self.store.1.lock().unwrap() = delay;
+        }
+
+        fn set_private(&mut self, cid: Cid) {
+            self.store.2.lock().unwrap().insert(cid);
+        }
+
+        fn spawn(mut self, name: &'static str) -> PeerId {
+            let peer_id = self.peer_id;
+            task::spawn(async move {
+                loop {
+                    let event = self.swarm.next().await;
+                    tracing::debug!("{}: {:?}", name, event);
+                }
+            });
+            peer_id
+        }
+
+        async fn next(&mut self) -> Option<BitswapEvent> {
+            loop {
+                let ev = self.swarm.next().await?;
+                if let SwarmEvent::Behaviour(event) = ev {
+                    return Some(event);
+                }
+            }
+        }
+
+        /// Drives the swarm until the want of `token` is resolved.
+        async fn wait_for(&mut self, mut token: WantToken) -> Result<(), WantListClosed> {
+            loop {
+                let next = Box::pin(self.next());
+                if let future::Either::Left((res, _)) = future::select(&mut token, next).await {
+                    return res;
+                }
+            }
+        }
+
+        /// Waits for the next event while driving `other`, which must be polled to
+        /// answer our requests.
+        async fn next_driving(&mut self, other: &mut Peer) -> Option<BitswapEvent> {
+            loop {
+                let next = Box::pin(self.next());
+                let next_other = Box::pin(other.next());
+                match future::select(next, next_other).await {
+                    future::Either::Left((event, _)) => return event,
+                    future::Either::Right((event, _)) => {
+                        tracing::trace!(?event, "event of driven peer");
+                    }
+                }
+            }
+        }
+
+        /// Waits for the next event while driving `others`.
+        async fn next_driving_all(&mut self, others: &mut [&mut Peer]) -> Option<BitswapEvent> {
+            loop {
+                let next = Box::pin(self.next());
+                let next_others =
+                    future::select_all(others.iter_mut().map(|other| Box::pin(other.next())));
+                match future::select(next, next_others).await {
+                    future::Either::Left((event, _)) => return event,
+                    future::Either::Right(((event, _, _), _)) => {
+                        tracing::trace!(?event, "event of driven peer");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drives `server` and `client` until `server` holds a request of `client` for `cid`,
+    /// checking that the query of `client` doesn't complete in the meantime.
+    async fn hold_request(server: &mut Peer, client: &mut Peer, cid: &Cid) {
+        while !server.swarm().behaviour().waiting.contains_key(cid) {
+            let next = Box::pin(server.next());
+            let next_client = Box::pin(client.next());
+            if let future::Either::Right((event, _)) = future::select(next, next_client).await {
+                assert!(
+                    !matches!(event, Some(BitswapEvent::Complete { .. })),
+                    "{:?} completed the query before the request was held",
+                    event
+                );
+            }
+        }
+    }
+
+    fn assert_progress(event: Option<BitswapEvent>, id: QueryId, discovered: u64, fetched: u64) {
+        if let Some(BitswapEvent::Progress {
+            id: id2,
+            discovered: discovered2,
+            fetched: fetched2,
+            ..
+        }) = event
+        {
+            assert_eq!(id2, id);
+            assert_eq!((discovered2, fetched2), (discovered, fetched));
+        } else {
+            panic!("{:?} is not a progress event", event);
+        }
+    }
+
+    fn assert_complete_ok(event: Option<BitswapEvent>, id: QueryId) {
+        if let Some(BitswapEvent::Complete {
+            id: id2,
+            result: Ok(()),
+            ..
+        }) = event
+        {
+            assert_eq!(id2, id);
+        } else {
+            panic!("{:?} is not a complete event", event);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+
+        assert_complete_ok(peer2.next().await, id);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_broadcasts_without_providers() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.broadcast_wants = true;
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let first = create_block(ipld!(&b"first"[..]));
+        let second = create_block(ipld!(&b"second"[..]));
+        peer1.store().insert(*first.cid(), first.data().to_vec());
+        peer1.store().insert(*second.cid(), second.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        // connects to peer1
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*first.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+
+        // no discovery is installed, so only the broadcast can find the block
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*second.cid(), std::iter::empty())
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(peer2.store().get(second.cid()).unwrap(), second.data());
+    }
+
+    #[async_std::test]
+    async fn test_block_counters_are_flushed_on_drop() {
+        tracing_try_init();
+        let received = RECEIVED_BLOCK_BYTES.with_label_values(&["true"]);
+        let before = received.get();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+
+        // other tests receive blocks concurrently, so only a lower bound holds
+        drop(peer2);
+        assert!(received.get() >= before + block.data().len() as u64);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_discovers_providers() {
+        use futures::channel::oneshot;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let provider = (peer1.peer_id, vec![peer1.addr.clone()]);
+        peer1.spawn("peer1");
+
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let rounds2 = rounds.clone();
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .set_provider_discovery(move |_cid: Cid| {
+                let (tx, rx) = oneshot::channel();
+                // the first round finds nothing
+                if rounds2.fetch_add(1, Ordering::SeqCst) > 0 {
+                    tx.send(vec![provider.clone()]).ok();
+                }
+                rx
+            });
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::empty())
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(rounds.load(Ordering::SeqCst), 2);
+
+        // the get fails once every round was used up
+        let missing = create_block(ipld!(&b"missing"[..]));
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*missing.cid(), std::iter::empty())
+            .unwrap();
+        match peer2.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::BlockNotFound(cid)),
+                ..
+            }) => {
+                assert_eq!(id2, id);
+                assert_eq!(cid, *missing.cid());
+            }
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+        assert_eq!(rounds.load(Ordering::SeqCst), 5);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_query_timeout() {
+        use futures::channel::oneshot;
+        tracing_try_init();
+        let mut peer = Peer::new();
+        // the lookups are kept pending in the channel, so only a deadline completes the
+        // gets
+        let (tx, _lookups) = mpsc::unbounded();
+        peer.swarm()
+            .behaviour_mut()
+            .set_provider_discovery(move |_cid: Cid| {
+                let (lookup, rx) = oneshot::channel::<DiscoveredProviders>();
+                tx.unbounded_send(lookup).ok();
+                rx
+            });
+        let block = create_block(ipld!(&b"hello world"[..]));
+
+        let bitswap = peer.swarm().behaviour_mut();
+        let id = bitswap.get(*block.cid(), std::iter::empty()).unwrap();
+        assert!(bitswap.set_query_timeout(id, Duration::from_millis(10)));
+        match peer.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::Timeout),
+                ..
+            }) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a timeout", event),
+        }
+        let bitswap = peer.swarm().behaviour_mut();
+        assert!(!bitswap.set_query_timeout(id, Duration::ZERO));
+
+        // the configured timeout applies to every query
+        bitswap.config.query_timeout = Some(Duration::from_millis(10));
+        let id = bitswap
+            .sync(*block.cid(), vec![], std::iter::once(*block.cid()))
+            .unwrap();
+        match peer.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::Timeout),
+                ..
+            }) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a timeout", event),
+        }
+        assert_eq!(peer.swarm().behaviour().query_manager.active_queries(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_want_events() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.emit_want_events = true;
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let peer2_id = peer2.peer_id;
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1.peer_id))
+            .unwrap();
+        peer2.spawn("peer2");
+
+        // the event is emitted although the block isn't served
+        match peer1.next().await {
+            Some(BitswapEvent::Want { peer, cid, ty }) => {
+                assert_eq!(peer, peer2_id);
+                assert_eq!(cid, *block.cid());
+                assert_eq!(ty, RequestType::Block);
+            }
+            event => panic!("{:?} is not a want event", event),
+        }
+        let wantlist = peer1.swarm().behaviour().peer_wantlist(&peer2_id);
+        assert_eq!(wantlist, vec![(*block.cid(), RequestType::Block)]);
+        // the want ends with the dont have
+        async_std::future::timeout(Duration::from_millis(500), peer1.next())
+            .await
+            .ok();
+        assert!(peer1
+            .swarm()
+            .behaviour()
+            .peer_wantlist(&peer2_id)
+            .is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_without_providers() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::empty())
+            .unwrap();
+        match peer.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::BlockNotFound(cid)),
+                ..
+            }) => {
+                assert_eq!(id2, id);
+                assert_eq!(cid, *block.cid());
+            }
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_records_peer_support() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+        assert_eq!(
+            peer2.swarm().behaviour().peer_support(&peer1),
+            PeerSupport::Unknown
+        );
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+
+        let bitswap = peer2.swarm().behaviour_mut();
+        assert_eq!(bitswap.peer_support(&peer1), PeerSupport::Native);
+        bitswap.config.protocol_support_ttl = Duration::from_secs(0);
+        assert_eq!(bitswap.peer_support(&peer1), PeerSupport::Unknown);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_bootstrap_peers() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let mut config = BitswapConfig::new();
+        config.bootstrap_peers = vec![(peer1.peer_id, peer1.addr.clone())];
+        let mut peer2 = Peer::with_config(config);
+        let peer1 = peer1.spawn("peer1");
+
+        // Drive the dial and the protocol detection until peer1 is ready.
+        while peer2.swarm().behaviour().bootstrap.ready().next().is_none() {
+            let next = peer2.swarm().next();
+            async_std::future::timeout(Duration::from_millis(100), next)
+                .await
+                .ok();
+        }
+        assert_eq!(
+            peer2.swarm().behaviour().peer_support(&peer1),
+            PeerSupport::Native
+        );
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::empty())
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+    }
+
+    #[test]
+    fn test_normalize_addr() {
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        assert_eq!(normalize_addr(&peer_id, addr.clone()), Ok(addr.clone()));
+        let full = addr.clone().with(Protocol::P2p(peer_id.into()));
+        assert_eq!(normalize_addr(&peer_id, full), Ok(addr.clone()));
+
+        let other = PeerId::random();
+        let mismatch = addr.with(Protocol::P2p(other.into()));
+        assert_eq!(
+            normalize_addr(&peer_id, mismatch.clone()),
+            Err(AddrError::PeerIdMismatch {
+                addr: mismatch,
+                expected: peer_id,
+                found: other,
+            })
+        );
+
+        let bare = Multiaddr::empty().with(Protocol::P2p(peer_id.into()));
+        assert_eq!(
+            normalize_addr(&peer_id, bare),
+            Err(AddrError::NoTransport(Multiaddr::empty()))
+        );
+        let ip: Multiaddr = "/ip4/127.0.0.1".parse().unwrap();
+        assert_eq!(
+            normalize_addr(&peer_id, ip.clone()),
+            Err(AddrError::NoTransport(ip))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_lenient_addresses() {
+        let peer_id = PeerId::random();
+        let bare = Multiaddr::empty().with(Protocol::P2p(peer_id.into()));
+        let mut peer = Peer::new();
+        let bitswap = peer.swarm().behaviour_mut();
+        assert!(bitswap.add_address(&peer_id, bare.clone()).is_err());
+        assert!(bitswap.add_bootstrap_peer(peer_id, bare.clone()).is_err());
+
+        let mut config = BitswapConfig::new();
+        config.lenient_addresses = true;
+        let mut peer = Peer::with_config(config);
+        assert!(peer
+            .swarm()
+            .behaviour_mut()
+            .add_address(&peer_id, bare)
+            .is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_negotiates_capabilities() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.capabilities = Capabilities::BATCHING | Capabilities::CANCEL;
+        let mut peer1 = Peer::with_config(config);
+        let mut config = BitswapConfig::new();
+        config.capabilities = Capabilities::CANCEL;
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let bitswap = peer2.swarm().behaviour_mut();
+        assert_eq!(bitswap.peer_capabilities(&peer1), None);
+        let id = bitswap.get(*block.cid(), std::iter::once(peer1)).unwrap();
+        assert_complete_ok(peer2.next().await, id);
+
+        let bitswap = peer2.swarm().behaviour();
+        assert_eq!(
+            bitswap.peer_capabilities(&peer1),
+            Some(Capabilities::BATCHING | Capabilities::CANCEL)
+        );
+        assert_eq!(
+            bitswap.negotiated_capabilities(&peer1),
+            Capabilities::CANCEL
+        );
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_wantlist() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_inline_store(BitswapConfig::new());
+        peer2.add_address(&peer1);
+
+        let stored = create_block(ipld!(&b"stored"[..]));
+        let fetched = create_block(ipld!(&b"fetched"[..]));
+        let notified = create_block(ipld!(&b"notified"[..]));
+        peer1
+            .store()
+            .insert(*fetched.cid(), fetched.data().to_vec());
+        peer2.store().insert(*stored.cid(), stored.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+        let handle = peer2.swarm().behaviour().wantlist_handle();
+
+        let token = handle.register_want(*stored.cid());
+        peer2.wait_for(token).await.unwrap();
+
+        let token = handle.register_want(*fetched.cid());
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*fetched.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        peer2.wait_for(token).await.unwrap();
+
+        let token = handle.register_want(*notified.cid());
+        peer2
+            .store()
+            .insert(*notified.cid(), notified.data().to_vec());
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .notify_block_stored(notified.cid());
+        peer2.wait_for(token).await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_completes_after_insert() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+        peer2.set_insert_delay(Duration::from_millis(200));
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(peer2.store().get(block.cid()).unwrap(), block.data());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_completes_after_insert() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+        peer2.set_insert_delay(Duration::from_millis(100));
+
+        let b0 = create_block(ipld!({ "n": 0 }));
+        let b1 = create_block(ipld!({ "prev": b0.cid(), "n": 1 }));
+        let b2 = create_block(ipld!({ "prev": b1.cid(), "n": 2 }));
+        for block in [&b0, &b1, &b2].iter() {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*b2.cid(), vec![peer1], std::iter::once(*b2.cid()))
+            .unwrap();
+        assert_progress(peer2.next().await, id, 2, 1);
+        assert_progress(peer2.next().await, id, 3, 2);
+        let len: u64 = [&b0, &b1, &b2].iter().map(|b| b.data().len() as u64).sum();
+        let event = peer2.next().await;
+        if let Some(BitswapEvent::Progress {
+            bytes,
+            duplicates,
+            peers,
+            ..
+        }) = event
+        {
+            assert_eq!((bytes, duplicates, peers), (len, 0, 1));
+        }
+        assert_progress(event, id, 3, 3);
+        let event = peer2.next().await;
+        if let Some(BitswapEvent::Complete { stats, .. }) = &event {
+            assert_eq!((stats.blocks, stats.bytes, stats.peers), (3, len, 1));
+            assert!(stats.elapsed >= Duration::from_millis(100));
+        }
+        assert_complete_ok(event, id);
+        for block in [&b0, &b1, &b2].iter() {
+            assert_eq!(peer2.store().get(block.cid()).unwrap(), block.data());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_spawner() {
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+        tracing_try_init();
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let blocking = Arc::new(AtomicUsize::new(0));
+        let counter = spawned.clone();
+        let spawner = Spawner::new(move |future| {
+            counter.fetch_add(1, SeqCst);
+            task::spawn(future);
+        });
+        // a blocking store keeps its thread without a blocking spawner
+        let store = Store::default();
+        drop(Bitswap::new_with_spawner(
+            BitswapConfig::new(),
+            store,
+            spawner.clone(),
+        ));
+        assert_eq!(spawned.load(SeqCst), 0);
+
+        let counter = blocking.clone();
+        let spawner = spawner.with_blocking(move |f| {
+            counter.fetch_add(1, SeqCst);
+            task::spawn_blocking(f);
+        });
+        let store = Store::default();
+        let behaviour =
+            Bitswap::new_with_spawner(BitswapConfig::new(), store.clone(), spawner.clone());
+        let mut peer2 = Peer::with_behaviour(store, behaviour, ConnectionLimits::default());
+        assert_eq!(blocking.load(SeqCst), 1);
+        assert_eq!(spawned.load(SeqCst), 0);
+        let store = Store::default();
+        let behaviour = Bitswap::new_async_with_spawner(
+            BitswapConfig::new(),
+            AsyncStore(store.clone()),
+            spawner,
+        );
+        let mut peer3 = Peer::with_behaviour(store, behaviour, ConnectionLimits::default());
+        assert_eq!(blocking.load(SeqCst), 1);
+        assert_eq!(spawned.load(SeqCst), 1);
+
+        let mut peer1 = Peer::new();
+        peer2.add_address(&peer1);
+        peer3.add_address(&peer1);
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        for peer in [&mut peer2, &mut peer3] {
+            let id = peer
+                .swarm()
+                .behaviour_mut()
+                .get(*block.cid(), std::iter::once(peer1))
+                .unwrap();
+            assert_complete_ok(peer.next().await, id);
+            assert_eq!(peer.store().get(block.cid()).unwrap(), block.data());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_db_workers_serve_during_slow_insert() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.db_workers = 2;
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        // keeps the first worker busy
+        peer1.set_insert_delay(Duration::from_secs(5));
+        let slow = create_block(ipld!(&b"slow"[..]));
+        let hint = InsertHint {
+            root: QueryId { epoch: 0, seq: 0 },
+            parent: None,
+        };
+        peer1
+            .swarm()
+            .behaviour_mut()
+            .insert_block(DbRequest::Insert(slow, hint));
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        let event = async_std::future::timeout(Duration::from_secs(2), peer2.next())
+            .await
+            .expect("the serve waited for the insert");
+        assert_complete_ok(event, id);
+    }
+
+    #[test]
+    fn test_db_queue_local_priority() {
+        let mut queue = DbQueue::new(DbPriority::Local);
+        for n in 0..2 {
+            queue.push(n, DbLane::Block, None);
+        }
+        for n in 10..16 {
+            queue.push(n, DbLane::Local, None);
+        }
+        // serves get one in four dequeues while local requests are pending
+        let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(order, vec![10, 11, 12, 0, 13, 14, 15, 1]);
+
+        let mut queue = DbQueue::new(DbPriority::Fifo);
+        queue.push(0, DbLane::Block, None);
+        queue.push(10, DbLane::Local, None);
+        queue.push(1, DbLane::Have, None);
+        let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(order, vec![0, 10, 1]);
+    }
+
+    #[test]
+    fn test_db_queue_orders_serves_by_priority() {
+        let mut queue = DbQueue::new(DbPriority::Local);
+        queue.push(0, DbLane::Block, Some(1));
+        queue.push(1, DbLane::Block, Some(5));
+        queue.push(10, DbLane::Local, None);
+        queue.push(2, DbLane::Block, Some(1));
+        queue.push(3, DbLane::Block, Some(5));
+        let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(order, vec![10, 1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn test_db_queue_serve_priority() {
+        let mut queue = DbQueue::new(DbPriority::Serves);
+        queue.push(20, DbLane::Traversal, None);
+        queue.push(10, DbLane::Local, None);
+        for n in 0..4 {
+            queue.push(n, DbLane::Block, None);
+        }
+        queue.push(4, DbLane::Have, None);
+        // haves before blocks, traversals after our other requests, and our
+        // own requests get one in four dequeues while serves are pending
+        let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(order, vec![4, 0, 1, 10, 2, 3, 20]);
+    }
+
+    #[test]
+    fn test_serve_queue_orders_serves() {
+        let (tx, requests) = mpsc::unbounded();
+        let mut serves = ServeQueue {
+            requests,
+            queue: DbQueue::new(DbPriority::Local),
+        };
+        let key = |request: &(i32, DbLane, Option<i32>)| (request.1, request.2);
+        tx.unbounded_send((0, DbLane::Block, None)).unwrap();
+        tx.unbounded_send((1, DbLane::Block, Some(5))).unwrap();
+        tx.unbounded_send((2, DbLane::Have, None)).unwrap();
+        assert!(serves.receive(key));
+        drop(tx);
+        assert!(!serves.receive(key));
+        // haves before blocks, and blocks by priority
+        let order = std::iter::from_fn(|| serves.queue.pop())
+            .map(|request| request.0)
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 1, 0]);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_insert_hints() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_inline_store(BitswapConfig::new());
+        peer2.add_address(&peer1);
+
+        let b0 = create_block(ipld!({ "n": 0 }));
+        let b1 = create_block(ipld!({ "prev": b0.cid(), "n": 1 }));
+        let b2 = create_block(ipld!({ "prev": b1.cid(), "n": 2 }));
+        for block in [&b0, &b1, &b2].iter() {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*b2.cid(), vec![peer1], std::iter::once(*b2.cid()))
+            .unwrap();
+        assert_progress(peer2.next().await, id, 2, 1);
+        assert_progress(peer2.next().await, id, 3, 2);
+        assert_progress(peer2.next().await, id, 3, 3);
+        assert_complete_ok(peer2.next().await, id);
+        let hint = |parent| InsertHint { root: id, parent };
+        assert_eq!(
+            *peer2.store.3.lock().unwrap(),
+            vec![
+                (*b2.cid(), hint(None)),
+                (*b1.cid(), hint(Some(*b2.cid()))),
+                (*b0.cid(), hint(Some(*b1.cid()))),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_private_block() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        peer1.set_private(*block.cid());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        if let Some(BitswapEvent::Complete {
+            id: id2,
+            result: Err(Error::BlockNotFound(cid)),
+            ..
+        }) = peer2.next().await
+        {
+            assert_eq!(id2, id);
+            assert_eq!(cid, *block.cid());
+        } else {
+            panic!("expected the private block not to be served");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_empty_block() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let cid = Cid::new_v1(0x55, Code::Blake3_256.digest(&[]));
+        peer1.store().insert(cid, vec![]);
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(cid, std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert!(peer2.store().get(&cid).unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_inline_blocks() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        // the provider only has the block linking to the inline one
+        let b0 = create_block(ipld!({ "n": 0 }));
+        let inline = Cid::new_v1(0x71, Multihash::wrap(0x00, b0.data()).unwrap());
+        let b1 = create_block(ipld!({ "prev": &inline, "n": 1 }));
+        peer1.store().insert(*b1.cid(), b1.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*b1.cid(), vec![peer1], std::iter::once(*b1.cid()))
+            .unwrap();
+        assert_progress(peer2.next().await, id, 2, 1);
+        assert_progress(peer2.next().await, id, 2, 2);
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(peer2.store().get(&inline).unwrap(), b0.data());
+
+        // a get of an inline block needs no provider, and empty blocks can be inlined
+        let empty = Cid::new_v1(0x55, Multihash::wrap(0x00, &[]).unwrap());
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(empty, std::iter::empty())
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert!(peer2.store().get(&empty).unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_connection_limits() {
+        tracing_try_init();
+        let limits = ConnectionLimits::default().with_max_pending_outgoing(Some(1));
+        let mut peer = Peer::with_limits(BitswapConfig::new(), limits);
+        let mut providers = vec![];
+        for name in ["provider1", "provider2", "provider3"].iter() {
+            let provider = Peer::new();
+            peer.add_address(&provider);
+            providers.push(provider.spawn(*name));
+        }
+
+        // none of the providers has the block, so the query fails once all of them
+        // answered
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), providers.clone().into_iter())
+            .unwrap();
+        match peer.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::BlockNotFound(_)),
+                ..
+            }) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+        for provider in &providers {
+            assert!(peer.swarm().is_connected(provider));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_circuit_breaker() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.circuit_breaker = Some(CircuitBreaker {
+            failure_threshold: 2,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+        });
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        // a peer without addresses fails every dial
+        let dead = PeerId::random();
+        for _ in 0..2 {
+            assert_eq!(
+                peer2.swarm().behaviour().peer_circuit(&dead),
+                CircuitState::Closed
+            );
+            let id = peer2
+                .swarm()
+                .behaviour_mut()
+                .get(*block.cid(), std::iter::once(dead))
+                .unwrap();
+            match peer2.next().await {
+                Some(BitswapEvent::Complete {
+                    id: id2,
+                    result: Err(Error::AllPeersFailed(_)),
+                    ..
+                }) => assert_eq!(id2, id),
+                event => panic!("{:?} is not a failed complete event", event),
+            }
+        }
+        assert_eq!(
+            peer2.swarm().behaviour().peer_circuit(&dead),
+            CircuitState::Open
+        );
+
+        // the dead peer is parked, so the block is requested from peer1
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), vec![dead, peer1].into_iter())
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(peer2.store().get(block.cid()).unwrap(), block.data());
+        assert_eq!(
+            peer2.swarm().behaviour().peer_circuit(&dead),
+            CircuitState::Open
+        );
+        assert_eq!(
+            peer2.swarm().behaviour().peer_circuit(&peer1),
+            CircuitState::Closed
+        );
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_emits_block() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.emit_blocks_in_events = true;
+        config.skip_store_insert = true;
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+
+        if let Some(BitswapEvent::Block { id: id2, cid, data }) = peer2.next().await {
+            assert_eq!(id2, id);
+            assert_eq!(cid, *block.cid());
+            assert_eq!(&data[..], block.data());
+        } else {
+            panic!("expected a block event");
+        }
+        assert_complete_ok(peer2.next().await, id);
+        assert!(!peer2.store().contains_key(block.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_trusted_peer() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.trusted_peers.insert(peer1.peer_id);
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), b"unverified".to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(peer2.store().get(block.cid()).unwrap(), &b"unverified"[..]);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_retries_dial() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.max_dial_retries = 2;
+        config.dial_retry_backoff = Duration::from_millis(500);
+        let mut peer2 = Peer::with_config(config);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+
+        // The first dial fails as the address of peer1 isn't known yet.
+        let next = Box::pin(peer2.next());
+        let delay = Delay::new(Duration::from_millis(100));
+        assert!(matches!(
+            future::select(next, delay).await,
+            future::Either::Right(_)
+        ));
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .add_address(&peer1, addr)
+            .unwrap();
+
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(peer2.store().get(block.cid()).unwrap(), block.data());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_exhausted_dial_retries_open_circuit() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.max_dial_retries = 1;
+        config.dial_retry_backoff = Duration::from_millis(100);
+        config.circuit_breaker = Some(CircuitBreaker {
+            failure_threshold: 5,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+        });
+        let mut peer = Peer::with_config(config);
+
+        // a peer without addresses fails every dial
+        let dead = PeerId::random();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(dead))
+            .unwrap();
+        match peer.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::AllPeersFailed(_)),
+                ..
+            }) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+        // two failures stay below the threshold, the exhausted retries open it
+        assert_eq!(
+            peer.swarm().behaviour().peer_circuit(&dead),
+            CircuitState::Open
+        );
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_retries_timeout() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.request_timeout = Duration::from_secs(1);
+        config.max_timeout_retries = 1;
+        config.timeout_retry_backoff = Duration::from_millis(100);
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        // The first request is held until it times out.
+        let mut first = true;
+        peer1.swarm().behaviour_mut().set_server_policy(
+            move |_: &PeerId, _: &Cid, _: RequestType, _: &Ledger| {
+                if std::mem::replace(&mut first, false) {
+                    PolicyDecision::Defer
+                } else {
+                    PolicyDecision::Serve
+                }
+            },
+        );
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1.peer_id))
+            .unwrap();
+        assert_complete_ok(peer2.next_driving(&mut peer1).await, id);
+        assert_eq!(peer2.store().get(block.cid()).unwrap(), block.data());
+        assert!(peer2.swarm().behaviour().timeout_retries.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_limits_requests_per_peer() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.max_requests_per_peer = Some(1);
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let blocks: Vec<_> = (0..3u8).map(|i| create_block(ipld!(vec![i; 16]))).collect();
+        let mut ids = FnvHashSet::default();
+        for block in &blocks {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+            let id = peer2
+                .swarm()
+                .behaviour_mut()
+                .get(*block.cid(), std::iter::once(peer1.peer_id))
+                .unwrap();
+            ids.insert(id);
+        }
+
+        while !ids.is_empty() {
+            match peer2.next_driving(&mut peer1).await {
+                Some(BitswapEvent::Complete { id, result, .. }) => {
+                    result.unwrap();
+                    assert!(ids.remove(&id));
+                }
+                Some(_) => {}
+                None => panic!("swarm ended"),
+            }
+            let bitswap = peer2.swarm().behaviour();
+            assert!(
+                bitswap
+                    .peer_inflight
+                    .get(&peer1.peer_id)
+                    .copied()
+                    .unwrap_or_default()
+                    <= 1
+            );
+        }
+        let bitswap = peer2.swarm().behaviour();
+        assert!(bitswap.peer_queues.is_empty());
+        assert!(bitswap.peer_inflight.is_empty());
+        for block in &blocks {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_per_peer_failure_metrics() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.per_peer_failure_metrics = Some(10);
+        let mut peer = Peer::with_config(config);
+        let registry = Registry::new();
+        peer.swarm()
+            .behaviour()
+            .register_metrics(&registry)
+            .unwrap();
+
+        // the dial fails as no address of the provider is known
+        let unknown = PeerId::random();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(unknown))
+            .unwrap();
+        match peer.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::AllPeersFailed(_)),
+                ..
+            }) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+
+        let unknown = unknown.to_base58();
+        let families = registry.gather();
+        let family = families
+            .iter()
+            .find(|family| family.get_name() == "bitswap_peer_failures_total")
+            .unwrap();
+        // label pairs are sorted by name
+        let labels: Vec<Vec<&str>> = family
+            .get_metric()
+            .iter()
+            .map(|metric| {
+                let labels = metric.get_label().iter().map(|label| label.get_value());
+                labels.collect()
+            })
+            .collect();
+        assert!(labels.iter().any(|labels| labels[0] == "outbound"
+            && unknown.ends_with(labels[1])
+            && labels[2] == "dial_failure"));
+        peer.swarm().behaviour().unregister_metrics(&registry);
+        assert!(registry.gather().is_empty());
+    }
+
+    #[cfg(feature = "chaos")]
+    #[async_std::test]
+    async fn test_bitswap_get_reroutes_dropped_request() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.request_timeout = Duration::from_secs(1);
+        let mut peer3 = Peer::with_config(config);
+        peer3.add_address(&peer1);
+        peer3.add_address(&peer2);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        peer2.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+        let peer2 = peer2.spawn("peer2");
+
+        peer3
+            .swarm()
+            .behaviour_mut()
+            .set_fault_injector(move |peer_id: &PeerId, _: &Cid| {
+                if *peer_id == peer1 {
+                    FaultAction::DropRequest
+                } else {
+                    FaultAction::Deliver
+                }
+            });
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), vec![peer1, peer2].into_iter())
+            .unwrap();
+
+        assert_complete_ok(peer3.next().await, id);
+        assert!(peer3.store().contains_key(block.cid()));
+    }
+
+    #[cfg(feature = "chaos")]
+    #[async_std::test]
+    async fn test_bitswap_get_rejects_corrupt_block() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        let mut peer3 = Peer::new();
+        peer3.add_address(&peer1);
+        peer3.add_address(&peer2);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        peer2.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+        let peer2 = peer2.spawn("peer2");
+
+        peer3
+            .swarm()
+            .behaviour_mut()
+            .set_fault_injector(move |peer_id: &PeerId, _: &Cid| {
+                if *peer_id == peer1 {
+                    FaultAction::CorruptResponse
+                } else {
+                    FaultAction::Deliver
+                }
+            });
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), vec![peer1, peer2].into_iter())
+            .unwrap();
+
+        assert_complete_ok(peer3.next().await, id);
+        assert_eq!(
+            peer3.store().get(block.cid()).unwrap(),
+            &block.data().to_vec()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_deferred_until_watermark() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block1 = create_block(ipld!(&b"hello"[..]));
+        let block2 = create_block(ipld!(&b"world"[..]));
+        peer1.store().insert(*block1.cid(), block1.data().to_vec());
+        peer1.store().insert(*block2.cid(), block2.data().to_vec());
+        peer1.swarm().behaviour_mut().set_server_policy(
+            |_: &PeerId, _: &Cid, ty: RequestType, ledger: &Ledger| {
+                if ty == RequestType::Block && ledger.since_watermark() > 0 {
+                    PolicyDecision::Defer
+                } else {
+                    PolicyDecision::Serve
+                }
+            },
+        );
+
+        let (peer_id1, peer_id2) = (peer1.peer_id, peer2.peer_id);
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block1.cid(), std::iter::once(peer_id1))
+            .unwrap();
+        assert_complete_ok(peer2.next_driving(&mut peer1).await, id);
+        let served = block1.data().len() as u64;
+        let ledger = *peer1.swarm().behaviour().ledger(&peer_id2).unwrap();
+        assert_eq!(ledger.since_watermark(), served);
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer_id1))
+            .unwrap();
+        let deferred =
+            async_std::future::timeout(Duration::from_millis(500), peer2.next_driving(&mut peer1))
+                .await;
+        assert!(deferred.is_err());
+
+        peer1
+            .swarm()
+            .behaviour_mut()
+            .set_peer_watermark(peer_id2, served);
+        assert_complete_ok(peer2.next_driving(&mut peer1).await, id);
+        assert!(peer2.store().contains_key(block2.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_throttles_peers_in_debt() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.max_debt_ratio = Some(1.0);
+        config.debt_allowance = 0;
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer1.add_address(&peer2);
+        peer2.add_address(&peer1);
+
+        let block1 = create_block(ipld!(&b"hello"[..]));
+        let block2 = create_block(ipld!(&b"world"[..]));
+        let block3 = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block1.cid(), block1.data().to_vec());
+        peer1.store().insert(*block2.cid(), block2.data().to_vec());
+        peer2.store().insert(*block3.cid(), block3.data().to_vec());
+
+        let (peer_id1, peer_id2) = (peer1.peer_id, peer2.peer_id);
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block1.cid(), std::iter::once(peer_id1))
+            .unwrap();
+        assert_complete_ok(peer2.next_driving(&mut peer1).await, id);
+
+        // peer2 only took so far
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer_id1))
+            .unwrap();
+        let deferred =
+            async_std::future::timeout(Duration::from_millis(500), peer2.next_driving(&mut peer1))
+                .await;
+        assert!(deferred.is_err());
+
+        // until it gives back
+        let id3 = peer1
+            .swarm()
+            .behaviour_mut()
+            .get(*block3.cid(), std::iter::once(peer_id2))
+            .unwrap();
+        assert_complete_ok(peer1.next_driving(&mut peer2).await, id3);
+        let ledger = *peer1.swarm().behaviour().ledger(&peer_id2).unwrap();
+        assert_eq!(ledger.bytes_received, block3.data().len() as u64);
+        assert!(ledger.debt_ratio() <= 1.0);
+        assert_complete_ok(peer2.next_driving(&mut peer1).await, id);
+        assert!(peer2.store().contains_key(block2.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_rate_limits_inbound_requests() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.inbound_rate_limit = Some(RateLimit {
+            rate: 0.001,
+            burst: 1,
+            action: PolicyDecision::Deny,
+        });
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block1 = create_block(ipld!(&b"hello"[..]));
+        let block2 = create_block(ipld!(&b"world"[..]));
+        peer1.store().insert(*block1.cid(), block1.data().to_vec());
+        peer1.store().insert(*block2.cid(), block2.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block1.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+
+        // the bucket of peer2 is empty, so peer1 answers that it doesn't have the block
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer1))
+            .unwrap();
+        match peer2.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::BlockNotFound(cid)),
+                ..
+            }) => assert_eq!((id2, cid), (id, *block2.cid())),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+        assert!(!peer2.store().contains_key(block2.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_refuses_blocked_peers() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        let mut peer3 = Peer::new();
+        peer2.add_address(&peer1);
+        peer2.add_address(&peer3);
+
+        let block1 = create_block(ipld!(&b"hello"[..]));
+        let block2 = create_block(ipld!(&b"world"[..]));
+        peer1.store().insert(*block1.cid(), block1.data().to_vec());
+        peer3.store().insert(*block2.cid(), block2.data().to_vec());
+        peer1.swarm().behaviour_mut().block_peer(peer2.peer_id);
+        let peer1 = peer1.spawn("peer1");
+        let peer3 = peer3.spawn("peer3");
+
+        // peer1 answers that it doesn't have the block
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block1.cid(), std::iter::once(peer1))
+            .unwrap();
+        match peer2.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::BlockNotFound(cid)),
+                ..
+            }) => assert_eq!((id2, cid), (id, *block1.cid())),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+
+        // peer3 isn't asked once peer2 only allows peer1
+        peer2.swarm().behaviour_mut().allow_peers(vec![peer1]);
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer3))
+            .unwrap();
+        match peer2.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::AllPeersFailed(cid)),
+                ..
+            }) => assert_eq!((id2, cid), (id, *block2.cid())),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+
+        peer2.swarm().behaviour_mut().allow_all_peers();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer3))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_throttles_uploads() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.max_upload_rate = Some(1000);
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block1 = create_block(ipld!(vec![1u8; 1500]));
+        let block2 = create_block(ipld!(vec![2u8; 1500]));
+        peer1.store().insert(*block1.cid(), block1.data().to_vec());
+        peer1.store().insert(*block2.cid(), block2.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        // the first block overdraws the budget of a second, so the second block is held
+        // until the overdraft is paid off
+        let started = Instant::now();
+        for block in &[&block1, &block2] {
+            let id = peer2
+                .swarm()
+                .behaviour_mut()
+                .get(*block.cid(), std::iter::once(peer1))
+                .unwrap();
+            assert_complete_ok(peer2.next().await, id);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_policy_ignores_requests() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.request_timeout = Duration::from_secs(1);
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let untrusted = peer2.peer_id;
+        peer1.swarm().behaviour_mut().set_server_policy(
+            move |peer_id: &PeerId, _: &Cid, _: RequestType, _: &Ledger| {
+                if *peer_id == untrusted {
+                    PolicyDecision::Ignore
+                } else {
+                    PolicyDecision::Serve
+                }
+            },
+        );
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1.peer_id))
+            .unwrap();
+        match peer2.next_driving(&mut peer1).await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::AllPeersFailed(_)),
+                ..
+            }) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+        assert!(!peer2.store().contains_key(block.cid()));
+    }
+
+    #[cfg(feature = "spill")]
+    #[async_std::test]
+    async fn test_bitswap_get_spills_blocks() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.max_pending_insert_bytes = 0;
+        let mut peer2 = Peer::with_inline_store(config);
+        peer2.add_address(&peer1);
+        let path = std::env::temp_dir().join(format!("bitswap-test-spill-{}", peer2.peer_id));
+        let spill = crate::FileSpillStore::new(&path).unwrap();
+        peer2.swarm().behaviour_mut().set_spill_store(spill);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert!(SPILLED_BYTES.get() > 0);
+
+        // the spilled block is drained once the db is idle
+        assert_eq!(peer2.store().get(block.cid()).unwrap(), block.data());
+        drop(peer2);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_delays_wants_while_fetching() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.fetching_wants = FetchingWants::Delay;
+        let mut peer2 = Peer::with_config(config);
+        let mut peer3 = Peer::new();
+        peer2.add_address(&peer1);
+        peer3.add_address(&peer2);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer_id1 = peer1.peer_id;
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id1))
+            .unwrap();
+        let peer_id2 = peer2.peer_id;
+
+        // peer2 holds the request while peer1 isn't polled.
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id2))
+            .unwrap();
+        hold_request(&mut peer2, &mut peer3, block.cid()).await;
+
+        let event = peer3.next_driving_all(&mut [&mut peer1, &mut peer2]).await;
+        assert_complete_ok(event, id);
+        assert_eq!(peer3.store().get(block.cid()).unwrap(), block.data());
+    }
+
+    #[async_std::test]
+    async fn test_db_workers_serve_held_requests_after_insert() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.fetching_wants = FetchingWants::Delay;
+        config.db_workers = 2;
+        let mut peer2 = Peer::with_config(config);
+        let mut peer3 = Peer::new();
+        peer2.add_address(&peer1);
+        peer3.add_address(&peer2);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        // the other worker is idle while the first one inserts the block
+        peer2.set_insert_delay(Duration::from_millis(200));
+        let peer_id1 = peer1.peer_id;
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id1))
+            .unwrap();
+        let peer_id2 = peer2.peer_id;
+
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id2))
+            .unwrap();
+        hold_request(&mut peer2, &mut peer3, block.cid()).await;
+
+        // answered with the block rather than that peer2 doesn't have it
+        let event = peer3.next_driving_all(&mut [&mut peer1, &mut peer2]).await;
+        assert_complete_ok(event, id);
+        assert_eq!(peer3.store().get(block.cid()).unwrap(), block.data());
+    }
+
+    #[async_std::test]
+    async fn test_serve_priority_serves_held_requests_after_insert() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.fetching_wants = FetchingWants::Delay;
+        config.serve_priority = true;
+        let mut peer2 = Peer::with_config(config);
+        let mut peer3 = Peer::new();
+        peer2.add_address(&peer1);
+        peer3.add_address(&peer2);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer_id1 = peer1.peer_id;
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id1))
+            .unwrap();
+        let peer_id2 = peer2.peer_id;
+
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id2))
+            .unwrap();
+        hold_request(&mut peer2, &mut peer3, block.cid()).await;
+
+        // keeps the worker busy until both the insert and the serve are queued
+        peer2.set_insert_delay(Duration::from_secs(1));
+        let slow = create_block(ipld!(&b"slow"[..]));
+        let hint = InsertHint {
+            root: QueryId { epoch: 0, seq: 0 },
+            parent: None,
+        };
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .insert_block(DbRequest::Insert(slow, hint));
+
+        let event = peer3.next_driving_all(&mut [&mut peer1, &mut peer2]).await;
+        assert_complete_ok(event, id);
+        assert_eq!(peer3.store().get(block.cid()).unwrap(), block.data());
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[test]
+    fn test_compat_id_of_equivalent_cids() {
+        let v0: Cid = "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n"
+            .parse()
+            .unwrap();
+        let v1: Cid = "bafybeihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+            .parse()
+            .unwrap();
+        assert_eq!(BitswapId::compat(v0), BitswapId::compat(v1));
+        assert_eq!(BitswapId::compat(v1), BitswapId::Compat(v0));
+        let raw = Cid::new_v1(0x55, *v0.hash());
+        assert_ne!(BitswapId::compat(raw), BitswapId::compat(v1));
+    }
+
+    #[cfg(feature = "compression")]
+    #[async_std::test]
+    async fn test_bitswap_compressed_blocks() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.compression = Some(Compression::Zstd(0));
+        config.capabilities = Capabilities::BATCHING | Capabilities::CHUNKING;
+        let mut peer1 = Peer::with_config(config.clone());
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!({
+            "data": "compressible ".repeat(1000),
+        }));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let received = COMPRESSION_RAW_BYTES.with_label_values(&["received"]).get();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert!(peer2.store().contains_key(block.cid()));
+
+        // the block was compressed on a stream that kept batching and chunking
+        let raw = COMPRESSION_RAW_BYTES.with_label_values(&["received"]).get();
+        assert!(raw >= received + block.data().len() as u64);
+        let bitswap = peer2.swarm().behaviour();
+        assert_eq!(
+            bitswap.negotiated_capabilities(&peer1),
+            Capabilities::BATCHING | Capabilities::CHUNKING | Capabilities::COMPRESSION
+        );
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_response_to_equivalent_cid() {
+        tracing_try_init();
+        let data = b"dag-pb block".to_vec();
+        let v0 = Cid::new_v0(Code::Sha2_256.digest(&data)).unwrap();
+        let v1 = Cid::new_v1(DAG_PB, *v0.hash());
+        for (want, answer) in [(v0, v1), (v1, v0)] {
+            let mut config = BitswapConfig::new();
+            config.emit_blocks_in_events = true;
+            let mut peer = Peer::with_config(config);
+            let provider = PeerId::random();
+            let bitswap = peer.swarm().behaviour_mut();
+            let id = bitswap.get(want, std::iter::once(provider)).unwrap();
+            let query = match bitswap.query_manager.next() {
+                Some(QueryEvent::Request(query, Request::Block(_, _))) => query,
+                event => panic!("{:?} is not a block request", event),
+            };
+            let request = PendingRequest {
+                query,
+                peer_id: provider,
+                cid: want,
+            };
+            bitswap.insert_request(BitswapId::compat(want), request);
+            let response = BitswapResponse::Block(data.clone());
+            bitswap.inject_response(BitswapId::compat(answer), provider, response);
+            match peer.next().await {
+                Some(BitswapEvent::Block { id: id2, cid, .. }) => {
+                    assert_eq!(id2, id);
+                    assert_eq!(cid, want);
+                }
+                event => panic!("{:?} is not a block event", event),
+            }
+            assert_complete_ok(peer.next().await, id);
+        }
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_wantlist_answered_in_one_message() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let remote = PeerId::random();
+        let cids: Vec<_> = (0..3u8)
+            .map(|i| *create_block(ipld!(&[i][..])).cid())
+            .collect();
+        let bitswap = peer.swarm().behaviour_mut();
+        // holds the entries so that they can be answered one by one
+        bitswap.set_server_policy(|_: &PeerId, _: &Cid, _: RequestType, _: &Ledger| {
+            PolicyDecision::Defer
+        });
+        let requests = cids
+            .iter()
+            .map(|cid| CompatMessage::Request(BitswapRequest::new(RequestType::Block, *cid)))
+            .collect();
+        bitswap.inject_compat_message(remote, requests, false);
+        assert_eq!(bitswap.deferred[&remote].len(), cids.len());
+        let batch = *bitswap.compat_batches.keys().next().unwrap();
+
+        let block = CompatMessage::Response(cids[0], BitswapResponse::Block(vec![0; 8]));
+        let have = CompatMessage::Response(cids[1], BitswapResponse::Have(false));
+        bitswap.compat_response(batch, Some(block.clone()));
+        bitswap.compat_response(batch, Some(have.clone()));
+        assert!(bitswap.compat_queue.is_empty());
+        // the ignored entry isn't answered
+        bitswap.compat_response(batch, None);
+        assert!(bitswap.compat_batches.is_empty());
+        let (peer_id, msg) = bitswap.compat_queue.pop_front().unwrap();
+        assert_eq!(peer_id, remote);
+        assert_eq!(msg, OutboundMessage::new(vec![block, have]));
+        assert!(bitswap.compat_queue.is_empty());
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_cancel_drops_want() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let remote = PeerId::random();
+        let cids: Vec<_> = (0..2u8)
+            .map(|i| *create_block(ipld!(&[i][..])).cid())
+            .collect();
+        let bitswap = peer.swarm().behaviour_mut();
+        bitswap.set_server_policy(|_: &PeerId, _: &Cid, _: RequestType, _: &Ledger| {
+            PolicyDecision::Defer
+        });
+        let requests = cids
+            .iter()
+            .map(|cid| CompatMessage::Request(BitswapRequest::new(RequestType::Block, *cid)))
+            .collect();
+        bitswap.inject_compat_message(remote, requests, false);
+        bitswap.inject_compat_message(remote, vec![CompatMessage::Cancel(cids[0])], false);
+        let deferred: Vec<_> = bitswap.deferred[&remote]
+            .iter()
+            .map(|req| req.request.cid)
+            .collect();
+        assert_eq!(deferred, vec![cids[1]]);
+        let wantlist = bitswap.peer_wantlist(&remote);
+        assert_eq!(wantlist, vec![(cids[1], RequestType::Block)]);
+        // a response to the cancelled want that was already underway is dropped
+        assert!(!bitswap.take_compat_want(remote, cids[0]));
+        assert!(bitswap.take_compat_want(remote, cids[1]));
+
+        let batch = *bitswap.compat_batches.keys().next().unwrap();
+        let have = CompatMessage::Response(cids[1], BitswapResponse::Have(false));
+        bitswap.compat_response(batch, Some(have.clone()));
+        let (_, msg) = bitswap.compat_queue.pop_front().unwrap();
+        assert_eq!(msg, OutboundMessage::from(have));
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_full_wantlist() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let remote = PeerId::random();
+        let cids: Vec<_> = (0..3u8)
+            .map(|i| *create_block(ipld!(&[i][..])).cid())
+            .collect();
+        let bitswap = peer.swarm().behaviour_mut();
+        bitswap.set_server_policy(|_: &PeerId, _: &Cid, _: RequestType, _: &Ledger| {
+            PolicyDecision::Defer
+        });
+        let requests = |cids: &[Cid]| {
+            cids.iter()
+                .map(|cid| CompatMessage::Request(BitswapRequest::new(RequestType::Block, *cid)))
+                .collect()
+        };
+        bitswap.inject_compat_message(remote, requests(&cids[..2]), false);
+        // replaces the first want and repeats the second, which is still being answered
+        bitswap.inject_compat_message(remote, requests(&cids[1..]), true);
+        let deferred: Vec<_> = bitswap.deferred[&remote]
+            .iter()
+            .map(|req| req.request.cid)
+            .collect();
+        assert_eq!(deferred, cids[1..].to_vec());
+        assert!(!bitswap.take_compat_want(remote, cids[0]));
+        assert!(bitswap.take_compat_want(remote, cids[1]));
+        assert!(!bitswap.take_compat_want(remote, cids[1]));
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_pending_bytes() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let remote = PeerId::random();
+        let bitswap = peer.swarm().behaviour_mut();
+        let msgs = [8, 16].iter().map(|len| {
+            let block = BitswapResponse::Block(vec![0; *len]);
+            OutboundMessage::from(CompatMessage::Response(Cid::default(), block))
+        });
+        bitswap.queue_compat(remote, msgs);
+        let have = CompatMessage::Response(Cid::default(), BitswapResponse::Have(true));
+        bitswap.queue_compat(remote, std::iter::once(OutboundMessage::from(have)));
+        let pending: Vec<_> = std::iter::from_fn(|| bitswap.next_compat())
+            .map(|(_, msg)| msg.pending_bytes)
+            .collect();
+        assert_eq!(pending, vec![16, 0, 0]);
+        assert!(bitswap.compat_queued_bytes.is_empty());
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_pending_by_id() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let remote = PeerId::random();
+        let conn = ConnectionId::new(1);
+        let bitswap = peer.swarm().behaviour_mut();
+        bitswap
+            .connections
+            .entry(remote)
+            .or_default()
+            .insert(conn, Instant::now());
+        let responses = [
+            BitswapResponse::Have(true),
+            BitswapResponse::Have(false),
+            BitswapResponse::Block(vec![1]),
+        ];
+        let mut ids = vec![];
+        for response in responses {
+            let msg = OutboundMessage::from(CompatMessage::Response(Cid::default(), response));
+            match bitswap.notify_compat(remote, msg) {
+                NetworkBehaviourAction::NotifyHandler {
+                    handler: NotifyHandler::One(handler),
+                    event: EitherOutput::Second(msg),
+                    ..
+                } => {
+                    assert_eq!(handler, conn);
+                    ids.push(msg.id);
+                }
+                _ => panic!("expected a compat message"),
+            }
+        }
+
+        // the second message is sent before the first, the third can't be written
+        let sent = InboundMessage::Sent {
+            id: ids[1],
+            version: CompatVersion::V1_2_0,
+        };
+        bitswap.on_connection_handler_event(remote, conn, EitherOutput::Second(sent));
+        let failed = InboundMessage::Failed {
+            id: ids[2],
+            error: std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset"),
+        };
+        bitswap.on_connection_handler_event(remote, conn, EitherOutput::Second(failed));
+        let pending: Vec<_> = bitswap.compat_pending[&conn]
+            .iter()
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(pending, vec![ids[0]]);
+        let sent = InboundMessage::Sent {
+            id: ids[0],
+            version: CompatVersion::V1_2_0,
+        };
+        bitswap.on_connection_handler_event(remote, conn, EitherOutput::Second(sent));
+        assert!(bitswap.compat_pending.is_empty());
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_notify_new_blocks_answers_compat_wants() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let remote = PeerId::random();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let cid = *block.cid();
+        let request = CompatMessage::Request(BitswapRequest::new(RequestType::Block, cid));
+        peer.swarm()
+            .behaviour_mut()
+            .inject_compat_message(remote, vec![request], false);
+        // answered with a dont have, but still wanted
+        async_std::future::timeout(Duration::from_millis(500), peer.next())
+            .await
+            .ok();
+        let bitswap = peer.swarm().behaviour_mut();
+        assert!(bitswap.compat_wants.is_empty());
+        assert_eq!(
+            bitswap.peer_wantlist(&remote),
+            vec![(cid, RequestType::Block)]
+        );
+
+        peer.store().insert(cid, block.data().to_vec());
+        let bitswap = peer.swarm().behaviour_mut();
+        bitswap.set_server_policy(|_: &PeerId, _: &Cid, _: RequestType, _: &Ledger| {
+            PolicyDecision::Defer
+        });
+        bitswap.notify_new_blocks(std::iter::once(cid));
+        assert_eq!(bitswap.deferred[&remote].len(), 1);
+        // the want is only notified once while it is being answered
+        bitswap.notify_new_blocks(std::iter::once(cid));
+        assert_eq!(bitswap.deferred[&remote].len(), 1);
+
+        let batch = *bitswap.compat_batches.keys().next().unwrap();
+        let response = CompatMessage::Response(cid, BitswapResponse::Have(false));
+        bitswap.compat_response(batch, Some(response));
+        // a notification doesn't repeat the dont have
+        assert!(bitswap.compat_batches.is_empty());
+        assert!(bitswap.compat_queue.is_empty());
+    }
+
+    /// Starts a get whose block request fell back to the compat protocol.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    fn start_compat_fallback(
+        bitswap: &mut Bitswap<DefaultParams>,
+        cid: Cid,
+        provider: PeerId,
+    ) -> (QueryId, BitswapId) {
+        let id = bitswap.get(cid, std::iter::once(provider)).unwrap();
+        let query = match bitswap.query_manager.next() {
+            Some(QueryEvent::Request(query, Request::Block(_, _))) => query,
+            event => panic!("{:?} is not a block request", event),
+        };
+        let request = PendingRequest {
+            query,
+            peer_id: provider,
+            cid,
+        };
+        let request_id = BitswapId::compat(cid);
+        bitswap.insert_request(request_id, request);
+        bitswap.fallbacks.insert(request_id);
+        bitswap.count_compat_fallback(query, provider, "started");
+        (id, request_id)
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_fallbacks_are_counted() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let provider = PeerId::random();
+        let bitswap = peer.swarm().behaviour_mut();
+        bitswap.record_support(provider, PeerSupport::Unsupported);
+
+        let (id, request_id) = start_compat_fallback(bitswap, *block.cid(), provider);
+        let response = BitswapResponse::Block(block.data().to_vec());
+        bitswap.inject_response(request_id, provider, response);
+        let expected = CompatFallbacks {
+            started: 1,
+            succeeded: 1,
+            failed: 0,
+        };
+        assert_eq!(bitswap.query_info(id).unwrap().compat, expected);
+        assert_eq!(bitswap.compat_fallbacks(&provider), expected);
+        assert_complete_ok(peer.next().await, id);
+
+        let missing = create_block(ipld!(&b"missing"[..]));
+        let bitswap = peer.swarm().behaviour_mut();
+        let (id, _) = start_compat_fallback(bitswap, *missing.cid(), provider);
+        bitswap.fail_compat_requests(provider);
+        let expected = CompatFallbacks {
+            started: 2,
+            succeeded: 1,
+            failed: 1,
+        };
+        assert_eq!(bitswap.compat_fallbacks(&provider), expected);
+        match peer.next().await {
+            Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::AllPeersFailed(_)),
+                ..
+            }) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_enable_compat() {
+        use libp2p::core::upgrade::{ProtocolName, UpgradeInfo};
+        tracing_try_init();
+        for enable_compat in [true, false] {
+            let mut config = BitswapConfig::new();
+            config.enable_compat = enable_compat;
+            let mut peer = Peer::with_config(config);
+            let bitswap = peer.swarm().behaviour_mut();
+
+            let protocols = bitswap
+                .new_handler()
+                .listen_protocol()
+                .upgrade()
+                .protocol_info()
+                .map(|info| info.protocol_name().to_vec())
+                .collect::<Vec<_>>();
+            let advertised = protocols
+                .iter()
+                .any(|protocol| protocol == b"/ipfs/bitswap/1.2.0");
+            assert_eq!(advertised, enable_compat);
+
+            // a peer without bitswap support is only retried over compat if enabled
+            let provider = PeerId::random();
+            let block = create_block(ipld!(&b"hello world"[..]));
+            bitswap
+                .get(*block.cid(), std::iter::once(provider))
+                .unwrap();
+            let query = match bitswap.query_manager.next() {
+                Some(QueryEvent::Request(query, Request::Block(_, _))) => query,
+                event => panic!("{:?} is not a block request", event),
+            };
+            let fallback = bitswap.compat_fallback(query, provider);
+            assert_eq!(fallback.is_some(), enable_compat);
+            let started = bitswap.compat_fallbacks(&provider).started;
+            assert_eq!(started, u64::from(enable_compat));
+        }
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_compat_mode() {
+        tracing_try_init();
+        for mode in [CompatMode::Fallback, CompatMode::Prefer, CompatMode::Only] {
+            let mut config = BitswapConfig::new();
+            config.compat_mode = mode;
+            let mut peer = Peer::with_config(config);
+            let bitswap = peer.swarm().behaviour_mut();
+            let native = PeerId::random();
+            bitswap.record_support(native, PeerSupport::Native);
+
+            for (provider, data) in [
+                (PeerId::random(), &b"unknown"[..]),
+                (native, &b"native"[..]),
+            ] {
+                let cid = *create_block(ipld!(data)).cid();
+                bitswap.get(cid, std::iter::once(provider)).unwrap();
+                let query = match bitswap.query_manager.next() {
+                    Some(QueryEvent::Request(query, Request::Block(_, _))) => query,
+                    event => panic!("{:?} is not a block request", event),
+                };
+                let req = BitswapRequest::new(RequestType::Block, cid);
+                bitswap.send_request(query, provider, req);
+                let compat = match mode {
+                    CompatMode::Fallback => false,
+                    CompatMode::Prefer => provider != native,
+                    CompatMode::Only => true,
+                };
+                assert_eq!(
+                    bitswap.requests.contains_key(&BitswapId::compat(cid)),
+                    compat
+                );
+                // neither provider is connected, so it is dialed for compat requests
+                assert_eq!(bitswap.compat_dials.contains_key(&provider), compat);
+            }
+        }
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[test]
+    fn test_fit_compat() {
+        let cid = *create_block(ipld!(&b"hello world"[..])).cid();
+        let small = BitswapResponse::Block(vec![0; 16]);
+        assert_eq!(fit_compat(&cid, small.clone()), small);
+
+        let before = COMPAT_UNSERVEABLE_BLOCKS.get();
+        let large = BitswapResponse::Block(vec![0; compat::MAX_BLOCK_SIZE + 1]);
+        assert_eq!(fit_compat(&cid, large), BitswapResponse::Have(false));
+        assert_eq!(COMPAT_UNSERVEABLE_BLOCKS.get(), before + 1);
+
+        let largest = BitswapResponse::Block(vec![0; compat::MAX_BLOCK_SIZE]);
+        let largest = fit_compat(&cid, largest);
+        let bytes = CompatMessage::Response(cid, largest).to_bytes().unwrap();
+        assert!(bytes.len() <= 2_097_152);
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[test]
+    fn test_validate_compat_block_size() {
+        #[derive(Clone, Debug, Default)]
+        struct LargeParams;
+        impl StoreParams for LargeParams {
+            const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+            type Codecs = <DefaultParams as StoreParams>::Codecs;
+            type Hashes = <DefaultParams as StoreParams>::Hashes;
+        }
+
+        let mut config = BitswapConfig::new();
+        assert!(config.validate::<LargeParams>().is_ok());
+        config.strict_compat_block_size = true;
+        assert!(config.validate::<DefaultParams>().is_ok());
+        match config.validate::<LargeParams>() {
+            Err(Error::CompatBlockSize {
+                max_block_size,
+                limit,
+            }) => {
+                assert_eq!(max_block_size, LargeParams::MAX_BLOCK_SIZE);
+                assert_eq!(limit, compat::MAX_BLOCK_SIZE);
+            }
+            res => panic!("{:?} is not a compat block size error", res),
+        }
+        config.enable_compat = false;
+        assert!(config.validate::<LargeParams>().is_ok());
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    #[async_std::test]
+    async fn test_mismatched_response_is_dropped() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let (provider, stranger) = (PeerId::random(), PeerId::random());
+        let bitswap = peer.swarm().behaviour_mut();
+        let id = bitswap
+            .get(*block.cid(), std::iter::once(provider))
+            .unwrap();
+        let query = match bitswap.query_manager.next() {
+            Some(QueryEvent::Request(query, Request::Block(_, _))) => query,
+            event => panic!("{:?} is not a block request", event),
+        };
+        let request = PendingRequest {
+            query,
+            peer_id: provider,
+            cid: *block.cid(),
+        };
+        let request_id = BitswapId::compat(*block.cid());
+        bitswap.insert_request(request_id, request);
+
+        for _ in 0..2 {
+            let response = BitswapResponse::Block(block.data().to_vec());
+            bitswap.inject_response(request_id, stranger, response);
+        }
+        assert!(bitswap.requests.contains_key(&request_id));
+        let response = BitswapResponse::Block(block.data().to_vec());
+        bitswap.inject_response(request_id, provider, response);
+
+        match peer.next().await {
+            Some(BitswapEvent::MismatchedResponse { id: id2, peer }) => {
+                assert_eq!(id2, query);
+                assert_eq!(peer, stranger);
+            }
+            event => panic!("{:?} is not a mismatched response event", event),
+        }
+        assert_complete_ok(peer.next().await, id);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_want_history() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.want_history_size = 1;
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+
+        let (peer_id1, peer_id2) = (peer1.peer_id, peer2.peer_id);
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id1))
+            .unwrap();
+
+        let next1 = Box::pin(peer1.next());
+        let next2 = Box::pin(peer2.next());
+        match future::select(next1, next2).await {
+            future::Either::Right((event, _)) => assert_complete_ok(event, id),
+            future::Either::Left((event, _)) => panic!("unexpected event {:?}", event),
+        }
+
+        let bitswap = peer1.swarm().behaviour_mut();
+        let history = bitswap.want_history().collect::<Vec<_>>();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].peer, peer_id2);
+        assert_eq!(history[0].cid, *block.cid());
+        assert!(history[0].served);
+        assert_eq!(bitswap.want_history_for_peer(&peer_id2).count(), 1);
+        assert_eq!(bitswap.want_history_for_peer(&peer_id1).count(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_hot_cids() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.hot_cid_tracking = Some(2);
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        assert!(peer1.swarm().behaviour().hot_cids(10).is_empty());
+
+        let peer_id1 = peer1.peer_id;
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id1))
+            .unwrap();
+
+        let next1 = Box::pin(peer1.next());
+        let next2 = Box::pin(peer2.next());
+        match future::select(next1, next2).await {
+            future::Either::Right((event, _)) => assert_complete_ok(event, id),
+            future::Either::Left((event, _)) => panic!("unexpected event {:?}", event),
+        }
+
+        let hot_cids = peer1.swarm().behaviour().hot_cids(10);
+        assert_eq!(hot_cids, [(*block.cid(), block.data().len() as u64)]);
+        assert!(peer2.swarm().behaviour().hot_cids(10).is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_simultaneous_dial() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.request_timeout = Duration::from_secs(60);
+        let mut peer1 = Peer::with_config(config.clone());
+        let mut peer2 = Peer::with_config(config);
+        peer1.add_address(&peer2);
+        peer2.add_address(&peer1);
+
+        let block1 = create_block(ipld!(&b"hello"[..]));
+        let block2 = create_block(ipld!(&b"world"[..]));
+        peer1.store().insert(*block1.cid(), block1.data().to_vec());
+        peer2.store().insert(*block2.cid(), block2.data().to_vec());
+
+        let (peer_id1, peer_id2) = (peer1.peer_id, peer2.peer_id);
+        let id1 = peer1
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer_id2))
+            .unwrap();
+        let id2 = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block1.cid(), std::iter::once(peer_id1))
+            .unwrap();
+
+        let drive = async {
+            let (mut done1, mut done2) = (false, false);
+            while !(done1 && done2) {
+                let next1 = Box::pin(peer1.next());
+                let next2 = Box::pin(peer2.next());
+                match future::select(next1, next2).await {
+                    future::Either::Left((event, _)) => {
+                        assert_complete_ok(event, id1);
+                        done1 = true;
+                    }
+                    future::Either::Right((event, _)) => {
+                        assert_complete_ok(event, id2);
+                        done2 = true;
+                    }
                 }
             }
-            Ok(missing)
-        }
+        };
+        async_std::future::timeout(Duration::from_secs(30), drive)
+            .await
+            .unwrap();
     }
 
-    struct Peer {
-        peer_id: PeerId,
-        addr: Multiaddr,
-        store: Store,
-        swarm: Swarm<Bitswap<DefaultParams>>,
+    #[async_std::test]
+    async fn test_bitswap_max_active_queries() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.max_active_queries = 1;
+        let mut peer = Peer::with_config(config);
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let provider = PeerId::random();
+        let bitswap = peer.swarm().behaviour_mut();
+
+        let id = bitswap
+            .get(*block.cid(), std::iter::once(provider))
+            .unwrap();
+        let request = bitswap.query_manager.next();
+        assert!(matches!(
+            request,
+            Some(QueryEvent::Request(_, Request::Block(_, _)))
+        ));
+        let rejected = QueryRejected::TooManyQueries(1);
+        let res = bitswap.get(*block.cid(), std::iter::once(provider));
+        assert_eq!(res.unwrap_err(), rejected);
+        let res = bitswap.sync(*block.cid(), vec![provider], std::iter::empty());
+        assert_eq!(res.unwrap_err(), rejected);
+
+        // the rejected queries left no events, queries or ids behind
+        assert!(bitswap.query_manager.next().is_none());
+        assert_eq!(bitswap.query_manager.active_queries(), 1);
+        assert!(bitswap.cancel(id));
+        let id2 = bitswap
+            .get(*block.cid(), std::iter::once(provider))
+            .unwrap();
+        // the block request of the first get took the id in between
+        assert_eq!(id2.seq, id.seq + 2);
     }
 
-    impl Peer {
-        fn new() -> Self {
-            let (peer_id, trans) = mk_transport();
-            let store = Store::default();
-            let mut swarm = Swarm::with_async_std_executor(
-                trans,
-                Bitswap::new(BitswapConfig::new(), store.clone()),
-                peer_id,
-            );
-            Swarm::listen_on(&mut swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
-            while swarm.next().now_or_never().is_some() {}
-            let addr = Swarm::listeners(&swarm).next().unwrap().clone();
-            Self {
-                peer_id,
-                addr,
-                store,
-                swarm,
-            }
-        }
+    #[async_std::test]
+    async fn test_bitswap_cancel_get() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
 
-        fn add_address(&mut self, peer: &Peer) {
-            self.swarm
-                .behaviour_mut()
-                .add_address(&peer.peer_id, peer.addr.clone());
-        }
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
 
-        fn store(&mut self) -> impl std::ops::DerefMut<Target = FnvHashMap<Cid, Vec<u8>>> + '_ {
-            self.store.0.lock().unwrap()
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert!(peer2.swarm().behaviour_mut().cancel(id));
+        match peer2.next().now_or_never() {
+            Some(Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::Cancelled),
+                ..
+            })) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a cancelled complete event", event),
         }
+        assert!(peer2.next().now_or_never().is_none());
+    }
 
-        fn swarm(&mut self) -> &mut Swarm<Bitswap<DefaultParams>> {
-            &mut self.swarm
-        }
+    #[async_std::test]
+    async fn test_bitswap_async_store() {
+        tracing_try_init();
+        let mut peer1 = Peer::with_async_store(BitswapConfig::new());
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
 
-        fn spawn(mut self, name: &'static str) -> PeerId {
-            let peer_id = self.peer_id;
-            task::spawn(async move {
-                loop {
-                    let event = self.swarm.next().await;
-                    tracing::debug!("{}: {:?}", name, event);
+        let b0 = create_block(ipld!({
+            "n": 0,
+        }));
+        let b1 = create_block(ipld!({
+            "prev": b0.cid(),
+            "n": 1,
+        }));
+        peer1.store().insert(*b0.cid(), b0.data().to_vec());
+        peer1.store().insert(*b1.cid(), b1.data().to_vec());
+        let peer1_id = peer1.peer_id;
+
+        // the async store serves the blocks
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*b1.cid(), vec![peer1_id], std::iter::once(*b1.cid()))
+            .unwrap();
+        loop {
+            match peer2.next_driving(&mut peer1).await {
+                Some(BitswapEvent::Progress { .. }) => {}
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
                 }
-            });
-            peer_id
+            }
         }
 
-        async fn next(&mut self) -> Option<BitswapEvent> {
-            loop {
-                let ev = self.swarm.next().await?;
-                if let SwarmEvent::Behaviour(event) = ev {
-                    return Some(event);
+        // and inserts the blocks of a sync
+        let b2 = create_block(ipld!({
+            "prev": b1.cid(),
+            "n": 2,
+        }));
+        peer2.store().insert(*b2.cid(), b2.data().to_vec());
+        let peer2 = peer2.spawn("peer2");
+        let id = peer1
+            .swarm()
+            .behaviour_mut()
+            .sync(*b2.cid(), vec![peer2], std::iter::once(*b2.cid()))
+            .unwrap();
+        loop {
+            match peer1.next().await {
+                Some(BitswapEvent::Progress { .. }) => {}
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
                 }
             }
         }
+        assert!(peer1.store().contains_key(b2.cid()));
     }
 
-    fn assert_progress(event: Option<BitswapEvent>, id: QueryId, missing: usize) {
-        if let Some(BitswapEvent::Progress(id2, missing2)) = event {
-            assert_eq!(id2, id);
-            assert_eq!(missing2, missing);
-        } else {
-            panic!("{:?} is not a progress event", event);
-        }
-    }
+    #[async_std::test]
+    async fn test_bitswap_session_prefers_useful_peers() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.want_history_size = 16;
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        let mut peer3 = Peer::new();
+        peer3.add_address(&peer1);
+        peer3.add_address(&peer2);
 
-    fn assert_complete_ok(event: Option<BitswapEvent>, id: QueryId) {
-        if let Some(BitswapEvent::Complete(id2, Ok(()))) = event {
-            assert_eq!(id2, id);
-        } else {
-            panic!("{:?} is not a complete event", event);
-        }
+        let b0 = create_block(ipld!(&b"hello"[..]));
+        let b1 = create_block(ipld!(&b"world"[..]));
+        peer2.store().insert(*b0.cid(), b0.data().to_vec());
+        peer2.store().insert(*b1.cid(), b1.data().to_vec());
+        let peer1_id = peer1.peer_id;
+        let peer2 = peer2.spawn("peer2");
+
+        let session = peer3
+            .swarm()
+            .behaviour_mut()
+            .new_session(vec![peer1_id, peer2]);
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .session_get(session, *b0.cid())
+            .unwrap();
+        assert_complete_ok(peer3.next_driving(&mut peer1).await, id);
+        assert_eq!(
+            peer3.swarm().behaviour().session_peers(session),
+            Some(vec![peer2, peer1_id])
+        );
+
+        // peer1 is parked and not asked while peer2 has the block
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .session_get(session, *b1.cid())
+            .unwrap();
+        assert_complete_ok(peer3.next_driving(&mut peer1).await, id);
+        let bitswap = peer1.swarm().behaviour();
+        assert_eq!(bitswap.want_history_for_cid(b1.cid()).count(), 0);
+
+        let bitswap = peer3.swarm().behaviour_mut();
+        assert!(bitswap.close_session(session));
+        assert!(matches!(
+            bitswap.session_get(session, *b1.cid()),
+            Err(QueryRejected::UnknownSession(session2)) if session2 == session
+        ));
     }
 
     #[async_std::test]
-    async fn test_bitswap_get() {
+    async fn test_bitswap_sync() {
         tracing_try_init();
         let mut peer1 = Peer::new();
         let mut peer2 = Peer::new();
         peer2.add_address(&peer1);
 
-        let block = create_block(ipld!(&b"hello world"[..]));
-        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let b0 = create_block(ipld!({
+            "n": 0,
+        }));
+        let b1 = create_block(ipld!({
+            "prev": b0.cid(),
+            "n": 1,
+        }));
+        let b2 = create_block(ipld!({
+            "prev": b1.cid(),
+            "n": 2,
+        }));
+        peer1.store().insert(*b0.cid(), b0.data().to_vec());
+        peer1.store().insert(*b1.cid(), b1.data().to_vec());
+        peer1.store().insert(*b2.cid(), b2.data().to_vec());
         let peer1 = peer1.spawn("peer1");
 
         let id = peer2
             .swarm()
             .behaviour_mut()
-            .get(*block.cid(), std::iter::once(peer1));
+            .sync(*b2.cid(), vec![peer1], std::iter::once(*b2.cid()))
+            .unwrap();
+
+        assert_progress(peer2.next().await, id, 2, 1);
+        assert_progress(peer2.next().await, id, 3, 2);
+        let info = peer2.swarm().behaviour().query_info(id).unwrap();
+        assert_eq!(info.missing, 1);
+        assert!(info.eta.is_some());
 
+        assert_progress(peer2.next().await, id, 3, 3);
         assert_complete_ok(peer2.next().await, id);
+        assert!(peer2.swarm().behaviour().query_info(id).is_none());
     }
 
     #[async_std::test]
-    async fn test_bitswap_cancel_get() {
+    async fn test_bitswap_sync_batches_wants() {
         tracing_try_init();
-        let mut peer1 = Peer::new();
-        let mut peer2 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.capabilities = Capabilities::BATCHING;
+        let mut peer1 = Peer::with_config(config.clone());
+        let mut peer2 = Peer::with_config(config);
         peer2.add_address(&peer1);
 
-        let block = create_block(ipld!(&b"hello world"[..]));
+        let leaves: Vec<_> = (0..4).map(|n| create_block(ipld!({ "n": n }))).collect();
+        let root = create_block(ipld!({
+            "leaves": leaves.iter().map(|b| Ipld::Link(*b.cid())).collect::<Vec<_>>(),
+        }));
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*root.cid(), vec![peer1], std::iter::once(*root.cid()))
+            .unwrap();
+        loop {
+            match peer2.next().await {
+                Some(BitswapEvent::Progress { .. }) => {}
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
+                }
+            }
+        }
+
+        // the root is requested before the capabilities are known, the leaves share
+        // one batch
+        let bitswap = peer2.swarm().behaviour();
+        assert!(bitswap
+            .negotiated_capabilities(&peer1)
+            .contains(Capabilities::BATCHING));
+        for block in &leaves {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_chunked_block() {
+        tracing_try_init();
+        let mut config = BitswapConfig::new();
+        config.capabilities = Capabilities::CHUNKING;
+        config.chunk_size = 64;
+        let mut peer1 = Peer::with_config(config.clone());
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!({
+            "data": vec![7u8; 1000],
+        }));
         peer1.store().insert(*block.cid(), block.data().to_vec());
         let peer1 = peer1.spawn("peer1");
 
         let id = peer2
             .swarm()
             .behaviour_mut()
-            .get(*block.cid(), std::iter::once(peer1));
-        peer2.swarm().behaviour_mut().cancel(id);
-        let res = peer2.next().now_or_never();
-        println!("{:?}", res);
-        assert!(res.is_none());
+            .get(*block.cid(), std::iter::once(peer1))
+            .unwrap();
+        assert_complete_ok(peer2.next().await, id);
+        assert_eq!(peer2.store().get(block.cid()).unwrap(), block.data());
+        let bitswap = peer2.swarm().behaviour();
+        assert!(bitswap
+            .negotiated_capabilities(&peer1)
+            .contains(Capabilities::CHUNKING));
     }
 
     #[async_std::test]
-    async fn test_bitswap_sync() {
+    async fn test_bitswap_sync_speculative_prefetch() {
         tracing_try_init();
         let mut peer1 = Peer::new();
-        let mut peer2 = Peer::new();
+        let mut config = BitswapConfig::new();
+        config.speculative_prefetch = true;
+        let mut peer2 = Peer::with_inline_store(config);
         peer2.add_address(&peer1);
 
         let b0 = create_block(ipld!({
@@ -906,16 +8163,34 @@ mod tests {
         peer1.store().insert(*b2.cid(), b2.data().to_vec());
         let peer1 = peer1.spawn("peer1");
 
-        let id =
-            peer2
-                .swarm()
-                .behaviour_mut()
-                .sync(*b2.cid(), vec![peer1], std::iter::once(*b2.cid()));
-
-        assert_progress(peer2.next().await, id, 1);
-        assert_progress(peer2.next().await, id, 1);
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*b2.cid(), vec![peer1], std::iter::once(*b2.cid()))
+            .unwrap();
 
-        assert_complete_ok(peer2.next().await, id);
+        // the child is requested before the root is inserted
+        assert_progress(peer2.next().await, id, 2, 0);
+        loop {
+            match peer2.next().await {
+                Some(BitswapEvent::Progress {
+                    discovered,
+                    fetched,
+                    ..
+                }) => {
+                    // the missing blocks don't request the prefetched blocks again
+                    assert!(discovered <= 3);
+                    assert!(fetched <= discovered);
+                }
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
+                }
+            }
+        }
+        let store = peer2.store();
+        assert!(store.contains_key(b0.cid()));
+        assert!(store.contains_key(b1.cid()));
     }
 
     #[async_std::test]
@@ -929,18 +8204,157 @@ mod tests {
         peer1.store().insert(*block.cid(), block.data().to_vec());
         let peer1 = peer1.spawn("peer1");
 
-        let id = peer2.swarm().behaviour_mut().sync(
-            *block.cid(),
-            vec![peer1],
-            std::iter::once(*block.cid()),
-        );
-        peer2.swarm().behaviour_mut().cancel(id);
-        let res = peer2.next().now_or_never();
-        println!("{:?}", res);
-        assert!(res.is_none());
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*block.cid(), vec![peer1], std::iter::once(*block.cid()))
+            .unwrap();
+        assert!(peer2.swarm().behaviour_mut().cancel(id));
+        match peer2.next().now_or_never() {
+            Some(Some(BitswapEvent::Complete {
+                id: id2,
+                result: Err(Error::Cancelled),
+                ..
+            })) => assert_eq!(id2, id),
+            event => panic!("{:?} is not a cancelled complete event", event),
+        }
+        assert!(peer2.next().now_or_never().is_none());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_strict() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let unsupported = Cid::new_v1(0x300, Code::Blake3_256.digest(b"unsupported"));
+        let block = create_block(ipld!({
+            "link": &unsupported,
+        }));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let options = SyncOptions::default().with_strict(true);
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync_with_options(
+                *block.cid(),
+                vec![peer1],
+                std::iter::once(*block.cid()),
+                options,
+            )
+            .unwrap();
+
+        if let Some(BitswapEvent::Complete {
+            id: id2,
+            result: Err(err),
+            ..
+        }) = peer2.next().await
+        {
+            assert_eq!(id2, id);
+            let err = if let Error::InvalidReferences(err) = err {
+                err
+            } else {
+                panic!("{} is not an invalid references error", err);
+            };
+            assert_eq!(err.cid, *block.cid());
+            assert_eq!(err.link, Some(unsupported));
+        } else {
+            panic!("expected strict sync to fail");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_selected() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let leaf = create_block(ipld!({
+            "n": 0,
+        }));
+        let a = create_block(ipld!({
+            "leaf": leaf.cid(),
+        }));
+        let b = create_block(ipld!({
+            "n": 1,
+        }));
+        let root = create_block(ipld!({
+            "a": a.cid(),
+            "b": b.cid(),
+        }));
+        for block in [&leaf, &a, &b, &root] {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        async fn sync(peer: &mut Peer, peer1: PeerId, root: Cid, selector: Selector) {
+            let id = peer
+                .swarm()
+                .behaviour_mut()
+                .sync_selected(root, vec![peer1], selector, SyncOptions::default())
+                .unwrap();
+            loop {
+                match peer.next().await {
+                    Some(BitswapEvent::Progress { .. }) => {}
+                    event => {
+                        assert_complete_ok(event, id);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // only the block the path ends in
+        sync(&mut peer2, peer1, *root.cid(), Selector::path("a").depth(0)).await;
+        assert!(peer2.store().contains_key(root.cid()));
+        assert!(peer2.store().contains_key(a.cid()));
+        assert!(!peer2.store().contains_key(leaf.cid()));
+        assert!(!peer2.store().contains_key(b.cid()));
+
+        // the blocks on the path are read from the store
+        sync(&mut peer2, peer1, *root.cid(), Selector::path("a")).await;
+        let store = peer2.store();
+        assert!(store.contains_key(leaf.cid()));
+        assert!(!store.contains_key(b.cid()));
     }
 
-    #[cfg(feature = "compat")]
+    #[async_std::test]
+    async fn test_bitswap_sync_selected_undecodable_block() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        // a truncated dag-cbor map
+        let data = vec![0xa1];
+        let cid = Cid::new_v1(0x71, Code::Blake3_256.digest(&data));
+        peer.store().insert(cid, data);
+
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .sync_selected(cid, vec![], Selector::path("a"), SyncOptions::default())
+            .unwrap();
+        loop {
+            match peer.next().await {
+                Some(BitswapEvent::Progress { .. }) => {}
+                Some(BitswapEvent::Complete {
+                    id: id2,
+                    result: Err(_),
+                    ..
+                }) => {
+                    assert_eq!(id2, id);
+                    break;
+                }
+                event => panic!("{:?} is not a failed complete event", event),
+            }
+        }
+        assert!(peer.swarm().behaviour_mut().query_info(id).is_none());
+        assert!(peer.swarm().behaviour_mut().selectors.is_empty());
+    }
+
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
     #[async_std::test]
     async fn compat_test() {
         tracing_try_init();
@@ -955,11 +8369,13 @@ mod tests {
         let mut peer = Peer::new();
         peer.swarm()
             .behaviour_mut()
-            .add_address(&peer_id, multiaddr);
+            .add_address(&peer_id, multiaddr)
+            .unwrap();
         let id = peer
             .swarm()
             .behaviour_mut()
-            .get(cid, std::iter::once(peer_id));
+            .get(cid, std::iter::once(peer_id))
+            .unwrap();
         assert_complete_ok(peer.next().await, id);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,227 @@
+use fnv::FnvHashMap;
+use libp2p::{Multiaddr, PeerId};
+use std::time::Duration;
+
+/// Upper bound of the backoff between dials of a bootstrap peer.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct BootstrapPeer {
+    addr: Multiaddr,
+    /// Dials that failed since the last established connection.
+    failures: u32,
+    connected: bool,
+    /// Whether protocol detection found the peer usable as a provider.
+    ready: bool,
+    /// Whether our dial of the peer is in flight.
+    dialing: bool,
+    /// Whether a redial of the peer is scheduled.
+    redial_pending: bool,
+}
+
+/// Bootstrap peers that are kept connected and used as providers when a query is
+/// started with too few of them.
+#[derive(Debug, Default)]
+pub(crate) struct Bootstrap {
+    peers: FnvHashMap<PeerId, BootstrapPeer>,
+    /// Peers in the order they were added.
+    order: Vec<PeerId>,
+}
+
+impl Bootstrap {
+    /// Adds a bootstrap peer. Returns `false` and only updates the address if the peer
+    /// was added before.
+    pub fn insert(&mut self, peer_id: PeerId, addr: Multiaddr) -> bool {
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.addr = addr;
+            return false;
+        }
+        self.peers.insert(
+            peer_id,
+            BootstrapPeer {
+                addr,
+                failures: 0,
+                connected: false,
+                ready: false,
+                dialing: false,
+                redial_pending: false,
+            },
+        );
+        self.order.push(peer_id);
+        true
+    }
+
+    /// Returns the address to dial a bootstrap peer at, unless it is connected or our
+    /// dial of it is in flight. The dial is in flight until it connects or fails.
+    pub fn start_dial(&mut self, peer_id: &PeerId) -> Option<Multiaddr> {
+        let peer = self.peers.get_mut(peer_id)?;
+        if peer.connected || peer.dialing {
+            return None;
+        }
+        peer.dialing = true;
+        Some(peer.addr.clone())
+    }
+
+    /// Schedules a redial of a bootstrap peer. Returns `false` if the peer isn't a
+    /// bootstrap peer or a redial of it is scheduled already.
+    pub fn schedule_redial(&mut self, peer_id: &PeerId) -> bool {
+        match self.peers.get_mut(peer_id) {
+            Some(peer) if !peer.redial_pending => {
+                peer.redial_pending = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records that the backoff of a scheduled redial elapsed.
+    pub fn redial_due(&mut self, peer_id: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.redial_pending = false;
+        }
+    }
+
+    /// Records that a connection to a peer was established. Returns `true` if the peer
+    /// is a bootstrap peer whose protocol support wasn't detected yet.
+    pub fn connected(&mut self, peer_id: &PeerId) -> bool {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.failures = 0;
+            peer.connected = true;
+            peer.dialing = false;
+            !peer.ready
+        } else {
+            false
+        }
+    }
+
+    /// Records that the last connection to a peer closed. Returns the backoff before
+    /// the peer is dialed again if it is a bootstrap peer.
+    pub fn disconnected(&mut self, peer_id: &PeerId, base: Duration) -> Option<Duration> {
+        let peer = self.peers.get_mut(peer_id)?;
+        peer.connected = false;
+        Some(backoff(base, peer.failures))
+    }
+
+    /// Records a dial rejected by the connection limits. Returns `true` if it is a
+    /// bootstrap peer that isn't connected.
+    pub fn dial_limited(&mut self, peer_id: &PeerId) -> bool {
+        match self.peers.get_mut(peer_id) {
+            Some(peer) => {
+                peer.dialing = false;
+                !peer.connected
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failed dial. Unreachable peers aren't handed out until they connect
+    /// again. Returns the backoff before the next dial if it is a bootstrap peer.
+    pub fn dial_failed(&mut self, peer_id: &PeerId, base: Duration) -> Option<Duration> {
+        let peer = self.peers.get_mut(peer_id)?;
+        // the failure may be of another dial, which is as good a sign that ours fails
+        peer.dialing = false;
+        if peer.connected {
+            return None;
+        }
+        peer.ready = false;
+        peer.failures = peer.failures.saturating_add(1);
+        Some(backoff(base, peer.failures))
+    }
+
+    /// Records the outcome of protocol detection.
+    pub fn set_ready(&mut self, peer_id: &PeerId, ready: bool) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.ready = ready;
+        }
+    }
+
+    /// Returns the bootstrap peers usable as providers in the order they were added.
+    pub fn ready(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.order
+            .iter()
+            .filter(move |peer_id| self.peers[peer_id].ready)
+            .copied()
+    }
+}
+
+/// Doubles the backoff with every failure up to `MAX_BACKOFF`.
+fn backoff(base: Duration, failures: u32) -> Duration {
+    base.checked_mul(2u32.saturating_pow(failures))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bootstrap_backoff() {
+        let base = Duration::from_secs(1);
+        let mut bootstrap = Bootstrap::default();
+        let peer = PeerId::random();
+        assert!(bootstrap.insert(peer, Multiaddr::empty()));
+        assert!(!bootstrap.insert(peer, Multiaddr::empty()));
+        assert!(bootstrap.start_dial(&peer).is_some());
+        assert_eq!(bootstrap.dial_failed(&peer, base), Some(base * 2));
+        assert_eq!(bootstrap.dial_failed(&peer, base), Some(base * 4));
+        for _ in 0..40 {
+            bootstrap.dial_failed(&peer, base);
+        }
+        assert_eq!(bootstrap.dial_failed(&peer, base), Some(MAX_BACKOFF));
+
+        assert!(bootstrap.connected(&peer));
+        assert!(bootstrap.start_dial(&peer).is_none());
+        assert_eq!(bootstrap.disconnected(&peer, base), Some(base));
+        assert!(bootstrap.disconnected(&PeerId::random(), base).is_none());
+    }
+
+    #[test]
+    fn test_bootstrap_dials_once() {
+        let base = Duration::from_secs(1);
+        let mut bootstrap = Bootstrap::default();
+        let peer = PeerId::random();
+        bootstrap.insert(peer, Multiaddr::empty());
+        assert!(bootstrap.start_dial(&peer).is_some());
+        assert!(bootstrap.start_dial(&peer).is_none());
+        assert!(bootstrap.dial_failed(&peer, base).is_some());
+        assert!(bootstrap.start_dial(&peer).is_some());
+        assert!(bootstrap.dial_limited(&peer));
+        assert!(bootstrap.start_dial(&peer).is_some());
+
+        // failures of other dials don't schedule more redials
+        assert!(bootstrap.schedule_redial(&peer));
+        assert!(!bootstrap.schedule_redial(&peer));
+        bootstrap.redial_due(&peer);
+        assert!(bootstrap.schedule_redial(&peer));
+        assert!(!bootstrap.schedule_redial(&PeerId::random()));
+    }
+
+    #[test]
+    fn test_bootstrap_ready() {
+        let base = Duration::from_secs(1);
+        let mut bootstrap = Bootstrap::default();
+        let peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for peer in peers.iter() {
+            bootstrap.insert(*peer, Multiaddr::empty());
+        }
+        assert_eq!(bootstrap.ready().count(), 0);
+        for peer in peers.iter().rev() {
+            assert!(bootstrap.connected(peer));
+            bootstrap.set_ready(peer, true);
+        }
+        assert_eq!(bootstrap.ready().collect::<Vec<_>>(), peers.to_vec());
+
+        // Detection isn't repeated when a ready peer reconnects.
+        bootstrap.disconnected(&peers[0], base);
+        assert!(!bootstrap.connected(&peers[0]));
+
+        bootstrap.disconnected(&peers[1], base);
+        bootstrap.dial_failed(&peers[1], base);
+        assert_eq!(
+            bootstrap.ready().collect::<Vec<_>>(),
+            vec![peers[0], peers[2]]
+        );
+        assert!(bootstrap.connected(&peers[1]));
+    }
+}
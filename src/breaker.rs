@@ -0,0 +1,169 @@
+use fnv::FnvHashMap;
+use instant::Instant;
+use libp2p::PeerId;
+use std::time::Duration;
+
+/// Circuit breaker configuration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CircuitBreaker {
+    /// Number of consecutive failed requests to a peer that open its circuit.
+    pub failure_threshold: u32,
+    /// Failures further apart than this don't count as consecutive.
+    pub window: Duration,
+    /// Time a circuit stays open before a single probe decides whether the peer is
+    /// readmitted.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of the circuit breaker of a peer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CircuitState {
+    /// The peer is selected as usual.
+    #[default]
+    Closed,
+    /// The peer failed repeatedly and is only asked if no other provider has a block.
+    Open,
+    /// The cooldown expired and a probe is in flight.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct PeerCircuit {
+    state: CircuitState,
+    failures: u32,
+    /// Time of the first failure of the current window.
+    since: Instant,
+}
+
+/// Circuit breakers of the peers with failed requests.
+#[derive(Debug, Default)]
+pub(crate) struct Circuits {
+    peers: FnvHashMap<PeerId, PeerCircuit>,
+}
+
+impl Circuits {
+    /// Returns the state of the circuit of a peer.
+    pub fn state(&self, peer_id: &PeerId) -> CircuitState {
+        self.peers
+            .get(peer_id)
+            .map(|circuit| circuit.state)
+            .unwrap_or_default()
+    }
+
+    /// Records a failed request. Returns `true` if the circuit opened, either because
+    /// the threshold was reached or because the probe of a half open circuit failed.
+    pub fn failure(&mut self, peer_id: PeerId, config: &CircuitBreaker, now: Instant) -> bool {
+        let circuit = self.peers.entry(peer_id).or_insert(PeerCircuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            since: now,
+        });
+        match circuit.state {
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                circuit.state = CircuitState::Open;
+                true
+            }
+            CircuitState::Closed => {
+                if now.saturating_duration_since(circuit.since) > config.window {
+                    circuit.failures = 0;
+                    circuit.since = now;
+                }
+                circuit.failures += 1;
+                if circuit.failures >= config.failure_threshold.max(1) {
+                    circuit.state = CircuitState::Open;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Opens the circuit of a peer regardless of its failures. Returns `false` if the
+    /// circuit was open already.
+    pub fn open(&mut self, peer_id: PeerId, now: Instant) -> bool {
+        let circuit = self.peers.entry(peer_id).or_insert(PeerCircuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            since: now,
+        });
+        let opened = circuit.state != CircuitState::Open;
+        circuit.state = CircuitState::Open;
+        opened
+    }
+
+    /// Records a response. Returns the state of the circuit before it closed.
+    pub fn success(&mut self, peer_id: &PeerId) -> CircuitState {
+        self.peers
+            .remove(peer_id)
+            .map(|circuit| circuit.state)
+            .unwrap_or_default()
+    }
+
+    /// Moves an open circuit to half open once its cooldown expired. Returns `false` if
+    /// the circuit isn't open.
+    pub fn half_open(&mut self, peer_id: &PeerId) -> bool {
+        match self.peers.get_mut(peer_id) {
+            Some(circuit) if circuit.state == CircuitState::Open => {
+                circuit.state = CircuitState::HalfOpen;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let config = CircuitBreaker {
+            failure_threshold: 2,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(1),
+        };
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let mut circuits = Circuits::default();
+
+        // failures outside of the window aren't consecutive
+        assert!(!circuits.failure(peer, &config, now));
+        let later = now + Duration::from_secs(11);
+        assert!(!circuits.failure(peer, &config, later));
+        assert_eq!(circuits.state(&peer), CircuitState::Closed);
+        assert!(circuits.failure(peer, &config, later));
+        assert_eq!(circuits.state(&peer), CircuitState::Open);
+        assert!(!circuits.failure(peer, &config, later));
+
+        // a failed probe opens the circuit again
+        assert!(circuits.half_open(&peer));
+        assert!(!circuits.half_open(&peer));
+        assert_eq!(circuits.state(&peer), CircuitState::HalfOpen);
+        assert!(circuits.failure(peer, &config, later));
+        assert_eq!(circuits.state(&peer), CircuitState::Open);
+
+        // a response closes it
+        assert!(circuits.half_open(&peer));
+        assert_eq!(circuits.success(&peer), CircuitState::HalfOpen);
+        assert_eq!(circuits.state(&peer), CircuitState::Closed);
+        assert_eq!(circuits.success(&peer), CircuitState::Closed);
+
+        // a dead peer opens right away
+        assert!(circuits.open(peer, later));
+        assert!(!circuits.open(peer, later));
+        assert_eq!(circuits.state(&peer), CircuitState::Open);
+    }
+}
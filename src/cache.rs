@@ -0,0 +1,117 @@
+use fnv::FnvHashMap;
+use libipld::Cid;
+use std::collections::BTreeMap;
+
+/// Least recently used cids known to be in the store, so that repeated have requests
+/// for popular blocks are answered without a round trip to the db thread.
+///
+/// Only hits are cached, since a block inserted into the store directly would otherwise
+/// be denied until its entry is evicted, so there are no misses for inserts to
+/// invalidate. A cached block that was removed from the store or that the store no
+/// longer serves is announced with a have until it is removed from the cache, requests
+/// for the block itself go to the store.
+#[derive(Debug)]
+pub(crate) struct HaveCache {
+    /// Cids and the sequence number of their last use.
+    cids: FnvHashMap<Cid, u64>,
+    /// Cids by the sequence number of their last use, the least recent first.
+    order: BTreeMap<u64, Cid>,
+    seq: u64,
+    capacity: usize,
+}
+
+impl HaveCache {
+    /// Creates a cache holding up to `capacity` cids.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cids: Default::default(),
+            order: Default::default(),
+            seq: 0,
+            capacity,
+        }
+    }
+
+    /// Returns `true` if the cid is cached and marks it as recently used.
+    pub fn contains(&mut self, cid: &Cid) -> bool {
+        if !self.cids.contains_key(cid) {
+            return false;
+        }
+        self.insert(*cid);
+        true
+    }
+
+    /// Caches a cid that is in the store, evicting the least recently used one if the
+    /// cache is full.
+    pub fn insert(&mut self, cid: Cid) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.seq += 1;
+        if let Some(seq) = self.cids.insert(cid, self.seq) {
+            self.order.remove(&seq);
+        } else if self.cids.len() > self.capacity {
+            let oldest = self.order.keys().next().copied();
+            if let Some(evicted) = oldest.and_then(|seq| self.order.remove(&seq)) {
+                self.cids.remove(&evicted);
+            }
+        }
+        self.order.insert(self.seq, cid);
+    }
+
+    /// Removes a cid from the cache.
+    pub fn remove(&mut self, cid: &Cid) {
+        if let Some(seq) = self.cids.remove(cid) {
+            self.order.remove(&seq);
+        }
+    }
+
+    /// Removes all cids from the cache.
+    pub fn clear(&mut self) {
+        self.cids.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::multihash::{Code, MultihashDigest};
+
+    fn cid(i: u8) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(&[i]))
+    }
+
+    #[test]
+    fn test_have_cache_evicts_least_recently_used() {
+        let mut cache = HaveCache::new(2);
+        cache.insert(cid(0));
+        cache.insert(cid(1));
+        assert!(cache.contains(&cid(0)));
+        cache.insert(cid(2));
+        assert!(!cache.contains(&cid(1)));
+        assert!(cache.contains(&cid(0)));
+        assert!(cache.contains(&cid(2)));
+        assert_eq!(cache.order.len(), 2);
+
+        let mut cache = HaveCache::new(0);
+        cache.insert(cid(0));
+        assert!(!cache.contains(&cid(0)));
+    }
+
+    #[test]
+    fn test_have_cache_remove() {
+        let mut cache = HaveCache::new(2);
+        cache.insert(cid(0));
+        cache.insert(cid(1));
+        cache.remove(&cid(0));
+        assert!(!cache.contains(&cid(0)));
+        assert_eq!(cache.order.len(), 1);
+        cache.insert(cid(2));
+        assert!(cache.contains(&cid(1)));
+
+        cache.clear();
+        assert!(!cache.contains(&cid(1)));
+        assert!(!cache.contains(&cid(2)));
+        assert!(cache.order.is_empty());
+    }
+}
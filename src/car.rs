@@ -0,0 +1,311 @@
+//! Import and export of CAR files, e.g. to seed a store before serving its blocks or to
+//! snapshot the dag fetched by a sync. Used by the `car` feature.
+use crate::behaviour::BitswapStore;
+use crate::error::Error;
+use crate::query::is_inline;
+use fnv::FnvHashSet;
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Longest header accepted in a CAR file.
+const MAX_HEADER_SIZE: u64 = 1024 * 1024;
+/// Longest cid accepted in a section, on top of the block size limit of the store.
+const MAX_CID_SIZE: u64 = 1024;
+/// Size of the CARv2 header following the pragma, which is a CARv1 header with
+/// version 2.
+const V2_HEADER_SIZE: u64 = 40;
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Reads a varint. Returns `None` at the end of the reader.
+fn read_varint(reader: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if i == 9 && byte[0] > 1 {
+            break;
+        }
+        value |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(invalid_data("varint overflows"))
+}
+
+fn write_varint(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    let mut buf = unsigned_varint::encode::u64_buffer();
+    writer.write_all(unsigned_varint::encode::u64(value, &mut buf))
+}
+
+/// Returns the size of a frame with `len` bytes including its length prefix.
+fn frame_size(len: usize) -> u64 {
+    let mut buf = unsigned_varint::encode::usize_buffer();
+    (unsigned_varint::encode::usize(len, &mut buf).len() + len) as u64
+}
+
+/// Reads a length prefixed frame of at most `max` bytes. Returns `None` at the end of
+/// the reader.
+fn read_frame(reader: &mut impl Read, max: u64) -> io::Result<Option<Vec<u8>>> {
+    let len = if let Some(len) = read_varint(reader)? {
+        len
+    } else {
+        return Ok(None);
+    };
+    if len > max {
+        return Err(invalid_data(format!(
+            "frame of {} bytes exceeds {}",
+            len, max
+        )));
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+/// Decodes a CAR header into its version and roots.
+fn decode_header(bytes: &[u8]) -> Result<(u64, Vec<Cid>), Error> {
+    let header = if let Ipld::Map(header) = DagCborCodec.decode::<Ipld>(bytes)? {
+        header
+    } else {
+        return Err(invalid_data("car header isn't a map").into());
+    };
+    let version = match header.get("version") {
+        Some(Ipld::Integer(version)) => u64::try_from(*version).map_err(invalid_data)?,
+        _ => return Err(invalid_data("car header has no version").into()),
+    };
+    let roots = match header.get("roots") {
+        Some(Ipld::List(roots)) => roots
+            .iter()
+            .map(|root| match root {
+                Ipld::Link(cid) => Ok(*cid),
+                _ => Err(invalid_data("car root isn't a link")),
+            })
+            .collect::<io::Result<_>>()?,
+        None if version == 2 => vec![],
+        _ => return Err(invalid_data("car header has no roots").into()),
+    };
+    Ok((version, roots))
+}
+
+/// Reads the header of a CARv1 payload. Returns the roots.
+fn read_v1_header(reader: &mut impl Read) -> Result<Vec<Cid>, Error> {
+    let header = read_frame(reader, MAX_HEADER_SIZE)?
+        .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+    match decode_header(&header)? {
+        (1, roots) => Ok(roots),
+        (version, _) => Err(invalid_data(format!("unexpected car version {}", version)).into()),
+    }
+}
+
+/// Inserts the blocks of a CARv1 payload following its header.
+fn import_blocks<S: BitswapStore>(store: &mut S, reader: &mut impl Read) -> Result<u64, Error> {
+    let max = <S::Params as StoreParams>::MAX_BLOCK_SIZE as u64 + MAX_CID_SIZE;
+    let mut imported = 0;
+    while let Some(section) = read_frame(reader, max)? {
+        let mut data = &section[..];
+        let cid = Cid::read_bytes(&mut data).map_err(invalid_data)?;
+        // the cid of every block is checked, a car file is as untrusted as a peer
+        let block = Block::<S::Params>::new(cid, data.to_vec())?;
+        store.insert(&block)?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+/// Reads a CARv1 or CARv2 file and inserts its blocks into the store, verifying each
+/// block against its cid. Returns the roots listed in the header.
+///
+/// The file is streamed, so the reader doesn't need to support seeking. The index of a
+/// CARv2 file is skipped.
+pub fn import_car<S: BitswapStore>(store: &mut S, reader: impl Read) -> Result<Vec<Cid>, Error> {
+    let mut reader = io::BufReader::new(reader);
+    let header = read_frame(&mut reader, MAX_HEADER_SIZE)?
+        .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+    let roots = match decode_header(&header)? {
+        (1, roots) => {
+            let imported = import_blocks(store, &mut reader)?;
+            tracing::debug!("imported {} blocks from a CARv1 file", imported);
+            roots
+        }
+        (2, _) => {
+            let mut v2 = [0; V2_HEADER_SIZE as usize];
+            reader.read_exact(&mut v2)?;
+            let u64_at = |i: usize| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&v2[i..i + 8]);
+                u64::from_le_bytes(bytes)
+            };
+            let (data_offset, data_size) = (u64_at(16), u64_at(24));
+            let skip = data_offset
+                .checked_sub(frame_size(header.len()) + V2_HEADER_SIZE)
+                .ok_or_else(|| invalid_data("CARv2 payload overlaps its header"))?;
+            io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+            let mut payload = reader.take(data_size);
+            let roots = read_v1_header(&mut payload)?;
+            let imported = import_blocks(store, &mut payload)?;
+            tracing::debug!("imported {} blocks from a CARv2 file", imported);
+            roots
+        }
+        (version, _) => {
+            return Err(invalid_data(format!("unsupported car version {}", version)).into())
+        }
+    };
+    Ok(roots)
+}
+
+/// Writes the dag rooted at `root` as a CARv1 file with `root` as its only root. Blocks
+/// are written once each, in depth first order. Returns the number of blocks written.
+///
+/// Fails with `Error::BlockNotFound` if a block of the dag is missing from the store,
+/// e.g. because the sync that fetched it didn't complete.
+pub fn export_car<S: BitswapStore>(
+    store: &mut S,
+    root: Cid,
+    writer: impl Write,
+) -> Result<u64, Error>
+where
+    Ipld: References<<S::Params as StoreParams>::Codecs>,
+{
+    let mut writer = io::BufWriter::new(writer);
+    let mut header = BTreeMap::new();
+    header.insert("roots".to_string(), Ipld::List(vec![Ipld::Link(root)]));
+    header.insert("version".to_string(), Ipld::Integer(1));
+    let header = DagCborCodec.encode(&Ipld::Map(header))?;
+    write_varint(&mut writer, header.len() as u64)?;
+    writer.write_all(&header)?;
+
+    let mut exported = 0;
+    let mut seen = FnvHashSet::default();
+    let mut stack = vec![root];
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        let data = if is_inline(&cid) {
+            cid.hash().digest().to_vec()
+        } else {
+            store.get(&cid)?.ok_or(Error::BlockNotFound(cid))?
+        };
+        let block = Block::<S::Params>::new_unchecked(cid, data);
+        let mut links = vec![];
+        block.references(&mut links)?;
+        stack.extend(links.into_iter().rev());
+
+        let cid = cid.to_bytes();
+        write_varint(&mut writer, (cid.len() + block.data().len()) as u64)?;
+        writer.write_all(&cid)?;
+        writer.write_all(block.data())?;
+        exported += 1;
+    }
+    writer.flush()?;
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fnv::FnvHashMap;
+    use libipld::ipld;
+    use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
+    use libipld::Result;
+
+    #[derive(Default)]
+    struct Store(FnvHashMap<Cid, Vec<u8>>);
+
+    impl BitswapStore for Store {
+        type Params = DefaultParams;
+        fn contains(&mut self, cid: &Cid) -> Result<bool> {
+            Ok(self.0.contains_key(cid))
+        }
+        fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(cid).cloned())
+        }
+        fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+            self.0.insert(*block.cid(), block.data().to_vec());
+            Ok(())
+        }
+        fn missing_blocks(&mut self, _cid: &Cid) -> Result<Vec<Cid>> {
+            Ok(vec![])
+        }
+    }
+
+    fn create_block(ipld: Ipld) -> Block<DefaultParams> {
+        Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
+    }
+
+    /// Returns a store with a dag of three blocks, the leaf linked twice, and its root.
+    fn create_dag() -> (Store, Cid) {
+        let mut store = Store::default();
+        let leaf = create_block(ipld!(&b"leaf"[..]));
+        let node = create_block(ipld!({ "leaf": leaf.cid() }));
+        let root = create_block(ipld!({ "node": node.cid(), "leaf": leaf.cid() }));
+        for block in &[&leaf, &node, &root] {
+            store.insert(block).unwrap();
+        }
+        (store, *root.cid())
+    }
+
+    #[test]
+    fn test_car_roundtrip() {
+        let (mut store, root) = create_dag();
+        let mut car = vec![];
+        assert_eq!(export_car(&mut store, root, &mut car).unwrap(), 3);
+
+        let mut imported = Store::default();
+        assert_eq!(import_car(&mut imported, &car[..]).unwrap(), vec![root]);
+        assert_eq!(imported.0, store.0);
+
+        // corrupted blocks are rejected
+        let last = car.len() - 1;
+        car[last] ^= 1;
+        assert!(import_car(&mut Store::default(), &car[..]).is_err());
+
+        // so are incomplete dags
+        let mut store = Store::default();
+        match export_car(&mut store, root, vec![]) {
+            Err(Error::BlockNotFound(cid)) => assert_eq!(cid, root),
+            res => panic!("{:?} didn't fail with BlockNotFound", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_car_import_v2() {
+        let (mut store, root) = create_dag();
+        let mut payload = vec![];
+        export_car(&mut store, root, &mut payload).unwrap();
+
+        let pragma = DagCborCodec.encode(&ipld!({ "version": 2 })).unwrap();
+        let mut car = vec![];
+        write_varint(&mut car, pragma.len() as u64).unwrap();
+        car.extend_from_slice(&pragma);
+        // a padding of 8 bytes separates the header from the payload
+        let data_offset = car.len() as u64 + V2_HEADER_SIZE + 8;
+        car.extend_from_slice(&[0; 16]);
+        car.extend_from_slice(&data_offset.to_le_bytes());
+        car.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        car.extend_from_slice(&0u64.to_le_bytes());
+        car.extend_from_slice(&[0; 8]);
+        car.extend_from_slice(&payload);
+        // the index following the payload is skipped
+        car.extend_from_slice(&[0xff; 16]);
+
+        let mut imported = Store::default();
+        assert_eq!(import_car(&mut imported, &car[..]).unwrap(), vec![root]);
+        assert_eq!(imported.0, store.0);
+    }
+}
@@ -0,0 +1,41 @@
+use libipld::Cid;
+use libp2p::PeerId;
+use std::time::Duration;
+
+/// Fault applied to an outbound request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultAction {
+    /// Sends the request unchanged.
+    Deliver,
+    /// Sends the request after a delay.
+    Delay(Duration),
+    /// Never sends the request. It fails like a timed out request after the
+    /// configured request timeout.
+    DropRequest,
+    /// Sends the request and corrupts the data of the block it returns.
+    CorruptResponse,
+}
+
+/// Hook for injecting faults into the requests of a `Bitswap` behaviour. Only
+/// available with the `chaos` feature and meant for testing.
+pub trait FaultInjector: Send + 'static {
+    /// Decides the fault applied to a request for `cid` to `peer_id`.
+    fn on_outbound_request(&mut self, peer_id: &PeerId, cid: &Cid) -> FaultAction;
+}
+
+impl<F> FaultInjector for F
+where
+    F: FnMut(&PeerId, &Cid) -> FaultAction + Send + 'static,
+{
+    fn on_outbound_request(&mut self, peer_id: &PeerId, cid: &Cid) -> FaultAction {
+        self(peer_id, cid)
+    }
+}
+
+/// Corrupts the data of a block so that it no longer matches its cid.
+pub(crate) fn corrupt(data: &mut Vec<u8>) {
+    match data.last_mut() {
+        Some(byte) => *byte ^= 0xff,
+        None => data.push(0),
+    }
+}
@@ -0,0 +1,832 @@
+//! Hand-rolled codec of the bitswap protobuf schema for builds without prost. The types
+//! mirror the ones prost generates from `bitswap_pb.proto` and encode to the same bytes.
+//! Used by the `compat-lite` feature. `compat` implies it and replaces this codec with the
+//! prost one. The unit tests of the crate build both codecs to test them against each other.
+#![allow(missing_docs)]
+use crate::compat::other;
+use std::convert::Infallible;
+use std::io;
+use thiserror::Error;
+
+const VARINT: u8 = 0;
+const SIXTY_FOUR_BIT: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+const START_GROUP: u8 = 3;
+const END_GROUP: u8 = 4;
+const THIRTY_TWO_BIT: u8 = 5;
+
+/// Nesting depth of messages and groups at which decoding fails, same as prost.
+const RECURSION_LIMIT: u32 = 100;
+
+#[derive(Debug, Error)]
+#[error("failed to decode bitswap message: {0}")]
+struct DecodeError(&'static str);
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Message {
+    pub wantlist: Option<message::Wantlist>,
+    pub blocks: Vec<Vec<u8>>,
+    pub payload: Vec<message::Block>,
+    pub block_presences: Vec<message::BlockPresence>,
+    pub pending_bytes: i32,
+}
+
+pub mod message {
+    #[derive(Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Wantlist {
+        pub entries: Vec<wantlist::Entry>,
+        pub full: bool,
+    }
+
+    pub mod wantlist {
+        #[derive(Clone, Debug, Default, Eq, PartialEq)]
+        pub struct Entry {
+            pub block: Vec<u8>,
+            pub priority: i32,
+            pub cancel: bool,
+            pub want_type: i32,
+            pub send_dont_have: bool,
+        }
+
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        #[repr(i32)]
+        pub enum WantType {
+            Block = 0,
+            Have = 1,
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Block {
+        pub prefix: Vec<u8>,
+        pub data: Vec<u8>,
+    }
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    #[repr(i32)]
+    pub enum BlockPresenceType {
+        Have = 0,
+        DontHave = 1,
+    }
+
+    #[derive(Clone, Debug, Default, Eq, PartialEq)]
+    pub struct BlockPresence {
+        pub cid: Vec<u8>,
+        pub r#type: i32,
+    }
+}
+
+impl Message {
+    pub fn encoded_len(&self) -> usize {
+        self.body_len()
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Infallible> {
+        buf.reserve(self.body_len());
+        self.encode_body(buf);
+        Ok(())
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut msg = Self::default();
+        merge(&mut msg, Reader(bytes), RECURSION_LIMIT).map_err(other)?;
+        Ok(msg)
+    }
+}
+
+/// A protobuf message. Fields are encoded in tag order and skipped if they hold their
+/// default value, like prost does.
+trait Proto: Default {
+    fn body_len(&self) -> usize;
+
+    fn encode_body(&self, buf: &mut Vec<u8>);
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: u8,
+        reader: &mut Reader<'_>,
+        depth: u32,
+    ) -> Result<(), DecodeError>;
+}
+
+impl Proto for Message {
+    fn body_len(&self) -> usize {
+        self.wantlist.as_ref().map_or(0, |w| message_len(1, w))
+            + self.blocks.iter().map(|b| bytes_len(2, b)).sum::<usize>()
+            + self
+                .payload
+                .iter()
+                .map(|p| message_len(3, p))
+                .sum::<usize>()
+            + self
+                .block_presences
+                .iter()
+                .map(|p| message_len(4, p))
+                .sum::<usize>()
+            + int32_len(5, self.pending_bytes)
+    }
+
+    fn encode_body(&self, buf: &mut Vec<u8>) {
+        if let Some(wantlist) = &self.wantlist {
+            put_message(buf, 1, wantlist);
+        }
+        for block in &self.blocks {
+            put_bytes(buf, 2, block);
+        }
+        for payload in &self.payload {
+            put_message(buf, 3, payload);
+        }
+        for presence in &self.block_presences {
+            put_message(buf, 4, presence);
+        }
+        put_int32(buf, 5, self.pending_bytes);
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: u8,
+        reader: &mut Reader<'_>,
+        depth: u32,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => merge_message(
+                self.wantlist.get_or_insert_with(Default::default),
+                wire_type,
+                reader,
+                depth,
+            ),
+            2 => {
+                self.blocks.push(merge_bytes(wire_type, reader)?);
+                Ok(())
+            }
+            3 => merge_repeated(&mut self.payload, wire_type, reader, depth),
+            4 => merge_repeated(&mut self.block_presences, wire_type, reader, depth),
+            5 => {
+                self.pending_bytes = merge_int32(wire_type, reader)?;
+                Ok(())
+            }
+            _ => reader.skip(tag, wire_type, depth),
+        }
+    }
+}
+
+impl Proto for message::Wantlist {
+    fn body_len(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|e| message_len(1, e))
+            .sum::<usize>()
+            + bool_len(2, self.full)
+    }
+
+    fn encode_body(&self, buf: &mut Vec<u8>) {
+        for entry in &self.entries {
+            put_message(buf, 1, entry);
+        }
+        put_bool(buf, 2, self.full);
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: u8,
+        reader: &mut Reader<'_>,
+        depth: u32,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => merge_repeated(&mut self.entries, wire_type, reader, depth),
+            2 => {
+                self.full = merge_bool(wire_type, reader)?;
+                Ok(())
+            }
+            _ => reader.skip(tag, wire_type, depth),
+        }
+    }
+}
+
+impl Proto for message::wantlist::Entry {
+    fn body_len(&self) -> usize {
+        singular_bytes_len(1, &self.block)
+            + int32_len(2, self.priority)
+            + bool_len(3, self.cancel)
+            + int32_len(4, self.want_type)
+            + bool_len(5, self.send_dont_have)
+    }
+
+    fn encode_body(&self, buf: &mut Vec<u8>) {
+        put_singular_bytes(buf, 1, &self.block);
+        put_int32(buf, 2, self.priority);
+        put_bool(buf, 3, self.cancel);
+        put_int32(buf, 4, self.want_type);
+        put_bool(buf, 5, self.send_dont_have);
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: u8,
+        reader: &mut Reader<'_>,
+        depth: u32,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => self.block = merge_bytes(wire_type, reader)?,
+            2 => self.priority = merge_int32(wire_type, reader)?,
+            3 => self.cancel = merge_bool(wire_type, reader)?,
+            4 => self.want_type = merge_int32(wire_type, reader)?,
+            5 => self.send_dont_have = merge_bool(wire_type, reader)?,
+            _ => reader.skip(tag, wire_type, depth)?,
+        }
+        Ok(())
+    }
+}
+
+impl Proto for message::Block {
+    fn body_len(&self) -> usize {
+        singular_bytes_len(1, &self.prefix) + singular_bytes_len(2, &self.data)
+    }
+
+    fn encode_body(&self, buf: &mut Vec<u8>) {
+        put_singular_bytes(buf, 1, &self.prefix);
+        put_singular_bytes(buf, 2, &self.data);
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: u8,
+        reader: &mut Reader<'_>,
+        depth: u32,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => self.prefix = merge_bytes(wire_type, reader)?,
+            2 => self.data = merge_bytes(wire_type, reader)?,
+            _ => reader.skip(tag, wire_type, depth)?,
+        }
+        Ok(())
+    }
+}
+
+impl Proto for message::BlockPresence {
+    fn body_len(&self) -> usize {
+        singular_bytes_len(1, &self.cid) + int32_len(2, self.r#type)
+    }
+
+    fn encode_body(&self, buf: &mut Vec<u8>) {
+        put_singular_bytes(buf, 1, &self.cid);
+        put_int32(buf, 2, self.r#type);
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: u8,
+        reader: &mut Reader<'_>,
+        depth: u32,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => self.cid = merge_bytes(wire_type, reader)?,
+            2 => self.r#type = merge_int32(wire_type, reader)?,
+            _ => reader.skip(tag, wire_type, depth)?,
+        }
+        Ok(())
+    }
+}
+
+fn varint_len(value: u64) -> usize {
+    ((((value | 1).leading_zeros() ^ 63) * 9 + 73) / 64) as usize
+}
+
+fn key_len(tag: u32) -> usize {
+    varint_len(u64::from(tag << 3))
+}
+
+fn bytes_len(tag: u32, value: &[u8]) -> usize {
+    key_len(tag) + varint_len(value.len() as u64) + value.len()
+}
+
+fn singular_bytes_len(tag: u32, value: &[u8]) -> usize {
+    if value.is_empty() {
+        0
+    } else {
+        bytes_len(tag, value)
+    }
+}
+
+fn int32_len(tag: u32, value: i32) -> usize {
+    if value == 0 {
+        0
+    } else {
+        key_len(tag) + varint_len(value as u64)
+    }
+}
+
+fn bool_len(tag: u32, value: bool) -> usize {
+    if value {
+        key_len(tag) + 1
+    } else {
+        0
+    }
+}
+
+fn message_len<M: Proto>(tag: u32, msg: &M) -> usize {
+    let len = msg.body_len();
+    key_len(tag) + varint_len(len as u64) + len
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, tag: u32, wire_type: u8) {
+    put_varint(buf, u64::from(tag << 3 | u32::from(wire_type)));
+}
+
+fn put_bytes(buf: &mut Vec<u8>, tag: u32, value: &[u8]) {
+    put_key(buf, tag, LENGTH_DELIMITED);
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+fn put_singular_bytes(buf: &mut Vec<u8>, tag: u32, value: &[u8]) {
+    if !value.is_empty() {
+        put_bytes(buf, tag, value);
+    }
+}
+
+fn put_int32(buf: &mut Vec<u8>, tag: u32, value: i32) {
+    if value != 0 {
+        put_key(buf, tag, VARINT);
+        // negative values are sign extended to ten bytes
+        put_varint(buf, value as u64);
+    }
+}
+
+fn put_bool(buf: &mut Vec<u8>, tag: u32, value: bool) {
+    if value {
+        put_key(buf, tag, VARINT);
+        buf.push(1);
+    }
+}
+
+fn put_message<M: Proto>(buf: &mut Vec<u8>, tag: u32, msg: &M) {
+    put_key(buf, tag, LENGTH_DELIMITED);
+    put_varint(buf, msg.body_len() as u64);
+    msg.encode_body(buf);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for i in 0..10 {
+            let byte = *self.0.get(i).ok_or(DecodeError("invalid varint"))?;
+            if i == 9 && byte > 1 {
+                break;
+            }
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte < 0x80 {
+                self.0 = &self.0[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(DecodeError("invalid varint"))
+    }
+
+    fn key(&mut self) -> Result<(u32, u8), DecodeError> {
+        let key = self.varint()?;
+        if key > u64::from(u32::MAX) {
+            return Err(DecodeError("invalid key value"));
+        }
+        let wire_type = (key & 0x07) as u8;
+        if wire_type > THIRTY_TWO_BIT {
+            return Err(DecodeError("invalid wire type"));
+        }
+        let tag = key as u32 >> 3;
+        if tag == 0 {
+            return Err(DecodeError("invalid tag value: 0"));
+        }
+        Ok((tag, wire_type))
+    }
+
+    fn advance(&mut self, len: u64) -> Result<&'a [u8], DecodeError> {
+        if len > self.0.len() as u64 {
+            return Err(DecodeError("buffer underflow"));
+        }
+        let (bytes, rest) = self.0.split_at(len as usize);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.varint()?;
+        self.advance(len)
+    }
+
+    /// Skips an unknown field.
+    fn skip(&mut self, tag: u32, wire_type: u8, depth: u32) -> Result<(), DecodeError> {
+        if depth == 0 {
+            return Err(DecodeError("recursion limit reached"));
+        }
+        match wire_type {
+            VARINT => {
+                self.varint()?;
+            }
+            SIXTY_FOUR_BIT => {
+                self.advance(8)?;
+            }
+            LENGTH_DELIMITED => {
+                self.bytes()?;
+            }
+            START_GROUP => loop {
+                let (inner_tag, inner_wire_type) = self.key()?;
+                if inner_wire_type == END_GROUP {
+                    if inner_tag != tag {
+                        return Err(DecodeError("unexpected end group tag"));
+                    }
+                    break;
+                }
+                self.skip(inner_tag, inner_wire_type, depth - 1)?;
+            },
+            THIRTY_TWO_BIT => {
+                self.advance(4)?;
+            }
+            _ => return Err(DecodeError("unexpected end group tag")),
+        }
+        Ok(())
+    }
+}
+
+fn check_wire_type(wire_type: u8, expected: u8) -> Result<(), DecodeError> {
+    if wire_type != expected {
+        return Err(DecodeError("invalid wire type"));
+    }
+    Ok(())
+}
+
+fn merge<M: Proto>(msg: &mut M, mut reader: Reader<'_>, depth: u32) -> Result<(), DecodeError> {
+    while !reader.0.is_empty() {
+        let (tag, wire_type) = reader.key()?;
+        msg.merge_field(tag, wire_type, &mut reader, depth)?;
+    }
+    Ok(())
+}
+
+fn merge_message<M: Proto>(
+    msg: &mut M,
+    wire_type: u8,
+    reader: &mut Reader<'_>,
+    depth: u32,
+) -> Result<(), DecodeError> {
+    check_wire_type(wire_type, LENGTH_DELIMITED)?;
+    if depth == 0 {
+        return Err(DecodeError("recursion limit reached"));
+    }
+    let bytes = reader.bytes()?;
+    merge(msg, Reader(bytes), depth - 1)
+}
+
+fn merge_repeated<M: Proto>(
+    msgs: &mut Vec<M>,
+    wire_type: u8,
+    reader: &mut Reader<'_>,
+    depth: u32,
+) -> Result<(), DecodeError> {
+    let mut msg = M::default();
+    merge_message(&mut msg, wire_type, reader, depth)?;
+    msgs.push(msg);
+    Ok(())
+}
+
+fn merge_bytes(wire_type: u8, reader: &mut Reader<'_>) -> Result<Vec<u8>, DecodeError> {
+    check_wire_type(wire_type, LENGTH_DELIMITED)?;
+    Ok(reader.bytes()?.to_vec())
+}
+
+fn merge_int32(wire_type: u8, reader: &mut Reader<'_>) -> Result<i32, DecodeError> {
+    check_wire_type(wire_type, VARINT)?;
+    Ok(reader.varint()? as i32)
+}
+
+fn merge_bool(wire_type: u8, reader: &mut Reader<'_>) -> Result<bool, DecodeError> {
+    check_wire_type(wire_type, VARINT)?;
+    Ok(reader.varint()? != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::message::wantlist::Entry;
+    use super::message::{Block, BlockPresence, Wantlist};
+    use super::*;
+
+    fn entry(block: &[u8], priority: i32, want_type: i32) -> Entry {
+        Entry {
+            block: block.to_vec(),
+            priority,
+            cancel: false,
+            want_type,
+            send_dont_have: true,
+        }
+    }
+
+    /// Messages covering default and non default values of every field, multi byte
+    /// lengths and negative integers.
+    fn corpus() -> Vec<Message> {
+        let cids: [&[u8]; 3] = [&[], &[0x12, 0x20, 0xab], &[0x01, 0x70, 0x12, 0x20, 0xcd]];
+        let ints = [0, 1, -1, 300, i32::MAX, i32::MIN];
+        let data = [0, 1, 127, 128, 16_384];
+        let mut corpus = vec![Message::default()];
+        for cid in cids {
+            for priority in ints {
+                for want_type in [0, 1, 7, -3] {
+                    for (cancel, send_dont_have) in [(false, false), (true, false), (false, true)] {
+                        let entry = Entry {
+                            cancel,
+                            send_dont_have,
+                            ..entry(cid, priority, want_type)
+                        };
+                        corpus.push(Message {
+                            wantlist: Some(Wantlist {
+                                entries: vec![entry],
+                                full: cancel,
+                            }),
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+            for ty in ints {
+                corpus.push(Message {
+                    block_presences: vec![BlockPresence {
+                        cid: cid.to_vec(),
+                        r#type: ty,
+                    }],
+                    ..Default::default()
+                });
+            }
+            for len in data {
+                corpus.push(Message {
+                    payload: vec![Block {
+                        prefix: cid.to_vec(),
+                        data: vec![0x55; len],
+                    }],
+                    blocks: vec![vec![0xaa; len]],
+                    ..Default::default()
+                });
+            }
+        }
+        for pending_bytes in ints {
+            corpus.push(Message {
+                pending_bytes,
+                ..Default::default()
+            });
+        }
+        corpus.push(Message {
+            wantlist: Some(Wantlist {
+                entries: (0..200).map(|i| entry(&[i as u8; 34], i, i % 2)).collect(),
+                full: true,
+            }),
+            blocks: vec![vec![], vec![1, 2, 3]],
+            payload: vec![Block::default(), Block::default()],
+            block_presences: vec![BlockPresence::default(); 3],
+            pending_bytes: 1 << 20,
+        });
+        corpus
+    }
+
+    fn encode(msg: &Message) -> Vec<u8> {
+        let mut bytes = vec![];
+        msg.encode(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), msg.encoded_len());
+        bytes
+    }
+
+    /// Malformed or unusual encodings.
+    fn malformed() -> Vec<Vec<u8>> {
+        let mut corpus: Vec<Vec<u8>> = vec![
+            // truncated and overlong varints
+            vec![0x28],
+            vec![0x28, 0x80],
+            vec![
+                0x28, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            ],
+            vec![
+                0x28, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02,
+            ],
+            vec![
+                0x28, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            ],
+            // tag 0, invalid wire types and keys above u32
+            vec![0x00, 0x00],
+            vec![0x0e],
+            vec![0x0f],
+            vec![0xf8, 0xff, 0xff, 0xff, 0x1f, 0x00],
+            vec![0xf8, 0xff, 0xff, 0xff, 0x0f, 0x00],
+            // known fields with the wrong wire type
+            vec![0x08, 0x01],
+            vec![0x2a, 0x00],
+            vec![0x0a, 0x02, 0x0d, 0x00],
+            // unknown fields of every wire type
+            vec![0x30, 0x96, 0x01],
+            vec![0x31, 1, 2, 3, 4, 5, 6, 7, 8],
+            vec![0x31, 1, 2, 3],
+            vec![0x32, 0x02, 0xaa, 0xbb],
+            vec![0x32, 0x05, 0xaa],
+            vec![0x35, 1, 2, 3, 4],
+            vec![0x33, 0x08, 0x01, 0x34],
+            vec![0x33, 0x08, 0x01, 0x3c],
+            vec![0x33, 0x08, 0x01],
+            vec![0x34],
+            // repeated singular fields and merged wantlists
+            vec![0x28, 0x01, 0x28, 0x02],
+            vec![0x0a, 0x02, 0x10, 0x01, 0x0a, 0x04, 0x0a, 0x02, 0x08, 0x05],
+            // nested lengths exceeding their parent
+            vec![0x22, 0x02, 0x0a, 0x05, 0x01, 0x02, 0x03],
+            vec![0x22, 0x0a, 0x0a, 0x01],
+        ];
+        // nested groups around the recursion limit
+        for depth in [98, 99, 100, 101] {
+            let mut group = vec![0x33; depth];
+            group.resize(2 * depth, 0x34);
+            corpus.push(group);
+        }
+        for msg in corpus.clone() {
+            // every truncation and single byte flip
+            for i in 0..msg.len() {
+                corpus.push(msg[..i].to_vec());
+                let mut flipped = msg.clone();
+                flipped[i] ^= 0x80;
+                corpus.push(flipped);
+            }
+        }
+        corpus
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for msg in corpus() {
+            assert_eq!(Message::decode(&encode(&msg)).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let msg = Message {
+            wantlist: Some(Wantlist {
+                entries: vec![entry(&[0x12], 1, 1)],
+                full: false,
+            }),
+            pending_bytes: -1,
+            ..Default::default()
+        };
+        let mut expected = vec![
+            0x0a, 0x0b, 0x0a, 0x09, 0x0a, 0x01, 0x12, 0x10, 0x01, 0x20, 0x01, 0x28, 0x01, 0x28,
+        ];
+        expected.extend([0xff; 9]);
+        expected.push(0x01);
+        assert_eq!(encode(&msg), expected);
+    }
+
+    #[cfg(feature = "compat")]
+    mod prost_compat {
+        use super::*;
+        use crate::compat::message::bitswap_pb as pb;
+        use crate::compat::CompatMessage;
+        use crate::protocol::{BitswapRequest, BitswapResponse, RequestType};
+        use libipld::multihash::{Code, MultihashDigest};
+        use libipld::Cid;
+        use prost::Message as _;
+
+        fn from_pb(msg: pb::Message) -> Message {
+            Message {
+                wantlist: msg.wantlist.map(|wantlist| Wantlist {
+                    entries: wantlist
+                        .entries
+                        .into_iter()
+                        .map(|e| Entry {
+                            block: e.block,
+                            priority: e.priority,
+                            cancel: e.cancel,
+                            want_type: e.want_type,
+                            send_dont_have: e.send_dont_have,
+                        })
+                        .collect(),
+                    full: wantlist.full,
+                }),
+                blocks: msg.blocks,
+                payload: msg
+                    .payload
+                    .into_iter()
+                    .map(|b| Block {
+                        prefix: b.prefix,
+                        data: b.data,
+                    })
+                    .collect(),
+                block_presences: msg
+                    .block_presences
+                    .into_iter()
+                    .map(|p| BlockPresence {
+                        cid: p.cid,
+                        r#type: p.r#type,
+                    })
+                    .collect(),
+                pending_bytes: msg.pending_bytes,
+            }
+        }
+
+        fn to_pb(msg: &Message) -> pb::Message {
+            pb::Message {
+                wantlist: msg.wantlist.as_ref().map(|wantlist| pb::message::Wantlist {
+                    entries: wantlist
+                        .entries
+                        .iter()
+                        .map(|e| pb::message::wantlist::Entry {
+                            block: e.block.clone(),
+                            priority: e.priority,
+                            cancel: e.cancel,
+                            want_type: e.want_type,
+                            send_dont_have: e.send_dont_have,
+                        })
+                        .collect(),
+                    full: wantlist.full,
+                }),
+                blocks: msg.blocks.clone(),
+                payload: msg
+                    .payload
+                    .iter()
+                    .map(|b| pb::message::Block {
+                        prefix: b.prefix.clone(),
+                        data: b.data.clone(),
+                    })
+                    .collect(),
+                block_presences: msg
+                    .block_presences
+                    .iter()
+                    .map(|p| pb::message::BlockPresence {
+                        cid: p.cid.clone(),
+                        r#type: p.r#type,
+                    })
+                    .collect(),
+                pending_bytes: msg.pending_bytes,
+            }
+        }
+
+        fn assert_same_decoding(bytes: &[u8]) {
+            let lite = Message::decode(bytes).ok();
+            let prost = pb::Message::decode(bytes).ok().map(from_pb);
+            assert_eq!(lite, prost, "decoding {:x?}", bytes);
+        }
+
+        #[test]
+        fn test_encoding_matches_prost() {
+            for msg in corpus() {
+                let pb = to_pb(&msg);
+                assert_eq!(msg.encoded_len(), pb.encoded_len());
+                let bytes = encode(&msg);
+                assert_eq!(bytes, pb.encode_to_vec());
+                assert_same_decoding(&bytes);
+            }
+        }
+
+        #[test]
+        fn test_decoding_matches_prost() {
+            for bytes in malformed() {
+                assert_same_decoding(&bytes);
+            }
+            for msg in corpus() {
+                let bytes = encode(&msg);
+                for i in 0..bytes.len().min(64) {
+                    assert_same_decoding(&bytes[..i]);
+                }
+            }
+        }
+
+        #[test]
+        fn test_compat_messages_match_prost() {
+            let v0 = Cid::new_v0(Code::Sha2_256.digest(b"hello world")).unwrap();
+            let v1 = Cid::new_v1(0x71, Code::Sha2_256.digest(b"hello world"));
+            for cid in [v0, v1] {
+                let msgs = [
+                    CompatMessage::Request(BitswapRequest::new(RequestType::Have, cid)),
+                    CompatMessage::Request(BitswapRequest::new(RequestType::Block, cid)),
+                    CompatMessage::Cancel(cid),
+                    CompatMessage::Response(cid, BitswapResponse::Have(true)),
+                    CompatMessage::Response(cid, BitswapResponse::Have(false)),
+                    CompatMessage::Response(cid, BitswapResponse::Block(b"hello world".to_vec())),
+                ];
+                for msg in msgs {
+                    let bytes = msg.to_bytes().unwrap();
+                    let lite = Message::decode(&bytes).unwrap();
+                    assert_eq!(encode(&lite), bytes);
+                    assert_same_decoding(&bytes);
+                }
+            }
+        }
+    }
+}
@@ -1,26 +1,161 @@
+#[cfg(not(feature = "compat"))]
+pub use crate::compat::lite as bitswap_pb;
 use crate::compat::other;
 use crate::compat::prefix::Prefix;
+use crate::compat::CompatVersion;
 use crate::protocol::{BitswapRequest, BitswapResponse, RequestType};
+use libipld::multihash::{Code, MultihashDigest};
 use libipld::Cid;
+#[cfg(feature = "compat")]
 use prost::Message;
 use std::convert::TryFrom;
 use std::io;
 
-mod bitswap_pb {
+/// Types generated from the bitswap 1.2.0 protobuf schema.
+#[cfg(feature = "compat")]
+pub mod bitswap_pb {
+    #![allow(missing_docs)]
     include!(concat!(env!("OUT_DIR"), "/bitswap_pb.rs"));
 }
 
+/// Most bytes the protobuf message wrapping a block adds to it: field tags, length
+/// varints and the cid prefix.
+pub const BLOCK_OVERHEAD: usize = 64;
+
+/// Part of a bitswap 1.2.0 message, which bundles any number of requests and responses.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum CompatMessage {
+    /// A wantlist entry.
     Request(BitswapRequest),
+    /// A wantlist entry cancelling an earlier want of the cid.
+    Cancel(Cid),
+    /// A block presence or a block.
     Response(Cid, BitswapResponse),
 }
 
 impl CompatMessage {
+    /// Encodes the message like `into_pb`.
     pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        Self::bundle_to_bytes(std::iter::once(self.clone()))
+    }
+
+    /// Encodes messages like `bundle_into_pb`.
+    pub fn bundle_to_bytes(msgs: impl IntoIterator<Item = Self>) -> io::Result<Vec<u8>> {
+        Self::bundle_to_bytes_version(msgs, CompatVersion::V1_2_0)
+    }
+
+    /// Encodes messages like `bundle_into_pb`, downgraded to `version` like
+    /// `downgrade_pb`.
+    pub fn bundle_to_bytes_version(
+        msgs: impl IntoIterator<Item = Self>,
+        version: CompatVersion,
+    ) -> io::Result<Vec<u8>> {
+        let mut msg = Self::bundle_into_pb(msgs);
+        Self::downgrade_pb(&mut msg, version);
+        Self::pb_to_bytes(&msg)
+    }
+
+    /// Encodes a protobuf message.
+    pub fn pb_to_bytes(msg: &bitswap_pb::Message) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(msg.encoded_len());
+        msg.encode(&mut bytes).map_err(other)?;
+        Ok(bytes)
+    }
+
+    /// Decodes a protobuf message.
+    pub fn pb_from_bytes(bytes: &[u8]) -> io::Result<bitswap_pb::Message> {
+        let msg = bitswap_pb::Message::decode(bytes)?;
+        Ok(msg)
+    }
+
+    /// Rewrites a bitswap 1.2.0 message for an older version. Want haves become wants
+    /// of the block, block presences are dropped and bitswap 1.0.0 gets the blocks
+    /// without their cid prefix.
+    pub fn downgrade_pb(msg: &mut bitswap_pb::Message, version: CompatVersion) {
+        if version.has_presences() {
+            return;
+        }
+        for entry in msg
+            .wantlist
+            .iter_mut()
+            .flat_map(|wantlist| &mut wantlist.entries)
+        {
+            entry.want_type = bitswap_pb::message::wantlist::WantType::Block as _;
+            entry.send_dont_have = false;
+        }
+        msg.block_presences.clear();
+        if version == CompatVersion::V1_0_0 {
+            let payload = std::mem::take(&mut msg.payload);
+            msg.blocks
+                .extend(payload.into_iter().map(|block| block.data));
+        }
+    }
+
+    /// Merges messages into a single protobuf message, e.g. the responses to all entries
+    /// of a wantlist.
+    pub fn bundle_into_pb(msgs: impl IntoIterator<Item = Self>) -> bitswap_pb::Message {
+        let mut bundle = bitswap_pb::Message::default();
+        for msg in msgs {
+            let msg = msg.into_pb();
+            if let Some(wantlist) = msg.wantlist {
+                bundle
+                    .wantlist
+                    .get_or_insert_with(Default::default)
+                    .entries
+                    .extend(wantlist.entries);
+            }
+            bundle.payload.extend(msg.payload);
+            bundle.block_presences.extend(msg.block_presences);
+        }
+        bundle
+    }
+
+    /// Returns an upper bound of the bytes the message adds to a bundle.
+    fn bundled_len(&self) -> usize {
+        match self {
+            CompatMessage::Response(_, BitswapResponse::Block(data)) => data.len() + BLOCK_OVERHEAD,
+            msg => msg.clone().into_pb().encoded_len(),
+        }
+    }
+
+    /// Splits messages into as few bundles of at most `max_size` encoded bytes as
+    /// possible, keeping their order. A message that exceeds the size on its own gets a
+    /// bundle of its own.
+    pub fn bundle(msgs: impl IntoIterator<Item = Self>, max_size: usize) -> Vec<Vec<Self>> {
+        let mut bundles = vec![];
+        let mut bundle = vec![];
+        let mut size = 0;
+        for msg in msgs {
+            let len = msg.bundled_len();
+            if !bundle.is_empty() && size + len > max_size {
+                bundles.push(std::mem::take(&mut bundle));
+                size = 0;
+            }
+            size += len;
+            bundle.push(msg);
+        }
+        if !bundle.is_empty() {
+            bundles.push(bundle);
+        }
+        bundles
+    }
+
+    /// Decodes a message like `try_from_pb`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Vec<Self>> {
+        Self::from_bytes_version(bytes, CompatVersion::V1_2_0)
+    }
+
+    /// Decodes a message like `try_from_pb_version`.
+    pub fn from_bytes_version(bytes: &[u8], version: CompatVersion) -> io::Result<Vec<Self>> {
+        Self::try_from_pb_version(Self::pb_from_bytes(bytes)?, version)
+    }
+
+    /// Converts the message into a protobuf message holding a single entry. Requests
+    /// always ask for a dont have.
+    pub fn into_pb(self) -> bitswap_pb::Message {
         let mut msg = bitswap_pb::Message::default();
         match self {
-            CompatMessage::Request(BitswapRequest { ty, cid }) => {
+            CompatMessage::Request(BitswapRequest { ty, cid, priority }) => {
                 let mut wantlist = bitswap_pb::message::Wantlist::default();
                 let entry = bitswap_pb::message::wantlist::Entry {
                     block: cid.to_bytes(),
@@ -30,7 +165,19 @@ impl CompatMessage {
                     } as _,
                     send_dont_have: true,
                     cancel: false,
-                    priority: 1,
+                    priority,
+                };
+                wantlist.entries.push(entry);
+                msg.wantlist = Some(wantlist);
+            }
+            CompatMessage::Cancel(cid) => {
+                let mut wantlist = bitswap_pb::message::Wantlist::default();
+                let entry = bitswap_pb::message::wantlist::Entry {
+                    block: cid.to_bytes(),
+                    want_type: bitswap_pb::message::wantlist::WantType::Block as _,
+                    send_dont_have: false,
+                    cancel: true,
+                    priority: 0,
                 };
                 wantlist.entries.push(entry);
                 msg.wantlist = Some(wantlist);
@@ -38,7 +185,7 @@ impl CompatMessage {
             CompatMessage::Response(cid, BitswapResponse::Have(have)) => {
                 let block_presence = bitswap_pb::message::BlockPresence {
                     cid: cid.to_bytes(),
-                    r#type: if *have {
+                    r#type: if have {
                         bitswap_pb::message::BlockPresenceType::Have
                     } else {
                         bitswap_pb::message::BlockPresenceType::DontHave
@@ -48,26 +195,40 @@ impl CompatMessage {
             }
             CompatMessage::Response(cid, BitswapResponse::Block(bytes)) => {
                 let payload = bitswap_pb::message::Block {
-                    prefix: Prefix::from(cid).to_bytes(),
-                    data: bytes.to_vec(),
+                    prefix: Prefix::from(&cid).to_bytes(),
+                    data: bytes,
                 };
                 msg.payload.push(payload);
             }
         }
-        let mut bytes = Vec::with_capacity(msg.encoded_len());
-        msg.encode(&mut bytes).map_err(other)?;
-        Ok(bytes)
+        msg
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Vec<Self>> {
-        let msg = bitswap_pb::Message::decode(bytes)?;
+    /// Splits a bitswap 1.2.0 protobuf message into its requests, cancels and responses.
+    /// Wants that don't ask for a dont have and entries of unknown types are skipped,
+    /// invalid cids and block prefixes fail the whole message.
+    pub fn try_from_pb(msg: bitswap_pb::Message) -> io::Result<Vec<Self>> {
+        Self::try_from_pb_version(msg, CompatVersion::V1_2_0)
+    }
+
+    /// Splits a protobuf message received with `version` like `try_from_pb`. Versions
+    /// older than 1.2.0 can't ask for a dont have, so their wants aren't skipped.
+    /// Blocks without a cid prefix get the v0 cid of their data.
+    pub fn try_from_pb_version(
+        msg: bitswap_pb::Message,
+        version: CompatVersion,
+    ) -> io::Result<Vec<Self>> {
         let mut parts = vec![];
         for entry in msg.wantlist.unwrap_or_default().entries {
-            if !entry.send_dont_have {
+            let cid = Cid::try_from(entry.block).map_err(other)?;
+            if entry.cancel {
+                parts.push(CompatMessage::Cancel(cid));
+                continue;
+            }
+            if !entry.send_dont_have && version.has_presences() {
                 tracing::error!("message hasn't set `send_dont_have`: skipping");
                 continue;
             }
-            let cid = Cid::try_from(entry.block).map_err(other)?;
             let ty = match entry.want_type {
                 ty if bitswap_pb::message::wantlist::WantType::Have as i32 == ty => {
                     RequestType::Have
@@ -80,14 +241,19 @@ impl CompatMessage {
                     continue;
                 }
             };
-            parts.push(CompatMessage::Request(BitswapRequest { ty, cid }));
+            let request = BitswapRequest::new(ty, cid).with_priority(entry.priority);
+            parts.push(CompatMessage::Request(request));
+        }
+        for data in msg.blocks {
+            let cid = Cid::new_v0(Code::Sha2_256.digest(&data)).map_err(other)?;
+            parts.push(CompatMessage::Response(cid, BitswapResponse::Block(data)));
         }
         for payload in msg.payload {
             let prefix = Prefix::new(&payload.prefix)?;
             let cid = prefix.to_cid(&payload.data)?;
             parts.push(CompatMessage::Response(
                 cid,
-                BitswapResponse::Block(payload.data.to_vec()),
+                BitswapResponse::Block(payload.data),
             ));
         }
         for presence in msg.block_presences {
@@ -105,3 +271,121 @@ impl CompatMessage {
         Ok(parts)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::tests::create_cid;
+
+    #[test]
+    fn test_pb_round_trip() {
+        let cid = create_cid(b"compat");
+        let messages = vec![
+            CompatMessage::Request(BitswapRequest::new(RequestType::Have, cid)),
+            CompatMessage::Request(BitswapRequest::new(RequestType::Block, cid)),
+            CompatMessage::Request(BitswapRequest::new(RequestType::Block, cid).with_priority(9)),
+            CompatMessage::Cancel(cid),
+            CompatMessage::Response(cid, BitswapResponse::Have(true)),
+            CompatMessage::Response(cid, BitswapResponse::Have(false)),
+            CompatMessage::Response(cid, BitswapResponse::Block(b"compat".to_vec())),
+        ];
+        for message in messages {
+            let msg = message.clone().into_pb();
+            let bytes = message.to_bytes().unwrap();
+            assert_eq!(bitswap_pb::Message::decode(&bytes[..]).unwrap(), msg);
+            assert_eq!(
+                CompatMessage::try_from_pb(msg).unwrap(),
+                vec![message.clone()]
+            );
+            assert_eq!(CompatMessage::from_bytes(&bytes).unwrap(), vec![message]);
+        }
+    }
+
+    #[test]
+    fn test_try_from_pb_bundle() {
+        let cid = create_cid(b"compat");
+        let request = CompatMessage::Request(BitswapRequest::new(RequestType::Block, cid));
+        let mut msg = request.clone().into_pb();
+        let mut skipped = msg.wantlist.clone().unwrap().entries[0].clone();
+        skipped.send_dont_have = false;
+        msg.wantlist.as_mut().unwrap().entries.push(skipped);
+        msg.block_presences = CompatMessage::Response(cid, BitswapResponse::Have(true))
+            .into_pb()
+            .block_presences;
+        assert_eq!(
+            CompatMessage::try_from_pb(msg.clone()).unwrap(),
+            vec![
+                request,
+                CompatMessage::Response(cid, BitswapResponse::Have(true))
+            ]
+        );
+
+        msg.block_presences[0].cid = vec![0xff];
+        assert!(CompatMessage::try_from_pb(msg).is_err());
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let data = b"compat".to_vec();
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+        let msgs = vec![
+            CompatMessage::Request(BitswapRequest::new(RequestType::Have, cid)),
+            CompatMessage::Response(cid, BitswapResponse::Have(true)),
+            CompatMessage::Response(cid, BitswapResponse::Block(data.clone())),
+        ];
+        let block = CompatMessage::Request(BitswapRequest::new(RequestType::Block, cid));
+
+        // haves become wants of the block and presences are dropped
+        let version = CompatVersion::V1_1_0;
+        let bytes = CompatMessage::bundle_to_bytes_version(msgs.clone(), version).unwrap();
+        assert_eq!(
+            CompatMessage::from_bytes_version(&bytes, version).unwrap(),
+            vec![block.clone(), msgs[2].clone()]
+        );
+        // 1.2.0 skips wants that don't ask for a dont have
+        assert_eq!(
+            CompatMessage::from_bytes(&bytes).unwrap(),
+            vec![msgs[2].clone()]
+        );
+
+        // blocks lose their cid prefix
+        let version = CompatVersion::V1_0_0;
+        let bytes = CompatMessage::bundle_to_bytes_version(msgs, version).unwrap();
+        let v0 = Cid::new_v0(*cid.hash()).unwrap();
+        assert_eq!(
+            CompatMessage::from_bytes_version(&bytes, version).unwrap(),
+            vec![
+                block,
+                CompatMessage::Response(v0, BitswapResponse::Block(data))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bundle() {
+        let cids: Vec<_> = (0..4u8).map(|i| create_cid(&[i])).collect();
+        let msgs = vec![
+            CompatMessage::Response(cids[0], BitswapResponse::Have(true)),
+            CompatMessage::Response(cids[1], BitswapResponse::Block(vec![1; 100])),
+            CompatMessage::Response(cids[2], BitswapResponse::Have(false)),
+            CompatMessage::Response(cids[3], BitswapResponse::Block(vec![3; 100])),
+        ];
+        let bytes = CompatMessage::bundle_to_bytes(msgs.clone()).unwrap();
+        assert_eq!(CompatMessage::from_bytes(&bytes).unwrap().len(), msgs.len());
+        let bundles = CompatMessage::bundle(msgs.clone(), 1024);
+        assert_eq!(bundles, vec![msgs.clone()]);
+
+        // the second block doesn't fit next to the first
+        let max_size = msgs[..3].iter().map(CompatMessage::bundled_len).sum();
+        let bundles = CompatMessage::bundle(msgs.clone(), max_size);
+        assert_eq!(bundles, vec![msgs[..3].to_vec(), msgs[3..].to_vec()]);
+        for bundle in bundles {
+            let bytes = CompatMessage::bundle_to_bytes(bundle).unwrap();
+            assert!(bytes.len() <= max_size);
+        }
+
+        // oversized messages are bundled alone
+        let bundles = CompatMessage::bundle(msgs.clone(), 10);
+        assert_eq!(bundles.len(), msgs.len());
+    }
+}
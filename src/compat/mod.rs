@@ -1,9 +1,13 @@
+#[cfg(all(feature = "compat-lite", any(not(feature = "compat"), test)))]
+pub mod lite;
 mod message;
 mod prefix;
 mod protocol;
 
-pub use message::CompatMessage;
-pub use protocol::{CompatProtocol, InboundMessage};
+pub use message::{bitswap_pb, CompatMessage};
+pub use protocol::{
+    CompatProtocol, CompatVersion, InboundMessage, OutboundMessage, MAX_BLOCK_SIZE,
+};
 
 fn other<E: std::error::Error + Send + Sync + 'static>(e: E) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, e)
@@ -1,22 +1,99 @@
 
-use crate::compat::{other, CompatMessage};
+use crate::compat::message::BLOCK_OVERHEAD;
+use crate::compat::{bitswap_pb, other, CompatMessage};
+use crate::protocol::BitswapResponse;
 use futures::future::BoxFuture;
 use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use libp2p::core::{upgrade, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
-use std::{io, iter};
+use std::convert::TryFrom;
+use std::io;
 
 // 2MB Block Size according to the specs at https://github.com/ipfs/specs/blob/main/BITSWAP.md
 const MAX_BUF_SIZE: usize = 2_097_152;
 
-#[derive(Clone, Debug, Default)]
-pub struct CompatProtocol;
+/// Largest block that fits into a compat message.
+pub const MAX_BLOCK_SIZE: usize = MAX_BUF_SIZE - BLOCK_OVERHEAD;
+
+/// Version of the go-ipfs bitswap protocol a message is exchanged with. Newer versions
+/// are preferred when negotiating a substream.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum CompatVersion {
+    /// `/ipfs/bitswap/1.0.0`. Blocks are sent without their cid prefix, so their cids
+    /// are v0 cids.
+    V1_0_0,
+    /// `/ipfs/bitswap/1.1.0`. Blocks are sent with their cid prefix, but wantlists
+    /// only ask for blocks and nothing tells that a peer doesn't have a block.
+    V1_1_0,
+    /// `/ipfs/bitswap/1.2.0`, adding want haves and block presences.
+    V1_2_0,
+}
+
+impl CompatVersion {
+    /// Every version, newest first.
+    pub const ALL: [Self; 3] = [Self::V1_2_0, Self::V1_1_0, Self::V1_0_0];
+
+    /// Returns the protocol name of the version.
+    pub fn protocol_name(self) -> &'static [u8] {
+        match self {
+            Self::V1_0_0 => b"/ipfs/bitswap/1.0.0",
+            Self::V1_1_0 => b"/ipfs/bitswap/1.1.0",
+            Self::V1_2_0 => b"/ipfs/bitswap/1.2.0",
+        }
+    }
+
+    /// Returns the version of a negotiated protocol name.
+    fn from_protocol_name(name: &[u8]) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|version| version.protocol_name() == name)
+    }
+
+    /// Whether the version has want haves and block presences.
+    pub fn has_presences(self) -> bool {
+        self >= Self::V1_2_0
+    }
+}
+
+fn protocol_names(versions: &[CompatVersion]) -> std::vec::IntoIter<&'static [u8]> {
+    versions
+        .iter()
+        .map(|version| version.protocol_name())
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+/// Inbound upgrade of `/ipfs/bitswap/1.2.0`, `/ipfs/bitswap/1.1.0` and
+/// `/ipfs/bitswap/1.0.0`. A disabled protocol advertises nothing and rejects inbound
+/// upgrades, as if compat wasn't compiled in.
+#[derive(Clone, Debug)]
+pub struct CompatProtocol {
+    enabled: bool,
+}
+
+impl CompatProtocol {
+    /// Creates the protocol, advertising every `CompatVersion` if `enabled`.
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+}
+
+impl Default for CompatProtocol {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
 
 impl UpgradeInfo for CompatProtocol {
     type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(b"/ipfs/bitswap/1.2.0")
+        protocol_names(if self.enabled {
+            &CompatVersion::ALL
+        } else {
+            &[]
+        })
     }
 }
 
@@ -28,8 +105,13 @@ where
     type Error = io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
-    fn upgrade_inbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
+    fn upgrade_inbound(self, mut socket: TSocket, info: Self::Info) -> Self::Future {
         Box::pin(async move {
+            if !self.enabled {
+                return Err(io::Error::new(io::ErrorKind::Other, "compat is disabled"));
+            }
+            let version = CompatVersion::from_protocol_name(info)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unknown protocol"))?;
             tracing::trace!("upgrading inbound");
             let packet = upgrade::read_length_prefixed(&mut socket, MAX_BUF_SIZE)
                 .await
@@ -39,50 +121,154 @@ where
                 })?;
             socket.close().await?;
             tracing::trace!("inbound upgrade done, closing");
-            let message = CompatMessage::from_bytes(&packet).map_err(|e| {
-                tracing::debug!(%e, "inbound upgrade error");
-                e
-            })?;
+            let (parts, full) = CompatMessage::pb_from_bytes(&packet)
+                .and_then(|msg| {
+                    let full = msg.wantlist.as_ref().map(|wantlist| wantlist.full);
+                    let parts = CompatMessage::try_from_pb_version(msg, version)?;
+                    Ok((parts, full.unwrap_or_default()))
+                })
+                .map_err(|e| {
+                    tracing::debug!(%e, "inbound upgrade error");
+                    e
+                })?;
             tracing::trace!("inbound upgrade closed");
-            Ok(InboundMessage(message))
+            Ok(InboundMessage::Received {
+                parts,
+                version,
+                full,
+            })
         })
     }
 }
 
-impl UpgradeInfo for CompatMessage {
+/// Outbound message, bundling its parts into a single protobuf message. It is sent with
+/// the newest `CompatVersion` the remote supports, dropping the parts that version
+/// can't express.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OutboundMessage {
+    /// Parts of the message.
+    pub parts: Vec<CompatMessage>,
+    /// Bytes of blocks queued for the remote behind this message, sent as
+    /// `pendingBytes`.
+    pub pending_bytes: usize,
+    /// Whether the wants of the message replace all wants sent to the remote before,
+    /// sent as the `full` flag of the wantlist.
+    pub full: bool,
+    /// Id reported back by `InboundMessage::Sent` or `InboundMessage::Failed`. Not sent.
+    pub id: u64,
+}
+
+impl OutboundMessage {
+    /// Creates a message that is neither followed by blocks nor a full wantlist.
+    pub fn new(parts: Vec<CompatMessage>) -> Self {
+        Self {
+            parts,
+            ..Default::default()
+        }
+    }
+
+    /// Bundles messages into as few outbound messages as fit into the message size
+    /// limit, keeping their order.
+    pub fn bundle(msgs: impl IntoIterator<Item = CompatMessage>) -> Vec<Self> {
+        CompatMessage::bundle(msgs, MAX_BUF_SIZE)
+            .into_iter()
+            .map(Self::new)
+            .collect()
+    }
+
+    /// Returns the bytes of the blocks in the message.
+    pub fn block_bytes(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                CompatMessage::Response(_, BitswapResponse::Block(data)) => data.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Converts the message into a protobuf message for `version`, see
+    /// `CompatMessage::downgrade_pb`.
+    pub fn into_pb(self, version: CompatVersion) -> bitswap_pb::Message {
+        let mut msg = CompatMessage::bundle_into_pb(self.parts);
+        msg.pending_bytes = i32::try_from(self.pending_bytes).unwrap_or(i32::MAX);
+        if self.full {
+            msg.wantlist.get_or_insert_with(Default::default).full = true;
+        }
+        CompatMessage::downgrade_pb(&mut msg, version);
+        msg
+    }
+}
+
+impl From<CompatMessage> for OutboundMessage {
+    fn from(msg: CompatMessage) -> Self {
+        Self::new(vec![msg])
+    }
+}
+
+impl UpgradeInfo for OutboundMessage {
     type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(b"/ipfs/bitswap/1.2.0")
+        protocol_names(&CompatVersion::ALL)
     }
 }
 
-impl<TSocket> OutboundUpgrade<TSocket> for CompatMessage
+impl<TSocket> OutboundUpgrade<TSocket> for OutboundMessage
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = ();
+    type Output = InboundMessage;
     type Error = io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
-    fn upgrade_outbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
+    fn upgrade_outbound(self, mut socket: TSocket, info: Self::Info) -> Self::Future {
         Box::pin(async move {
-            let bytes = self.to_bytes()?;
-            upgrade::write_length_prefixed(&mut socket, bytes).await?;
-            socket.close().await?;
-            Ok(())
+            let version = CompatVersion::from_protocol_name(info)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unknown protocol"))?;
+            let id = self.id;
+            // a failed write is reported with the id of the message instead of closing
+            // the connection, so the behaviour knows which message to reroute
+            let res = async {
+                let bytes = CompatMessage::pb_to_bytes(&self.into_pb(version))?;
+                upgrade::write_length_prefixed(&mut socket, bytes).await?;
+                socket.close().await
+            }
+            .await;
+            Ok(match res {
+                Ok(()) => InboundMessage::Sent { id, version },
+                Err(error) => InboundMessage::Failed { id, error },
+            })
         })
     }
 }
 
 #[derive(Debug)]
-pub struct InboundMessage(pub Vec<CompatMessage>);
-
-impl From<()> for InboundMessage {
-    fn from(_: ()) -> Self {
-        Self(Default::default())
-    }
+pub enum InboundMessage {
+    /// Message received from the remote.
+    Received {
+        /// Parts of the message.
+        parts: Vec<CompatMessage>,
+        /// Version the remote chose.
+        version: CompatVersion,
+        /// Whether the wants of the message replace all wants the remote sent before.
+        full: bool,
+    },
+    /// An outbound message was sent to the remote with the newest version it supports.
+    Sent {
+        /// Id of the outbound message.
+        id: u64,
+        /// Version the message was sent with.
+        version: CompatVersion,
+    },
+    /// An outbound message couldn't be written to its substream.
+    Failed {
+        /// Id of the outbound message.
+        id: u64,
+        /// Error writing the message.
+        error: io::Error,
+    },
 }
 
 #[cfg(test)]
@@ -101,25 +287,61 @@ mod tests {
 
         let server = async move {
             let incoming = listener.incoming().into_future().await.0.unwrap().unwrap();
-            upgrade::apply_inbound(incoming, CompatProtocol)
+            upgrade::apply_inbound(incoming, CompatProtocol::default())
                 .await
                 .unwrap();
         };
 
         let client = async move {
             let stream = TcpStream::connect(&listener_addr).await.unwrap();
-            upgrade::apply_outbound(
-                stream,
-                CompatMessage::Request(BitswapRequest {
-                    ty: RequestType::Have,
-                    cid: Cid::default(),
-                }),
-                upgrade::Version::V1,
-            )
-            .await
-            .unwrap();
+            let mut msg = OutboundMessage::from(CompatMessage::Request(BitswapRequest::new(
+                RequestType::Have,
+                Cid::default(),
+            )));
+            msg.id = 7;
+            let sent = upgrade::apply_outbound(stream, msg, upgrade::Version::V1)
+                .await
+                .unwrap();
+            assert!(matches!(
+                sent,
+                InboundMessage::Sent {
+                    id: 7,
+                    version: CompatVersion::V1_2_0
+                }
+            ));
         };
 
         future::select(Box::pin(server), Box::pin(client)).await;
     }
+
+    #[test]
+    fn test_outbound_into_pb() {
+        let request =
+            CompatMessage::Request(BitswapRequest::new(RequestType::Have, Cid::default()));
+        let mut msg = OutboundMessage::from(request.clone());
+        let pb = msg.clone().into_pb(CompatVersion::V1_2_0);
+        assert_eq!(pb, request.into_pb());
+
+        msg.pending_bytes = 1024;
+        msg.full = true;
+        let pb = msg.into_pb(CompatVersion::V1_2_0);
+        assert_eq!(pb.pending_bytes, 1024);
+        assert!(pb.wantlist.unwrap().full);
+    }
+
+    #[test]
+    fn test_disabled_protocol_info() {
+        let enabled = CompatProtocol::new(true)
+            .protocol_info()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            enabled,
+            vec![
+                &b"/ipfs/bitswap/1.2.0"[..],
+                &b"/ipfs/bitswap/1.1.0"[..],
+                &b"/ipfs/bitswap/1.0.0"[..],
+            ]
+        );
+        assert_eq!(CompatProtocol::new(false).protocol_info().next(), None);
+    }
 }
\ No newline at end of file
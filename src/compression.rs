@@ -0,0 +1,185 @@
+use crate::protocol::BitswapResponse;
+use crate::stats::{COMPRESSION_RAW_BYTES, COMPRESSION_WIRE_BYTES};
+use std::io;
+use thiserror::Error;
+
+/// Flag of a block sent as is.
+const RAW: u8 = 0;
+/// Flag of a block compressed with zstd.
+const ZSTD: u8 = 1;
+/// Flag of a block compressed with lz4.
+const LZ4: u8 = 2;
+
+/// Bytes the flag adds to a block. Compressed blocks are only sent if they are smaller
+/// than the block itself.
+pub(crate) const COMPRESSION_OVERHEAD: usize = 1;
+
+/// Algorithm compressing the blocks sent on streams that negotiated
+/// `Capabilities::COMPRESSION`. Each block carries a flag telling how it was compressed,
+/// so peers may use different ones.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// zstd at a compression level, `0` for the default level of zstd.
+    Zstd(i32),
+    /// lz4 block format. Faster than zstd, but compresses less.
+    Lz4,
+}
+
+/// Replaces the data of a block response with the compressed block, prefixed by its
+/// flag and uncompressed length. Blocks that don't get smaller are sent as is, after
+/// the `RAW` flag.
+pub(crate) fn compress_response(
+    res: BitswapResponse,
+    compression: Option<Compression>,
+) -> io::Result<BitswapResponse> {
+    let data = if let BitswapResponse::Block(data) = res {
+        data
+    } else {
+        return Ok(res);
+    };
+    let compressed = match compression {
+        Some(Compression::Zstd(level)) => Some((ZSTD, zstd::bulk::compress(&data, level)?)),
+        Some(Compression::Lz4) => Some((LZ4, lz4_flex::block::compress(&data))),
+        None => None,
+    };
+    let mut buf = unsigned_varint::encode::usize_buffer();
+    let len = unsigned_varint::encode::usize(data.len(), &mut buf);
+    let payload = match compressed {
+        Some((flag, compressed)) if 1 + len.len() + compressed.len() <= data.len() => {
+            let mut payload = Vec::with_capacity(1 + len.len() + compressed.len());
+            payload.push(flag);
+            payload.extend_from_slice(len);
+            payload.extend_from_slice(&compressed);
+            payload
+        }
+        _ => {
+            let mut payload = Vec::with_capacity(COMPRESSION_OVERHEAD + data.len());
+            payload.push(RAW);
+            payload.extend_from_slice(&data);
+            payload
+        }
+    };
+    COMPRESSION_RAW_BYTES
+        .with_label_values(&["sent"])
+        .inc_by(data.len() as u64);
+    COMPRESSION_WIRE_BYTES
+        .with_label_values(&["sent"])
+        .inc_by(payload.len() as u64);
+    Ok(BitswapResponse::Block(payload))
+}
+
+/// Restores the block of a response written by `compress_response`. Blocks larger than
+/// `max_len` are rejected before they are decompressed.
+pub(crate) fn decompress_response(
+    res: BitswapResponse,
+    max_len: usize,
+) -> io::Result<BitswapResponse> {
+    let mut payload = if let BitswapResponse::Block(payload) = res {
+        payload
+    } else {
+        return Ok(res);
+    };
+    let wire_len = payload.len();
+    let data = match payload.first().copied() {
+        Some(RAW) => {
+            payload.remove(0);
+            payload
+        }
+        Some(flag) => {
+            let (len, compressed) =
+                unsigned_varint::decode::usize(&payload[1..]).map_err(invalid_data)?;
+            if len > max_len {
+                return Err(invalid_data(DecompressedTooLarge(len)));
+            }
+            let data = match flag {
+                ZSTD => zstd::bulk::decompress(compressed, len)?,
+                LZ4 => lz4_flex::block::decompress(compressed, len).map_err(invalid_data)?,
+                _ => return Err(invalid_data(UnknownCompression(flag))),
+            };
+            if data.len() != len {
+                return Err(invalid_data(LengthMismatch(len)));
+            }
+            data
+        }
+        None => return Err(invalid_data(MissingFlag)),
+    };
+    COMPRESSION_RAW_BYTES
+        .with_label_values(&["received"])
+        .inc_by(data.len() as u64);
+    COMPRESSION_WIRE_BYTES
+        .with_label_values(&["received"])
+        .inc_by(wire_len as u64);
+    Ok(BitswapResponse::Block(data))
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[derive(Debug, Error)]
+#[error("unknown compression flag {0}")]
+pub struct UnknownCompression(u8);
+
+#[derive(Debug, Error)]
+#[error("block without compression flag")]
+pub struct MissingFlag;
+
+#[derive(Debug, Error)]
+#[error("decompressed block of {0} bytes exceeds the maximum")]
+pub struct DecompressedTooLarge(usize);
+
+#[derive(Debug, Error)]
+#[error("decompressed block doesn't have its length of {0} bytes")]
+pub struct LengthMismatch(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let compressible = br#"{"name":"file","size":1024}"#.repeat(64);
+        let responses = [
+            BitswapResponse::Have(true),
+            BitswapResponse::Block(vec![]),
+            BitswapResponse::Block(b"short".to_vec()),
+            BitswapResponse::Block(compressible.clone()),
+        ];
+        for compression in [None, Some(Compression::Zstd(0)), Some(Compression::Lz4)] {
+            for res in &responses {
+                let compressed = compress_response(res.clone(), compression).unwrap();
+                let decompressed = decompress_response(compressed, 1024 * 1024).unwrap();
+                assert_eq!(&decompressed, res);
+            }
+        }
+
+        // compressed blocks are smaller, incompressible ones only carry the flag
+        let res = BitswapResponse::Block(compressible.clone());
+        let compressed = compress_response(res, Some(Compression::Lz4)).unwrap();
+        let payload = if let BitswapResponse::Block(payload) = compressed {
+            payload
+        } else {
+            panic!("not a block");
+        };
+        assert_eq!(payload[0], LZ4);
+        assert!(payload.len() < compressible.len() / 4);
+        let res = BitswapResponse::Block(b"short".to_vec());
+        let compressed = compress_response(res, Some(Compression::Zstd(3))).unwrap();
+        assert_eq!(compressed, BitswapResponse::Block(b"\0short".to_vec()));
+    }
+
+    #[test]
+    fn test_decompress_rejects_invalid_blocks() {
+        let compressible = vec![7; 4096];
+        let res = BitswapResponse::Block(compressible);
+        let compressed = compress_response(res, Some(Compression::Zstd(0))).unwrap();
+        assert!(decompress_response(compressed.clone(), 4095).is_err());
+        assert!(decompress_response(compressed, 4096).is_ok());
+
+        assert!(decompress_response(BitswapResponse::Block(vec![]), 4096).is_err());
+        assert!(decompress_response(BitswapResponse::Block(vec![9, 1, 0]), 4096).is_err());
+        // the length of a block doesn't match its data
+        let res = BitswapResponse::Block(vec![LZ4, 8, 0x10, 1]);
+        assert!(decompress_response(res, 4096).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Link extraction of dag-pb blocks, e.g. UnixFS files and directories, for
+//! `BitswapStore::missing_blocks` implementations. Used by the `dagpb` feature.
+use libipld::{Cid, Result};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+const VARINT: u8 = 0;
+const LENGTH_DELIMITED: u8 = 2;
+
+#[derive(Debug, Error)]
+#[error("invalid dag-pb block: {0}")]
+struct InvalidDagPb(&'static str);
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn varint(&mut self) -> std::result::Result<u64, InvalidDagPb> {
+        let (value, rest) =
+            unsigned_varint::decode::u64(self.0).map_err(|_| InvalidDagPb("invalid varint"))?;
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn key(&mut self) -> std::result::Result<(u64, u8), InvalidDagPb> {
+        let key = self.varint()?;
+        Ok((key >> 3, (key & 0x7) as u8))
+    }
+
+    fn bytes(&mut self) -> std::result::Result<&'a [u8], InvalidDagPb> {
+        let len = self.varint()?;
+        if len > self.0.len() as u64 {
+            return Err(InvalidDagPb("truncated field"));
+        }
+        let (bytes, rest) = self.0.split_at(len as usize);
+        self.0 = rest;
+        Ok(bytes)
+    }
+}
+
+/// Returns the links of a dag-pb block in the order they are encoded.
+///
+/// Blocks with fields that the dag-pb spec doesn't define, links without a hash and
+/// truncated blocks are rejected. Link names and sizes aren't checked.
+pub fn dagpb_references(data: &[u8]) -> Result<Vec<Cid>> {
+    let mut node = Reader(data);
+    let mut links = vec![];
+    let mut has_data = false;
+    while !node.is_empty() {
+        match node.key()? {
+            (1, LENGTH_DELIMITED) if !has_data => {
+                node.bytes()?;
+                has_data = true;
+            }
+            (2, LENGTH_DELIMITED) => links.push(link(node.bytes()?)?),
+            _ => return Err(InvalidDagPb("unexpected node field").into()),
+        }
+    }
+    Ok(links)
+}
+
+/// Decodes the hash of a link.
+fn link(bytes: &[u8]) -> Result<Cid> {
+    let mut link = Reader(bytes);
+    let mut hash = None;
+    while !link.is_empty() {
+        match link.key()? {
+            (1, LENGTH_DELIMITED) if hash.is_none() => hash = Some(link.bytes()?),
+            (2, LENGTH_DELIMITED) => {
+                link.bytes()?;
+            }
+            (3, VARINT) => {
+                link.varint()?;
+            }
+            _ => return Err(InvalidDagPb("unexpected link field").into()),
+        }
+    }
+    let hash = hash.ok_or(InvalidDagPb("link without hash"))?;
+    Ok(Cid::try_from(hash)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // UnixFS nodes encoded like kubo encodes them, links before data.
+    const EMPTY_DIR: &str = "0a020801";
+    const FILE: &str = "122a0a24015512205e3235a8346e5a4585f8c58562f5052b8fe26a3bb122e1e96c76784964dfc46112001806122a0a2401551220e258d248fda94c63753607f7c4494ee0fcbe92f1a76bfdac795c9d84101eb317120018060a080802180c20062006";
+    const DIR: &str = "122d0a22122059948439065f29619ef41280cbb932be52c56d99c5966b65e0111239f098bbef1205656d707479180412310a2212209535a0cba3b76a3b14393350a9770fa026a16b5d5c6bcd7120d5baba5379d39f120968656c6c6f2e747874186e0a020801";
+
+    #[test]
+    fn test_dagpb_references() {
+        assert!(dagpb_references(&hex(EMPTY_DIR)).unwrap().is_empty());
+        assert!(dagpb_references(&[]).unwrap().is_empty());
+
+        // a file chunked into raw leaves
+        let links = dagpb_references(&hex(FILE)).unwrap();
+        let links = links.iter().map(|cid| cid.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            vec![
+                "bafkreic6gi22qndoljcyl6gfqvrpkbjlr7rguo5relq6s3dwpbewjx6eme",
+                "bafkreihcldjer7njjrrxknqh67cestxa7s7jf4nhnp62y6k4twcbahvtc4",
+            ]
+        );
+
+        // a directory holding an empty directory and the file
+        let links = dagpb_references(&hex(DIR)).unwrap();
+        let links = links.iter().map(|cid| cid.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            vec![
+                "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn",
+                "QmYP6tJzHpLk2t9kagJgERMj1pcgM5zJ88S2WNNqeXqY6a",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dagpb_references_malformed() {
+        let malformed: &[&[u8]] = &[
+            // truncated key, length and field
+            &[0x80],
+            &[0x12],
+            &[0x12, 0x05, 0x0a],
+            // varint longer than ten bytes
+            &[
+                0x12, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            ],
+            // unknown field and wrong wire type
+            &[0x18, 0x01],
+            &[0x10, 0x01],
+            // data twice
+            &[0x0a, 0x00, 0x0a, 0x00],
+            // link without hash, with a truncated hash and with an unknown field
+            &[0x12, 0x00],
+            &[0x12, 0x04, 0x0a, 0x02, 0x12, 0x20],
+            &[0x12, 0x02, 0x20, 0x01],
+        ];
+        for data in malformed {
+            assert!(dagpb_references(data).is_err(), "{:x?}", data);
+        }
+        // cut short anywhere decodes or errors without panicking
+        let dir = hex(DIR);
+        for len in 0..dir.len() {
+            dagpb_references(&dir[..len]).ok();
+        }
+    }
+}
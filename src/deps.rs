@@ -0,0 +1,15 @@
+//! Dependencies appearing in the public api, re-exported so that users get the
+//! versions this crate was built against. Mixing in other versions of these crates
+//! fails with trait bound errors that don't mention the version skew.
+
+pub use async_trait::{self, async_trait};
+pub use bytes::{self, Bytes};
+pub use futures::{self, channel::oneshot};
+pub use libipld::{
+    self,
+    codec::References,
+    store::{DefaultParams, StoreParams},
+    Block, Cid, Ipld, Result,
+};
+pub use libp2p::{self, Multiaddr, PeerId};
+pub use prometheus::{self, Registry};
@@ -0,0 +1,27 @@
+use futures::channel::oneshot;
+use libipld::Cid;
+use libp2p::{Multiaddr, PeerId};
+
+/// Providers found by a `ProviderDiscovery` with the addresses to dial them at. Peers
+/// the swarm already knows how to reach may come without addresses.
+pub type DiscoveredProviders = Vec<(PeerId, Vec<Multiaddr>)>;
+
+/// Looks up providers of blocks for get queries that ran out of providers, e.g. in a
+/// dht running in the same swarm.
+///
+/// See [`BitswapConfig::discovery_rounds`](crate::BitswapConfig::discovery_rounds).
+pub trait ProviderDiscovery: Send + 'static {
+    /// Starts looking up the providers of `cid`. The lookup ends when the sender is
+    /// used, a dropped sender counts as no providers found. A lookup that never ends
+    /// stalls the query until it is canceled.
+    fn discover(&mut self, cid: Cid) -> oneshot::Receiver<DiscoveredProviders>;
+}
+
+impl<F> ProviderDiscovery for F
+where
+    F: FnMut(Cid) -> oneshot::Receiver<DiscoveredProviders> + Send + 'static,
+{
+    fn discover(&mut self, cid: Cid) -> oneshot::Receiver<DiscoveredProviders> {
+        self(cid)
+    }
+}
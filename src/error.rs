@@ -0,0 +1,160 @@
+use crate::session::SessionId;
+use libipld::error::BlockNotFound;
+use libipld::Cid;
+use libp2p::{Multiaddr, PeerId};
+use thiserror::Error;
+
+/// Error returned when a block received by a strict sync has links that can't be
+/// traversed.
+#[derive(Debug, Error)]
+#[error("invalid references in block {cid}: {reason}")]
+#[non_exhaustive]
+pub struct InvalidReferences {
+    /// Cid of the offending block.
+    pub cid: Cid,
+    /// Offending link, if the references could be decoded.
+    pub link: Option<Cid>,
+    /// Reason the block was rejected.
+    pub reason: String,
+}
+
+/// Error returned when an address of a peer can't be dialed.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[non_exhaustive]
+pub enum AddrError {
+    /// The address ends with the `/p2p` component of another peer.
+    #[error("address {addr} belongs to {found}, not {expected}")]
+    PeerIdMismatch {
+        /// Rejected address.
+        addr: Multiaddr,
+        /// Peer the address was added for.
+        expected: PeerId,
+        /// Peer of the `/p2p` component.
+        found: PeerId,
+    },
+    /// The `/p2p` component doesn't contain a peer id.
+    #[error("address {0} has an invalid peer id")]
+    InvalidPeerId(Multiaddr),
+    /// The address has no transport to dial, e.g. a bare `/p2p/<id>`.
+    #[error("address {0} has no transport")]
+    NoTransport(Multiaddr),
+}
+
+/// Error returned when a get or sync query can't be started.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+#[non_exhaustive]
+pub enum QueryRejected {
+    /// `BitswapConfig::max_active_queries` get and sync queries are in progress.
+    #[error("too many active queries, the limit is {0}")]
+    TooManyQueries(usize),
+    /// The session was closed or never opened.
+    #[error("unknown session {0}")]
+    UnknownSession(SessionId),
+}
+
+/// Error returned by the public api of the bitswap behaviour, e.g. as the result of a
+/// `BitswapEvent::Complete`.
+///
+/// New variants may be added in minor releases.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// None of the providers had the block.
+    #[error("failed to retrieve block {0}")]
+    BlockNotFound(Cid),
+    /// Every request for the block failed without an answer, e.g. because none of the
+    /// providers could be dialed.
+    #[error("failed to retrieve block {0}, no provider answered")]
+    AllPeersFailed(Cid),
+    /// A block received by a strict sync has links that can't be traversed.
+    #[error(transparent)]
+    InvalidReferences(Box<InvalidReferences>),
+    /// The `BitswapStore` or `SpillStore` failed.
+    #[error(transparent)]
+    Store(#[from] libipld::error::Error),
+    /// An io operation failed.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Registering the prometheus metrics failed.
+    #[error(transparent)]
+    Metrics(#[from] prometheus::Error),
+    /// An address of a peer was rejected.
+    #[error(transparent)]
+    Addr(#[from] AddrError),
+    /// A query couldn't be started.
+    #[error(transparent)]
+    QueryRejected(#[from] QueryRejected),
+    /// The deadline of the query passed before it completed.
+    #[error("query timed out")]
+    Timeout,
+    /// The query was cancelled with `Bitswap::cancel`.
+    #[error("query cancelled")]
+    Cancelled,
+    /// `StoreParams::MAX_BLOCK_SIZE` exceeds the block size compat peers accept while
+    /// `BitswapConfig::strict_compat_block_size` is set.
+    #[error("MAX_BLOCK_SIZE of {max_block_size} bytes exceeds the compat limit of {limit} bytes")]
+    CompatBlockSize {
+        /// `StoreParams::MAX_BLOCK_SIZE`.
+        max_block_size: usize,
+        /// Largest block compat peers accept.
+        limit: usize,
+    },
+}
+
+impl Error {
+    /// Converts the error into an `anyhow` error as returned by earlier versions, so that
+    /// `downcast_ref` against the underlying error types keeps working.
+    pub fn into_anyhow(self) -> libipld::error::Error {
+        match self {
+            Self::BlockNotFound(cid) | Self::AllPeersFailed(cid) => BlockNotFound(cid).into(),
+            Self::InvalidReferences(err) => (*err).into(),
+            Self::Store(err) => err,
+            Self::Io(err) => err.into(),
+            Self::Metrics(err) => err.into(),
+            Self::Addr(err) => err.into(),
+            Self::QueryRejected(err) => err.into(),
+            Self::Timeout => std::io::Error::from(std::io::ErrorKind::TimedOut).into(),
+            Self::Cancelled => std::io::Error::from(std::io::ErrorKind::Interrupted).into(),
+            err @ Self::CompatBlockSize { .. } => {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()).into()
+            }
+        }
+    }
+}
+
+impl From<InvalidReferences> for Error {
+    fn from(err: InvalidReferences) -> Self {
+        Self::InvalidReferences(Box::new(err))
+    }
+}
+
+impl From<BlockNotFound> for Error {
+    fn from(err: BlockNotFound) -> Self {
+        Self::BlockNotFound(err.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_anyhow() {
+        let cid = Cid::default();
+        let err = Error::from(BlockNotFound(cid));
+        assert!(matches!(err, Error::BlockNotFound(cid2) if cid2 == cid));
+        let err = err.into_anyhow();
+        assert_eq!(err.downcast_ref::<BlockNotFound>().unwrap().0, cid);
+
+        let err = Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(err.into_anyhow().downcast_ref::<std::io::Error>().is_some());
+
+        let err = Error::Timeout.into_anyhow();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        // earlier versions didn't tell failed providers apart from missing blocks
+        let err = Error::AllPeersFailed(cid).into_anyhow();
+        assert_eq!(err.downcast_ref::<BlockNotFound>().unwrap().0, cid);
+    }
+}
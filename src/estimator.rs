@@ -0,0 +1,171 @@
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::time::Duration;
+
+/// Weight of a new sample in the moving averages.
+const ALPHA: f64 = 0.125;
+
+/// Adaptive request timeout configuration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AdaptiveTimeout {
+    /// Time added to every timeout on top of the peer's round trip time.
+    pub base: Duration,
+    /// Lower bound of the bandwidth estimate in bytes per second.
+    pub min_bandwidth: u64,
+    /// Lower bound of a timeout.
+    pub min: Duration,
+    /// Upper bound of a timeout.
+    pub max: Duration,
+}
+
+impl Default for AdaptiveTimeout {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(500),
+            min_bandwidth: 64 * 1024,
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Latency and bandwidth estimates of a peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeerEstimate {
+    /// Smoothed round trip time.
+    pub rtt: Option<Duration>,
+    /// Smoothed bandwidth in bytes per second.
+    pub bandwidth: Option<f64>,
+}
+
+fn ewma(avg: Option<f64>, sample: f64) -> f64 {
+    match avg {
+        Some(avg) => avg + ALPHA * (sample - avg),
+        None => sample,
+    }
+}
+
+/// Estimates per peer latency and bandwidth from completed requests.
+#[derive(Debug, Default)]
+pub struct PeerEstimator {
+    peers: FnvHashMap<PeerId, PeerEstimate>,
+    block_size: Option<f64>,
+}
+
+impl PeerEstimator {
+    /// Returns the estimate of a peer.
+    pub fn estimate(&self, peer_id: &PeerId) -> Option<&PeerEstimate> {
+        self.peers.get(peer_id)
+    }
+
+    /// Returns the average size of received blocks.
+    pub fn block_size(&self) -> Option<usize> {
+        self.block_size.map(|size| size as usize)
+    }
+
+    /// Records a completed request. Responses without a block are used as round trip
+    /// time samples, the time a block took to arrive on top of it as bandwidth sample.
+    pub fn observe(&mut self, peer_id: PeerId, elapsed: Duration, bytes: usize) {
+        let estimate = self.peers.entry(peer_id).or_default();
+        if bytes == 0 {
+            let rtt = ewma(
+                estimate.rtt.map(|rtt| rtt.as_secs_f64()),
+                elapsed.as_secs_f64(),
+            );
+            estimate.rtt = Some(Duration::from_secs_f64(rtt));
+            return;
+        }
+        self.block_size = Some(ewma(self.block_size, bytes as f64));
+        let transfer = elapsed.saturating_sub(estimate.rtt.unwrap_or_default());
+        if transfer.is_zero() {
+            return;
+        }
+        let bandwidth = bytes as f64 / transfer.as_secs_f64();
+        estimate.bandwidth = Some(ewma(estimate.bandwidth, bandwidth));
+    }
+
+    /// Returns the expected time a peer takes to deliver `size` bytes, or `None` if
+    /// nothing is known about the peer yet.
+    pub fn expected(&self, peer_id: &PeerId, size: usize) -> Option<Duration> {
+        let estimate = self.peers.get(peer_id)?;
+        let transfer = estimate
+            .bandwidth
+            .map(|bandwidth| Duration::from_secs_f64(size as f64 / bandwidth));
+        if estimate.rtt.is_none() && transfer.is_none() {
+            return None;
+        }
+        Some(estimate.rtt.unwrap_or_default() + transfer.unwrap_or_default())
+    }
+
+    /// Removes the estimate of a peer.
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    /// Returns the timeout of a request expecting `size` bytes from a peer, or `None` if
+    /// nothing is known about the peer yet.
+    pub fn timeout(
+        &self,
+        peer_id: &PeerId,
+        size: usize,
+        config: &AdaptiveTimeout,
+    ) -> Option<Duration> {
+        let estimate = self.peers.get(peer_id)?;
+        let rtt = estimate.rtt?;
+        let min_bandwidth = config.min_bandwidth.max(1) as f64;
+        let bandwidth = estimate
+            .bandwidth
+            .unwrap_or(min_bandwidth)
+            .max(min_bandwidth);
+        let transfer = Duration::from_secs_f64(size as f64 / bandwidth);
+        Some((config.base + rtt + transfer).clamp(config.min, config.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout() {
+        let config = AdaptiveTimeout {
+            base: Duration::from_millis(100),
+            min_bandwidth: 1000,
+            min: Duration::from_millis(200),
+            max: Duration::from_secs(10),
+        };
+        let peer = PeerId::random();
+        let mut estimator = PeerEstimator::default();
+        assert_eq!(estimator.timeout(&peer, 0, &config), None);
+
+        estimator.observe(peer, Duration::from_millis(50), 0);
+        assert_eq!(
+            estimator.timeout(&peer, 0, &config),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            estimator.timeout(&peer, 1000, &config),
+            Some(Duration::from_millis(1150))
+        );
+
+        estimator.observe(peer, Duration::from_millis(150), 10_000);
+        assert_eq!(estimator.block_size(), Some(10_000));
+        assert_eq!(
+            estimator.timeout(&peer, 10_000, &config),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            estimator.timeout(&peer, 1_000_000, &config),
+            Some(Duration::from_secs(10))
+        );
+
+        assert_eq!(
+            estimator.expected(&peer, 10_000),
+            Some(Duration::from_millis(150))
+        );
+
+        estimator.remove(&peer);
+        assert_eq!(estimator.timeout(&peer, 0, &config), None);
+        assert_eq!(estimator.expected(&peer, 0), None);
+    }
+}
@@ -0,0 +1,149 @@
+use fnv::{FnvHashMap, FnvHasher};
+use libipld::Cid;
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+
+/// Rows of the sketch. Each row is an independent estimate, the smallest one wins.
+const DEPTH: usize = 4;
+
+/// Counters per row for each tracked cid. Collisions only overestimate, so a wider
+/// sketch trades memory for accuracy.
+const WIDTH_PER_CID: usize = 16;
+
+/// Smallest width of a row.
+const MIN_WIDTH: usize = 1024;
+
+/// Tracks the cids generating the most upload in bounded memory. Bytes are counted in a
+/// count-min sketch, which never underestimates, and the `capacity` cids with the
+/// highest estimates are kept alongside so they can be listed.
+#[derive(Debug)]
+pub(crate) struct HotCids {
+    sketch: Vec<u64>,
+    width: usize,
+    top: FnvHashMap<Cid, u64>,
+    /// The entries of `top` ordered by count, coldest first. Cids with the same count are
+    /// ordered from the highest to the lowest, so that listing them in reverse lists
+    /// the lowest first.
+    by_count: BTreeSet<(u64, Reverse<Cid>)>,
+    capacity: usize,
+}
+
+impl HotCids {
+    /// Creates a tracker listing up to `capacity` cids.
+    pub fn new(capacity: usize) -> Self {
+        let width =
+            usize::max(capacity.saturating_mul(WIDTH_PER_CID), MIN_WIDTH).next_power_of_two();
+        Self {
+            sketch: vec![0; DEPTH * width],
+            width,
+            top: FnvHashMap::default(),
+            by_count: BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    /// Returns the counter of `cid` in each row.
+    fn slots(&self, cid: &Cid) -> impl Iterator<Item = usize> {
+        let hash = |key| {
+            let mut hasher = FnvHasher::with_key(key);
+            Hash::hash(cid, &mut hasher);
+            hasher.finish() as usize
+        };
+        // double hashing derives the rows from two hashes
+        let (h1, h2) = (hash(0xcbf2_9ce4_8422_2325), hash(0x1000_0000_01b3) | 1);
+        let width = self.width;
+        (0..DEPTH)
+            .map(move |row| row * width + (h1.wrapping_add(row.wrapping_mul(h2)) & (width - 1)))
+    }
+
+    /// Accounts `bytes` served for `cid`.
+    pub fn record(&mut self, cid: &Cid, bytes: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut estimate = u64::MAX;
+        for slot in self.slots(cid) {
+            let counter = &mut self.sketch[slot];
+            *counter = counter.saturating_add(bytes);
+            estimate = estimate.min(*counter);
+        }
+        if let Some(count) = self.top.get_mut(cid) {
+            self.by_count.remove(&(*count, Reverse(*cid)));
+            self.by_count.insert((estimate, Reverse(*cid)));
+            *count = estimate;
+            return;
+        }
+        if self.top.len() >= self.capacity {
+            let (min, Reverse(coldest)) = *self.by_count.first().expect("capacity is not zero");
+            if estimate <= min {
+                return;
+            }
+            self.by_count.remove(&(min, Reverse(coldest)));
+            self.top.remove(&coldest);
+        }
+        self.top.insert(*cid, estimate);
+        self.by_count.insert((estimate, Reverse(*cid)));
+    }
+
+    /// Returns up to `n` cids with the most bytes served, hottest first.
+    pub fn top(&self, n: usize) -> Vec<(Cid, u64)> {
+        self.by_count
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, Reverse(cid))| (*cid, *count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::multihash::{Code, MultihashDigest};
+
+    fn cid(i: u32) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(&i.to_le_bytes()))
+    }
+
+    #[test]
+    fn test_hot_cids() {
+        let mut hot = HotCids::new(3);
+        for i in 0..1000 {
+            hot.record(&cid(i), 1);
+        }
+        for (i, bytes) in [(1, 500), (2, 300), (3, 400), (4, 100)].iter() {
+            hot.record(&cid(*i), *bytes);
+        }
+        let top = hot.top(3);
+        let cids: Vec<_> = top.iter().map(|(cid, _)| *cid).collect();
+        assert_eq!(cids, [cid(1), cid(3), cid(2)]);
+        // counts are never underestimated
+        assert!(top[0].1 >= 501);
+        assert!(top[1].1 >= 401);
+        assert_eq!(hot.top(1).len(), 1);
+        assert!(hot.top.len() <= 3);
+        assert_eq!(hot.by_count.len(), hot.top.len());
+    }
+
+    #[test]
+    fn test_hot_cids_ties() {
+        let mut hot = HotCids::new(2);
+        let mut cids = [cid(0), cid(1), cid(2)];
+        cids.sort();
+        for cid in &cids {
+            hot.record(cid, 10);
+        }
+        // a cid has to be hotter than the coldest one to replace it
+        assert_eq!(hot.top(3), [(cids[0], 10), (cids[1], 10)]);
+        hot.record(&cids[2], 1);
+        assert_eq!(hot.top(3), [(cids[2], 11), (cids[0], 10)]);
+    }
+
+    #[test]
+    fn test_hot_cids_zero_capacity() {
+        let mut hot = HotCids::new(0);
+        hot.record(&cid(0), 1);
+        assert!(hot.top(10).is_empty());
+    }
+}
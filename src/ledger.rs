@@ -0,0 +1,148 @@
+use crate::estimator::PeerEstimator;
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::cmp::Ordering;
+
+/// Weight of a new sample in the moving average.
+const ALPHA: f64 = 0.125;
+/// Latency assumed for peers without an estimate.
+const UNKNOWN_LATENCY: f64 = 1.0;
+/// Lower bound of the hit rate used for ranking, so that peers that never had a block
+/// are still ordered by latency.
+const MIN_HIT_RATE: f64 = 0.01;
+/// Number of peers remembered. The least recently updated peer is forgotten first.
+const MAX_PEERS: usize = 4096;
+
+/// Hit rate of a peer, see `Bitswap::peer_score`. Its latency is part of the peer's
+/// estimate, see `Bitswap::peer_estimate`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerScore {
+    /// Smoothed fraction of requests the peer answered with the block or a have,
+    /// starting at one. Failed requests count as misses.
+    pub hit_rate: f64,
+    /// Number of responses and failures observed.
+    pub responses: u64,
+}
+
+impl Default for PeerScore {
+    fn default() -> Self {
+        Self {
+            hit_rate: 1.0,
+            responses: 0,
+        }
+    }
+}
+
+/// Hit rates of the peers requests were sent to. Together with the latency and bandwidth
+/// estimates of the peers they are used to ask the fastest and most reliable providers
+/// first.
+#[derive(Debug, Default)]
+pub(crate) struct PeerLedger {
+    /// Score of each peer and the sequence number of its last update.
+    peers: FnvHashMap<PeerId, (PeerScore, u64)>,
+    seq: u64,
+}
+
+impl PeerLedger {
+    /// Returns the score of a peer.
+    pub fn score(&self, peer_id: &PeerId) -> Option<&PeerScore> {
+        self.peers.get(peer_id).map(|(score, _)| score)
+    }
+
+    /// Expected time in seconds until a peer delivers a block. Lower is better.
+    fn cost(&self, estimator: &PeerEstimator, peer_id: &PeerId) -> f64 {
+        let size = estimator.block_size().unwrap_or_default();
+        let latency = estimator
+            .expected(peer_id, size)
+            .map(|latency| latency.as_secs_f64())
+            .unwrap_or(UNKNOWN_LATENCY);
+        let hit_rate = self.score(peer_id).copied().unwrap_or_default().hit_rate;
+        latency / hit_rate.max(MIN_HIT_RATE)
+    }
+
+    fn entry(&mut self, peer_id: PeerId) -> &mut PeerScore {
+        if self.peers.len() >= MAX_PEERS && !self.peers.contains_key(&peer_id) {
+            let oldest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, (_, seq))| *seq)
+                .map(|(peer_id, _)| *peer_id);
+            if let Some(oldest) = oldest {
+                self.peers.remove(&oldest);
+            }
+        }
+        self.seq += 1;
+        let entry = self.peers.entry(peer_id).or_default();
+        entry.1 = self.seq;
+        &mut entry.0
+    }
+
+    /// Records whether a peer had the block it was asked for.
+    pub fn observe_response(&mut self, peer_id: PeerId, hit: bool) {
+        let score = self.entry(peer_id);
+        let sample = if hit { 1.0 } else { 0.0 };
+        score.hit_rate += ALPHA * (sample - score.hit_rate);
+        score.responses += 1;
+    }
+
+    /// Orders peers from the lowest to the highest expected time until they deliver a
+    /// block. Peers with the same score keep their order.
+    pub fn rank(&self, estimator: &PeerEstimator, peers: &mut [PeerId]) {
+        peers.sort_by(|a, b| {
+            self.cost(estimator, a)
+                .partial_cmp(&self.cost(estimator, b))
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+
+    /// Returns the index of the peer with the lowest expected time until it delivers a
+    /// block, the last one of peers with the same score.
+    pub fn best(&self, estimator: &PeerEstimator, peers: &[PeerId]) -> Option<usize> {
+        let mut best: Option<(usize, f64)> = None;
+        for (index, peer_id) in peers.iter().enumerate() {
+            let cost = self.cost(estimator, peer_id);
+            match best {
+                Some((_, best)) if cost > best => {}
+                _ => best = Some((index, cost)),
+            }
+        }
+        best.map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ledger_ranks_peers() {
+        let peers: Vec<_> = (0..4).map(|_| PeerId::random()).collect();
+        let mut ledger = PeerLedger::default();
+        let mut estimator = PeerEstimator::default();
+        let mut ranked = peers.clone();
+        ledger.rank(&estimator, &mut ranked);
+        assert_eq!(ranked, peers);
+        assert_eq!(ledger.best(&estimator, &peers), Some(3));
+
+        // fast
+        estimator.observe(peers[1], Duration::from_millis(10), 0);
+        ledger.observe_response(peers[1], true);
+        // never has the block
+        estimator.observe(peers[2], Duration::from_millis(100), 0);
+        for _ in 0..40 {
+            ledger.observe_response(peers[2], false);
+        }
+        // slower than unknown peers are assumed to be
+        estimator.observe(peers[3], Duration::from_secs(2), 0);
+        ledger.rank(&estimator, &mut ranked);
+        assert_eq!(ranked, vec![peers[1], peers[0], peers[3], peers[2]]);
+        assert_eq!(ledger.best(&estimator, &peers), Some(1));
+
+        let score = ledger.score(&peers[2]).unwrap();
+        assert_eq!(score.responses, 40);
+        assert!(score.hit_rate < 0.01);
+        assert!(ledger.score(&peers[0]).is_none());
+        assert!(ledger.score(&peers[3]).is_none());
+    }
+}
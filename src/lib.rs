@@ -5,11 +5,78 @@
 #![allow(clippy::derive_partial_eq_without_eq)]
 
 mod behaviour;
-#[cfg(feature = "compat")]
+mod bootstrap;
+mod breaker;
+mod cache;
+#[cfg(feature = "car")]
+mod car;
+#[cfg(feature = "chaos")]
+mod chaos;
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
 mod compat;
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(feature = "dagpb")]
+mod dagpb;
+pub mod deps;
+mod discovery;
+mod error;
+mod estimator;
+mod hot;
+mod ledger;
+mod limiter;
+mod policy;
+mod pool;
 mod protocol;
 mod query;
+mod record;
+#[cfg(any(test, feature = "test-util"))]
+mod replay;
+mod selector;
+mod session;
+#[cfg(feature = "sim")]
+mod sim;
+#[cfg(feature = "spill")]
+mod spill;
 mod stats;
+mod wantlist;
 
-pub use crate::behaviour::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore, Channel};
-pub use crate::query::QueryId;
\ No newline at end of file
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+pub use crate::behaviour::CompatMode;
+#[cfg(feature = "default-params")]
+pub use crate::behaviour::DefaultBitswap;
+pub use crate::behaviour::{
+    AsyncBitswapStore, Bitswap, BitswapConfig, BitswapEvent, BitswapStore, Channel, FetchingWants,
+    InsertHint, PeerSupport, Spawner, StoreDriver, WantRecord,
+};
+pub use crate::breaker::{CircuitBreaker, CircuitState};
+#[cfg(feature = "car")]
+pub use crate::car::{export_car, import_car};
+#[cfg(feature = "chaos")]
+pub use crate::chaos::{FaultAction, FaultInjector};
+#[cfg(any(feature = "compat", feature = "compat-lite"))]
+pub use crate::compat::{bitswap_pb, CompatMessage, CompatVersion};
+#[cfg(feature = "compression")]
+pub use crate::compression::Compression;
+#[cfg(feature = "dagpb")]
+pub use crate::dagpb::dagpb_references;
+pub use crate::discovery::{DiscoveredProviders, ProviderDiscovery};
+pub use crate::error::{AddrError, Error, InvalidReferences, QueryRejected};
+pub use crate::estimator::{AdaptiveTimeout, PeerEstimate};
+pub use crate::ledger::PeerScore;
+pub use crate::limiter::RateLimit;
+pub use crate::policy::{Ledger, PolicyDecision, ServerPolicy};
+pub use crate::protocol::{BitswapRequest, BitswapResponse, Capabilities, RequestType};
+pub use crate::query::{
+    ActiveQuery, CompatFallbacks, PartialSync, QueryId, QueryInfo, QueryKind, QueryStats,
+    SyncOptions,
+};
+#[cfg(feature = "test-util")]
+pub use crate::replay::replay;
+pub use crate::selector::Selector;
+pub use crate::session::SessionId;
+#[cfg(feature = "sim")]
+pub use crate::sim::{DagModel, InvariantViolation, ProviderModel, Scenario, SimReport, Simulator};
+#[cfg(feature = "spill")]
+pub use crate::spill::{FileSpillStore, SpillStore};
+pub use crate::wantlist::{WantListClosed, WantListHandle, WantToken};
\ No newline at end of file
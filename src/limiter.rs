@@ -0,0 +1,204 @@
+use crate::policy::PolicyDecision;
+use crate::stats::THROTTLED_BLOCK_RESPONSES;
+use fnv::FnvHashMap;
+use futures::prelude::*;
+use futures_timer::Delay;
+use instant::Instant;
+use libp2p::PeerId;
+use std::collections::VecDeque;
+use std::task::Context;
+use std::time::Duration;
+
+/// Number of peers with a bucket above which the full buckets are dropped. A full
+/// bucket limits a peer the same way a missing one does.
+const MAX_BUCKETS: usize = 4096;
+
+/// Rate limit of the inbound requests of each peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Requests per second a peer may send on average. Entries of a batch and of a
+    /// compat wantlist count as one request each.
+    pub rate: f64,
+    /// Requests a peer may send at once after it was idle.
+    pub burst: u32,
+    /// How requests over the limit are handled, like a decision of the server policy.
+    /// `PolicyDecision::Deny` answers that we don't have the block,
+    /// `PolicyDecision::Ignore` drops the request.
+    pub action: PolicyDecision,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            rate: 100.0,
+            burst: 200,
+            action: PolicyDecision::Deny,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets of the peers that sent requests.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: FnvHashMap<PeerId, Bucket>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the bucket of a peer. Returns `false` if the peer exceeded
+    /// the limit.
+    pub fn acquire(&mut self, peer_id: PeerId, limit: &RateLimit, now: Instant) -> bool {
+        let burst = f64::from(limit.burst.max(1));
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * limit.rate).min(burst)
+        };
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&peer_id) {
+            self.buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+        let bucket = self.buckets.entry(peer_id).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Queue releasing items at a rate of bytes per second. Up to a second worth of bytes
+/// is released at once after the queue was idle.
+pub(crate) struct Throttle<T> {
+    rate: u64,
+    /// Bytes that may be released right away. Negative after an item larger than the
+    /// budget was released, so the following items wait until it is paid off.
+    budget: f64,
+    updated: Instant,
+    queue: VecDeque<(usize, T)>,
+    timer: Option<Delay>,
+}
+
+impl<T> Throttle<T> {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            budget: rate as f64,
+            updated: Instant::now(),
+            queue: Default::default(),
+            timer: None,
+        }
+    }
+
+    /// Queues an item of `bytes` behind the queued ones.
+    pub fn push(&mut self, bytes: usize, item: T) {
+        THROTTLED_BLOCK_RESPONSES.inc();
+        self.queue.push_back((bytes, item));
+    }
+
+    /// Releases the first item if the budget allows it at `now`.
+    fn pop_at(&mut self, now: Instant) -> Option<T> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.budget = (self.budget + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+        if self.budget < 0.0 {
+            return None;
+        }
+        let (bytes, item) = self.queue.pop_front()?;
+        THROTTLED_BLOCK_RESPONSES.dec();
+        self.budget -= bytes as f64;
+        Some(item)
+    }
+
+    /// Releases the first item if the budget allows it, otherwise arms a timer waking
+    /// the task once it does.
+    pub fn poll_pop(&mut self, cx: &mut Context) -> Option<T> {
+        loop {
+            if self.queue.is_empty() {
+                self.timer = None;
+                return None;
+            }
+            if let Some(item) = self.pop_at(Instant::now()) {
+                self.timer = None;
+                return Some(item);
+            }
+            let wait = Duration::from_secs_f64(-self.budget / self.rate as f64);
+            let timer = self.timer.get_or_insert_with(|| Delay::new(wait));
+            timer.reset(wait);
+            if timer.poll_unpin(cx).is_pending() {
+                return None;
+            }
+        }
+    }
+}
+
+impl<T> Drop for Throttle<T> {
+    fn drop(&mut self) {
+        THROTTLED_BLOCK_RESPONSES.sub(self.queue.len() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limit = RateLimit {
+            rate: 10.0,
+            burst: 2,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let (peer1, peer2) = (PeerId::random(), PeerId::random());
+        let mut limiter = RateLimiter::default();
+
+        // a burst is allowed, then the bucket is empty
+        assert!(limiter.acquire(peer1, &limit, now));
+        assert!(limiter.acquire(peer1, &limit, now));
+        assert!(!limiter.acquire(peer1, &limit, now));
+        // other peers have their own bucket
+        assert!(limiter.acquire(peer2, &limit, now));
+
+        // a token is added every 100ms
+        let later = now + Duration::from_millis(150);
+        assert!(limiter.acquire(peer1, &limit, later));
+        assert!(!limiter.acquire(peer1, &limit, later));
+
+        // up to the burst
+        let idle = later + Duration::from_secs(10);
+        assert!(limiter.acquire(peer1, &limit, idle));
+        assert!(limiter.acquire(peer1, &limit, idle));
+        assert!(!limiter.acquire(peer1, &limit, idle));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::new(100);
+        let now = throttle.updated;
+        throttle.push(60, 1);
+        throttle.push(60, 2);
+        throttle.push(10, 3);
+
+        // a second worth of bytes is released right away, even if the last item
+        // overdraws the budget
+        assert_eq!(throttle.pop_at(now), Some(1));
+        assert_eq!(throttle.pop_at(now), Some(2));
+        assert_eq!(throttle.pop_at(now), None);
+
+        // the overdraft of 20 bytes is paid off after 200ms
+        assert_eq!(throttle.pop_at(now + Duration::from_millis(100)), None);
+        assert_eq!(throttle.pop_at(now + Duration::from_millis(250)), Some(3));
+        assert_eq!(throttle.pop_at(now + Duration::from_secs(10)), None);
+    }
+}
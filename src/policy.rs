@@ -0,0 +1,71 @@
+use crate::protocol::RequestType;
+use libipld::Cid;
+use libp2p::PeerId;
+
+/// Bytes exchanged with a peer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Ledger {
+    /// Total number of block bytes served to the peer.
+    pub bytes_served: u64,
+    /// Total number of valid block bytes received from the peer.
+    pub bytes_received: u64,
+    /// Number of served bytes the peer has been credited for. Moved by
+    /// `Bitswap::set_peer_watermark`.
+    pub watermark: u64,
+}
+
+impl Ledger {
+    /// Returns the number of bytes served since the watermark.
+    pub fn since_watermark(&self) -> u64 {
+        self.bytes_served.saturating_sub(self.watermark)
+    }
+
+    /// Returns the bytes served since the watermark per byte received from the peer,
+    /// like the debt ratio of go-bitswap. Peers that only take and never give have a
+    /// ratio of the bytes served to them.
+    pub fn debt_ratio(&self) -> f64 {
+        self.since_watermark() as f64 / (self.bytes_received as f64 + 1.0)
+    }
+}
+
+/// Decision of a `ServerPolicy` about an inbound request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PolicyDecision {
+    /// Serves the request from the store.
+    Serve,
+    /// Answers that we don't have the block.
+    Deny,
+    /// Holds the request until the peer's watermark moves or the request expires.
+    Defer,
+    /// Drops the request without an answer, so the peer can't tell whether we have the
+    /// block. Entries of a batched request are answered with a dont have instead, since
+    /// the rest of the batch still needs its answer.
+    Ignore,
+}
+
+/// Decides which inbound requests are served.
+pub trait ServerPolicy: Send + 'static {
+    /// Decides how to handle a request of `peer_id` given the bytes served to it so far.
+    fn on_request(
+        &mut self,
+        peer_id: &PeerId,
+        cid: &Cid,
+        ty: RequestType,
+        ledger: &Ledger,
+    ) -> PolicyDecision;
+}
+
+impl<F> ServerPolicy for F
+where
+    F: FnMut(&PeerId, &Cid, RequestType, &Ledger) -> PolicyDecision + Send + 'static,
+{
+    fn on_request(
+        &mut self,
+        peer_id: &PeerId,
+        cid: &Cid,
+        ty: RequestType,
+        ledger: &Ledger,
+    ) -> PolicyDecision {
+        self(peer_id, cid, ty, ledger)
+    }
+}
@@ -0,0 +1,209 @@
+use crate::stats::CODEC_BUFFER_BYTES;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Size of the smallest buffers of a pool.
+const MIN_CLASS_SIZE: usize = 16 * 1024;
+
+/// Buffers shared by the codecs of all connections, so that a large message doesn't
+/// allocate a buffer of its own for every connection it is exchanged on.
+///
+/// Buffers are kept in size classes of powers of two, starting at `MIN_CLASS_SIZE`. The
+/// largest class holds buffers of the maximum message size. Buffers that are returned
+/// while the pool keeps `max_idle` bytes are freed.
+#[derive(Clone, Debug)]
+pub(crate) struct BufferPool(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    max_size: usize,
+    max_idle: usize,
+    classes: Mutex<Classes>,
+}
+
+#[derive(Debug, Default)]
+struct Classes {
+    /// Unused buffers of each size class.
+    buffers: Vec<Vec<Vec<u8>>>,
+    /// Capacity of the unused buffers.
+    idle: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool for messages of up to `max_size` bytes keeping up to `max_idle`
+    /// bytes of unused buffers.
+    pub fn new(max_size: usize, max_idle: usize) -> Self {
+        let pool = Self(Arc::new(Inner {
+            max_size,
+            max_idle,
+            classes: Default::default(),
+        }));
+        let classes = pool.class(max_size) + 1;
+        pool.0
+            .classes
+            .lock()
+            .unwrap()
+            .buffers
+            .resize_with(classes, Vec::new);
+        pool
+    }
+
+    /// Returns the size of the buffers of a class.
+    fn class_size(&self, class: usize) -> usize {
+        usize::min(
+            MIN_CLASS_SIZE << class,
+            usize::max(self.0.max_size, MIN_CLASS_SIZE),
+        )
+    }
+
+    /// Returns the smallest class whose buffers hold `len` bytes, the largest class if
+    /// none does.
+    fn class(&self, len: usize) -> usize {
+        let mut class = 0;
+        while self.class_size(class) < len && MIN_CLASS_SIZE << class < self.0.max_size {
+            class += 1;
+        }
+        class
+    }
+
+    /// Takes an empty buffer holding at least `len` bytes, allocating one if the pool has
+    /// none of its class. The buffer is returned to the pool when it is dropped.
+    pub fn take(&self, len: usize) -> PooledBuffer {
+        let class = self.class(len);
+        let reused = {
+            let mut classes = self.0.classes.lock().unwrap();
+            let buffer = classes.buffers[class].pop();
+            if let Some(buffer) = &buffer {
+                classes.idle -= buffer.capacity();
+            }
+            buffer
+        };
+        let buffer = reused.unwrap_or_else(|| {
+            let buffer = Vec::with_capacity(usize::max(self.class_size(class), len));
+            CODEC_BUFFER_BYTES.add(buffer.capacity() as i64);
+            buffer
+        });
+        PooledBuffer {
+            taken: buffer.capacity(),
+            buffer,
+            pool: self.clone(),
+        }
+    }
+
+    /// Keeps a buffer for reuse, or frees it if the pool is full. `taken` is the capacity
+    /// the buffer had when it was taken.
+    fn put(&self, mut buffer: Vec<u8>, taken: usize) {
+        let capacity = buffer.capacity();
+        CODEC_BUFFER_BYTES.add(capacity as i64 - taken as i64);
+        let mut classes = self.0.classes.lock().unwrap();
+        if capacity < MIN_CLASS_SIZE || classes.idle + capacity > self.0.max_idle {
+            CODEC_BUFFER_BYTES.sub(capacity as i64);
+            return;
+        }
+        // the largest class whose size the buffer holds
+        let mut class = self.class(capacity);
+        if self.class_size(class) > capacity {
+            class -= 1;
+        }
+        buffer.clear();
+        classes.buffers[class].push(buffer);
+        classes.idle += capacity;
+    }
+
+    /// Returns the capacity of the unused buffers.
+    #[cfg(test)]
+    pub fn idle(&self) -> usize {
+        self.0.classes.lock().unwrap().idle
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let idle = self.classes.get_mut().map(|classes| classes.idle);
+        CODEC_BUFFER_BYTES.sub(idle.unwrap_or_default() as i64);
+    }
+}
+
+/// A buffer taken from a `BufferPool`, returned to it on drop.
+#[derive(Debug)]
+pub(crate) struct PooledBuffer {
+    buffer: Vec<u8>,
+    taken: usize,
+    pool: BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.pool.put(buffer, self.taken);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_size_classes() {
+        let pool = BufferPool::new(100_000, 0);
+        assert_eq!(pool.class(1), 0);
+        assert_eq!(pool.class(MIN_CLASS_SIZE), 0);
+        assert_eq!(pool.class(MIN_CLASS_SIZE + 1), 1);
+        // the largest class holds messages of the maximum size
+        assert_eq!(pool.class(100_000), 3);
+        assert_eq!(pool.class_size(3), 100_000);
+        assert_eq!(pool.0.classes.lock().unwrap().buffers.len(), 4);
+        assert!(pool.take(MIN_CLASS_SIZE + 1).capacity() >= 2 * MIN_CLASS_SIZE);
+    }
+
+    #[test]
+    fn test_pool_reuses_buffers() {
+        let pool = BufferPool::new(1024 * 1024, 2 * 1024 * 1024);
+        let mut buffer = pool.take(100_000);
+        buffer.extend_from_slice(&[1; 100_000]);
+        let ptr = buffer.as_ptr();
+        drop(buffer);
+        assert_eq!(pool.idle(), 128 * 1024);
+
+        // a buffer of the same class is reused, empty
+        let buffer = pool.clone().take(70_000);
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert!(buffer.is_empty());
+        assert_eq!(pool.idle(), 0);
+        let other = pool.take(70_000);
+        assert_ne!(other.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_pool_frees_buffers_when_full() {
+        let pool = BufferPool::new(1024 * 1024, 1024 * 1024);
+        let first = pool.take(1024 * 1024);
+        let second = pool.take(1024 * 1024);
+        drop(first);
+        drop(second);
+        assert_eq!(pool.idle(), 1024 * 1024);
+
+        // small buffers that grew are kept in the class they fill
+        let pool = BufferPool::new(1024 * 1024, 1024 * 1024);
+        let mut buffer = pool.take(0);
+        buffer.resize(3 * MIN_CLASS_SIZE, 0);
+        let capacity = buffer.capacity();
+        drop(buffer);
+        assert_eq!(pool.idle(), capacity);
+        assert!(pool.take(3 * MIN_CLASS_SIZE).capacity() >= 3 * MIN_CLASS_SIZE);
+    }
+}
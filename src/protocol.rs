@@ -1,4 +1,9 @@
-
+#[cfg(feature = "compression")]
+use crate::compression::{
+    compress_response, decompress_response, Compression, COMPRESSION_OVERHEAD,
+};
+use crate::pool::{BufferPool, PooledBuffer};
+use crate::stats::CODEC_BUFFER_BYTES;
 use async_trait::async_trait;
 use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use libipld::cid::Cid;
@@ -7,85 +12,615 @@ use libp2p::request_response::{ProtocolName, RequestResponseCodec};
 use std::convert::TryFrom;
 use std::io::{self, Write};
 use std::marker::PhantomData;
+use std::ops::BitOr;
 use thiserror::Error;
 use unsigned_varint::{aio, io::ReadError};
 
 // version codec hash size (u64 varint is max 10 bytes) + digest
 const MAX_CID_SIZE: usize = 4 * 10 + 64;
 
-#[derive(Clone, Debug)]
-pub struct BitswapProtocol;
+// u32 varint
+const MAX_PRIORITY_SIZE: usize = 5;
+
+/// Default capacity retained by a codec buffer between messages.
+pub const DEFAULT_CODEC_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Default capacity of the unused buffers kept by the pool shared by all codecs.
+pub const DEFAULT_CODEC_POOL_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default number of bytes of a response carried by each frame once it is split into
+/// chunks.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Maximum number of entries in a batch.
+pub const MAX_BATCH_SIZE: usize = 32;
+
+/// Message type of a batch, shared by requests and responses.
+const BATCH: u8 = 3;
+
+/// Added to the message type of a request that carries a priority.
+const PRIORITIZED: u8 = 4;
+
+/// Message type of a frame carrying a chunk of a response.
+const CHUNK: u8 = 8;
+
+// type + message length + offset of the chunk
+const CHUNK_HEADER_SIZE: usize = 1 + 10 + 10;
+
+// type + entry count + entries, each with a length prefix
+const MAX_BATCH_REQUEST_SIZE: usize =
+    1 + 10 + MAX_BATCH_SIZE * (10 + 1 + MAX_PRIORITY_SIZE + MAX_CID_SIZE);
+
+// type + entry count + the length prefix and type of each entry. The blocks of a batch
+// response share the block size limit.
+const BATCH_RESPONSE_OVERHEAD: usize = 1 + 10 + MAX_BATCH_SIZE * (10 + 1);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BitswapProtocol {
+    /// Original protocol without capabilities.
+    V1,
+    /// Starts each direction of a stream with the capabilities of the sender.
+    V2,
+}
 
 impl ProtocolName for BitswapProtocol {
     fn protocol_name(&self) -> &[u8] {
-        b"/ipfs-embed/bitswap/1.0.0"
+        match self {
+            Self::V1 => b"/ipfs-embed/bitswap/1.0.0",
+            Self::V2 => b"/ipfs-embed/bitswap/2.0.0",
+        }
+    }
+}
+
+/// Optional wire features of `/ipfs-embed/bitswap/2.0.0`. Both peers advertise their
+/// capabilities at the start of every stream and a feature is only used if both
+/// support it. Unknown flags of newer peers are kept but never negotiated.
+#[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Multiple wants in one request.
+    pub const BATCHING: Self = Self(1);
+    /// Cancelling wants.
+    pub const CANCEL: Self = Self(1 << 1);
+    /// Blocks split into several responses.
+    pub const CHUNKING: Self = Self(1 << 2);
+    /// Blocks of responses prefixed by a flag telling how they were compressed.
+    pub const COMPRESSION: Self = Self(1 << 3);
+    /// Requests for the size of a block.
+    pub const SIZE_QUERY: Self = Self(1 << 4);
+    /// Requests with a priority other than `BitswapRequest::DEFAULT_PRIORITY`.
+    pub const PRIORITY: Self = Self(1 << 5);
+
+    const NAMES: [(Self, &'static str); 6] = [
+        (Self::BATCHING, "batching"),
+        (Self::CANCEL, "cancel"),
+        (Self::CHUNKING, "chunking"),
+        (Self::COMPRESSION, "compression"),
+        (Self::SIZE_QUERY, "size-query"),
+        (Self::PRIORITY, "priority"),
+    ];
+
+    /// No capabilities.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Creates capabilities from their wire representation.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the wire representation.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns `true` if no capability is set.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if all capabilities of `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the capabilities set in both.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut set = f.debug_set();
+        let mut rest = self.0;
+        for (cap, name) in Self::NAMES.iter() {
+            if self.contains(*cap) {
+                set.entry(&format_args!("{}", name));
+                rest &= !cap.0;
+            }
+        }
+        if rest != 0 {
+            set.entry(&format_args!("{:#x}", rest));
+        }
+        set.finish()
+    }
+}
+
+/// A message with the capabilities its sender advertised, `None` if it was received
+/// over `/ipfs-embed/bitswap/1.0.0`. The capabilities are ignored when writing, the
+/// codec always advertises its own.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Negotiated<T> {
+    pub msg: T,
+    pub caps: Option<Capabilities>,
+}
+
+impl<T> From<T> for Negotiated<T> {
+    fn from(msg: T) -> Self {
+        Self { msg, caps: None }
+    }
+}
+
+impl<T> From<T> for Negotiated<Batch<T>> {
+    fn from(msg: T) -> Self {
+        Self {
+            msg: Batch::One(msg),
+            caps: None,
+        }
+    }
+}
+
+/// Wants or responses carried by a message. Batches are only sent on streams that
+/// negotiated `Capabilities::BATCHING` and are answered by a batch with a response for
+/// each want, in order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Batch<T> {
+    /// A single entry, encoded like on `/ipfs-embed/bitswap/1.0.0`.
+    One(T),
+    /// Up to `MAX_BATCH_SIZE` entries.
+    Many(Vec<T>),
+}
+
+/// Entry of a message.
+pub(crate) trait Entry: Sized {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self>;
+}
+
+#[cfg(feature = "compression")]
+impl<T> Batch<T> {
+    /// Applies a fallible function to each entry.
+    pub(crate) fn try_map<U>(self, mut f: impl FnMut(T) -> io::Result<U>) -> io::Result<Batch<U>> {
+        match self {
+            Self::One(entry) => f(entry).map(Batch::One),
+            Self::Many(entries) => entries
+                .into_iter()
+                .map(f)
+                .collect::<io::Result<_>>()
+                .map(Batch::Many),
+        }
+    }
+}
+
+impl<T: Entry> Batch<T> {
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Self::One(entry) => entry.write_to(w),
+            Self::Many(entries) => {
+                let mut buf = unsigned_varint::encode::usize_buffer();
+                w.push(BATCH);
+                w.extend_from_slice(unsigned_varint::encode::usize(entries.len(), &mut buf));
+                let mut entry_buf = vec![];
+                for entry in entries {
+                    entry_buf.clear();
+                    entry.write_to(&mut entry_buf)?;
+                    w.extend_from_slice(unsigned_varint::encode::usize(entry_buf.len(), &mut buf));
+                    w.extend_from_slice(&entry_buf);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Decodes a message. Batches are rejected unless `batching` was negotiated.
+    pub(crate) fn from_bytes(bytes: &[u8], batching: bool) -> io::Result<Self> {
+        if bytes.first() != Some(&BATCH) {
+            return T::from_bytes(bytes).map(Self::One);
+        }
+        if !batching {
+            return Err(invalid_data(UnknownMessageType(BATCH)));
+        }
+        let (len, mut rest) = unsigned_varint::decode::usize(&bytes[1..]).map_err(invalid_data)?;
+        if len > MAX_BATCH_SIZE {
+            return Err(invalid_data(BatchTooLarge(len)));
+        }
+        let mut entries = Vec::with_capacity(len);
+        for _ in 0..len {
+            let (entry_len, tail) = unsigned_varint::decode::usize(rest).map_err(invalid_data)?;
+            if entry_len > tail.len() {
+                return Err(invalid_data(TruncatedBatch));
+            }
+            let (entry, tail) = tail.split_at(entry_len);
+            entries.push(T::from_bytes(entry)?);
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(invalid_data(TruncatedBatch));
+        }
+        Ok(Self::Many(entries))
     }
 }
 
-#[derive(Clone)]
 pub struct BitswapCodec<P> {
     _marker: PhantomData<P>,
     buffer: Vec<u8>,
+    retain: usize,
+    /// Buffers of responses larger than `retain`, shared with the clones of the codec.
+    pool: BufferPool,
+    /// Bytes of a response sent per frame if `Capabilities::CHUNKING` was negotiated.
+    chunk_size: usize,
+    /// Capabilities advertised on 2.0.0 streams.
+    caps: Capabilities,
+    /// Capabilities the remote advertised on the current stream.
+    remote: Option<Capabilities>,
+    /// Compresses the blocks of responses on streams that negotiated
+    /// `Capabilities::COMPRESSION`.
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
 }
 
-impl<P: StoreParams> Default for BitswapCodec<P> {
-    fn default() -> Self {
-        let capacity = usize::max(P::MAX_BLOCK_SIZE, MAX_CID_SIZE) + 1;
-        debug_assert!(capacity <= u32::MAX as usize);
+impl<P: StoreParams> BitswapCodec<P> {
+    /// Creates a codec retaining at most `retain` bytes of buffer between messages.
+    /// The buffer grows on demand up to the maximum message size. Larger responses use
+    /// buffers of a pool that frees them after use, see `with_pool`.
+    pub fn new(retain: usize) -> Self {
+        let max_capacity = usize::max(max_response_size::<P>(true), MAX_BATCH_REQUEST_SIZE);
+        debug_assert!(max_capacity <= u32::MAX as usize);
+        let retain = usize::min(retain, max_capacity);
+        let buffer = Vec::with_capacity(retain);
+        CODEC_BUFFER_BYTES.add(buffer.capacity() as i64);
         Self {
             _marker: PhantomData,
-            buffer: Vec::with_capacity(capacity),
+            buffer,
+            retain,
+            pool: BufferPool::new(max_response_size::<P>(true), 0),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            caps: Capabilities::empty(),
+            remote: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+        }
+    }
+
+    /// Reads and writes responses larger than the retained buffer with buffers of a
+    /// pool shared by the clones of the codec, keeping up to `max_idle` bytes of
+    /// buffers for reuse.
+    pub fn with_pool(mut self, max_idle: usize) -> Self {
+        self.pool = BufferPool::new(max_response_size::<P>(true), max_idle);
+        self
+    }
+
+    /// Advertises `caps` on 2.0.0 streams.
+    pub fn with_capabilities(mut self, caps: Capabilities) -> Self {
+        self.caps = caps;
+        self
+    }
+
+    /// Splits responses larger than `chunk_size` bytes into frames of `chunk_size` bytes
+    /// on streams that negotiated `Capabilities::CHUNKING`.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = usize::max(chunk_size, 1);
+        self
+    }
+
+    /// Compresses the blocks of responses on streams that negotiated
+    /// `Capabilities::COMPRESSION`. Without compression they are sent as is, but blocks
+    /// compressed by the remote are still read.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Returns the largest response accepted on a stream.
+    fn max_response_size(&self) -> usize {
+        let batching = self.negotiated().contains(Capabilities::BATCHING);
+        #[cfg(feature = "compression")]
+        if self.compressed() {
+            // every block of a batch carries its own flag
+            let blocks = if batching { MAX_BATCH_SIZE } else { 1 };
+            return max_response_size::<P>(batching) + blocks * COMPRESSION_OVERHEAD;
+        }
+        max_response_size::<P>(batching)
+    }
+
+    /// Returns `true` if the blocks of responses on the current stream are compressed.
+    #[cfg(feature = "compression")]
+    fn compressed(&self) -> bool {
+        self.negotiated().contains(Capabilities::COMPRESSION)
+    }
+
+    /// Returns the capabilities both sides of the current stream support. Wire features
+    /// must only be used if they were negotiated.
+    pub fn negotiated(&self) -> Capabilities {
+        self.remote
+            .map(|remote| remote.intersection(self.caps))
+            .unwrap_or_default()
+    }
+
+    /// Reads the capabilities of the remote on a 2.0.0 stream.
+    async fn read_capabilities<T>(
+        &mut self,
+        protocol: &BitswapProtocol,
+        io: &mut T,
+    ) -> io::Result<()>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        if *protocol == BitswapProtocol::V2 {
+            let bits = aio::read_u64(&mut *io).await.map_err(|e| match e {
+                ReadError::Io(e) => e,
+                err => other(err),
+            })?;
+            self.remote = Some(Capabilities::from_bits(bits));
         }
+        Ok(())
+    }
+
+    /// Writes our capabilities on a 2.0.0 stream.
+    async fn write_capabilities<T>(&self, protocol: &BitswapProtocol, io: &mut T) -> io::Result<()>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        if *protocol == BitswapProtocol::V2 {
+            let mut buf = unsigned_varint::encode::u64_buffer();
+            io.write_all(unsigned_varint::encode::u64(self.caps.bits(), &mut buf))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Writes our capabilities on a 2.0.0 stream followed by a message, unless the
+    /// message is larger than `max_len`. Messages larger than `chunk_size` are split
+    /// into chunks if the stream negotiated chunking.
+    async fn write_message<T>(
+        &self,
+        protocol: &BitswapProtocol,
+        io: &mut T,
+        msg: &[u8],
+        max_len: usize,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        if msg.len() > max_len {
+            return Err(invalid_data(MessageTooLarge(msg.len())));
+        }
+        self.write_capabilities(protocol, io).await?;
+        if msg.len() > self.chunk_size && self.negotiated().contains(Capabilities::CHUNKING) {
+            return self.write_chunks(io, msg).await;
+        }
+        let mut buf = unsigned_varint::encode::u32_buffer();
+        io.write_all(unsigned_varint::encode::u32(msg.len() as u32, &mut buf))
+            .await?;
+        io.write_all(msg).await?;
+        Ok(())
+    }
+
+    /// Writes a message as frames of `chunk_size` bytes, each preceded by the length of
+    /// the message and the offset of the chunk.
+    async fn write_chunks<T>(&self, io: &mut T, msg: &[u8]) -> io::Result<()>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        let mut total_buf = unsigned_varint::encode::usize_buffer();
+        let total = unsigned_varint::encode::usize(msg.len(), &mut total_buf);
+        for (i, chunk) in msg.chunks(self.chunk_size).enumerate() {
+            let mut offset_buf = unsigned_varint::encode::usize_buffer();
+            let offset = unsigned_varint::encode::usize(i * self.chunk_size, &mut offset_buf);
+            let frame_len = 1 + total.len() + offset.len() + chunk.len();
+            let mut buf = unsigned_varint::encode::u32_buffer();
+            io.write_all(unsigned_varint::encode::u32(frame_len as u32, &mut buf))
+                .await?;
+            io.write_all(&[CHUNK]).await?;
+            io.write_all(total).await?;
+            io.write_all(offset).await?;
+            io.write_all(chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Decodes a response from its first frame, reading the remaining chunks first if it
+    /// was split into chunks.
+    async fn decode_response<T>(
+        &self,
+        io: &mut T,
+        frame: &[u8],
+        max_len: usize,
+    ) -> io::Result<Batch<BitswapResponse>>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        let negotiated = self.negotiated();
+        let batching = negotiated.contains(Capabilities::BATCHING);
+        if frame.first() == Some(&CHUNK) && negotiated.contains(Capabilities::CHUNKING) {
+            let msg = self.read_chunks(io, frame, max_len).await?;
+            Batch::from_bytes(&msg, batching)
+        } else {
+            Batch::from_bytes(frame, batching)
+        }
+    }
+
+    /// Reads the frames following the first chunk of a response and returns the
+    /// reassembled message.
+    async fn read_chunks<T>(
+        &self,
+        io: &mut T,
+        first: &[u8],
+        max_len: usize,
+    ) -> io::Result<PooledBuffer>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        let (total, _, _) = parse_chunk(first)?;
+        if total > max_len {
+            return Err(invalid_data(MessageTooLarge(total)));
+        }
+        let mut msg = self.pool.take(total);
+        append_chunk(&mut msg, first, total)?;
+        let mut frame = self.pool.take(first.len());
+        while msg.len() < total {
+            let frame_len = read_len(io).await?;
+            if frame_len > max_len + CHUNK_HEADER_SIZE {
+                return Err(invalid_data(MessageTooLarge(frame_len)));
+            }
+            frame.resize(frame_len, 0);
+            io.read_exact(&mut frame).await?;
+            append_chunk(&mut msg, &frame, total)?;
+        }
+        Ok(msg)
+    }
+
+    /// Runs `f` on the buffer, keeping the buffer gauge in sync with its capacity.
+    fn with_buffer<R>(&mut self, f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+        let before = self.buffer.capacity();
+        let res = f(&mut self.buffer);
+        let after = self.buffer.capacity();
+        CODEC_BUFFER_BYTES.add(after as i64 - before as i64);
+        res
+    }
+
+    /// Releases the memory of a large message, shrinking back to the retained capacity.
+    fn shrink(&mut self) {
+        let retain = self.retain;
+        if self.buffer.capacity() > retain {
+            self.with_buffer(|buffer| {
+                buffer.clear();
+                buffer.shrink_to(retain);
+            });
+        }
+    }
+}
+
+impl<P: StoreParams> Default for BitswapCodec<P> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CODEC_BUFFER_SIZE)
+    }
+}
+
+impl<P: StoreParams> Clone for BitswapCodec<P> {
+    fn clone(&self) -> Self {
+        let mut codec = Self::new(self.retain).with_capabilities(self.caps);
+        codec.pool = self.pool.clone();
+        codec.chunk_size = self.chunk_size;
+        #[cfg(feature = "compression")]
+        codec.compression = self.compression;
+        codec
+    }
+}
+
+impl<P> Drop for BitswapCodec<P> {
+    fn drop(&mut self) {
+        CODEC_BUFFER_BYTES.sub(self.buffer.capacity() as i64);
     }
 }
 
 #[async_trait]
 impl<P: StoreParams> RequestResponseCodec for BitswapCodec<P> {
     type Protocol = BitswapProtocol;
-    type Request = BitswapRequest;
-    type Response = BitswapResponse;
+    type Request = Negotiated<Batch<BitswapRequest>>;
+    type Response = Negotiated<Batch<BitswapResponse>>;
 
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Send + Unpin,
     {
-        let msg_len = u32_to_usize(aio::read_u32(&mut *io).await.map_err(|e| match e {
-            ReadError::Io(e) => e,
-            err => other(err),
-        })?);
-        if msg_len > MAX_CID_SIZE + 1 {
+        self.read_capabilities(protocol, io).await?;
+        let msg_len = read_len(io).await?;
+        let batching = self.negotiated().contains(Capabilities::BATCHING);
+        if msg_len > max_request_size(batching) {
             return Err(invalid_data(MessageTooLarge(msg_len)));
         }
-        self.buffer.resize(msg_len, 0);
+        self.with_buffer(|buffer| buffer.resize(msg_len, 0));
         io.read_exact(&mut self.buffer).await?;
-        let request = BitswapRequest::from_bytes(&self.buffer).map_err(invalid_data)?;
-        Ok(request)
+        let request = Batch::from_bytes(&self.buffer, batching);
+        self.shrink();
+        Ok(Negotiated {
+            msg: request?,
+            caps: self.remote,
+        })
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Send + Unpin,
     {
-        let msg_len = u32_to_usize(aio::read_u32(&mut *io).await.map_err(|e| match e {
-            ReadError::Io(e) => e,
-            err => other(err),
-        })?);
-        if msg_len > P::MAX_BLOCK_SIZE + 1 {
+        self.read_capabilities(protocol, io).await?;
+        let msg_len = read_len(io).await?;
+        let max_len = self.max_response_size();
+        let max_frame_len = if self.negotiated().contains(Capabilities::CHUNKING) {
+            max_len + CHUNK_HEADER_SIZE
+        } else {
+            max_len
+        };
+        if msg_len > max_frame_len {
             return Err(invalid_data(MessageTooLarge(msg_len)));
         }
-        self.buffer.resize(msg_len, 0);
-        io.read_exact(&mut self.buffer).await?;
-        let response = BitswapResponse::from_bytes(&self.buffer).map_err(invalid_data)?;
-        Ok(response)
+        let result = if msg_len > self.retain {
+            // large responses borrow a buffer of the pool
+            let mut buffer = self.pool.take(msg_len);
+            buffer.resize(msg_len, 0);
+            async {
+                io.read_exact(&mut buffer).await?;
+                self.decode_response(io, &buffer, max_len).await
+            }
+            .await
+        } else {
+            self.with_buffer(|buffer| buffer.resize(msg_len, 0));
+            let result = async {
+                io.read_exact(&mut self.buffer).await?;
+                self.decode_response(io, &self.buffer, max_len).await
+            }
+            .await;
+            self.shrink();
+            result
+        };
+        let msg = result?;
+        #[cfg(feature = "compression")]
+        let msg = if self.compressed() {
+            msg.try_map(|res| decompress_response(res, P::MAX_BLOCK_SIZE))?
+        } else {
+            msg
+        };
+        Ok(Negotiated {
+            msg,
+            caps: self.remote,
+        })
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> io::Result<()>
@@ -93,91 +628,165 @@ impl<P: StoreParams> RequestResponseCodec for BitswapCodec<P> {
         T: AsyncWrite + Send + Unpin,
     {
         self.buffer.clear();
-        req.write_to(&mut self.buffer)?;
-        if self.buffer.len() > MAX_CID_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(self.buffer.len())));
-        }
-        let mut buf = unsigned_varint::encode::u32_buffer();
-        let msg_len = unsigned_varint::encode::u32(self.buffer.len() as u32, &mut buf);
-        io.write_all(msg_len).await?;
-        io.write_all(&self.buffer).await?;
-        Ok(())
+        self.with_buffer(|buffer| req.msg.write_to(buffer))?;
+        // the capabilities of the remote aren't known yet, batches are only sent to
+        // peers that advertised batching before
+        let batching = self.caps.contains(Capabilities::BATCHING);
+        self.write_message(protocol, io, &self.buffer, max_request_size(batching))
+            .await
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Send + Unpin,
     {
-        self.buffer.clear();
-        res.write_to(&mut self.buffer)?;
-        if self.buffer.len() > P::MAX_BLOCK_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(self.buffer.len())));
+        #[cfg(feature = "compression")]
+        let res = if self.compressed() {
+            let compression = self.compression;
+            Negotiated {
+                msg: res.msg.try_map(|res| compress_response(res, compression))?,
+                caps: res.caps,
+            }
+        } else {
+            res
+        };
+        let max_len = self.max_response_size();
+        let len = res.msg.encoded_len();
+        if len > self.retain {
+            // large responses borrow a buffer of the pool
+            let mut buffer = self.pool.take(len);
+            res.msg.write_to(&mut buffer)?;
+            return self.write_message(protocol, io, &buffer, max_len).await;
         }
-        let mut buf = unsigned_varint::encode::u32_buffer();
-        let msg_len = unsigned_varint::encode::u32(self.buffer.len() as u32, &mut buf);
-        io.write_all(msg_len).await?;
-        io.write_all(&self.buffer).await?;
-        Ok(())
+        let result = async {
+            self.buffer.clear();
+            self.with_buffer(|buffer| res.msg.write_to(buffer))?;
+            self.write_message(protocol, io, &self.buffer, max_len)
+                .await
+        }
+        .await;
+        self.shrink();
+        result
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Type of a bitswap request.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum RequestType {
+    /// Asks if the peer has a block.
     Have,
+    /// Asks the peer for a block.
     Block,
 }
 
+/// A bitswap request.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct BitswapRequest {
+    /// Type of the request.
     pub ty: RequestType,
+    /// Requested block.
     pub cid: Cid,
+    /// Requests with a higher priority are answered first. Only sent over
+    /// `/ipfs-embed/bitswap` to peers that advertised `Capabilities::PRIORITY`.
+    pub priority: i32,
 }
 
 impl BitswapRequest {
-    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        match self {
-            BitswapRequest {
-                ty: RequestType::Have,
-                cid,
-            } => {
-                w.write_all(&[0])?;
-                cid.write_bytes(&mut *w).map_err(other)?;
-            }
-            BitswapRequest {
-                ty: RequestType::Block,
-                cid,
-            } => {
-                w.write_all(&[1])?;
-                cid.write_bytes(&mut *w).map_err(other)?;
-            }
+    /// Priority of requests that don't ask for one, like the default of go-ipfs.
+    pub const DEFAULT_PRIORITY: i32 = 1;
+
+    /// Creates a request with the default priority.
+    pub fn new(ty: RequestType, cid: Cid) -> Self {
+        Self {
+            ty,
+            cid,
+            priority: Self::DEFAULT_PRIORITY,
         }
+    }
+
+    /// Sets the priority of the request.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+impl Entry for BitswapRequest {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let ty = match self.ty {
+            RequestType::Have => 0,
+            RequestType::Block => 1,
+        };
+        if self.priority == Self::DEFAULT_PRIORITY {
+            w.write_all(&[ty])?;
+        } else {
+            let mut buf = unsigned_varint::encode::u32_buffer();
+            w.write_all(&[PRIORITIZED + ty])?;
+            w.write_all(unsigned_varint::encode::u32(self.priority as u32, &mut buf))?;
+        }
+        self.cid.write_bytes(&mut *w).map_err(other)?;
         Ok(())
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
-        let ty = match bytes[0] {
-            0 => RequestType::Have,
-            1 => RequestType::Block,
-            c => return Err(invalid_data(UnknownMessageType(c))),
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let ty = match bytes.first() {
+            Some(0) | Some(4) => RequestType::Have,
+            Some(1) | Some(5) => RequestType::Block,
+            Some(c) => return Err(invalid_data(UnknownMessageType(*c))),
+            None => return Err(invalid_data(EmptyMessage)),
         };
-        let cid = Cid::try_from(&bytes[1..]).map_err(invalid_data)?;
-        Ok(Self { ty, cid })
+        let mut rest = &bytes[1..];
+        let mut priority = Self::DEFAULT_PRIORITY;
+        if bytes[0] >= PRIORITIZED {
+            let (n, tail) = unsigned_varint::decode::u32(rest).map_err(invalid_data)?;
+            priority = n as i32;
+            rest = tail;
+        }
+        let cid = Cid::try_from(rest).map_err(invalid_data)?;
+        Ok(Self { ty, cid, priority })
     }
 }
 
+/// A bitswap response.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BitswapResponse {
+    /// Whether the peer has the block.
     Have(bool),
+    /// Data of the block.
     Block(Vec<u8>),
 }
 
 impl BitswapResponse {
-    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    /// Returns the length of the encoded response.
+    fn encoded_len(&self) -> usize {
+        match self {
+            Self::Have(_) => 1,
+            Self::Block(data) => 1 + data.len(),
+        }
+    }
+}
+
+impl Batch<BitswapResponse> {
+    /// Returns an upper bound of the length of the encoded message.
+    fn encoded_len(&self) -> usize {
+        match self {
+            Self::One(response) => response.encoded_len(),
+            Self::Many(responses) => {
+                // type + entry count + responses, each with a length prefix
+                let entries: usize = responses.iter().map(|res| 10 + res.encoded_len()).sum();
+                1 + 10 + entries
+            }
+        }
+    }
+}
+
+impl Entry for BitswapResponse {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
         match self {
             BitswapResponse::Have(have) => {
                 if *have {
@@ -194,16 +803,71 @@ impl BitswapResponse {
         Ok(())
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
-        let res = match bytes[0] {
-            0 | 2 => BitswapResponse::Have(bytes[0] == 0),
-            1 => BitswapResponse::Block(bytes[1..].to_vec()),
-            c => return Err(invalid_data(UnknownMessageType(c))),
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let res = match bytes.first() {
+            Some(0) => BitswapResponse::Have(true),
+            Some(2) => BitswapResponse::Have(false),
+            // a block may be empty, in which case the message is just the type
+            Some(1) => BitswapResponse::Block(bytes[1..].to_vec()),
+            Some(c) => return Err(invalid_data(UnknownMessageType(*c))),
+            None => return Err(invalid_data(EmptyMessage)),
         };
         Ok(res)
     }
 }
 
+/// Reads the length of a frame.
+async fn read_len<T>(io: &mut T) -> io::Result<usize>
+where
+    T: AsyncRead + Send + Unpin,
+{
+    let len = aio::read_u32(&mut *io).await.map_err(|e| match e {
+        ReadError::Io(e) => e,
+        err => other(err),
+    })?;
+    Ok(u32_to_usize(len))
+}
+
+/// Decodes a chunk frame into the length of the message, the offset of the chunk in the
+/// message and the chunk.
+fn parse_chunk(frame: &[u8]) -> io::Result<(usize, usize, &[u8])> {
+    if frame.first() != Some(&CHUNK) {
+        return Err(invalid_data(InvalidChunk));
+    }
+    let (total, rest) = unsigned_varint::decode::usize(&frame[1..]).map_err(invalid_data)?;
+    let (offset, chunk) = unsigned_varint::decode::usize(rest).map_err(invalid_data)?;
+    Ok((total, offset, chunk))
+}
+
+/// Appends the chunk of a frame to a message of `total` bytes. Chunks must follow each
+/// other without gaps and can't be empty.
+fn append_chunk(msg: &mut Vec<u8>, frame: &[u8], total: usize) -> io::Result<()> {
+    let (len, offset, chunk) = parse_chunk(frame)?;
+    if len != total || offset != msg.len() || chunk.is_empty() || chunk.len() > total - offset {
+        return Err(invalid_data(InvalidChunk));
+    }
+    msg.extend_from_slice(chunk);
+    Ok(())
+}
+
+/// Largest request accepted, with or without batching.
+fn max_request_size(batching: bool) -> usize {
+    if batching {
+        MAX_BATCH_REQUEST_SIZE
+    } else {
+        1 + MAX_PRIORITY_SIZE + MAX_CID_SIZE
+    }
+}
+
+/// Largest response accepted, with or without batching.
+fn max_response_size<P: StoreParams>(batching: bool) -> usize {
+    if batching {
+        P::MAX_BLOCK_SIZE + BATCH_RESPONSE_OVERHEAD
+    } else {
+        P::MAX_BLOCK_SIZE + 1
+    }
+}
+
 fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidData, e)
 }
@@ -221,14 +885,32 @@ fn u32_to_usize(n: u32) -> usize {
 #[error("unknown message type {0}")]
 pub struct UnknownMessageType(u8);
 
+#[derive(Debug, Error)]
+#[error("empty message")]
+pub struct EmptyMessage;
+
 #[derive(Debug, Error)]
 #[error("message too large {0}")]
 pub struct MessageTooLarge(usize);
 
+#[derive(Debug, Error)]
+#[error("batch of {0} entries exceeds the maximum")]
+pub struct BatchTooLarge(usize);
+
+#[derive(Debug, Error)]
+#[error("batch entries don't match the message length")]
+pub struct TruncatedBatch;
+
+#[derive(Debug, Error)]
+#[error("chunk doesn't continue the message")]
+pub struct InvalidChunk;
+
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use futures::io::Cursor;
     use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
     use multihash::MultihashDigest;
 
     pub fn create_cid(bytes: &[u8]) -> Cid {
@@ -239,16 +921,13 @@ pub(crate) mod tests {
     #[test]
     fn test_request_encode_decode() {
         let requests = [
-            BitswapRequest {
-                ty: RequestType::Have,
-                cid: create_cid(&b"have_request"[..]),
-            },
-            BitswapRequest {
-                ty: RequestType::Block,
-                cid: create_cid(&b"block_request"[..]),
-            },
+            BitswapRequest::new(RequestType::Have, create_cid(&b"have_request"[..])),
+            BitswapRequest::new(RequestType::Block, create_cid(&b"block_request"[..])),
+            BitswapRequest::new(RequestType::Have, create_cid(&b"urgent"[..])).with_priority(-7),
+            BitswapRequest::new(RequestType::Block, create_cid(&b"urgent"[..]))
+                .with_priority(i32::MAX),
         ];
-        let mut buf = Vec::with_capacity(MAX_CID_SIZE + 1);
+        let mut buf = Vec::with_capacity(max_request_size(false));
         for request in &requests {
             buf.clear();
             request.write_to(&mut buf).unwrap();
@@ -262,6 +941,7 @@ pub(crate) mod tests {
             BitswapResponse::Have(true),
             BitswapResponse::Have(false),
             BitswapResponse::Block(b"block_response".to_vec()),
+            BitswapResponse::Block(vec![]),
         ];
         let mut buf = Vec::with_capacity(13 + 1);
         for response in &responses {
@@ -270,4 +950,406 @@ pub(crate) mod tests {
             assert_eq!(&BitswapResponse::from_bytes(&buf).unwrap(), response);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decode_empty_message() {
+        assert!(BitswapRequest::from_bytes(&[]).is_err());
+        assert!(BitswapRequest::from_bytes(&[1]).is_err());
+        assert!(BitswapResponse::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn test_codec_buffer_shrinks_after_large_message() {
+        let mut codec = BitswapCodec::<DefaultParams>::new(64);
+        let response = BitswapResponse::Block(vec![42; DefaultParams::MAX_BLOCK_SIZE]);
+        let mut io = Cursor::new(Vec::new());
+        futures::executor::block_on(codec.write_response(
+            &BitswapProtocol::V1,
+            &mut io,
+            response.clone().into(),
+        ))
+        .unwrap();
+        assert!(codec.buffer.capacity() <= 64);
+
+        io.set_position(0);
+        let decoded =
+            futures::executor::block_on(codec.read_response(&BitswapProtocol::V1, &mut io))
+                .unwrap();
+        assert_eq!(decoded, response.into());
+        assert!(codec.buffer.capacity() <= 64);
+    }
+
+    #[test]
+    fn test_codec_large_blocks_reuse_pool() {
+        let mut codec = BitswapCodec::<DefaultParams>::default().with_pool(DEFAULT_CODEC_POOL_SIZE);
+        let response = BitswapResponse::Block(vec![42; DefaultParams::MAX_BLOCK_SIZE]);
+        let mut io = Cursor::new(Vec::with_capacity(DefaultParams::MAX_BLOCK_SIZE + 16));
+        let mut idle = None;
+        for _ in 0..10 {
+            io.get_mut().clear();
+            io.set_position(0);
+            futures::executor::block_on(codec.write_response(
+                &BitswapProtocol::V1,
+                &mut io,
+                response.clone().into(),
+            ))
+            .unwrap();
+            io.set_position(0);
+            let decoded =
+                futures::executor::block_on(codec.read_response(&BitswapProtocol::V1, &mut io))
+                    .unwrap();
+            assert!(matches!(decoded, Batch::One(BitswapResponse::Block(_))));
+            // the buffers of the first round trip are reused by the later ones
+            let now = codec.pool.idle();
+            assert_eq!(*idle.get_or_insert(now), now);
+            assert!(now <= DEFAULT_CODEC_POOL_SIZE);
+            assert!(codec.buffer.capacity() <= DEFAULT_CODEC_BUFFER_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_codec_shares_pool_with_clones() {
+        let codec = BitswapCodec::<DefaultParams>::new(64).with_pool(DEFAULT_CODEC_POOL_SIZE);
+        let mut writer = codec.clone();
+        let mut reader = codec.clone();
+        let response = BitswapResponse::Block(vec![42; 100_000]);
+        let mut io = Cursor::new(Vec::new());
+        futures::executor::block_on(writer.write_response(
+            &BitswapProtocol::V1,
+            &mut io,
+            response.clone().into(),
+        ))
+        .unwrap();
+        let idle = codec.pool.idle();
+        assert!(idle > 100_000);
+        assert!(writer.buffer.capacity() <= 64);
+
+        // the reader reuses the buffer the writer returned
+        io.set_position(0);
+        let decoded =
+            futures::executor::block_on(reader.read_response(&BitswapProtocol::V1, &mut io))
+                .unwrap();
+        assert_eq!(decoded, response.into());
+        assert_eq!(codec.pool.idle(), idle);
+        assert!(reader.buffer.capacity() <= 64);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_codec_compresses_blocks() {
+        let max = DefaultParams::MAX_BLOCK_SIZE;
+        let caps = Capabilities::BATCHING | Capabilities::CHUNKING | Capabilities::COMPRESSION;
+        let mut requester = BitswapCodec::<DefaultParams>::default().with_capabilities(caps);
+        let mut responder = BitswapCodec::<DefaultParams>::default()
+            .with_capabilities(caps)
+            .with_compression(Some(Compression::Lz4));
+        let compressible = BitswapResponse::Block(vec![42; max]);
+        // xorshift, so that lz4 doesn't find repetitions
+        let mut x = 0x2545_f491_u32;
+        let random = (0..max)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let incompressible = BitswapResponse::Block(random);
+        let request = BitswapRequest::new(RequestType::Block, create_cid(&b"block_request"[..]));
+        futures::executor::block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            requester
+                .write_request(&BitswapProtocol::V2, &mut io, request.into())
+                .await
+                .unwrap();
+            io.set_position(0);
+            responder
+                .read_request(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(responder.negotiated(), caps);
+
+            // the flag, the capabilities and the headers of the chunks add less than 64 bytes
+            for (response, max_len) in [(compressible, max / 100), (incompressible, max + 64)] {
+                let mut io = Cursor::new(Vec::new());
+                responder
+                    .write_response(&BitswapProtocol::V2, &mut io, response.clone().into())
+                    .await
+                    .unwrap();
+                assert!(io.get_ref().len() <= max_len);
+                io.set_position(0);
+                let received = requester
+                    .read_response(&BitswapProtocol::V2, &mut io)
+                    .await
+                    .unwrap();
+                assert_eq!(received.msg, Batch::One(response));
+            }
+
+            // batches are compressed too
+            let responses = vec![BitswapResponse::Block(vec![42; 1000]); 2];
+            let mut io = Cursor::new(Vec::new());
+            let batch = Batch::Many(responses.clone());
+            responder
+                .write_response(&BitswapProtocol::V2, &mut io, batch.into())
+                .await
+                .unwrap();
+            assert!(io.get_ref().len() < 1000);
+            io.set_position(0);
+            let received = requester
+                .read_response(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received.msg, Batch::Many(responses));
+
+            // streams that didn't negotiate compression don't compress
+            let response = BitswapResponse::Block(vec![42; 1000]);
+            let mut io = Cursor::new(Vec::new());
+            responder
+                .clone()
+                .write_response(&BitswapProtocol::V1, &mut io, response.into())
+                .await
+                .unwrap();
+            assert_eq!(io.get_ref().len(), 2 + 1 + 1000);
+        });
+    }
+
+    #[test]
+    fn test_codec_chunks_large_responses() {
+        let caps = Capabilities::CHUNKING;
+        let mut requester = BitswapCodec::<DefaultParams>::default().with_capabilities(caps);
+        let mut responder = BitswapCodec::<DefaultParams>::default()
+            .with_capabilities(caps)
+            .with_chunk_size(1000);
+        let request = BitswapRequest::new(RequestType::Block, create_cid(&b"block_request"[..]));
+        let response = BitswapResponse::Block((0..10_000).map(|i| i as u8).collect());
+        futures::executor::block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            requester
+                .write_request(&BitswapProtocol::V2, &mut io, request.into())
+                .await
+                .unwrap();
+            io.set_position(0);
+            responder
+                .read_request(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+
+            let mut io = Cursor::new(Vec::new());
+            responder
+                .write_response(&BitswapProtocol::V2, &mut io, response.clone().into())
+                .await
+                .unwrap();
+            // the capabilities are followed by the length of the first frame and its type
+            assert_eq!(io.get_ref()[3], CHUNK);
+            io.set_position(0);
+            let received = requester
+                .read_response(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received.msg, Batch::One(response.clone()));
+
+            // chunks of a message of 3 bytes, the second one with a gap
+            let chunks = |offset| vec![4, 4, CHUNK, 3, 0, 1, 5, CHUNK, 3, offset, 9, 9];
+            let mut io = Cursor::new(chunks(1));
+            let received = requester
+                .read_response(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received.msg, Batch::One(BitswapResponse::Block(vec![9, 9])));
+            let mut io = Cursor::new(chunks(2));
+            assert!(requester
+                .read_response(&BitswapProtocol::V2, &mut io)
+                .await
+                .is_err());
+
+            // peers that don't support chunking get the whole message
+            let mut legacy = BitswapCodec::<DefaultParams>::default();
+            let mut io = Cursor::new(Vec::new());
+            legacy
+                .write_request(&BitswapProtocol::V2, &mut io, request.into())
+                .await
+                .unwrap();
+            io.set_position(0);
+            responder
+                .read_request(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            let mut io = Cursor::new(Vec::new());
+            responder
+                .write_response(&BitswapProtocol::V2, &mut io, response.into())
+                .await
+                .unwrap();
+            assert_eq!(io.get_ref().len(), 1 + 2 + 10_001);
+        });
+    }
+
+    #[test]
+    fn test_codec_rejects_oversized_response() {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let response = BitswapResponse::Block(vec![0; DefaultParams::MAX_BLOCK_SIZE + 1]);
+        let mut io = Cursor::new(Vec::new());
+        let res = futures::executor::block_on(codec.write_response(
+            &BitswapProtocol::V2,
+            &mut io,
+            response.into(),
+        ));
+        assert!(res.is_err());
+        assert!(io.get_ref().is_empty());
+        assert!(codec.buffer.capacity() <= DEFAULT_CODEC_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_codec_negotiates_capabilities() {
+        let requester_caps = Capabilities::BATCHING | Capabilities::CANCEL;
+        let responder_caps = Capabilities::CANCEL | Capabilities::from_bits(1 << 40);
+        let mut requester =
+            BitswapCodec::<DefaultParams>::default().with_capabilities(requester_caps);
+        let mut responder =
+            BitswapCodec::<DefaultParams>::default().with_capabilities(responder_caps);
+        let request = BitswapRequest::new(RequestType::Block, create_cid(&b"block_request"[..]));
+        let response = BitswapResponse::Have(true);
+        futures::executor::block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            requester
+                .write_request(&BitswapProtocol::V2, &mut io, request.into())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let received = responder
+                .read_request(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received.msg, Batch::One(request));
+            assert_eq!(received.caps, Some(requester_caps));
+            assert_eq!(responder.negotiated(), Capabilities::CANCEL);
+
+            let mut io = Cursor::new(Vec::new());
+            responder
+                .write_response(&BitswapProtocol::V2, &mut io, response.clone().into())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let received = requester
+                .read_response(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received.msg, Batch::One(response));
+            assert_eq!(received.caps, Some(responder_caps));
+            assert_eq!(requester.negotiated(), Capabilities::CANCEL);
+        });
+    }
+
+    #[test]
+    fn test_codec_v1_has_no_capabilities() {
+        let mut codec =
+            BitswapCodec::<DefaultParams>::default().with_capabilities(Capabilities::BATCHING);
+        let request = BitswapRequest::new(RequestType::Have, create_cid(&b"have_request"[..]));
+        let mut buf = vec![];
+        request.write_to(&mut buf).unwrap();
+        futures::executor::block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            codec
+                .write_request(&BitswapProtocol::V1, &mut io, request.into())
+                .await
+                .unwrap();
+            // only the length prefix precedes the request
+            assert_eq!(&io.get_ref()[1..], &buf[..]);
+            io.set_position(0);
+            let received = codec
+                .read_request(&BitswapProtocol::V1, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received, request.into());
+            assert!(codec.negotiated().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_batch_encode_decode() {
+        let requests = Batch::Many(vec![
+            BitswapRequest::new(RequestType::Have, create_cid(&b"have_request"[..])),
+            BitswapRequest::new(RequestType::Block, create_cid(&b"block_request"[..])),
+        ]);
+        let responses = Batch::Many(vec![
+            BitswapResponse::Have(false),
+            BitswapResponse::Block(vec![]),
+            BitswapResponse::Block(b"block_response".to_vec()),
+        ]);
+        let mut buf = vec![];
+        requests.write_to(&mut buf).unwrap();
+        assert_eq!(Batch::from_bytes(&buf, true).unwrap(), requests);
+        assert!(Batch::<BitswapRequest>::from_bytes(&buf, false).is_err());
+        assert!(Batch::<BitswapRequest>::from_bytes(&buf[..buf.len() - 1], true).is_err());
+
+        buf.clear();
+        responses.write_to(&mut buf).unwrap();
+        assert_eq!(Batch::from_bytes(&buf, true).unwrap(), responses);
+        buf.push(0);
+        assert!(Batch::<BitswapResponse>::from_bytes(&buf, true).is_err());
+
+        let mut buf = vec![BATCH];
+        let mut len = unsigned_varint::encode::usize_buffer();
+        buf.extend_from_slice(unsigned_varint::encode::usize(MAX_BATCH_SIZE + 1, &mut len));
+        assert!(Batch::<BitswapRequest>::from_bytes(&buf, true).is_err());
+    }
+
+    #[test]
+    fn test_codec_batches_if_negotiated() {
+        let mut requester =
+            BitswapCodec::<DefaultParams>::default().with_capabilities(Capabilities::BATCHING);
+        let mut responder =
+            BitswapCodec::<DefaultParams>::default().with_capabilities(Capabilities::BATCHING);
+        let mut legacy = BitswapCodec::<DefaultParams>::default();
+        let request = Batch::Many(
+            (0..MAX_BATCH_SIZE)
+                .map(|i| BitswapRequest::new(RequestType::Block, create_cid(&i.to_le_bytes())))
+                .collect(),
+        );
+        // the blocks of a batch share the block size limit
+        let response = Batch::Many(vec![
+            BitswapResponse::Block(vec![1; DefaultParams::MAX_BLOCK_SIZE / 2]),
+            BitswapResponse::Block(vec![2; DefaultParams::MAX_BLOCK_SIZE / 2]),
+            BitswapResponse::Have(true),
+        ]);
+        futures::executor::block_on(async {
+            let mut io = Cursor::new(Vec::new());
+            let msg = Negotiated::from(request.clone());
+            requester
+                .write_request(&BitswapProtocol::V2, &mut io, msg.clone())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let received = responder
+                .read_request(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received.msg, request);
+
+            io.set_position(0);
+            assert!(legacy
+                .read_request(&BitswapProtocol::V2, &mut io)
+                .await
+                .is_err());
+
+            let mut io = Cursor::new(Vec::new());
+            responder
+                .write_response(&BitswapProtocol::V2, &mut io, response.clone().into())
+                .await
+                .unwrap();
+            io.set_position(0);
+            let received = requester
+                .read_response(&BitswapProtocol::V2, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(received.msg, response);
+        });
+    }
+
+    #[test]
+    fn test_capabilities_debug() {
+        let caps =
+            Capabilities::BATCHING | Capabilities::SIZE_QUERY | Capabilities::from_bits(1 << 8);
+        assert_eq!(format!("{:?}", caps), "{batching, size-query, 0x100}");
+        assert_eq!(format!("{:?}", Capabilities::empty()), "{}");
+    }
+}
@@ -1,29 +1,344 @@
-use crate::stats::{REQUESTS_TOTAL, REQUEST_DURATION_SECONDS};
+use crate::estimator::{PeerEstimate, PeerEstimator};
+use crate::ledger::{PeerLedger, PeerScore};
+use crate::record::{Record, Recorder};
+use crate::stats::{
+    query_type_metrics, ACTIVE_QUERIES, JOINED_GETS, QUERIES_TIMED_OUT,
+    TIME_TO_FIRST_REQUEST_SECONDS, UNSUPPORTED_PROVIDERS_AVOIDED,
+};
 use fnv::{FnvHashMap, FnvHashSet};
+use instant::Instant;
 use libipld::Cid;
 use libp2p::PeerId;
-use prometheus::HistogramTimer;
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+
+/// Default number of providers a get query pulls from its provider iterator at a time.
+pub const DEFAULT_PROVIDER_BATCH: usize = 32;
+
+/// Multihash code of the identity hash, whose digest is the data itself.
+const IDENTITY: u64 = 0x00;
+
+/// Returns `true` if the data of a block is inlined in its cid, so it never needs to be
+/// fetched or served from a store.
+pub fn is_inline(cid: &Cid) -> bool {
+    cid.hash().code() == IDENTITY
+}
 
 /// Query id.
+///
+/// Ids are unique within a `Bitswap` instance. Each instance numbers its queries
+/// sequentially starting at zero and draws a random 64 bit epoch that is part of every
+/// id it assigns, so ids of different instances, e.g. across restarts, only collide with
+/// negligible probability. The sequence number still reveals how many queries, including
+/// the subqueries of gets and syncs, an instance started before.
+///
+/// The id displays as `<epoch>-<seq>` with the epoch in 16 hex digits, which `FromStr`
+/// parses back. Ids created with `From<u64>` have epoch zero and display as the bare
+/// sequence number.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct QueryId(u64);
+pub struct QueryId {
+    pub(crate) epoch: u64,
+    pub(crate) seq: u64,
+}
+
+impl From<u64> for QueryId {
+    /// Creates a query id with epoch zero, e.g. to construct events in tests. Ids of
+    /// queries started by the behaviour are unique, ids created this way are not checked.
+    fn from(seq: u64) -> Self {
+        Self { epoch: 0, seq }
+    }
+}
 
 impl std::fmt::Display for QueryId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.0.fmt(f)
+        if self.epoch == 0 {
+            write!(f, "{}", self.seq)
+        } else {
+            write!(f, "{:016x}-{}", self.epoch, self.seq)
+        }
+    }
+}
+
+impl std::str::FromStr for QueryId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('-') {
+            Some((epoch, seq)) => Ok(Self {
+                epoch: u64::from_str_radix(epoch, 16)?,
+                seq: seq.parse()?,
+            }),
+            None => Ok(Self::from(s.parse::<u64>()?)),
+        }
+    }
+}
+
+/// Returns a random nonzero epoch for the query ids of a query manager.
+fn random_epoch() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    // the keys of every `RandomState` are seeded from the os
+    RandomState::new().build_hasher().finish().max(1)
+}
+
+/// Options of a sync query. Start from `SyncOptions::default()` and set options with
+/// the `with_*` methods, new options may be added in any release.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct SyncOptions {
+    /// Verifies that the links of every received block decode and use codecs and hashes
+    /// supported by the store params. A violation fails the sync with a descriptive error
+    /// instead of leaving parts of the dag silently untraversed.
+    pub strict: bool,
+    /// Runs the sync until every block was either fetched or failed against every
+    /// provider. Instead of failing with the first block that couldn't be found, the
+    /// sync completes successfully and reports the blocks that are still missing in
+    /// `QueryStats::partial`.
+    pub partial_ok: bool,
+    /// Fetches blocks at most this many links below the blocks the sync starts with,
+    /// `Some(0)` only fetches those. The depth of a block is counted from the block that
+    /// listed it as missing, blocks in between that are already in the store aren't
+    /// counted.
+    pub max_depth: Option<u32>,
+    /// Starts at most this many gets of blocks.
+    pub max_blocks: Option<u64>,
+    /// Stops starting gets once the fetched blocks add up to this many bytes. Gets in
+    /// progress still complete, so the sync fetches a bit more.
+    pub max_bytes: Option<u64>,
+    /// Reports every block the sync fetched in a `SyncBlock` event, in depth first order
+    /// of the dag, once the block is in the store. At most this many blocks are fetched
+    /// ahead of the last reported one, the block the order waits for is always fetched.
+    /// Blocks are reported in the order the store lists them as missing, blocks that
+    /// were in the store already aren't reported. Disables speculative prefetching.
+    pub ordered_window: Option<usize>,
+}
+
+impl SyncOptions {
+    /// Sets `strict`.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets `partial_ok`.
+    pub fn with_partial_ok(mut self, partial_ok: bool) -> Self {
+        self.partial_ok = partial_ok;
+        self
+    }
+
+    /// Sets `max_depth`.
+    pub fn with_max_depth(mut self, max_depth: u32) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Sets `max_blocks`.
+    pub fn with_max_blocks(mut self, max_blocks: u64) -> Self {
+        self.max_blocks = Some(max_blocks);
+        self
+    }
+
+    /// Sets `max_bytes`.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets `ordered_window`.
+    pub fn with_ordered_window(mut self, window: usize) -> Self {
+        self.ordered_window = Some(window);
+        self
+    }
+}
+
+/// Outcome of a sync that ran with `SyncOptions::partial_ok` and couldn't fetch every
+/// block, or that reached a limit of its `SyncOptions`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct PartialSync {
+    /// Blocks that couldn't be fetched. Their children weren't traversed, so they can be
+    /// passed as `missing` blocks to a later sync to resume it.
+    pub missing: Vec<Cid>,
+    /// Blocks that weren't fetched because a limit was reached. They can be passed as
+    /// `missing` blocks to a later sync too.
+    pub truncated: Vec<Cid>,
+    /// Number of blocks fetched by the sync.
+    pub fetched: u64,
+}
+
+/// Progress of a get or sync query.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct QueryInfo {
+    /// Number of blocks known to be missing. More may be discovered once they arrive.
+    pub missing: usize,
+    /// Number of blocks a sync discovered so far, including the ones it started with.
+    /// Never decreases.
+    pub discovered: u64,
+    /// Number of blocks fetched so far. Never decreases and equals `discovered` once a
+    /// sync completes without `QueryStats::partial`.
+    pub fetched: u64,
+    /// Estimated time until a sync completes, if enough blocks were received to tell.
+    pub eta: Option<Duration>,
+    /// Bytes of the blocks peers sent for a sync, including duplicates.
+    pub bytes: u64,
+    /// Blocks of a sync that arrived after another peer had sent them.
+    pub duplicates: u64,
+    /// Number of peers that sent blocks of a sync.
+    pub peers: usize,
+    /// Requests of the query that fell back to the compat protocol.
+    pub compat: CompatFallbacks,
+    /// Time from starting the query until its first request was handed to the network.
+    /// `None` until then, e.g. while a sync waits for its missing blocks.
+    pub first_request: Option<Duration>,
+}
+
+/// Summary of a completed get or sync query, delivered with its complete event.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct QueryStats {
+    /// Time from starting the query until it completed.
+    pub elapsed: Duration,
+    /// Number of blocks peers sent, not counting duplicates and inline blocks.
+    pub blocks: u64,
+    /// Bytes of the blocks peers sent, including duplicates.
+    pub bytes: u64,
+    /// Number of peers that sent blocks.
+    pub peers: usize,
+    /// Requests retried after their provider couldn't be dialed or timed out.
+    pub retries: u64,
+    /// Requests peers answered, with the block or whether they have it.
+    pub answers: u64,
+    /// Requests that failed without an answer, e.g. because their provider couldn't be
+    /// dialed, closed the connection or didn't answer in time.
+    pub failures: u64,
+    /// Blocks a successful sync couldn't fetch or left out, see `PartialSync`. `None`
+    /// if the sync fetched the whole dag.
+    pub partial: Option<PartialSync>,
+}
+
+/// Kind of a query started by the user.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueryKind {
+    /// A get of a single block.
+    Get,
+    /// A sync of a dag.
+    Sync,
+}
+
+/// Snapshot of a get or sync query in progress, see `Bitswap::queries`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ActiveQuery {
+    /// Id of the query.
+    pub id: QueryId,
+    /// Block the get fetches or the sync starts from.
+    pub cid: Cid,
+    /// Whether the query is a get or a sync.
+    pub kind: QueryKind,
+    /// Number of subqueries in progress: requests to peers, provider lookups, missing
+    /// blocks lookups and the gets of a sync.
+    pub pending: usize,
+    /// Time since the query started. A get that joined the get of the same cid reports
+    /// the time since the joined get started.
+    pub elapsed: Duration,
+}
+
+/// Requests that were retried over the compat protocol because the peer didn't support
+/// bitswap, and how the retries ended. Always zero without the `compat` or
+/// `compat-lite` features.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CompatFallbacks {
+    /// Requests retried over the compat protocol.
+    pub started: u64,
+    /// Retried requests the peer answered.
+    pub succeeded: u64,
+    /// Retried requests that were dropped unanswered because the peer disconnected.
+    pub failed: u64,
+}
+
+/// Weight of a new estimate in the smoothed eta.
+const ETA_ALPHA: f64 = 0.25;
+
+/// Longest eta that is reported. Anything longer means the sync is stalled.
+const MAX_ETA_SECS: f64 = 365.0 * 24.0 * 3600.0;
+
+/// Estimates the time left until a sync completes from the throughput of the blocks
+/// received so far and the number of blocks known to be missing.
+#[derive(Debug, Default)]
+struct Eta {
+    started: Option<Instant>,
+    bytes: u64,
+    blocks: u64,
+    eta: Option<f64>,
+}
+
+impl Eta {
+    fn new(started: Instant) -> Self {
+        Self {
+            started: Some(started),
+            ..Default::default()
+        }
+    }
+
+    /// Records a received block.
+    fn observe(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.blocks += 1;
+    }
+
+    /// Updates the smoothed eta with the blocks that are missing at `now`. Keeps the
+    /// previous estimate if nothing is known about the throughput yet.
+    fn update(&mut self, now: Instant, missing: usize) -> Option<Duration> {
+        if missing == 0 {
+            self.eta = Some(0.0);
+            return self.get();
+        }
+        let elapsed = match self.started {
+            Some(started) => now.saturating_duration_since(started).as_secs_f64(),
+            None => return self.get(),
+        };
+        if self.bytes == 0 || elapsed <= 0.0 {
+            return self.get();
+        }
+        let throughput = self.bytes as f64 / elapsed;
+        let block_size = self.bytes as f64 / self.blocks as f64;
+        let sample = missing as f64 * block_size / throughput;
+        if !sample.is_finite() {
+            return self.get();
+        }
+        self.eta = Some(match self.eta {
+            Some(eta) => eta + ETA_ALPHA * (sample - eta),
+            None => sample,
+        });
+        self.get()
+    }
+
+    fn get(&self) -> Option<Duration> {
+        self.eta
+            .filter(|eta| *eta <= MAX_ETA_SECS)
+            .map(Duration::from_secs_f64)
     }
 }
 
 /// Request.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Request {
     /// Have query.
     Have(PeerId, Cid),
     /// Block query.
     Block(PeerId, Cid),
+    /// Have query probing if a provider is alive.
+    Probe(PeerId, Cid),
     /// Missing blocks query.
     MissingBlocks(Cid),
+    /// Inline query of a block whose data is the digest of its identity cid. Answered
+    /// without asking a peer.
+    Inline(Cid),
+    /// Provider discovery query of a get that ran out of providers.
+    Providers(Cid),
 }
 
 impl std::fmt::Display for Request {
@@ -31,20 +346,33 @@ impl std::fmt::Display for Request {
         match self {
             Self::Have(_, _) => write!(f, "have"),
             Self::Block(_, _) => write!(f, "block"),
+            Self::Probe(_, _) => write!(f, "probe"),
             Self::MissingBlocks(_) => write!(f, "missing-blocks"),
+            Self::Inline(_) => write!(f, "inline"),
+            Self::Providers(_) => write!(f, "providers"),
         }
     }
 }
 
 /// Response.
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Response {
     /// Have query.
     Have(PeerId, bool),
     /// Block query.
     Block(PeerId, bool),
+    /// A probe didn't get a response in time.
+    Unresponsive(PeerId),
+    /// A have or block query failed without an answer.
+    Failed(PeerId),
+    /// A have or block query exceeded its adaptive deadline.
+    Expired(PeerId),
     /// Missing blocks query.
     MissingBlocks(Vec<Cid>),
+    /// Inline query, whether the block was built from its cid.
+    Inline(bool),
+    /// Provider discovery query.
+    Providers(Vec<PeerId>),
 }
 
 impl std::fmt::Display for Response {
@@ -52,20 +380,30 @@ impl std::fmt::Display for Response {
         match self {
             Self::Have(_, have) => write!(f, "have {}", have),
             Self::Block(_, block) => write!(f, "block {}", block),
+            Self::Unresponsive(_) => write!(f, "unresponsive"),
+            Self::Failed(_) => write!(f, "failed"),
+            Self::Expired(_) => write!(f, "expired"),
             Self::MissingBlocks(missing) => write!(f, "missing-blocks {}", missing.len()),
+            Self::Inline(valid) => write!(f, "inline {}", valid),
+            Self::Providers(providers) => write!(f, "providers {}", providers.len()),
         }
     }
 }
 
 /// Event emitted by a query.
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum QueryEvent {
     /// A subquery to run.
     Request(QueryId, Request),
-    /// A progress event.
-    Progress(QueryId, usize),
+    /// A progress event with the number of missing blocks and the estimated time left.
+    Progress(QueryId, QueryInfo),
+    /// The next block of an ordered sync, see `SyncOptions::ordered_window`.
+    SyncBlock(QueryId, Cid),
     /// Complete event.
-    Complete(QueryId, Result<(), Cid>),
+    Complete(QueryId, Result<(), Cid>, QueryStats),
+    /// The deadline of a query passed and it was canceled. Emitted instead of a complete
+    /// event.
+    Timeout(QueryId, QueryStats),
 }
 
 #[derive(Debug)]
@@ -78,18 +416,62 @@ pub struct Header {
     pub parent: Option<QueryId>,
     /// Cid.
     pub cid: Cid,
-    /// Timer.
-    pub timer: HistogramTimer,
+    /// Start time.
+    pub started: Instant,
     /// Type.
     pub label: &'static str,
+    /// Blocks peers sent for a get or sync query that wasn't started by a sync.
+    transfer: Transfer,
+}
+
+impl Header {
+    /// Returns the stats of a get or sync query that completed.
+    fn stats(&self) -> QueryStats {
+        QueryStats {
+            elapsed: self.started.elapsed(),
+            blocks: self.transfer.blocks,
+            bytes: self.transfer.bytes,
+            peers: self.transfer.peers.len(),
+            retries: self.transfer.retries,
+            answers: self.transfer.answers,
+            failures: self.transfer.failures,
+            partial: None,
+        }
+    }
+}
+
+/// Blocks peers sent for a query and the retries it took, see `QueryStats`. Not
+/// recorded, since they are only reported.
+#[derive(Debug, Default)]
+struct Transfer {
+    blocks: u64,
+    bytes: u64,
+    duplicates: u64,
+    retries: u64,
+    answers: u64,
+    failures: u64,
+    peers: FnvHashSet<PeerId>,
 }
 
 impl Drop for Header {
     fn drop(&mut self) {
-        REQUESTS_TOTAL.with_label_values(&[self.label]).inc();
+        let metrics = query_type_metrics(self.label);
+        metrics.requests.inc();
+        // `HistogramTimer` reads `std::time::Instant` which panics on wasm
+        metrics
+            .duration
+            .observe(self.started.elapsed().as_secs_f64());
     }
 }
 
+/// Records the duration of a get that joined another get, which has no header of its
+/// own, from when it joined.
+fn observe_joined_get(started: Instant) {
+    query_type_metrics("get")
+        .duration
+        .observe(started.elapsed().as_secs_f64());
+}
+
 /// Query.
 #[derive(Debug)]
 struct Query {
@@ -99,25 +481,288 @@ struct Query {
     state: State,
 }
 
+// gets and syncs are both large and moved through their transitions by value, boxing
+// either would allocate on every transition
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 enum State {
     None,
     Get(GetState),
     Sync(SyncState),
 }
 
+/// Peers in the order they were added, each at most once. Provider iterators can
+/// contain duplicates, e.g. from duplicated dht records.
+#[derive(Debug, Default)]
+struct Providers {
+    order: Vec<PeerId>,
+    set: FnvHashSet<PeerId>,
+}
+
+impl Providers {
+    /// Adds a peer. Returns `false` if it was already added.
+    fn insert(&mut self, peer_id: PeerId) -> bool {
+        if self.set.insert(peer_id) {
+            self.order.push(peer_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Removes the peer the ledger ranks best, the last added one of peers ranked the
+    /// same.
+    fn pop_best(&mut self, ledger: &PeerLedger, estimator: &PeerEstimator) -> Option<PeerId> {
+        let peer_id = self.order.remove(ledger.best(estimator, &self.order)?);
+        self.set.remove(&peer_id);
+        Some(peer_id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.order.iter().copied()
+    }
+}
+
+impl std::iter::FromIterator<PeerId> for Providers {
+    fn from_iter<I: IntoIterator<Item = PeerId>>(iter: I) -> Self {
+        let mut providers = Self::default();
+        for peer_id in iter {
+            providers.insert(peer_id);
+        }
+        providers
+    }
+}
+
+/// Providers of a get query that weren't pulled yet.
+struct MoreProviders(Box<dyn Iterator<Item = PeerId> + Send>);
+
+impl std::fmt::Debug for MoreProviders {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("MoreProviders")
+    }
+}
+
 #[derive(Debug, Default)]
 struct GetState {
     have: FnvHashSet<QueryId>,
     block: Option<QueryId>,
-    providers: Vec<PeerId>,
+    /// Peers with an in progress have, probe or block query. A peer is never asked twice
+    /// at the same time.
+    asked: FnvHashSet<PeerId>,
+    providers: Providers,
+    parked: Providers,
+    /// Providers known not to support bitswap. Only asked once every other provider
+    /// failed.
+    unsupported: Providers,
+    /// Providers that are only pulled once every pulled provider failed, with the get
+    /// they were passed to.
+    more: VecDeque<(QueryId, MoreProviders)>,
+    /// Peers the block was requested from so far.
+    tried: FnvHashSet<PeerId>,
+    /// Peers whose request exceeded its adaptive deadline. They are only parked once.
+    expired: FnvHashSet<PeerId>,
+    /// In progress provider discovery query.
+    discovery: Option<QueryId>,
+    /// Number of provider discovery queries started.
+    rounds: usize,
+    /// Block whose missing blocks listed the block of a get started by a sync.
+    parent_block: Option<Cid>,
+    compat: CompatFallbacks,
+    first_request: Option<Duration>,
 }
 
 #[derive(Debug, Default)]
 struct SyncState {
     missing: FnvHashSet<QueryId>,
     children: FnvHashSet<QueryId>,
-    providers: Vec<PeerId>,
+    providers: Providers,
+    options: SyncOptions,
+    failed: Vec<Cid>,
+    /// Blocks that weren't fetched because a limit of the options was reached.
+    truncated: Vec<Cid>,
+    /// Blocks whose gets were started before their missing blocks query listed them.
+    prefetched: FnvHashSet<Cid>,
+    /// Blocks waiting for a get to be started, with the block that listed them and
+    /// their depth.
+    queued: VecDeque<(Cid, Option<Cid>, u32)>,
+    /// Depth of the blocks whose gets were started, only tracked with a `max_depth`.
+    depths: FnvHashMap<Cid, u32>,
+    /// Order the blocks are reported in, only tracked with an `ordered_window`.
+    ordered: Option<Ordered>,
+    discovered: u64,
+    fetched: u64,
+    /// Bytes of the received blocks, see `QueryManager::observe_block`.
+    bytes: u64,
+    eta: Eta,
+    compat: CompatFallbacks,
+    first_request: Option<Duration>,
+}
+
+impl SyncState {
+    /// Returns the number of blocks being fetched or waiting to be fetched.
+    fn pending(&self) -> usize {
+        self.missing.len() + self.queued.len()
+    }
+
+    /// Returns the depth of a block whose get was started, zero if it is unknown.
+    fn depth(&self, cid: &Cid) -> u32 {
+        self.depths.get(cid).copied().unwrap_or_default()
+    }
+
+    /// Returns `true` if a block at `depth` can't be fetched without exceeding a limit
+    /// of the options.
+    fn exceeds_limits(&self, depth: u32) -> bool {
+        let options = &self.options;
+        matches!(options.max_depth, Some(max) if depth > max)
+            || matches!(options.max_blocks, Some(max) if self.discovered >= max)
+            || matches!(options.max_bytes, Some(max) if self.bytes >= max)
+    }
+
+    /// Returns the index of the queued get to start next. Ordered syncs hold back gets
+    /// while their window is full, except for the get of the block the order waits for.
+    fn next_queued(&self) -> Option<usize> {
+        if self.queued.is_empty() {
+            return None;
+        }
+        let ordered = if let Some(ordered) = self.ordered.as_ref() {
+            ordered
+        } else {
+            return Some(0);
+        };
+        let next = ordered.next();
+        if let Some(index) = self
+            .queued
+            .iter()
+            .position(|(cid, _, _)| Some(*cid) == next)
+        {
+            Some(index)
+        } else if !self.window_full() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the get of a block has to wait for the window of an ordered
+    /// sync.
+    fn holds_back(&self, cid: &Cid) -> bool {
+        match self.ordered.as_ref() {
+            Some(ordered) => self.window_full() && ordered.next() != Some(*cid),
+            None => false,
+        }
+    }
+
+    /// Returns `true` if an ordered sync fetched its window of blocks ahead of the last
+    /// reported one. Blocks count from starting their get until they are reported.
+    fn window_full(&self) -> bool {
+        match (self.ordered.as_ref(), self.options.ordered_window) {
+            (Some(ordered), Some(window)) => {
+                self.missing.len() + self.children.len() + ordered.ready.len() >= window.max(1)
+            }
+            _ => false,
+        }
+    }
+
+    fn info(&self, hdr: &Header) -> QueryInfo {
+        QueryInfo {
+            missing: self.pending(),
+            discovered: self.discovered,
+            fetched: self.fetched,
+            eta: self.eta.get(),
+            bytes: hdr.transfer.bytes,
+            duplicates: hdr.transfer.duplicates,
+            peers: hdr.transfer.peers.len(),
+            compat: self.compat,
+            first_request: self.first_request,
+        }
+    }
+}
+
+/// Depth first order of the blocks of an ordered sync. A block is reported once its
+/// missing blocks are known, which also tells that it is in the store, and every block
+/// before it was reported.
+#[derive(Debug, Default)]
+struct Ordered {
+    /// Blocks that weren't reported yet, the listed missing blocks of each reported block
+    /// on top of the blocks after it.
+    stack: Vec<VecDeque<Cid>>,
+    /// Missing blocks of the blocks that are in the store but weren't reported yet.
+    ready: FnvHashMap<Cid, Vec<Cid>>,
+    /// Blocks that are passed over without reporting them: failed and truncated blocks
+    /// and the root if the sync didn't fetch it.
+    silent: FnvHashSet<Cid>,
+    /// Blocks that were reported or passed over, so blocks listed twice are reported at
+    /// the first position the order reaches.
+    done: FnvHashSet<Cid>,
+    /// Blocks whose gets were started or queued, so blocks listed twice are fetched once.
+    listed: FnvHashSet<Cid>,
+}
+
+impl Ordered {
+    fn new(first: impl IntoIterator<Item = Cid>) -> Self {
+        Self {
+            stack: vec![first.into_iter().collect()],
+            ..Default::default()
+        }
+    }
+
+    /// Returns the block the order waits for.
+    fn next(&self) -> Option<Cid> {
+        self.stack
+            .iter()
+            .rev()
+            .flat_map(|blocks| blocks.iter())
+            .find(|cid| !self.done.contains(cid))
+            .copied()
+    }
+
+    /// Records the missing blocks of a block in the store.
+    fn insert(&mut self, cid: Cid, missing: Vec<Cid>) {
+        if !self.done.contains(&cid) {
+            self.ready.insert(cid, missing);
+        }
+    }
+
+    /// Passes over a block that won't be fetched.
+    fn skip(&mut self, cid: Cid) {
+        self.silent.insert(cid);
+        self.insert(cid, vec![]);
+    }
+
+    /// Returns the blocks that can be reported, in order.
+    fn advance(&mut self) -> Vec<Cid> {
+        let mut reported = vec![];
+        while let Some(blocks) = self.stack.last_mut() {
+            let cid = if let Some(cid) = blocks.front() {
+                *cid
+            } else {
+                self.stack.pop();
+                continue;
+            };
+            if self.done.contains(&cid) {
+                blocks.pop_front();
+                continue;
+            }
+            let missing = if let Some(missing) = self.ready.remove(&cid) {
+                missing
+            } else {
+                break;
+            };
+            blocks.pop_front();
+            self.done.insert(cid);
+            if !self.silent.remove(&cid) {
+                reported.push(cid);
+            }
+            if !missing.is_empty() {
+                self.stack.push(missing.into());
+            }
+        }
+        reported
+    }
 }
 
 enum Transition<S, C> {
@@ -125,14 +770,416 @@ enum Transition<S, C> {
     Complete(C),
 }
 
+/// Gets that joined a get of the same cid in progress instead of fetching the block
+/// again. The get they joined completes all of them.
+#[derive(Debug, Default)]
+struct Listeners {
+    ids: Vec<QueryId>,
+    /// Whether the joined get was canceled or timed out itself, so it only keeps
+    /// running for the gets that joined it.
+    detached: bool,
+}
+
+/// Pending query events grouped by root query, so that cancelling a query only touches
+/// its own events.
 #[derive(Default)]
+struct EventQueue {
+    /// Root of each pending event by priority of the root, then emission order.
+    order: BTreeMap<(Reverse<i32>, u64), QueryId>,
+    /// Number of events pushed so far, orders events of the same priority.
+    seq: u64,
+    /// Pending events of each root by emission order.
+    events: FnvHashMap<QueryId, BTreeMap<u64, QueryEvent>>,
+    /// Priorities of the roots other than the default of zero.
+    priorities: FnvHashMap<QueryId, i32>,
+    /// Keys of the pending have and block requests to each peer, see `take_requests`.
+    requests: FnvHashMap<PeerId, BTreeSet<(Reverse<i32>, u64)>>,
+}
+
+/// Returns the peer of a have or block request event.
+fn request_peer(event: &QueryEvent) -> Option<PeerId> {
+    match event {
+        QueryEvent::Request(_, Request::Have(peer_id, _))
+        | QueryEvent::Request(_, Request::Block(peer_id, _)) => Some(*peer_id),
+        _ => None,
+    }
+}
+
+impl EventQueue {
+    /// Returns the key ordering the events of a root emitted at `seq`.
+    fn key(&self, root: QueryId, seq: u64) -> (Reverse<i32>, u64) {
+        let priority = self.priorities.get(&root).copied().unwrap_or_default();
+        (Reverse(priority), seq)
+    }
+
+    /// Appends an event of a root query.
+    fn push(&mut self, root: QueryId, event: QueryEvent) {
+        self.seq += 1;
+        let key = self.key(root, self.seq);
+        self.order.insert(key, root);
+        if let Some(peer_id) = request_peer(&event) {
+            self.requests.entry(peer_id).or_default().insert(key);
+        }
+        self.events.entry(root).or_default().insert(self.seq, event);
+    }
+
+    /// Sets the priority of a root query, moving its pending events ahead of the events
+    /// of roots with a lower priority.
+    fn set_priority(&mut self, root: QueryId, priority: i32) {
+        let previous = self.priorities.get(&root).copied().unwrap_or_default();
+        if priority == 0 {
+            self.priorities.remove(&root);
+        } else {
+            self.priorities.insert(root, priority);
+        }
+        if let Some(events) = self.events.get(&root) {
+            for (seq, event) in events {
+                let (old, new) = ((Reverse(previous), *seq), (Reverse(priority), *seq));
+                self.order.remove(&old);
+                self.order.insert(new, root);
+                if let Some(peer_id) = request_peer(event) {
+                    if let Some(keys) = self.requests.get_mut(&peer_id) {
+                        keys.remove(&old);
+                        keys.insert(new);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the event of a root emitted at `seq` along with its key.
+    fn remove(&mut self, root: QueryId, seq: u64) -> Option<QueryEvent> {
+        let key = self.key(root, seq);
+        self.order.remove(&key);
+        let events = self.events.get_mut(&root)?;
+        let event = events.remove(&seq)?;
+        if events.is_empty() {
+            self.events.remove(&root);
+        }
+        if let Some(peer_id) = request_peer(&event) {
+            if let Some(keys) = self.requests.get_mut(&peer_id) {
+                keys.remove(&key);
+                if keys.is_empty() {
+                    self.requests.remove(&peer_id);
+                }
+            }
+        }
+        Some(event)
+    }
+
+    /// Removes the next event, the oldest one of the roots with the highest priority.
+    fn pop(&mut self) -> Option<QueryEvent> {
+        let ((_, seq), root) = self.order.iter().next().map(|(key, root)| (*key, *root))?;
+        let event = self.remove(root, seq)?;
+        match &event {
+            QueryEvent::Complete(id, _, _) | QueryEvent::Timeout(id, _) => {
+                self.priorities.remove(id);
+            }
+            _ => {}
+        }
+        Some(event)
+    }
+
+    /// Removes up to `max` pending have and block requests to a peer, in the order they
+    /// would be popped.
+    fn take_requests(&mut self, peer_id: &PeerId, max: usize) -> Vec<(QueryId, Request)> {
+        let keys = self
+            .requests
+            .get(peer_id)
+            .into_iter()
+            .flatten()
+            .take(max)
+            .copied()
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .filter_map(|key| {
+                let root = *self.order.get(&key)?;
+                match self.remove(root, key.1) {
+                    Some(QueryEvent::Request(id, req)) => Some((id, req)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Removes the pending events of a root query, except for events reporting its
+    /// completion, which keep their place in the order.
+    fn cancel(&mut self, root: QueryId) {
+        let canceled = self
+            .events
+            .get(&root)
+            .into_iter()
+            .flatten()
+            .filter(|(_, event)| match event {
+                QueryEvent::Request(id, req) => {
+                    tracing::trace!("{} {} {} cancel", root, id, req);
+                    true
+                }
+                QueryEvent::Progress(_, _) | QueryEvent::SyncBlock(_, _) => true,
+                QueryEvent::Complete(_, _, _) | QueryEvent::Timeout(_, _) => false,
+            })
+            .map(|(seq, _)| *seq)
+            .collect::<Vec<_>>();
+        for seq in canceled {
+            self.remove(root, seq);
+        }
+        // the priority of a completion event is removed once it is popped
+        if !self.events.contains_key(&root) {
+            self.priorities.remove(&root);
+        }
+    }
+}
+
 pub struct QueryManager {
+    /// Epoch of the assigned query ids.
+    epoch: u64,
     id_counter: u64,
     queries: FnvHashMap<QueryId, Query>,
-    events: VecDeque<QueryEvent>,
+    /// Number of get and sync queries in progress that weren't started by a sync.
+    active: usize,
+    events: EventQueue,
+    /// Peers known not to support bitswap.
+    unsupported: FnvHashSet<PeerId>,
+    /// Peers whose circuit breaker is open.
+    circuit_open: FnvHashSet<PeerId>,
+    /// Records inputs and events for replaying them.
+    recorder: Option<Recorder>,
+    /// Number of providers pulled from a provider iterator at a time.
+    provider_batch: usize,
+    /// Number of provider discovery queries a get starts once it ran out of providers.
+    discovery_rounds: usize,
+    /// Deadlines of get and sync queries that weren't started by a sync.
+    deadlines: FnvHashMap<QueryId, Instant>,
+    /// Number of gets started by syncs that may be in progress at a time.
+    max_sync_gets: usize,
+    /// Number of gets started by syncs in progress.
+    sync_gets: usize,
+    /// Syncs with queued gets, in the order they take turns.
+    waiting: VecDeque<QueryId>,
+    /// Latency and bandwidth estimates of the peers requests were sent to.
+    estimator: PeerEstimator,
+    /// Hit rate of the peers requests were sent to.
+    ledger: PeerLedger,
+    /// Get in progress for each cid that wasn't started by a sync.
+    gets: FnvHashMap<Cid, QueryId>,
+    /// Gets that joined each get in progress.
+    listeners: FnvHashMap<QueryId, Listeners>,
+    /// Get each joining get joined and when it joined.
+    joined: FnvHashMap<QueryId, (QueryId, Instant)>,
+    /// Number of gets in progress for each cid, including the gets of syncs.
+    fetching: FnvHashMap<Cid, usize>,
+    /// Cids whose last get ended since `take_unfetched` was called.
+    unfetched: Vec<Cid>,
+    /// Blocks each partial sync failed to fetch, until it completes.
+    partials: FnvHashMap<QueryId, PartialSync>,
+}
+
+impl Default for QueryManager {
+    fn default() -> Self {
+        Self {
+            epoch: random_epoch(),
+            id_counter: 0,
+            queries: Default::default(),
+            active: 0,
+            events: Default::default(),
+            unsupported: Default::default(),
+            circuit_open: Default::default(),
+            recorder: None,
+            provider_batch: DEFAULT_PROVIDER_BATCH,
+            discovery_rounds: 0,
+            deadlines: Default::default(),
+            max_sync_gets: usize::MAX,
+            sync_gets: 0,
+            waiting: Default::default(),
+            estimator: Default::default(),
+            ledger: Default::default(),
+            gets: Default::default(),
+            listeners: Default::default(),
+            joined: Default::default(),
+            fetching: Default::default(),
+            unfetched: Default::default(),
+            partials: Default::default(),
+        }
+    }
+}
+
+impl Drop for QueryManager {
+    fn drop(&mut self) {
+        ACTIVE_QUERIES.sub(self.active as i64);
+    }
 }
 
 impl QueryManager {
+    /// Records every input and emitted event from now on.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+        let batch = self.provider_batch;
+        self.record(|| Record::ProviderBatch(batch));
+        let rounds = self.discovery_rounds;
+        self.record(|| Record::DiscoveryRounds(rounds));
+        let max = self.max_sync_gets;
+        self.record(|| Record::MaxSyncGets(max));
+    }
+
+    /// Sets the number of providers a get query pulls from its provider iterator at a
+    /// time. A batch of zero is treated as one.
+    pub fn set_provider_batch(&mut self, batch: usize) {
+        self.provider_batch = batch.max(1);
+        let batch = self.provider_batch;
+        self.record(|| Record::ProviderBatch(batch));
+    }
+
+    /// Sets the number of provider discovery queries a get query starts once every
+    /// provider failed before it fails. Discovered providers that were asked before are
+    /// skipped. `0` disables discovery, except for the one query of a get started
+    /// without providers.
+    pub fn set_discovery_rounds(&mut self, rounds: usize) {
+        self.discovery_rounds = rounds;
+        self.record(|| Record::DiscoveryRounds(rounds));
+    }
+
+    /// Sets the number of gets started by syncs that may be in progress at a time,
+    /// across all syncs. Further gets are queued and started as others complete, the
+    /// syncs taking turns. A limit of zero is treated as one.
+    pub fn set_max_sync_gets(&mut self, max: usize) {
+        self.max_sync_gets = max.max(1);
+        let max = self.max_sync_gets;
+        self.record(|| Record::MaxSyncGets(max));
+        self.start_queued_gets();
+    }
+
+    /// Sets the epoch of the query ids assigned from now on.
+    pub(crate) fn set_epoch(&mut self, epoch: u64) {
+        self.epoch = epoch;
+    }
+
+    /// Assigns the id of a new query.
+    fn next_id(&mut self) -> QueryId {
+        let id = QueryId {
+            epoch: self.epoch,
+            seq: self.id_counter,
+        };
+        self.id_counter += 1;
+        id
+    }
+
+    /// Returns the number of get and sync queries in progress, not counting the gets
+    /// started by a sync.
+    pub fn active_queries(&self) -> usize {
+        self.active
+    }
+
+    fn root_started(&mut self) {
+        self.active += 1;
+        ACTIVE_QUERIES.inc();
+    }
+
+    fn root_ended(&mut self, root: QueryId) {
+        self.active -= 1;
+        ACTIVE_QUERIES.dec();
+        self.deadlines.remove(&root);
+    }
+
+    fn get_started(&mut self, cid: Cid) {
+        *self.fetching.entry(cid).or_default() += 1;
+    }
+
+    fn get_ended(&mut self, cid: &Cid) {
+        if let Some(count) = self.fetching.get_mut(cid) {
+            *count -= 1;
+            if *count == 0 {
+                self.fetching.remove(cid);
+                self.unfetched.push(*cid);
+            }
+        }
+    }
+
+    /// Sets the deadline of a get or sync query that wasn't started by a sync. Once it
+    /// passed, `expire` cancels the query with a `Timeout` event. Returns `false` if the
+    /// query isn't in progress.
+    pub fn set_deadline(&mut self, id: QueryId, deadline: Instant) -> bool {
+        let root = match self.shared(id) {
+            // joined another get
+            Some(shared) if shared != id => true,
+            Some(_) => matches!(
+                self.queries.get(&id),
+                Some(query) if query.hdr.parent.is_none() && !matches!(query.state, State::None)
+            ),
+            None => false,
+        };
+        if root {
+            self.deadlines.insert(id, deadline);
+        }
+        root
+    }
+
+    /// Sets the priority of a get or sync query that wasn't started by a sync. Its
+    /// requests are emitted before the requests of queries with a lower priority, the
+    /// default priority is zero. A get that joined another get sets the priority of the
+    /// get it joined. Returns `false` if the query isn't in progress.
+    pub fn set_priority(&mut self, id: QueryId, priority: i32) -> bool {
+        self.record(|| Record::Priority(id, priority));
+        let root = match self.shared(id) {
+            Some(root) => root,
+            None => return false,
+        };
+        match self.queries.get(&root) {
+            Some(query) if query.hdr.parent.is_none() && !matches!(query.state, State::None) => {
+                self.events.set_priority(root, priority);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the earliest deadline of the in progress queries.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+
+    /// Times out the queries whose deadline passed at `now`, oldest query first.
+    pub fn expire(&mut self, now: Instant) {
+        let mut expired: Vec<QueryId> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort();
+        for id in expired {
+            self.deadlines.remove(&id);
+            self.timeout(id);
+        }
+    }
+
+    /// Cancels a query, emitting a `Timeout` event instead of a complete event. Returns
+    /// `false` if the query isn't in progress.
+    pub fn timeout(&mut self, root: QueryId) -> bool {
+        self.record(|| Record::Timeout(root));
+        let stats = self.stats(root).unwrap_or_default();
+        let removed = match self.leave(root) {
+            Some(left) => left,
+            None => self.remove_root(root),
+        };
+        if !removed {
+            return false;
+        }
+        tracing::trace!("{} {} timeout", root, root);
+        QUERIES_TIMED_OUT.inc();
+        self.events.push(root, QueryEvent::Timeout(root, stats));
+        true
+    }
+
+    /// Appends a record if recording. Recording stops when the writer fails.
+    fn record(&mut self, record: impl FnOnce() -> Record) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(&record()) {
+                tracing::warn!("stopped recording queries: {}", err);
+                self.recorder = None;
+            }
+        }
+    }
+
     /// Start a new subquery.
     fn start_query(
         &mut self,
@@ -142,25 +1189,22 @@ impl QueryManager {
         req: Request,
         label: &'static str,
     ) -> QueryId {
-        let timer = REQUEST_DURATION_SECONDS
-            .with_label_values(&[label])
-            .start_timer();
-        let id = QueryId(self.id_counter);
-        self.id_counter += 1;
+        let id = self.next_id();
         let query = Query {
             hdr: Header {
                 id,
                 root,
                 parent,
                 cid,
-                timer,
+                started: Instant::now(),
                 label,
+                transfer: Default::default(),
             },
             state: State::None,
         };
         self.queries.insert(id, query);
         tracing::trace!("{} {} {}", root, id, req);
-        self.events.push_back(QueryEvent::Request(id, req));
+        self.events.push(root, QueryEvent::Request(id, req));
         id
     }
 
@@ -180,55 +1224,194 @@ impl QueryManager {
         )
     }
 
-    /// Starts a query to determine the missing blocks of a dag.
-    fn missing_blocks(&mut self, parent: QueryId, cid: Cid) -> QueryId {
+    /// Starts a new probe query to check if a peer is alive and has a block.
+    fn probe(&mut self, root: QueryId, parent: QueryId, peer_id: PeerId, cid: Cid) -> QueryId {
         self.start_query(
-            parent,
+            root,
             Some(parent),
             cid,
-            Request::MissingBlocks(cid),
-            "missing-blocks",
+            Request::Probe(peer_id, cid),
+            "probe",
         )
     }
 
-    /// Starts a query to locate and retrieve a block. Panics if no providers are supplied.
-    pub fn get(
+    /// Starts a query to discover more providers of a block.
+    fn discover(&mut self, root: QueryId, parent: QueryId, cid: Cid) -> QueryId {
+        self.start_query(
+            root,
+            Some(parent),
+            cid,
+            Request::Providers(cid),
+            "providers",
+        )
+    }
+
+    /// Starts a query to determine the missing blocks of a dag.
+    fn missing_blocks(&mut self, parent: QueryId, cid: Cid) -> QueryId {
+        self.start_query(
+            parent,
+            Some(parent),
+            cid,
+            Request::MissingBlocks(cid),
+            "missing-blocks",
+        )
+    }
+
+    /// Starts a query to locate and retrieve a block. Without providers it starts with a
+    /// provider discovery query.
+    ///
+    /// Only a batch of providers is pulled from the iterator right away, the rest is
+    /// pulled once every pulled provider failed. Blocks inlined in their cid are never
+    /// requested from providers, see `is_inline`.
+    ///
+    /// A get of a block that another get not started by a sync is fetching joins that
+    /// get instead, adding its providers to it, and completes when it completes.
+    pub fn get(
         &mut self,
         parent: Option<QueryId>,
         cid: Cid,
-        providers: impl Iterator<Item = PeerId>,
+        providers: impl Iterator<Item = PeerId> + Send + 'static,
     ) -> QueryId {
-        let timer = REQUEST_DURATION_SECONDS
-            .with_label_values(&["get"])
-            .start_timer();
-        let id = QueryId(self.id_counter);
-        self.id_counter += 1;
+        self.get_probing(parent, cid, providers, 0)
+    }
+
+    /// Starts a query to locate and retrieve a block, probing the first `probes`
+    /// providers before requesting the block from one of them. Providers that don't
+    /// answer the probe in time and providers that weren't probed are parked and only
+    /// tried if none of the probed providers has the block.
+    pub fn get_probing(
+        &mut self,
+        parent: Option<QueryId>,
+        cid: Cid,
+        mut providers: impl Iterator<Item = PeerId> + Send + 'static,
+        probes: usize,
+    ) -> QueryId {
+        let id = self.next_id();
         let root = parent.unwrap_or(id);
         tracing::trace!("{} {} get", root, id);
+        if parent.is_none() {
+            self.root_started();
+        }
+        let inline = is_inline(&cid);
+        let batch = if inline {
+            vec![]
+        } else {
+            providers
+                .by_ref()
+                .take(self.provider_batch)
+                .collect::<Vec<_>>()
+        };
+        // gets of a sync are replayed by the sync
+        if parent.is_none() {
+            self.record(|| Record::Get {
+                id,
+                cid,
+                providers: batch.clone(),
+                probes,
+            });
+        }
+        if parent.is_none() && !inline {
+            if let Some(shared) = self.gets.get(&cid).copied() {
+                self.join(shared, id, batch, providers);
+                return id;
+            }
+            self.gets.insert(cid, id);
+        }
         let mut state = GetState::default();
-        for peer in providers {
-            if state.block.is_none() {
-                state.block = Some(self.block(root, id, peer, cid));
-            } else {
-                state.have.insert(self.have(root, id, peer, cid));
+        if inline {
+            let req = Request::Inline(cid);
+            state.block = Some(self.start_query(root, Some(id), cid, req, "inline"));
+        } else if batch.len() == self.provider_batch {
+            state
+                .more
+                .push_back((id, MoreProviders(Box::new(providers))));
+        }
+        let providers = batch;
+        let mut providers: Vec<PeerId> = providers
+            .into_iter()
+            .filter(|peer| {
+                if self.unsupported.contains(peer) {
+                    state.unsupported.insert(*peer);
+                    false
+                } else if self.circuit_open.contains(peer) {
+                    state.parked.insert(*peer);
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect();
+        if providers.is_empty() {
+            providers = std::mem::take(&mut state.parked).order;
+        }
+        if providers.is_empty() {
+            // asking an unsupported peer beats failing right away
+            providers = std::mem::take(&mut state.unsupported).order;
+        }
+        // a get started without providers asks for some even if discovery is disabled,
+        // failing once the discovery comes back empty
+        if providers.is_empty() && !inline {
+            tracing::trace!("{} {} get discovers providers", root, id);
+            state.rounds = 1;
+            state.discovery = Some(self.discover(root, id, cid));
+        }
+        let providers = providers.into_iter();
+        if probes == 0 {
+            self.request_block(root, id, cid, &mut state, providers);
+        } else {
+            for peer in providers {
+                if state.asked.contains(&peer) {
+                    continue;
+                }
+                if state.have.len() < probes {
+                    state.asked.insert(peer);
+                    state.tried.insert(peer);
+                    state.have.insert(self.probe(root, id, peer, cid));
+                } else {
+                    state.parked.insert(peer);
+                }
             }
         }
-        assert!(state.block.is_some());
+        assert!(state.block.is_some() || !state.have.is_empty() || state.discovery.is_some());
         let query = Query {
             hdr: Header {
                 id,
                 root,
                 parent,
                 cid,
-                timer,
+                started: Instant::now(),
                 label: "get",
+                transfer: Default::default(),
             },
             state: State::Get(state),
         };
         self.queries.insert(id, query);
+        self.get_started(cid);
         id
     }
 
+    /// Marks a peer as known not to support bitswap. Get queries started while a peer is
+    /// marked only ask it if none of the other providers has the block.
+    pub fn set_unsupported(&mut self, peer_id: PeerId, unsupported: bool) {
+        self.record(|| Record::Unsupported(peer_id, unsupported));
+        if unsupported {
+            self.unsupported.insert(peer_id);
+        } else {
+            self.unsupported.remove(&peer_id);
+        }
+    }
+
+    /// Marks a peer whose circuit breaker is open. Get queries started while a peer is
+    /// marked park it and only ask it if none of the other providers has the block.
+    pub fn set_circuit_open(&mut self, peer_id: PeerId, open: bool) {
+        self.record(|| Record::CircuitOpen(peer_id, open));
+        if open {
+            self.circuit_open.insert(peer_id);
+        } else {
+            self.circuit_open.remove(&peer_id);
+        }
+    }
+
     /// Starts a query to recursively retrieve a dag. The missing blocks are the first
     /// blocks that need to be retrieved.
     pub fn sync(
@@ -236,69 +1419,245 @@ impl QueryManager {
         cid: Cid,
         providers: Vec<PeerId>,
         missing: impl Iterator<Item = Cid>,
+        options: SyncOptions,
     ) -> QueryId {
-        let timer = REQUEST_DURATION_SECONDS
-            .with_label_values(&["sync"])
-            .start_timer();
-        let id = QueryId(self.id_counter);
-        self.id_counter += 1;
+        let id = self.next_id();
         tracing::trace!("{} {} sync", id, id);
-        let mut state = SyncState::default();
+        self.root_started();
+        let missing = missing.collect::<Vec<_>>();
+        self.record(|| Record::Sync {
+            id,
+            cid,
+            providers: providers.clone(),
+            missing: missing.clone(),
+            options,
+        });
+        let mut state = SyncState {
+            providers: providers.into_iter().collect(),
+            options,
+            ..Default::default()
+        };
+        if options.ordered_window.is_some() {
+            // the root is only reported if the sync fetches it
+            let mut ordered = Ordered::new(missing.iter().copied());
+            if missing.is_empty() {
+                ordered = Ordered::new(std::iter::once(cid));
+                ordered.silent.insert(cid);
+            }
+            state.ordered = Some(ordered);
+        }
         for cid in missing {
-            state
-                .missing
-                .insert(self.get(Some(id), cid, providers.iter().copied()));
+            self.sync_get(id, &mut state, cid, None, 0);
         }
-        if state.missing.is_empty() {
+        if state.pending() == 0 && state.truncated.is_empty() {
             state.children.insert(self.missing_blocks(id, cid));
         }
-        state.providers = providers;
+        let started = Instant::now();
+        state.eta = Eta::new(started);
         let query = Query {
             hdr: Header {
                 id,
                 root: id,
                 parent: None,
                 cid,
-                timer,
+                started,
                 label: "sync",
+                transfer: Default::default(),
             },
             state: State::Sync(state),
         };
         self.queries.insert(id, query);
+        // every block exceeded the limits
+        self.sync_query(id, |mgr, parent, state| {
+            if state.pending() == 0 && state.children.is_empty() {
+                mgr.complete_sync(parent, state)
+            } else {
+                Transition::Next(state)
+            }
+        });
         id
     }
 
+    /// Requests a block from the provider the ledger ranks best and asks the others if
+    /// they have it. Peers that were already asked are skipped.
+    fn request_block(
+        &mut self,
+        root: QueryId,
+        id: QueryId,
+        cid: Cid,
+        state: &mut GetState,
+        providers: impl Iterator<Item = PeerId>,
+    ) {
+        let mut providers: Vec<_> = providers.collect();
+        self.ledger.rank(&self.estimator, &mut providers);
+        for peer in providers {
+            if !state.asked.insert(peer) {
+                continue;
+            }
+            state.tried.insert(peer);
+            if state.block.is_none() {
+                state.block = Some(self.block(root, id, peer, cid));
+            } else {
+                state.have.insert(self.have(root, id, peer, cid));
+            }
+        }
+    }
+
+    /// Pulls the next batch of providers of a get query, setting aside providers known
+    /// not to support bitswap and parking providers with an open circuit. Returns `None`
+    /// once the iterator is exhausted.
+    fn more_providers(&mut self, hdr: &Header, state: &mut GetState) -> Option<Vec<PeerId>> {
+        loop {
+            let (id, more) = state.more.front_mut()?;
+            let id = *id;
+            let batch = more
+                .0
+                .by_ref()
+                .take(self.provider_batch)
+                .collect::<Vec<_>>();
+            if batch.len() < self.provider_batch {
+                state.more.pop_front();
+            }
+            if batch.is_empty() {
+                continue;
+            }
+            // pulls of a sync's gets are replayed by the sync
+            if hdr.parent.is_none() {
+                self.record(|| Record::Providers(id, batch.clone()));
+            }
+            return Some(self.admit_providers(state, batch));
+        }
+    }
+
+    /// Sets aside providers known not to support bitswap and parks providers with an
+    /// open circuit. Returns the providers to ask right away.
+    fn admit_providers(&self, state: &mut GetState, batch: Vec<PeerId>) -> Vec<PeerId> {
+        let mut providers = Vec::with_capacity(batch.len());
+        for peer_id in batch {
+            if self.unsupported.contains(&peer_id) {
+                state.unsupported.insert(peer_id);
+            } else if self.circuit_open.contains(&peer_id) {
+                state.parked.insert(peer_id);
+            } else {
+                providers.push(peer_id);
+            }
+        }
+        providers
+    }
+
     /// Cancels an in progress query.
     pub fn cancel(&mut self, root: QueryId) -> bool {
+        self.record(|| Record::Cancel(root));
+        match self.leave(root) {
+            Some(left) => left,
+            None => self.remove_root(root),
+        }
+    }
+
+    /// Returns the get answering a get or sync query that wasn't started by a sync, the
+    /// get it joined or itself. `None` once the query was canceled but keeps running for
+    /// the gets that joined it.
+    fn shared(&self, id: QueryId) -> Option<QueryId> {
+        if let Some((shared, _)) = self.joined.get(&id) {
+            return Some(*shared);
+        }
+        match self.listeners.get(&id) {
+            Some(listeners) if listeners.detached => None,
+            _ => Some(id),
+        }
+    }
+
+    /// Joins a get to the get of the same cid in progress. The providers of the joining
+    /// get are asked too, the rest of them once the providers pulled so far failed.
+    fn join(
+        &mut self,
+        shared: QueryId,
+        id: QueryId,
+        batch: Vec<PeerId>,
+        providers: impl Iterator<Item = PeerId> + Send + 'static,
+    ) {
+        tracing::trace!("{} {} get joins {}", id, id, shared);
+        JOINED_GETS.inc();
+        self.joined.insert(id, (shared, Instant::now()));
+        self.listeners.entry(shared).or_default().ids.push(id);
+        let more = batch.len() == self.provider_batch;
+        self.get_query(shared, |mgr, hdr, mut state| {
+            let batch = batch
+                .into_iter()
+                .filter(|peer_id| !state.tried.contains(peer_id))
+                .collect();
+            let batch = mgr.admit_providers(&mut state, batch);
+            mgr.request_block(hdr.root, hdr.id, hdr.cid, &mut state, batch.into_iter());
+            if more {
+                state
+                    .more
+                    .push_back((id, MoreProviders(Box::new(providers))));
+            }
+            Transition::Next(state)
+        });
+    }
+
+    /// Cancels a get that joined another get, or the get it joined while joined gets
+    /// remain. Returns `None` if the query neither joined nor was joined by a get.
+    fn leave(&mut self, id: QueryId) -> Option<bool> {
+        if let Some((shared, started)) = self.joined.remove(&id) {
+            tracing::trace!("{} {} get leaves {}", id, id, shared);
+            observe_joined_get(started);
+            self.root_ended(id);
+            self.events.cancel(id);
+            let listeners = self.listeners.get_mut(&shared)?;
+            listeners.ids.retain(|id2| *id2 != id);
+            if listeners.ids.is_empty() {
+                if listeners.detached {
+                    self.remove_root(shared);
+                } else {
+                    self.listeners.remove(&shared);
+                }
+            }
+            return Some(true);
+        }
+        let listeners = self.listeners.get_mut(&id)?;
+        if listeners.detached {
+            return Some(false);
+        }
+        tracing::trace!("{} {} get detaches", id, id);
+        listeners.detached = true;
+        self.root_ended(id);
+        Some(true)
+    }
+
+    /// Removes a root query together with its gets and pending events.
+    fn remove_root(&mut self, root: QueryId) -> bool {
         let query = if let Some(query) = self.queries.remove(&root) {
             query
         } else {
             return false;
         };
-        let queries = &self.queries;
-        self.events.retain(|event| {
-            let (id, req) = match event {
-                QueryEvent::Request(id, req) => (id, req),
-                QueryEvent::Progress(id, _) => return *id != root,
-                QueryEvent::Complete(_, _) => return true,
-            };
-            if queries.get(id).map(|q| q.hdr.root) != Some(root) {
-                return true;
-            }
-            tracing::trace!("{} {} {} cancel", root, id, req);
-            false
-        });
+        self.events.cancel(root);
+        let detached =
+            matches!(self.listeners.remove(&root), Some(listeners) if listeners.detached);
+        if self.gets.get(&query.hdr.cid) == Some(&root) {
+            self.gets.remove(&query.hdr.cid);
+        }
+        if query.hdr.parent.is_none() && !matches!(query.state, State::None) && !detached {
+            self.root_ended(root);
+        }
         match query.state {
             State::Get(_) => {
                 tracing::trace!("{} {} get cancel", root, root);
+                self.get_ended(&query.hdr.cid);
                 true
             }
             State::Sync(state) => {
+                self.sync_gets -= state.missing.len();
                 for id in state.missing {
                     tracing::trace!("{} {} get cancel", root, id);
-                    self.queries.remove(&id);
+                    if let Some(get) = self.queries.remove(&id) {
+                        self.get_ended(&get.hdr.cid);
+                    }
                 }
                 tracing::trace!("{} {} sync cancel", root, root);
+                self.start_queued_gets();
                 true
             }
             State::None => {
@@ -329,6 +1688,7 @@ impl QueryManager {
                         Ok(()) => tracing::trace!("{} {} get ok", parent.hdr.root, parent.hdr.id),
                         Err(_) => tracing::trace!("{} {} get err", parent.hdr.root, parent.hdr.id),
                     }
+                    self.get_ended(&parent.hdr.cid);
                     self.recv_get(parent.hdr, res);
                 }
             }
@@ -372,38 +1732,129 @@ impl QueryManager {
     fn recv_have(&mut self, query: Header, peer_id: PeerId, have: bool) {
         self.get_query(query.parent.unwrap(), |mgr, parent, mut state| {
             state.have.remove(&query.id);
+            state.asked.remove(&peer_id);
             if state.block == Some(query.id) {
                 state.block = None;
             }
             if have {
-                state.providers.push(peer_id);
-            }
-            if state.block.is_none() && !state.providers.is_empty() {
-                state.block = Some(mgr.block(
-                    parent.root,
-                    parent.id,
-                    state.providers.pop().unwrap(),
-                    query.cid,
-                ));
-            }
-            if state.have.is_empty() && state.block.is_none() && state.providers.is_empty() {
-                if state.providers.is_empty() {
-                    return Transition::Complete(Err(query.cid));
-                } else {
-                    return Transition::Complete(Ok(()));
+                state.providers.insert(peer_id);
+            }
+            if state.block.is_none() {
+                if let Some(peer_id) = state.providers.pop_best(&mgr.ledger, &mgr.estimator) {
+                    state.asked.insert(peer_id);
+                    state.block = Some(mgr.block(parent.root, parent.id, peer_id, query.cid));
                 }
             }
-            Transition::Next(state)
+            mgr.ask_more_providers(parent, query.cid, state)
         });
     }
 
+    /// Asks more providers of a get query once no request is in progress: the next
+    /// batch of providers, then the parked ones, then the ones known not to support
+    /// bitswap and finally discovered ones. Fails the get if none is left.
+    fn ask_more_providers(
+        &mut self,
+        parent: &Header,
+        cid: Cid,
+        mut state: GetState,
+    ) -> Transition<GetState, Result<(), Cid>> {
+        while state.have.is_empty() && state.block.is_none() {
+            let providers = if let Some(providers) = self.more_providers(parent, &mut state) {
+                providers
+            } else {
+                break;
+            };
+            tracing::trace!("{} {} get pulls more providers", parent.root, parent.id);
+            self.request_block(
+                parent.root,
+                parent.id,
+                cid,
+                &mut state,
+                providers.into_iter(),
+            );
+        }
+        if state.have.is_empty() && state.block.is_none() && !state.parked.is_empty() {
+            tracing::trace!("{} {} get unparks providers", parent.root, parent.id);
+            let parked = std::mem::take(&mut state.parked);
+            self.request_block(
+                parent.root,
+                parent.id,
+                cid,
+                &mut state,
+                parked.order.into_iter(),
+            );
+        }
+        if state.have.is_empty() && state.block.is_none() && !state.unsupported.is_empty() {
+            tracing::trace!(
+                "{} {} get asks unsupported providers",
+                parent.root,
+                parent.id
+            );
+            let unsupported = std::mem::take(&mut state.unsupported);
+            self.request_block(
+                parent.root,
+                parent.id,
+                cid,
+                &mut state,
+                unsupported.order.into_iter(),
+            );
+        }
+        let idle = state.have.is_empty()
+            && state.block.is_none()
+            && state.discovery.is_none()
+            && state.providers.is_empty();
+        if idle && state.rounds < self.discovery_rounds {
+            tracing::trace!("{} {} get discovers providers", parent.root, parent.id);
+            state.rounds += 1;
+            state.discovery = Some(self.discover(parent.root, parent.id, cid));
+        } else if idle {
+            return Transition::Complete(Err(cid));
+        }
+        Transition::Next(state)
+    }
+
+    /// Processes a probe that didn't get a response in time.
+    ///
+    /// Parks the peer so that it is retried if none of the other providers has the block.
+    fn recv_unresponsive(&mut self, query: Header, peer_id: PeerId) {
+        if query.label != "probe" {
+            return self.recv_have(query, peer_id, false);
+        }
+        if let Some(State::Get(state)) = query
+            .parent
+            .and_then(|parent| self.queries.get_mut(&parent))
+            .map(|parent| &mut parent.state)
+        {
+            state.parked.insert(peer_id);
+        }
+        self.recv_have(query, peer_id, false);
+    }
+
+    /// Processes a have or block query that exceeded its adaptive deadline.
+    ///
+    /// The peer is slow rather than known not to have the block, so it is parked and
+    /// retried once if none of the other providers has the block.
+    fn recv_expired(&mut self, query: Header, peer_id: PeerId) {
+        if let Some(State::Get(state)) = query
+            .parent
+            .and_then(|parent| self.queries.get_mut(&parent))
+            .map(|parent| &mut parent.state)
+        {
+            if state.expired.insert(peer_id) {
+                state.parked.insert(peer_id);
+            }
+        }
+        self.recv_have(query, peer_id, false);
+    }
+
     /// Processes the response of a block query.
     ///
     /// Either completes the get query or processes it like a have query response.
     fn recv_block(&mut self, query: Header, peer_id: PeerId, block: bool) {
         if block {
             self.get_query(query.parent.unwrap(), |_mgr, _parent, mut state| {
-                state.providers.push(peer_id);
+                state.providers.insert(peer_id);
+                UNSUPPORTED_PROVIDERS_AVOIDED.inc_by(state.unsupported.order.len() as u64);
                 Transition::Complete(Ok(()))
             });
         } else {
@@ -411,35 +1862,283 @@ impl QueryManager {
         }
     }
 
+    /// Processes the response of a provider discovery query. Providers the get asked
+    /// before are skipped.
+    fn recv_providers(&mut self, query: Header, providers: Vec<PeerId>) {
+        self.get_query(query.parent.unwrap(), |mgr, parent, mut state| {
+            state.discovery = None;
+            let providers = providers
+                .into_iter()
+                .filter(|peer_id| !state.tried.contains(peer_id))
+                .collect();
+            let providers = mgr.admit_providers(&mut state, providers);
+            tracing::trace!(
+                "{} {} get discovered {} providers",
+                parent.root,
+                parent.id,
+                providers.len()
+            );
+            mgr.request_block(
+                parent.root,
+                parent.id,
+                query.cid,
+                &mut state,
+                providers.into_iter(),
+            );
+            mgr.ask_more_providers(parent, query.cid, state)
+        });
+    }
+
+    /// Processes the response of an inline query. The get completes right away since
+    /// there is no provider to fall back to.
+    fn recv_inline(&mut self, query: Header, valid: bool) {
+        self.get_query(query.parent.unwrap(), |_mgr, parent, _state| {
+            if valid {
+                Transition::Complete(Ok(()))
+            } else {
+                Transition::Complete(Err(parent.cid))
+            }
+        });
+    }
+
+    /// Completes a sync query that has no in progress queries left. The final progress
+    /// is reported before the sync completes successfully, the blocks that failed in a
+    /// partial sync with its stats.
+    fn complete_sync(
+        &mut self,
+        query: &Header,
+        mut state: SyncState,
+    ) -> Transition<SyncState, Result<(), Cid>> {
+        state.eta.update(Instant::now(), 0);
+        self.events.push(
+            query.root,
+            QueryEvent::Progress(query.id, state.info(query)),
+        );
+        if !state.failed.is_empty() || !state.truncated.is_empty() {
+            tracing::trace!("{} {} sync partial", query.root, query.id);
+            let partial = PartialSync {
+                missing: state.failed,
+                truncated: state.truncated,
+                fetched: state.fetched,
+            };
+            self.partials.insert(query.id, partial);
+        }
+        Transition::Complete(Ok(()))
+    }
+
     /// Processes the response of a missing blocks query.
     ///
-    /// Starts a get query for each missing block. If there are no in progress queries
-    /// the sync query is marked as complete.
+    /// Starts a get query for each missing block and reports the progress. If there are
+    /// no in progress queries the sync query is marked as complete.
     fn recv_missing_blocks(&mut self, query: Header, missing: Vec<Cid>) {
-        let mut num_missing = 0;
-        let num_missing_ref = &mut num_missing;
+        let mut info = None;
+        let info_ref = &mut info;
         self.sync_query(query.parent.unwrap(), |mgr, parent, mut state| {
             state.children.remove(&query.id);
+            if let Some(ordered) = state.ordered.as_mut() {
+                ordered.insert(query.cid, missing.clone());
+            }
+            mgr.advance_ordered(parent.root, &mut state);
+            let depth = state.depth(&query.cid) + 1;
             for cid in missing {
-                state.missing.insert(mgr.get(
-                    Some(parent.root),
-                    cid,
-                    state.providers.iter().copied(),
-                ));
-            }
-            *num_missing_ref = state.missing.len();
-            if state.missing.is_empty() && state.children.is_empty() {
-                Transition::Complete(Ok(()))
+                if !state.prefetched.contains(&cid) {
+                    mgr.sync_get(parent.root, &mut state, cid, Some(query.cid), depth);
+                }
+            }
+            state.eta.update(Instant::now(), state.pending());
+            if state.pending() == 0 && state.children.is_empty() {
+                mgr.complete_sync(parent, state)
             } else {
+                *info_ref = Some(state.info(parent));
                 Transition::Next(state)
             }
         });
-        if num_missing != 0 {
+        if let Some(info) = info {
             self.events
-                .push_back(QueryEvent::Progress(query.root, num_missing));
+                .push(query.root, QueryEvent::Progress(query.root, info));
+        }
+        // reporting blocks makes room in the window of ordered syncs
+        self.start_queued_gets();
+    }
+
+    /// Reports the blocks of an ordered sync that are next in order. The sync waits to
+    /// start queued gets again if the order moved on to a queued block or made room in
+    /// the window.
+    fn advance_ordered(&mut self, root: QueryId, state: &mut SyncState) {
+        let reported = if let Some(ordered) = state.ordered.as_mut() {
+            ordered.advance()
+        } else {
+            return;
+        };
+        for cid in reported {
+            tracing::trace!("{} {} sync reports {}", root, root, cid);
+            self.events.push(root, QueryEvent::SyncBlock(root, cid));
+        }
+        if state.next_queued().is_some() && !self.waiting.contains(&root) {
+            self.waiting.push_back(root);
+        }
+    }
+
+    /// Starts a get query of a sync query for a block listed by `parent_block`, or
+    /// queues it while `max_sync_gets` gets of syncs are in progress. Blocks exceeding
+    /// the limits of the sync are truncated instead.
+    fn sync_get(
+        &mut self,
+        root: QueryId,
+        state: &mut SyncState,
+        cid: Cid,
+        parent_block: Option<Cid>,
+        depth: u32,
+    ) {
+        if state.exceeds_limits(depth) {
+            tracing::trace!("{} {} sync truncates {}", root, root, cid);
+            if let Some(ordered) = state.ordered.as_mut() {
+                ordered.skip(cid);
+            }
+            state.truncated.push(cid);
+            return;
+        }
+        if let Some(ordered) = state.ordered.as_mut() {
+            if !ordered.listed.insert(cid) {
+                return;
+            }
+        }
+        state.discovered += 1;
+        if self.sync_gets >= self.max_sync_gets || state.holds_back(&cid) {
+            tracing::trace!("{} {} sync queues get", root, root);
+            // ordered syncs holding back gets aren't necessarily waiting
+            if state.queued.is_empty() || (state.ordered.is_some() && !self.waiting.contains(&root))
+            {
+                self.waiting.push_back(root);
+            }
+            state.queued.push_back((cid, parent_block, depth));
+        } else {
+            self.start_sync_get(root, state, cid, parent_block, depth);
+        }
+    }
+
+    fn start_sync_get(
+        &mut self,
+        root: QueryId,
+        state: &mut SyncState,
+        cid: Cid,
+        parent_block: Option<Cid>,
+        depth: u32,
+    ) {
+        if state.options.max_depth.is_some() {
+            state.depths.insert(cid, depth);
+        }
+        let get = self.get(Some(root), cid, state.providers.order.clone().into_iter());
+        if let Some(State::Get(get_state)) = self.queries.get_mut(&get).map(|q| &mut q.state) {
+            get_state.parent_block = parent_block;
+        }
+        state.missing.insert(get);
+        self.sync_gets += 1;
+    }
+
+    /// Starts queued gets of syncs while fewer than `max_sync_gets` are in progress,
+    /// one get of each waiting sync at a time.
+    fn start_queued_gets(&mut self) {
+        while self.sync_gets < self.max_sync_gets {
+            let root = if let Some(root) = self.waiting.pop_front() {
+                root
+            } else {
+                break;
+            };
+            // syncs that completed or were canceled in the meantime are skipped
+            self.sync_query(root, |mgr, parent, mut state| {
+                if matches!(state.options.max_bytes, Some(max) if state.bytes >= max) {
+                    tracing::trace!("{} {} sync truncates queued gets", root, root);
+                    for (cid, _, _) in state.queued.drain(..) {
+                        if let Some(ordered) = state.ordered.as_mut() {
+                            ordered.skip(cid);
+                        }
+                        state.truncated.push(cid);
+                    }
+                    mgr.advance_ordered(parent.root, &mut state);
+                    if state.pending() == 0 && state.children.is_empty() {
+                        return mgr.complete_sync(parent, state);
+                    }
+                }
+                let next = state.next_queued().and_then(|i| state.queued.remove(i));
+                if let Some((cid, parent_block, depth)) = next {
+                    mgr.start_sync_get(parent.root, &mut state, cid, parent_block, depth);
+                }
+                if state.next_queued().is_some() && !mgr.waiting.contains(&parent.root) {
+                    mgr.waiting.push_back(parent.root);
+                }
+                Transition::Next(state)
+            });
+        }
+    }
+
+    /// Starts get queries for the links of a block received by the block query `id` of
+    /// a sync query, before its missing blocks are known. Links the sync is fetching
+    /// already are skipped, and the missing blocks query of the block skips the
+    /// prefetched ones.
+    pub fn prefetch(&mut self, id: QueryId, links: Vec<Cid>) {
+        self.record(|| Record::Prefetch(id, links.clone()));
+        // the block query belongs to a get, which belongs to the sync
+        let sync = self
+            .queries
+            .get(&id)
+            .and_then(|query| self.queries.get(&query.hdr.parent?))
+            .and_then(|get| Some((get.hdr.parent?, get.hdr.cid)));
+        let (sync, block) = if let Some(sync) = sync {
+            sync
+        } else {
+            return;
+        };
+        let mut info = None;
+        let info_ref = &mut info;
+        self.sync_query(sync, |mgr, parent, mut state| {
+            // the window of ordered syncs only counts the blocks in order
+            if state.ordered.is_some() {
+                return Transition::Next(state);
+            }
+            let mut fetching: FnvHashSet<Cid> = state
+                .missing
+                .iter()
+                .filter_map(|get| mgr.queries.get(get))
+                .map(|get| get.hdr.cid)
+                .chain(state.queued.iter().map(|(cid, _, _)| *cid))
+                .collect();
+            let discovered = state.discovered;
+            let depth = state.depth(&block) + 1;
+            for cid in links {
+                if state.exceeds_limits(depth) {
+                    // left to the missing blocks query, which truncates them
+                    break;
+                }
+                if fetching.insert(cid) && state.prefetched.insert(cid) {
+                    mgr.sync_get(parent.root, &mut state, cid, Some(block), depth);
+                }
+            }
+            if state.discovered > discovered {
+                state.eta.update(Instant::now(), state.pending());
+                *info_ref = Some((parent.root, state.info(parent)));
+            }
+            Transition::Next(state)
+        });
+        if let Some((root, info)) = info {
+            self.events.push(root, QueryEvent::Progress(root, info));
         }
     }
 
+    /// Parks more providers of a get query. They are only asked once the providers of
+    /// the query failed. Providers the query asked before are skipped.
+    pub fn park(&mut self, id: QueryId, providers: Vec<PeerId>) {
+        self.record(|| Record::Park(id, providers.clone()));
+        self.get_query(id, |_mgr, _hdr, mut state| {
+            for peer_id in providers {
+                if !state.tried.contains(&peer_id) {
+                    state.parked.insert(peer_id);
+                }
+            }
+            Transition::Next(state)
+        });
+    }
+
     /// Processes the response of a get query.
     ///
     /// If it is part of a sync query a new missing blocks query is started. Otherwise
@@ -447,18 +2146,65 @@ impl QueryManager {
     fn recv_get(&mut self, query: Header, res: Result<(), Cid>) {
         if let Some(id) = query.parent {
             self.sync_query(id, |mgr, parent, mut state| {
-                state.missing.remove(&query.id);
-                if res.is_err() {
-                    Transition::Complete(res)
-                } else {
-                    state
-                        .children
-                        .insert(mgr.missing_blocks(parent.root, query.cid));
-                    Transition::Next(state)
+                if state.missing.remove(&query.id) {
+                    mgr.sync_gets -= 1;
+                }
+                match res {
+                    Ok(()) => {
+                        state.fetched += 1;
+                        state
+                            .children
+                            .insert(mgr.missing_blocks(parent.root, query.cid));
+                        Transition::Next(state)
+                    }
+                    Err(cid) if state.options.partial_ok => {
+                        state.failed.push(cid);
+                        if let Some(ordered) = state.ordered.as_mut() {
+                            ordered.skip(query.cid);
+                        }
+                        mgr.advance_ordered(parent.root, &mut state);
+                        if state.pending() == 0 && state.children.is_empty() {
+                            mgr.complete_sync(parent, state)
+                        } else {
+                            Transition::Next(state)
+                        }
+                    }
+                    Err(_) => {
+                        // the remaining gets can't change the outcome anymore
+                        mgr.sync_gets -= state.missing.len();
+                        for id in state.missing.drain() {
+                            tracing::trace!("{} {} get cancel", parent.root, id);
+                            if let Some(get) = mgr.queries.remove(&id) {
+                                mgr.get_ended(&get.hdr.cid);
+                            }
+                        }
+                        mgr.events.cancel(parent.root);
+                        Transition::Complete(res)
+                    }
                 }
             });
+            self.start_queued_gets();
         } else {
-            self.events.push_back(QueryEvent::Complete(query.id, res));
+            let stats = query.stats();
+            let listeners = self.listeners.remove(&query.id).unwrap_or_default();
+            if self.gets.get(&query.cid) == Some(&query.id) {
+                self.gets.remove(&query.cid);
+            }
+            if !listeners.detached {
+                self.root_ended(query.id);
+                self.events.push(
+                    query.root,
+                    QueryEvent::Complete(query.id, res, stats.clone()),
+                );
+            }
+            for id in listeners.ids {
+                if let Some((_, started)) = self.joined.remove(&id) {
+                    observe_joined_get(started);
+                }
+                self.root_ended(id);
+                self.events
+                    .push(id, QueryEvent::Complete(id, res, stats.clone()));
+            }
         }
     }
 
@@ -466,27 +2212,58 @@ impl QueryManager {
     ///
     /// The sync query emits a `complete` event.
     fn recv_sync(&mut self, query: Header, res: Result<(), Cid>) {
-        self.events.push_back(QueryEvent::Complete(query.id, res));
+        self.root_ended(query.id);
+        let mut stats = query.stats();
+        stats.partial = self.partials.remove(&query.id);
+        self.events
+            .push(query.root, QueryEvent::Complete(query.id, res, stats));
     }
 
     /// Dispatches the response to a query handler.
     pub fn inject_response(&mut self, id: QueryId, res: Response) {
+        self.record(|| Record::Response(id, res.clone()));
         let query = if let Some(query) = self.queries.remove(&id) {
             query.hdr
         } else {
             return;
         };
         tracing::trace!("{} {} {}", query.root, query.id, res);
+        if let Some(root) = self.queries.get_mut(&query.root) {
+            match res {
+                Response::Have(_, _) | Response::Block(_, _) => root.hdr.transfer.answers += 1,
+                Response::Failed(_) | Response::Expired(_) => root.hdr.transfer.failures += 1,
+                _ => {}
+            }
+        }
         match res {
             Response::Have(peer, have) => {
+                self.ledger.observe_response(peer, have);
                 self.recv_have(query, peer, have);
             }
             Response::Block(peer, block) => {
+                self.ledger.observe_response(peer, block);
                 self.recv_block(query, peer, block);
             }
+            Response::Unresponsive(peer) => {
+                self.ledger.observe_response(peer, false);
+                self.recv_unresponsive(query, peer);
+            }
+            Response::Failed(peer) => {
+                self.ledger.observe_response(peer, false);
+                self.recv_have(query, peer, false);
+            }
+            Response::Expired(peer) => {
+                self.recv_expired(query, peer);
+            }
             Response::MissingBlocks(cids) => {
                 self.recv_missing_blocks(query, cids);
             }
+            Response::Inline(valid) => {
+                self.recv_inline(query, valid);
+            }
+            Response::Providers(providers) => {
+                self.recv_providers(query, providers);
+            }
         }
     }
 
@@ -495,15 +2272,271 @@ impl QueryManager {
         self.queries.get(&id).map(|q| &q.hdr)
     }
 
-    /// Retrieves the next query event.
-    pub fn next(&mut self) -> Option<QueryEvent> {
-        self.events.pop_front()
+    /// Returns the block whose missing blocks listed the block requested by a subquery,
+    /// usually the block linking to it. `None` for blocks that weren't discovered by a
+    /// sync query.
+    pub fn parent_block(&self, id: QueryId) -> Option<Cid> {
+        let get = self.queries.get(&id)?.hdr.parent?;
+        if let State::Get(state) = &self.queries.get(&get)?.state {
+            state.parent_block
+        } else {
+            None
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Returns `true` if a get query, on its own or as part of a sync query, is fetching
+    /// the block.
+    pub fn is_fetching(&self, cid: &Cid) -> bool {
+        self.fetching.contains_key(cid)
+    }
+
+    /// Returns the cids that stopped being fetched since the last call. A cid may be
+    /// fetched again by now.
+    pub fn take_unfetched(&mut self) -> Vec<Cid> {
+        std::mem::take(&mut self.unfetched)
+    }
+
+    /// Returns the cids with a have or block subquery in progress, each with the get and
+    /// sync queries it is fetched for, ordered by cid.
+    pub fn wantlist(&self) -> Vec<(Cid, QueryId)> {
+        let wants = self
+            .queries
+            .values()
+            .filter(|q| q.hdr.label == "have" || q.hdr.label == "block")
+            // requests of completed queries are kept until they are answered
+            .filter(|q| self.queries.contains_key(&q.hdr.root))
+            .flat_map(|q| {
+                let cid = q.hdr.cid;
+                self.answered(q.hdr.root)
+                    .into_iter()
+                    .map(move |id| (cid, id))
+            })
+            .collect::<BTreeSet<_>>();
+        wants.into_iter().collect()
+    }
+
+    /// Returns the get and sync queries in progress that weren't started by a sync,
+    /// ordered by id.
+    pub fn queries(&self) -> Vec<ActiveQuery> {
+        let mut pending = FnvHashMap::<QueryId, usize>::default();
+        for query in self.queries.values() {
+            if query.hdr.id != query.hdr.root {
+                *pending.entry(query.hdr.root).or_default() += 1;
+            }
+        }
+        let mut queries = self
+            .queries
+            .values()
+            .filter(|q| q.hdr.parent.is_none() && !matches!(q.state, State::None))
+            .flat_map(|q| {
+                let kind = if let State::Sync(_) = q.state {
+                    QueryKind::Sync
+                } else {
+                    QueryKind::Get
+                };
+                let snapshot = ActiveQuery {
+                    id: q.hdr.id,
+                    cid: q.hdr.cid,
+                    kind,
+                    pending: pending.get(&q.hdr.id).copied().unwrap_or_default(),
+                    elapsed: q.hdr.started.elapsed(),
+                };
+                self.answered(q.hdr.id)
+                    .into_iter()
+                    .map(move |id| ActiveQuery { id, ..snapshot })
+            })
+            .collect::<Vec<_>>();
+        queries.sort_by_key(|q| q.id);
+        queries
+    }
+
+    /// Records a block received by a get query of a sync query.
+    pub fn observe_block(&mut self, root: QueryId, bytes: usize) {
+        self.record(|| Record::ObserveBlock(root, bytes));
+        if let Some(State::Sync(state)) = self.queries.get_mut(&root).map(|q| &mut q.state) {
+            state.eta.observe(bytes);
+            state.bytes += bytes as u64;
+        }
+    }
+
+    /// Records a block a peer sent for a get or sync query, counting it as a duplicate
+    /// if the get had completed.
+    pub fn observe_transfer(
+        &mut self,
+        root: QueryId,
+        peer_id: PeerId,
+        bytes: usize,
+        duplicate: bool,
+    ) {
+        if let Some(query) = self.queries.get_mut(&root) {
+            let transfer = &mut query.hdr.transfer;
+            transfer.bytes += bytes as u64;
+            if duplicate {
+                transfer.duplicates += 1;
+            } else {
+                transfer.blocks += 1;
+            }
+            transfer.peers.insert(peer_id);
+        }
+    }
+
+    /// Records that a request of a query is retried.
+    pub fn observe_retry(&mut self, id: QueryId) {
+        let root = match self.queries.get(&id) {
+            Some(query) => query.hdr.root,
+            None => return,
+        };
+        if let Some(query) = self.queries.get_mut(&root) {
+            query.hdr.transfer.retries += 1;
+        }
+    }
+
+    /// Records the time a peer took to answer a request with `bytes` of block data.
+    /// Peers that answer faster are asked for blocks first.
+    pub fn observe_latency(&mut self, peer_id: PeerId, latency: Duration, bytes: usize) {
+        self.record(|| Record::Latency(peer_id, latency, bytes));
+        self.estimator.observe(peer_id, latency, bytes);
+    }
+
+    /// Forgets the latency and bandwidth estimate of a peer, e.g. once it disconnected.
+    pub fn forget_estimate(&mut self, peer_id: &PeerId) {
+        self.record(|| Record::ForgetEstimate(*peer_id));
+        self.estimator.remove(peer_id);
+    }
+
+    /// Returns the latency and bandwidth estimates used to rank providers and derive
+    /// adaptive timeouts.
+    pub fn estimator(&self) -> &PeerEstimator {
+        &self.estimator
+    }
+
+    /// Returns the latency and bandwidth estimate of a peer.
+    pub fn peer_estimate(&self, peer_id: &PeerId) -> Option<&PeerEstimate> {
+        self.estimator.estimate(peer_id)
+    }
+
+    /// Returns the hit rate of a peer.
+    pub fn peer_score(&self, peer_id: &PeerId) -> Option<&PeerScore> {
+        self.ledger.score(peer_id)
+    }
+
+    /// Returns the progress of a get or sync query.
+    pub fn info(&self, id: QueryId) -> Option<QueryInfo> {
+        let query = self.queries.get(&self.shared(id)?)?;
+        match &query.state {
+            State::Get(state) if query.hdr.parent.is_none() => Some(QueryInfo {
+                missing: 1,
+                bytes: query.hdr.transfer.bytes,
+                duplicates: query.hdr.transfer.duplicates,
+                peers: query.hdr.transfer.peers.len(),
+                compat: state.compat,
+                first_request: state.first_request,
+                ..Default::default()
+            }),
+            State::Sync(state) => Some(state.info(&query.hdr)),
+            _ => None,
+        }
+    }
+
+    /// Returns the get and sync queries a root query answers: itself unless it was
+    /// canceled, and the gets that joined it.
+    pub fn answered(&self, root: QueryId) -> Vec<QueryId> {
+        match self.listeners.get(&root) {
+            Some(listeners) if listeners.detached => listeners.ids.clone(),
+            Some(listeners) => std::iter::once(root)
+                .chain(listeners.ids.iter().copied())
+                .collect(),
+            None => vec![root],
+        }
+    }
+
+    /// Returns the stats of a get or sync query that wasn't started by a sync so far.
+    pub fn stats(&self, id: QueryId) -> Option<QueryStats> {
+        let query = self.queries.get(&self.shared(id)?)?;
+        if query.hdr.parent.is_some() {
+            return None;
+        }
+        Some(query.hdr.stats())
+    }
+
+    /// Returns the compat fallbacks of the get or sync query a request belongs to.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub fn compat_fallbacks(&mut self, id: QueryId) -> Option<&mut CompatFallbacks> {
+        let root = self.queries.get(&id)?.hdr.root;
+        match &mut self.queries.get_mut(&root)?.state {
+            State::Get(state) => Some(&mut state.compat),
+            State::Sync(state) => Some(&mut state.compat),
+            State::None => None,
+        }
+    }
+
+    /// Records that a request of a query was handed to the network. The first one sets
+    /// the time to first request of the get or sync query it belongs to.
+    pub fn request_sent(&mut self, id: QueryId) {
+        let root = if let Some(query) = self.queries.get(&id) {
+            query.hdr.root
+        } else {
+            return;
+        };
+        let query = if let Some(query) = self.queries.get_mut(&root) {
+            query
+        } else {
+            return;
+        };
+        let first_request = match &mut query.state {
+            State::Get(state) => &mut state.first_request,
+            State::Sync(state) => &mut state.first_request,
+            State::None => return,
+        };
+        if first_request.is_none() {
+            let elapsed = query.hdr.started.elapsed();
+            *first_request = Some(elapsed);
+            TIME_TO_FIRST_REQUEST_SECONDS
+                .with_label_values(&[query.hdr.label])
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Returns the options of a sync query.
+    pub fn sync_options(&self, id: QueryId) -> Option<&SyncOptions> {
+        if let State::Sync(state) = &self.queries.get(&id)?.state {
+            Some(&state.options)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if no query is in progress and no event is pending.
+    #[cfg(feature = "sim")]
+    pub fn is_idle(&self) -> bool {
+        self.queries.is_empty() && self.events.events.is_empty()
+    }
+
+    /// Removes up to `max` pending have and block requests to a peer, so that they can
+    /// be sent together with the request just taken from `next`. Probes aren't
+    /// coalesced since they have their own deadline.
+    pub fn coalesce(&mut self, peer_id: PeerId, max: usize) -> Vec<(QueryId, Request)> {
+        self.record(|| Record::Coalesce(peer_id, max));
+        let taken = self.events.take_requests(&peer_id, max);
+        for (id, req) in &taken {
+            let root = self.queries.get(id).map(|q| q.hdr.root).unwrap_or(*id);
+            tracing::trace!("{} {} {} coalesced", root, id, req);
+        }
+        taken
+    }
+
+    /// Retrieves the next query event.
+    pub fn next(&mut self) -> Option<QueryEvent> {
+        let event = self.events.pop()?;
+        self.record(|| Record::Event(event.clone()));
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
+    use libipld::multihash::{Code, Multihash, MultihashDigest};
     use tracing_subscriber::fmt::TestWriter;
 
     fn tracing_try_init() {
@@ -522,6 +2555,11 @@ mod tests {
         peers
     }
 
+    /// Returns a cid that isn't inlined, unlike `Cid::default()`.
+    fn gen_cid() -> Cid {
+        Cid::new_v1(0x55, Code::Blake3_256.digest(&[]))
+    }
+
     fn assert_request(event: Option<QueryEvent>, req: Request) -> QueryId {
         if let Some(QueryEvent::Request(id, req2)) = event {
             assert_eq!(req2, req);
@@ -531,8 +2569,17 @@ mod tests {
         }
     }
 
+    fn assert_progress(event: Option<QueryEvent>, id: QueryId, discovered: u64, fetched: u64) {
+        if let Some(QueryEvent::Progress(id2, info)) = event {
+            assert_eq!(id, id2);
+            assert_eq!((info.discovered, info.fetched), (discovered, fetched));
+        } else {
+            panic!("{:?} is not a progress event", event);
+        }
+    }
+
     fn assert_complete(event: Option<QueryEvent>, id: QueryId, res: Result<(), Cid>) {
-        if let Some(QueryEvent::Complete(id2, res2)) = event {
+        if let Some(QueryEvent::Complete(id2, res2, _)) = event {
             assert_eq!(id, id2);
             assert_eq!(res, res2);
         } else {
@@ -540,13 +2587,472 @@ mod tests {
         }
     }
 
+    fn assert_partial(event: Option<QueryEvent>, id: QueryId) -> Option<PartialSync> {
+        if let Some(QueryEvent::Complete(id2, Ok(()), stats)) = event {
+            assert_eq!(id, id2);
+            stats.partial
+        } else {
+            panic!("{:?} is not a successful complete event", event);
+        }
+    }
+
+    #[test]
+    fn test_query_ids_of_managers_differ() {
+        let peers = gen_peers(1);
+        let mut mgr1 = QueryManager::default();
+        let mut mgr2 = QueryManager::default();
+        let id1 = mgr1.get(None, gen_cid(), peers.clone().into_iter());
+        let id2 = mgr2.get(None, gen_cid(), peers.into_iter());
+        assert_eq!(id1.seq, id2.seq);
+        assert_ne!(id1, id2);
+        assert_ne!(id1.to_string(), id2.to_string());
+
+        for id in [id1, id2, QueryId::from(7)] {
+            assert_eq!(id.to_string().parse::<QueryId>().unwrap(), id);
+        }
+        assert_eq!(QueryId::from(7).to_string(), "7");
+        assert!("7-".parse::<QueryId>().is_err());
+        assert!("x-7".parse::<QueryId>().is_err());
+    }
+
+    #[test]
+    fn test_get_query_prefers_ranked_providers() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(3);
+        let cid = gen_cid();
+        mgr.observe_latency(peers[0], Duration::from_secs(2), 0);
+        mgr.observe_latency(peers[2], Duration::from_millis(10), 0);
+
+        let id = mgr.get(None, cid, peers.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(peers[2], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(peers[1], cid));
+        let id3 = assert_request(mgr.next(), Request::Have(peers[0], cid));
+
+        // of the peers that have the block the faster one is asked
+        mgr.inject_response(id2, Response::Have(peers[1], true));
+        mgr.inject_response(id3, Response::Have(peers[0], true));
+        mgr.inject_response(id1, Response::Block(peers[2], false));
+        let id4 = assert_request(mgr.next(), Request::Block(peers[1], cid));
+        mgr.inject_response(id4, Response::Block(peers[1], true));
+        assert_complete(mgr.next(), id, Ok(()));
+
+        let score = mgr.peer_score(&peers[2]).unwrap();
+        assert_eq!(score.responses, 1);
+        assert!(score.hit_rate < 1.0);
+    }
+
+    #[test]
+    fn test_get_query_deduplicates_providers() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid = gen_cid();
+        let providers = vec![peers[0], peers[1], peers[0], peers[1]];
+
+        let id = mgr.get(None, cid, providers.clone().into_iter());
+
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(peers[1], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id1, Response::Block(peers[0], false));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id2, Response::Have(peers[1], true));
+        let id3 = assert_request(mgr.next(), Request::Block(peers[1], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id3, Response::Block(peers[1], true));
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_get_query_joins_get_of_same_cid() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid = gen_cid();
+
+        let id1 = mgr.get(None, cid, std::iter::once(peers[0]));
+        let block = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        // the providers of the joining get are asked by the get it joined
+        let id2 = mgr.get(None, cid, std::iter::once(peers[1]));
+        assert_request(mgr.next(), Request::Have(peers[1], cid));
+        assert!(mgr.next().is_none());
+        assert_eq!(mgr.active_queries(), 2);
+        assert_eq!(mgr.answered(id1), vec![id1, id2]);
+
+        // the block keeps being fetched for the joining get
+        assert!(mgr.cancel(id1));
+        assert!(!mgr.cancel(id1));
+        assert!(mgr.info(id1).is_none());
+        assert!(mgr.info(id2).is_some());
+        assert_eq!(mgr.active_queries(), 1);
+        let id3 = mgr.get(None, cid, std::iter::empty());
+        assert_eq!(mgr.answered(id1), vec![id2, id3]);
+
+        mgr.observe_transfer(id1, peers[0], 100, false);
+        mgr.inject_response(block, Response::Block(peers[0], true));
+        for id in [id2, id3].iter() {
+            match mgr.next() {
+                Some(QueryEvent::Complete(id2, Ok(()), stats)) if id2 == *id => {
+                    assert_eq!((stats.blocks, stats.bytes), (1, 100));
+                }
+                event => panic!("{:?} is not a complete event of {}", event, id),
+            }
+        }
+        assert!(mgr.next().is_none());
+        assert_eq!(mgr.active_queries(), 0);
+
+        // a get of the block started later fetches it again
+        let id4 = mgr.get(None, cid, std::iter::once(peers[0]));
+        assert_request(mgr.next(), Request::Block(peers[0], cid));
+        assert!(mgr.cancel(id4));
+        assert_eq!(mgr.active_queries(), 0);
+    }
+
+    #[test]
+    fn test_active_queries() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(1);
+        let cid = gen_cid();
+        let get = mgr.get(None, cid, peers.clone().into_iter());
+        let sync = mgr.sync(
+            cid,
+            peers.clone(),
+            std::iter::once(cid),
+            SyncOptions::default(),
+        );
+        // the get started by the sync isn't counted
+        assert_eq!(mgr.active_queries(), 2);
+        assert!(mgr.cancel(sync));
+        assert!(!mgr.cancel(sync));
+        assert_eq!(mgr.active_queries(), 1);
+
+        let block = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(block, Response::Block(peers[0], true));
+        assert_complete(mgr.next(), get, Ok(()));
+        assert_eq!(mgr.active_queries(), 0);
+    }
+
+    #[test]
+    fn test_query_deadline() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(1);
+        let cid = gen_cid();
+        let now = Instant::now();
+
+        let id = mgr.get(None, cid, peers.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        // only queries that weren't started by another query have a deadline
+        assert!(!mgr.set_deadline(id1, now));
+        assert!(mgr.set_deadline(id, now + Duration::from_secs(1)));
+        assert_eq!(mgr.next_deadline(), Some(now + Duration::from_secs(1)));
+        mgr.expire(now);
+        assert!(mgr.next().is_none());
+
+        mgr.expire(now + Duration::from_secs(1));
+        assert!(matches!(mgr.next(), Some(QueryEvent::Timeout(id2, _)) if id2 == id));
+        assert_eq!(mgr.active_queries(), 0);
+        assert_eq!(mgr.next_deadline(), None);
+        mgr.inject_response(id1, Response::Block(peers[0], true));
+        assert!(mgr.next().is_none());
+
+        // the deadline of a completed query is dropped
+        let id = mgr.get(None, cid, peers.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        assert!(mgr.set_deadline(id, now));
+        mgr.inject_response(id1, Response::Block(peers[0], true));
+        assert_eq!(mgr.next_deadline(), None);
+        mgr.expire(now);
+        assert_complete(mgr.next(), id, Ok(()));
+        assert!(!mgr.timeout(id));
+    }
+
+    #[test]
+    fn test_get_query_pulls_providers_lazily() {
+        let mut mgr = QueryManager::default();
+        mgr.set_provider_batch(2);
+        let cid = gen_cid();
+        let pulled = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let pulled2 = pulled.clone();
+        let peers = gen_peers(3);
+        let providers = peers.clone().into_iter().inspect(move |_| {
+            pulled2.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        });
+        let pulled = move || pulled.load(std::sync::atomic::Ordering::SeqCst);
+
+        let id = mgr.get(None, cid, providers);
+        assert_eq!(pulled(), 2);
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(peers[1], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id1, Response::Block(peers[0], false));
+        assert!(mgr.next().is_none());
+        assert_eq!(pulled(), 2);
+        mgr.inject_response(id2, Response::Have(peers[1], false));
+        assert_eq!(pulled(), 3);
+        let id3 = assert_request(mgr.next(), Request::Block(peers[2], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id3, Response::Block(peers[2], false));
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
+    #[test]
+    fn test_get_query_parks_open_circuits() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid = gen_cid();
+        mgr.set_circuit_open(peers[0], true);
+
+        let id = mgr.get(None, cid, peers.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(peers[1], cid));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id1, Response::Block(peers[1], false));
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id1, Response::Block(peers[0], false));
+        assert_complete(mgr.next(), id, Err(cid));
+
+        // a parked peer is asked if it is the only provider
+        let id = mgr.get(None, cid, std::iter::once(peers[0]));
+        assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.cancel(id);
+
+        mgr.set_circuit_open(peers[0], false);
+        mgr.get(None, cid, peers.clone().into_iter());
+        assert_request(mgr.next(), Request::Block(peers[0], cid));
+        assert_request(mgr.next(), Request::Have(peers[1], cid));
+    }
+
+    #[test]
+    fn test_get_query_infinite_providers() {
+        let mut mgr = QueryManager::default();
+        let cid = gen_cid();
+        mgr.get(None, cid, std::iter::repeat_with(PeerId::random));
+        let mut requests = 0;
+        while let Some(QueryEvent::Request(_, _)) = mgr.next() {
+            requests += 1;
+        }
+        assert_eq!(requests, DEFAULT_PROVIDER_BATCH);
+    }
+
+    #[test]
+    fn test_inline_queries() {
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(1);
+        let cid = Cid::new_v1(0x55, Multihash::wrap(IDENTITY, b"inline").unwrap());
+
+        let id = mgr.get(None, cid, providers.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Inline(cid));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id1, Response::Inline(true));
+        assert_complete(mgr.next(), id, Ok(()));
+
+        let id = mgr.get(None, cid, std::iter::empty());
+        let id1 = assert_request(mgr.next(), Request::Inline(cid));
+        mgr.inject_response(id1, Response::Inline(false));
+        assert_complete(mgr.next(), id, Err(cid));
+
+        // a sync traverses inline blocks without asking its providers
+        let id = mgr.sync(cid, providers, std::iter::once(cid), SyncOptions::default());
+        let id1 = assert_request(mgr.next(), Request::Inline(cid));
+        mgr.inject_response(id1, Response::Inline(true));
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![]));
+        assert_progress(mgr.next(), id, 1, 1);
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_get_query_discovers_providers() {
+        let mut mgr = QueryManager::default();
+        mgr.set_discovery_rounds(2);
+        let peers = gen_peers(3);
+        let cid = gen_cid();
+
+        // a get without providers discovers them right away
+        let id = mgr.get(None, cid, std::iter::empty());
+        let id1 = assert_request(mgr.next(), Request::Providers(cid));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id1, Response::Providers(vec![peers[0]]));
+        let id2 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id2, Response::Block(peers[0], false));
+
+        // providers asked before are skipped
+        let id3 = assert_request(mgr.next(), Request::Providers(cid));
+        mgr.inject_response(id3, Response::Providers(vec![peers[0], peers[1]]));
+        let id4 = assert_request(mgr.next(), Request::Block(peers[1], cid));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id4, Response::Block(peers[1], false));
+
+        // the get fails once the rounds are used up
+        assert_complete(mgr.next(), id, Err(cid));
+
+        let id = mgr.get(None, cid, std::iter::once(peers[2]));
+        let id1 = assert_request(mgr.next(), Request::Block(peers[2], cid));
+        mgr.inject_response(id1, Response::Block(peers[2], false));
+        let id2 = assert_request(mgr.next(), Request::Providers(cid));
+        mgr.inject_response(id2, Response::Providers(vec![]));
+        let id3 = assert_request(mgr.next(), Request::Providers(cid));
+        mgr.inject_response(id3, Response::Providers(vec![peers[0]]));
+        let id4 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id4, Response::Block(peers[0], true));
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_get_query_parked_providers() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(3);
+        let cid = gen_cid();
+
+        let id = mgr.get(None, cid, std::iter::once(peers[0]));
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.park(id, vec![peers[0], peers[1], peers[2]]);
+        assert!(mgr.next().is_none());
+
+        // the parked providers are asked once the provider failed, the asked one is
+        // skipped
+        mgr.inject_response(id1, Response::Block(peers[0], false));
+        let id2 = assert_request(mgr.next(), Request::Block(peers[1], cid));
+        let id3 = assert_request(mgr.next(), Request::Have(peers[2], cid));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id3, Response::Have(peers[2], false));
+        mgr.inject_response(id2, Response::Block(peers[1], true));
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_get_query_expired_provider_is_parked() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid = gen_cid();
+
+        let id = mgr.get(None, cid, peers.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(peers[1], cid));
+        assert!(mgr.next().is_none());
+
+        // an expiry isn't scored as a miss and the slow peer is asked again once the
+        // other provider doesn't have the block
+        mgr.inject_response(id1, Response::Expired(peers[0]));
+        assert!(mgr.peer_score(&peers[0]).is_none());
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id2, Response::Have(peers[1], false));
+        let id3 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id3, Response::Block(peers[0], true));
+        assert_complete(mgr.next(), id, Ok(()));
+
+        // a peer expiring again isn't parked a second time
+        let id = mgr.get(None, cid, std::iter::once(peers[0]));
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id1, Response::Expired(peers[0]));
+        let id2 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id2, Response::Expired(peers[0]));
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
+    #[test]
+    fn test_get_query_without_providers_or_discovery() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(1);
+        let cid = gen_cid();
+
+        let id = mgr.get(None, cid, std::iter::empty());
+        let id1 = assert_request(mgr.next(), Request::Providers(cid));
+        mgr.inject_response(id1, Response::Providers(vec![]));
+        assert_complete(mgr.next(), id, Err(cid));
+
+        // the providers found by the single round are still asked
+        let id = mgr.get(None, cid, std::iter::empty());
+        let id1 = assert_request(mgr.next(), Request::Providers(cid));
+        mgr.inject_response(id1, Response::Providers(vec![peers[0]]));
+        let id2 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id2, Response::Block(peers[0], false));
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
+    #[test]
+    fn test_get_query_probing_deduplicates_providers() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(3);
+        let cid = gen_cid();
+        let providers = vec![peers[0], peers[0], peers[1], peers[1], peers[2], peers[2]];
+
+        let id = mgr.get_probing(None, cid, providers.clone().into_iter(), 2);
+
+        let id1 = assert_request(mgr.next(), Request::Probe(peers[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Probe(peers[1], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id1, Response::Unresponsive(peers[0]));
+        mgr.inject_response(id2, Response::Have(peers[1], false));
+
+        let id1 = assert_request(mgr.next(), Request::Block(peers[2], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(peers[0], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id1, Response::Block(peers[2], true));
+        mgr.inject_response(id2, Response::Have(peers[0], false));
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_get_query_parks_unsupported_providers() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid = gen_cid();
+        mgr.set_unsupported(peers[0], true);
+
+        let id = mgr.get(None, cid, peers.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(peers[1], cid));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id1, Response::Block(peers[1], false));
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id1, Response::Block(peers[0], true));
+        assert_complete(mgr.next(), id, Ok(()));
+
+        // unsupported providers are asked if there is nobody else
+        let id = mgr.get(None, cid, std::iter::once(peers[0]));
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        mgr.inject_response(id1, Response::Block(peers[0], true));
+        assert_complete(mgr.next(), id, Ok(()));
+
+        mgr.set_unsupported(peers[0], false);
+        mgr.get(None, cid, peers.clone().into_iter());
+        assert_request(mgr.next(), Request::Block(peers[0], cid));
+        assert_request(mgr.next(), Request::Have(peers[1], cid));
+    }
+
+    #[test]
+    fn test_sync_query_deduplicates_providers() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid = gen_cid();
+        let providers = vec![peers[0], peers[0], peers[1], peers[0]];
+
+        mgr.sync(cid, providers, std::iter::once(cid), SyncOptions::default());
+
+        let id1 = assert_request(mgr.next(), Request::Block(peers[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(peers[1], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id1, Response::Block(peers[0], true));
+        mgr.inject_response(id2, Response::Have(peers[1], true));
+        let id3 = assert_request(mgr.next(), Request::MissingBlocks(cid));
+        mgr.inject_response(id3, Response::MissingBlocks(vec![cid]));
+        assert_request(mgr.next(), Request::Block(peers[0], cid));
+        assert_request(mgr.next(), Request::Have(peers[1], cid));
+        assert!(matches!(mgr.next(), Some(QueryEvent::Progress(..))));
+        assert!(mgr.next().is_none());
+    }
+
     #[test]
     fn test_get_query_block_not_found() {
         let mut mgr = QueryManager::default();
         let initial_set = gen_peers(3);
-        let cid = Cid::default();
+        let cid = gen_cid();
 
-        let id = mgr.get(None, cid, initial_set.iter().copied());
+        let id = mgr.get(None, cid, initial_set.clone().into_iter());
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -559,13 +3065,46 @@ mod tests {
         assert_complete(mgr.next(), id, Err(cid));
     }
 
+    #[test]
+    fn test_get_query_counts_failed_requests() {
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(2);
+        let cid = gen_cid();
+
+        let id = mgr.get(None, cid, initial_set.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
+        mgr.inject_response(id1, Response::Failed(initial_set[0]));
+        mgr.inject_response(id2, Response::Failed(initial_set[1]));
+        match mgr.next() {
+            Some(QueryEvent::Complete(id2, Err(cid2), stats)) => {
+                assert_eq!((id2, cid2), (id, cid));
+                assert_eq!((stats.answers, stats.failures), (0, 2));
+            }
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+
+        let id = mgr.get(None, cid, initial_set.clone().into_iter());
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
+        mgr.inject_response(id1, Response::Failed(initial_set[0]));
+        mgr.inject_response(id2, Response::Have(initial_set[1], false));
+        match mgr.next() {
+            Some(QueryEvent::Complete(id2, Err(_), stats)) => {
+                assert_eq!(id2, id);
+                assert_eq!((stats.answers, stats.failures), (1, 1));
+            }
+            event => panic!("{:?} is not a failed complete event", event),
+        }
+    }
+
     #[test]
     fn test_cid_query_block_found() {
         let mut mgr = QueryManager::default();
         let initial_set = gen_peers(3);
-        let cid = Cid::default();
+        let cid = gen_cid();
 
-        let id = mgr.get(None, cid, initial_set.iter().copied());
+        let id = mgr.get(None, cid, initial_set.clone().into_iter());
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -582,9 +3121,9 @@ mod tests {
     fn test_get_query_gets_from_spare_if_block_request_fails() {
         let mut mgr = QueryManager::default();
         let initial_set = gen_peers(3);
-        let cid = Cid::default();
+        let cid = gen_cid();
 
-        let id = mgr.get(None, cid, initial_set.iter().copied());
+        let id = mgr.get(None, cid, initial_set.clone().into_iter());
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -604,9 +3143,9 @@ mod tests {
     fn test_get_query_gets_from_spare_if_block_request_fails_after_have_is_received() {
         let mut mgr = QueryManager::default();
         let initial_set = gen_peers(3);
-        let cid = Cid::default();
+        let cid = gen_cid();
 
-        let id = mgr.get(None, cid, initial_set.iter().copied());
+        let id = mgr.get(None, cid, initial_set.clone().into_iter());
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -625,14 +3164,260 @@ mod tests {
         assert_complete(mgr.next(), id, Ok(()));
     }
 
+    #[test]
+    fn test_eta() {
+        let start = Instant::now();
+        let mut eta = Eta::new(start);
+        // nothing received yet
+        assert_eq!(eta.update(start + Duration::from_secs(1), 10), None);
+        // no time passed
+        eta.observe(100);
+        assert_eq!(eta.update(start, 10), None);
+        // 100 bytes per second and 10 blocks of 100 bytes left
+        let first = eta.update(start + Duration::from_secs(1), 10).unwrap();
+        assert_eq!(first, Duration::from_secs(10));
+        // smoothed towards the new sample
+        let second = eta.update(start + Duration::from_secs(1), 2).unwrap();
+        assert!(second < first && second > Duration::from_secs(2));
+        // nothing left
+        assert_eq!(eta.update(start, 0), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_eta_zero_throughput() {
+        let start = Instant::now();
+        let mut eta = Eta::new(start);
+        eta.observe(0);
+        assert_eq!(eta.update(start + Duration::from_secs(1), 10), None);
+        eta.observe(1);
+        let year = Duration::from_secs(365 * 24 * 3600);
+        assert_eq!(eta.update(start + year, usize::MAX), None);
+        assert_eq!(Eta::default().update(start, 1), None);
+    }
+
+    #[test]
+    fn test_is_fetching() {
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(1);
+        let cid = gen_cid();
+
+        let id = mgr.get(None, cid, initial_set.clone().into_iter());
+        assert!(mgr.is_fetching(&cid));
+
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        mgr.inject_response(id1, Response::Block(initial_set[0], true));
+        assert!(!mgr.is_fetching(&cid));
+        assert_eq!(mgr.take_unfetched(), vec![cid]);
+        assert!(mgr.take_unfetched().is_empty());
+        assert_complete(mgr.next(), id, Ok(()));
+
+        let get1 = mgr.get(None, cid, initial_set.clone().into_iter());
+        let get2 = mgr.get(None, cid, initial_set.clone().into_iter());
+        assert!(mgr.cancel(get1));
+        assert!(mgr.is_fetching(&cid));
+        assert!(mgr.take_unfetched().is_empty());
+        assert!(mgr.cancel(get2));
+        assert!(!mgr.is_fetching(&cid));
+        assert_eq!(mgr.take_unfetched(), vec![cid]);
+
+        let sync = mgr.sync(
+            cid,
+            initial_set,
+            std::iter::once(cid),
+            SyncOptions::default(),
+        );
+        assert!(mgr.is_fetching(&cid));
+        assert!(mgr.cancel(sync));
+        assert!(!mgr.is_fetching(&cid));
+    }
+
+    #[test]
+    fn test_query_priority() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid1 = gen_cid();
+        let cid2 = Cid::new_v1(0x55, Code::Blake3_256.digest(b"cid2"));
+
+        let sync = mgr.sync(
+            cid1,
+            vec![peers[0]],
+            std::iter::once(cid1),
+            SyncOptions::default(),
+        );
+        let get = mgr.get(None, cid2, std::iter::once(peers[1]));
+        assert!(mgr.set_priority(get, 1));
+        // the pending request of the get overtakes the one of the sync
+        let block2 = assert_request(mgr.next(), Request::Block(peers[1], cid2));
+        let block1 = assert_request(mgr.next(), Request::Block(peers[0], cid1));
+
+        mgr.inject_response(block1, Response::Block(peers[0], true));
+        mgr.inject_response(block2, Response::Block(peers[1], true));
+        assert_complete(mgr.next(), get, Ok(()));
+        assert_request(mgr.next(), Request::MissingBlocks(cid1));
+        assert!(!mgr.set_priority(get, 1));
+        assert!(mgr.events.priorities.is_empty());
+
+        // subqueries run with the priority of their root
+        assert!(!mgr.set_priority(block1, 1));
+        assert!(mgr.set_priority(sync, -1));
+        assert!(mgr.cancel(sync));
+        assert!(mgr.events.priorities.is_empty());
+    }
+
+    #[test]
+    fn test_take_requests_keeps_order() {
+        let mut queue = EventQueue::default();
+        let (root1, root2) = (QueryId::from(1), QueryId::from(2));
+        let peers = gen_peers(3);
+        let cid = gen_cid();
+        let request = |id, peer| QueryEvent::Request(QueryId::from(id), Request::Have(peer, cid));
+        queue.push(root1, request(3, peers[0]));
+        queue.push(root1, request(4, peers[1]));
+        queue.push(root2, request(5, peers[1]));
+        queue.push(root1, request(6, peers[2]));
+
+        let taken = queue.take_requests(&peers[0], 8);
+        assert_eq!(
+            taken,
+            vec![(QueryId::from(3), Request::Have(peers[0], cid))]
+        );
+        assert!(!queue.requests.contains_key(&peers[0]));
+        assert_eq!(queue.order.len(), 3);
+        // the later events of the root don't move into the slot of the taken one
+        for (id, peer) in [(4, peers[1]), (5, peers[1]), (6, peers[2])] {
+            assert_eq!(queue.pop(), Some(request(id, peer)));
+        }
+        assert!(queue.requests.is_empty());
+    }
+
+    #[test]
+    fn test_set_priority_moves_only_its_root() {
+        let mut queue = EventQueue::default();
+        let (root1, root2) = (QueryId::from(1), QueryId::from(2));
+        let peer = PeerId::random();
+        let cid = gen_cid();
+        let request = |id| QueryEvent::Request(QueryId::from(id), Request::Have(peer, cid));
+        queue.push(root1, request(3));
+        queue.push(root2, request(4));
+        queue.push(root1, request(5));
+        queue.push(root2, request(6));
+
+        queue.set_priority(root2, 1);
+        assert_eq!(queue.order.len(), 4);
+        // raising it twice moves the keys again instead of duplicating them
+        queue.set_priority(root2, 2);
+        assert_eq!(queue.order.len(), 4);
+        for id in [4, 6, 3, 5] {
+            assert_eq!(queue.pop(), Some(request(id)));
+        }
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_wantlist() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cid1 = gen_cid();
+        let cid2 = Cid::new_v1(0x55, Code::Blake3_256.digest(b"cid2"));
+
+        let id1 = mgr.get(None, cid1, std::iter::once(peers[0]));
+        let block1 = assert_request(mgr.next(), Request::Block(peers[0], cid1));
+        let id2 = mgr.get(None, cid1, std::iter::once(peers[1]));
+        assert_request(mgr.next(), Request::Have(peers[1], cid1));
+        let id3 = mgr.get(None, cid2, std::iter::once(peers[0]));
+        let block2 = assert_request(mgr.next(), Request::Block(peers[0], cid2));
+
+        // cid1 is listed once for each get, even though two requests are outstanding
+        let mut expected = vec![(cid1, id1), (cid1, id2), (cid2, id3)];
+        expected.sort();
+        assert_eq!(mgr.wantlist(), expected);
+
+        mgr.inject_response(block2, Response::Block(peers[0], true));
+        assert_complete(mgr.next(), id3, Ok(()));
+        assert!(mgr.cancel(id1));
+        assert_eq!(mgr.wantlist(), vec![(cid1, id2)]);
+
+        mgr.inject_response(block1, Response::Block(peers[0], true));
+        assert_complete(mgr.next(), id2, Ok(()));
+        // the have request of peers[1] is still outstanding, but nobody waits for it
+        assert!(mgr.wantlist().is_empty());
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(3);
+        let cid1 = gen_cid();
+        let cid2 = Cid::new_v1(0x55, Code::Blake3_256.digest(b"cid2"));
+
+        let sync = mgr.sync(
+            cid1,
+            providers.clone(),
+            std::iter::once(cid1),
+            SyncOptions::default(),
+        );
+        let get1 = mgr.get(None, cid2, std::iter::once(providers[0]));
+        let get2 = mgr.get(None, cid2, std::iter::empty());
+        let queries = mgr.queries();
+        let summary = queries
+            .iter()
+            .map(|q| (q.id, q.cid, q.kind, q.pending))
+            .collect::<Vec<_>>();
+        // the sync runs a get asking each provider, the joining get shares the requests
+        // of the joined one
+        assert_eq!(
+            summary,
+            vec![
+                (sync, cid1, QueryKind::Sync, 4),
+                (get1, cid2, QueryKind::Get, 1),
+                (get2, cid2, QueryKind::Get, 1),
+            ]
+        );
+
+        assert!(mgr.cancel(sync));
+        assert!(mgr.cancel(get1));
+        let ids = mgr.queries().iter().map(|q| q.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![get2]);
+    }
+
+    #[test]
+    fn test_get_query_probes_providers() {
+        tracing_try_init();
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(3);
+        let cid = gen_cid();
+
+        let id = mgr.get_probing(None, cid, initial_set.clone().into_iter(), 2);
+
+        let id1 = assert_request(mgr.next(), Request::Probe(initial_set[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Probe(initial_set[1], cid));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id1, Response::Unresponsive(initial_set[0]));
+        mgr.inject_response(id2, Response::Have(initial_set[1], false));
+
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[2], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(initial_set[0], cid));
+
+        mgr.inject_response(id1, Response::Block(initial_set[2], true));
+        mgr.inject_response(id2, Response::Have(initial_set[0], false));
+
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
     #[test]
     fn test_sync_query() {
         tracing_try_init();
         let mut mgr = QueryManager::default();
         let providers = gen_peers(3);
-        let cid = Cid::default();
+        let cid = gen_cid();
 
-        let id = mgr.sync(cid, providers.clone(), std::iter::once(cid));
+        let id = mgr.sync(
+            cid,
+            providers.clone(),
+            std::iter::once(cid),
+            SyncOptions::default(),
+        );
 
         let id1 = assert_request(mgr.next(), Request::Block(providers[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(providers[1], cid));
@@ -645,17 +3430,519 @@ mod tests {
         let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid));
         mgr.inject_response(id1, Response::MissingBlocks(vec![]));
 
+        assert_progress(mgr.next(), id, 1, 1);
         assert_complete(mgr.next(), id, Ok(()));
     }
 
+    #[test]
+    fn test_sync_query_parent_block() {
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(1);
+        let cid1 = gen_cid();
+        let cid2 = Cid::new_v1(0x55, *cid1.hash());
+
+        mgr.sync(
+            cid1,
+            providers.clone(),
+            std::iter::once(cid1),
+            SyncOptions::default(),
+        );
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], cid1));
+        assert_eq!(mgr.parent_block(id1), None);
+        mgr.inject_response(id1, Response::Block(providers[0], true));
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid1));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![cid2]));
+        let id2 = assert_request(mgr.next(), Request::Block(providers[0], cid2));
+        assert_eq!(mgr.parent_block(id2), Some(cid1));
+    }
+
+    #[test]
+    fn test_sync_query_prefetch() {
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(1);
+        let cid1 = gen_cid();
+        let cid2 = Cid::new_v1(0x70, *cid1.hash());
+        let cid3 = Cid::new_v1(0x71, *cid1.hash());
+
+        let id = mgr.sync(
+            cid1,
+            providers.clone(),
+            std::iter::once(cid1),
+            SyncOptions::default(),
+        );
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], cid1));
+        // the block is fetched already and the duplicate link is skipped
+        mgr.prefetch(id1, vec![cid2, cid1, cid2]);
+        let id2 = assert_request(mgr.next(), Request::Block(providers[0], cid2));
+        assert_eq!(mgr.parent_block(id2), Some(cid1));
+        assert_progress(mgr.next(), id, 2, 0);
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id1, Response::Block(providers[0], true));
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid1));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![cid2, cid3]));
+        assert_request(mgr.next(), Request::Block(providers[0], cid3));
+        assert_progress(mgr.next(), id, 3, 1);
+        assert!(mgr.next().is_none());
+    }
+
+    #[test]
+    fn test_coalesce_requests() {
+        let mut mgr = QueryManager::default();
+        let peers = gen_peers(2);
+        let cids: Vec<_> = (0..3u8)
+            .map(|i| Cid::new_v1(0x55, Code::Blake3_256.digest(&[i])))
+            .collect();
+        mgr.sync(
+            cids[0],
+            peers.clone(),
+            cids.clone().into_iter(),
+            SyncOptions::default(),
+        );
+
+        assert_request(mgr.next(), Request::Block(peers[0], cids[0]));
+        let taken = mgr.coalesce(peers[0], 1);
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].1, Request::Block(peers[0], cids[1]));
+        let taken = mgr.coalesce(peers[0], 8);
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].1, Request::Block(peers[0], cids[2]));
+        assert!(mgr.coalesce(peers[0], 8).is_empty());
+
+        // the requests to other peers are left alone
+        for cid in &cids {
+            assert_request(mgr.next(), Request::Have(peers[1], *cid));
+        }
+        assert!(mgr.next().is_none());
+    }
+
+    #[test]
+    fn test_sync_query_first_request() {
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(1);
+        let cid1 = gen_cid();
+        let cid2 = Cid::new_v1(0x55, *cid1.hash());
+
+        let id = mgr.sync(
+            cid1,
+            providers.clone(),
+            std::iter::empty(),
+            SyncOptions::default(),
+        );
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid1));
+        std::thread::sleep(Duration::from_millis(10));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![cid2]));
+        assert_eq!(mgr.info(id).unwrap().first_request, None);
+
+        let id2 = assert_request(mgr.next(), Request::Block(providers[0], cid2));
+        mgr.request_sent(id2);
+        let first_request = mgr.info(id).unwrap().first_request.unwrap();
+        assert!(first_request >= Duration::from_millis(10));
+        mgr.request_sent(id2);
+        assert_eq!(mgr.info(id).unwrap().first_request, Some(first_request));
+
+        let get = mgr.get(None, cid1, providers.clone().into_iter());
+        assert_eq!(mgr.info(get).unwrap().first_request, None);
+    }
+
+    #[test]
+    fn test_sync_query_transfer_stats() {
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(2);
+        let cid = gen_cid();
+
+        let id = mgr.sync(
+            cid,
+            providers.clone(),
+            std::iter::once(cid),
+            SyncOptions::default(),
+        );
+        mgr.observe_transfer(id, providers[0], 100, false);
+        mgr.observe_transfer(id, providers[1], 100, true);
+        mgr.observe_transfer(id, providers[1], 50, false);
+        mgr.observe_retry(id);
+        let info = mgr.info(id).unwrap();
+        assert_eq!((info.bytes, info.duplicates, info.peers), (250, 1, 2));
+        let stats = mgr.stats(id).unwrap();
+        assert_eq!(
+            (stats.blocks, stats.bytes, stats.peers, stats.retries),
+            (2, 250, 2, 1)
+        );
+
+        // root gets report them too
+        let get = mgr.get(None, cid, providers.clone().into_iter());
+        mgr.observe_transfer(get, providers[0], 100, false);
+        assert_eq!(mgr.info(get).unwrap().bytes, 100);
+        assert_eq!(mgr.stats(get).unwrap().blocks, 1);
+    }
+
+    #[test]
+    fn test_sync_query_max_gets() {
+        tracing_try_init();
+        let mut mgr = QueryManager::default();
+        mgr.set_max_sync_gets(1);
+        let providers = gen_peers(1);
+        let cids: Vec<_> = (0..4u8)
+            .map(|i| Cid::new_v1(0x55, Code::Blake3_256.digest(&[i])))
+            .collect();
+
+        let id1 = mgr.sync(
+            cids[0],
+            providers.clone(),
+            cids[..3].iter().copied(),
+            SyncOptions::default(),
+        );
+        let get = assert_request(mgr.next(), Request::Block(providers[0], cids[0]));
+        assert!(mgr.next().is_none());
+        assert_eq!(mgr.info(id1).unwrap().missing, 3);
+        let id2 = mgr.sync(
+            cids[3],
+            providers.clone(),
+            std::iter::once(cids[3]),
+            SyncOptions::default(),
+        );
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(get, Response::Block(providers[0], true));
+        assert_request(mgr.next(), Request::MissingBlocks(cids[0]));
+        let get = assert_request(mgr.next(), Request::Block(providers[0], cids[1]));
+        mgr.inject_response(get, Response::Block(providers[0], true));
+        assert_request(mgr.next(), Request::MissingBlocks(cids[1]));
+        // the syncs take turns
+        assert_request(mgr.next(), Request::Block(providers[0], cids[3]));
+        assert!(mgr.next().is_none());
+
+        // canceling a sync frees its gets
+        assert!(mgr.cancel(id2));
+        assert_request(mgr.next(), Request::Block(providers[0], cids[2]));
+        assert!(mgr.next().is_none());
+        assert_eq!(mgr.info(id1).unwrap().missing, 1);
+    }
+
     #[test]
     fn test_sync_query_empty() {
         tracing_try_init();
         let mut mgr = QueryManager::default();
-        let cid = Cid::default();
-        let id = mgr.sync(cid, vec![], std::iter::empty());
+        let cid = gen_cid();
+        let id = mgr.sync(cid, vec![], std::iter::empty(), SyncOptions::default());
         let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid));
         mgr.inject_response(id1, Response::MissingBlocks(vec![]));
+        assert_progress(mgr.next(), id, 0, 0);
         assert_complete(mgr.next(), id, Ok(()));
     }
+
+    /// Runs a sync of a dag with a root linking to `a` and `b`, and `a` linking to `c`,
+    /// returning the partial outcome if the sync completed with one.
+    fn run_limited_sync(options: SyncOptions) -> Option<PartialSync> {
+        let mut mgr = QueryManager::default();
+        let peer = gen_peers(1)[0];
+        let cid = |name: &[u8]| Cid::new_v1(0x55, Code::Blake3_256.digest(name));
+        let (root, a, b, c) = (cid(b"root"), cid(b"a"), cid(b"b"), cid(b"c"));
+        let sync = mgr.sync(root, vec![peer], std::iter::once(root), options);
+        loop {
+            match mgr.next() {
+                Some(QueryEvent::Request(id, Request::Block(peer, _))) => {
+                    mgr.observe_block(sync, 100);
+                    mgr.inject_response(id, Response::Block(peer, true));
+                }
+                Some(QueryEvent::Request(id, Request::MissingBlocks(block))) => {
+                    let missing = match block {
+                        block if block == root => vec![a, b],
+                        block if block == a => vec![c],
+                        _ => vec![],
+                    };
+                    mgr.inject_response(id, Response::MissingBlocks(missing));
+                }
+                Some(QueryEvent::Progress(_, _)) => {}
+                event => return assert_partial(event, sync),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_query_limits() {
+        let cid = |name: &[u8]| Cid::new_v1(0x55, Code::Blake3_256.digest(name));
+        let truncated = |options| run_limited_sync(options).map(|partial| partial.truncated);
+        assert_eq!(truncated(SyncOptions::default()), None);
+
+        let options = SyncOptions::default().with_max_depth(1);
+        assert_eq!(truncated(options), Some(vec![cid(b"c")]));
+
+        let options = SyncOptions::default().with_max_blocks(2);
+        assert_eq!(truncated(options), Some(vec![cid(b"b"), cid(b"c")]));
+
+        // each block has 100 bytes, the root already reaches the limit
+        let options = SyncOptions::default().with_max_bytes(100);
+        let partial = run_limited_sync(options).unwrap();
+        assert_eq!(partial.truncated, vec![cid(b"a"), cid(b"b")]);
+        assert_eq!(partial.fetched, 1);
+
+        let options = SyncOptions::default().with_max_blocks(0);
+        assert_eq!(truncated(options), Some(vec![cid(b"root")]));
+    }
+
+    #[test]
+    fn test_ordered_sync() {
+        let mut mgr = QueryManager::default();
+        let peer = gen_peers(1)[0];
+        let cid = |name: &[u8]| Cid::new_v1(0x55, Code::Blake3_256.digest(name));
+        let (root, a, b, c) = (cid(b"root"), cid(b"a"), cid(b"b"), cid(b"c"));
+        let options = SyncOptions::default().with_ordered_window(2);
+        let sync = mgr.sync(root, vec![peer], std::iter::once(root), options);
+        let mut requests = vec![];
+        let mut reported = vec![];
+        let mut in_flight = 0;
+        loop {
+            match mgr.next() {
+                Some(QueryEvent::Request(id, req)) => {
+                    if let Request::Block(_, _) = req {
+                        in_flight += 1;
+                        assert!(in_flight <= 2);
+                    }
+                    requests.push((id, req));
+                }
+                Some(QueryEvent::SyncBlock(id, cid)) => {
+                    assert_eq!(id, sync);
+                    reported.push(cid);
+                }
+                Some(QueryEvent::Progress(_, _)) => {}
+                Some(event) => {
+                    assert_complete(Some(event), sync, Ok(()));
+                    break;
+                }
+                // the latest request is answered first, so b is fetched before a
+                None => match requests.pop() {
+                    Some((id, Request::Block(peer, _))) => {
+                        in_flight -= 1;
+                        mgr.inject_response(id, Response::Block(peer, true));
+                    }
+                    Some((id, Request::MissingBlocks(block))) => {
+                        let missing = match block {
+                            block if block == root => vec![a, b],
+                            block if block == a => vec![c],
+                            _ => vec![],
+                        };
+                        mgr.inject_response(id, Response::MissingBlocks(missing));
+                    }
+                    req => panic!("unexpected request {:?}", req),
+                },
+            }
+        }
+        assert_eq!(reported, vec![root, a, c, b]);
+    }
+
+    #[test]
+    fn test_sync_query_partial() {
+        tracing_try_init();
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(1);
+        let cid = gen_cid();
+        let options = SyncOptions::default().with_partial_ok(true);
+
+        let id = mgr.sync(cid, providers.clone(), std::iter::once(cid), options);
+
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], cid));
+        mgr.inject_response(id1, Response::Block(providers[0], true));
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid));
+        mgr.inject_response(id1, Response::MissingBlocks(vec![cid]));
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], cid));
+        assert_progress(mgr.next(), id, 2, 1);
+        mgr.inject_response(id1, Response::Block(providers[0], false));
+
+        assert_progress(mgr.next(), id, 2, 1);
+        assert_eq!(
+            assert_partial(mgr.next(), id),
+            Some(PartialSync {
+                missing: vec![cid],
+                truncated: vec![],
+                fetched: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sync_progress_is_monotonic() {
+        use libipld::multihash::{Code, MultihashDigest};
+        tracing_try_init();
+        // a tree where block i links to the blocks 3i + 1 to 3i + 3
+        const BLOCKS: u64 = 40;
+        let cid = |i: u64| Cid::new_v1(0x55, Code::Sha2_256.digest(&i.to_le_bytes()));
+        let index: FnvHashMap<Cid, u64> = (0..BLOCKS).map(|i| (cid(i), i)).collect();
+        let peers = gen_peers(2);
+        for seed in 1..50u64 {
+            let mut rng = seed;
+            let mut mgr = QueryManager::default();
+            let root = cid(0);
+            let id = mgr.sync(
+                root,
+                peers.clone(),
+                std::iter::once(root),
+                SyncOptions::default(),
+            );
+            let mut pending = Vec::new();
+            let mut last = QueryInfo::default();
+            let mut complete = false;
+            loop {
+                while let Some(event) = mgr.next() {
+                    assert!(!complete, "{:?} after completion", event);
+                    match event {
+                        QueryEvent::Request(id, req) => pending.push((id, req)),
+                        QueryEvent::Progress(_, info) => {
+                            assert!(info.discovered >= last.discovered);
+                            assert!(info.fetched >= last.fetched);
+                            assert!(info.fetched <= info.discovered);
+                            last = info;
+                        }
+                        QueryEvent::Complete(id2, res, _) => {
+                            assert_eq!((id2, res), (id, Ok(())));
+                            complete = true;
+                        }
+                        event => panic!("unexpected {:?}", event),
+                    }
+                }
+                if pending.is_empty() {
+                    break;
+                }
+                // answer the requests in a random order
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                let (id, req) = pending.swap_remove(rng as usize % pending.len());
+                let res = match req {
+                    Request::Have(peer, _) => Response::Have(peer, true),
+                    Request::Block(peer, _) => Response::Block(peer, true),
+                    Request::MissingBlocks(parent) => {
+                        let i = index[&parent];
+                        let children = (3 * i + 1..3 * i + 4).filter(|i| *i < BLOCKS);
+                        Response::MissingBlocks(children.map(cid).collect())
+                    }
+                    Request::Probe(_, _) | Request::Inline(_) | Request::Providers(_) => {
+                        unreachable!()
+                    }
+                };
+                mgr.inject_response(id, res);
+            }
+            assert!(complete);
+            assert_eq!(
+                (last.missing, last.discovered, last.fetched),
+                (0, BLOCKS, BLOCKS)
+            );
+        }
+    }
+
+    #[test]
+    fn test_sync_query_failure_cancels_gets() {
+        tracing_try_init();
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(1);
+        let cid1 = gen_cid();
+        let cid2 = Cid::new_v1(0x55, *cid1.hash());
+        let cid3 = Cid::new_v1(0x71, *cid1.hash());
+
+        let id = mgr.sync(
+            cid1,
+            providers.clone(),
+            vec![cid1, cid2, cid3].into_iter(),
+            SyncOptions::default(),
+        );
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], cid1));
+        let id2 = assert_request(mgr.next(), Request::Block(providers[0], cid2));
+        mgr.inject_response(id1, Response::Block(providers[0], false));
+        // the request for the third block that wasn't sent yet is dropped
+        assert_complete(mgr.next(), id, Err(cid1));
+        assert!(mgr.next().is_none());
+
+        mgr.inject_response(id2, Response::Block(providers[0], true));
+        assert!(mgr.next().is_none());
+        assert!(mgr.queries.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_preserves_order() {
+        tracing_try_init();
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(2);
+        // gets of the same cid would join each other
+        let cids: Vec<_> = (0..3u8)
+            .map(|i| Cid::new_v1(0x55, Code::Blake3_256.digest(&[i])))
+            .collect();
+        let id1 = mgr.get(None, cids[0], providers.clone().into_iter());
+        let id2 = mgr.get(None, cids[1], providers.clone().into_iter());
+        let id3 = mgr.get(None, cids[2], providers.clone().into_iter());
+        assert!(mgr.cancel(id2));
+        assert!(!mgr.cancel(id2));
+
+        for (root, cid) in [(id1, cids[0]), (id3, cids[2])] {
+            let id = assert_request(mgr.next(), Request::Block(providers[0], cid));
+            assert_eq!(mgr.query_info(id).unwrap().root, root);
+            let id = assert_request(mgr.next(), Request::Have(providers[1], cid));
+            assert_eq!(mgr.query_info(id).unwrap().root, root);
+        }
+        assert!(mgr.next().is_none());
+    }
+
+    #[test]
+    fn test_cancel_keeps_completion_in_place() {
+        let mut queue = EventQueue::default();
+        let (root1, root2) = (QueryId::from(1), QueryId::from(2));
+        let peer = PeerId::random();
+        let cid = gen_cid();
+        let request = |id| QueryEvent::Request(QueryId::from(id), Request::Have(peer, cid));
+        queue.push(root1, request(3));
+        queue.push(root2, request(4));
+        let complete = QueryEvent::Complete(root1, Err(cid), QueryStats::default());
+        queue.push(root1, complete.clone());
+        queue.cancel(root1);
+        assert_eq!(queue.order.len(), 2);
+
+        // the completion isn't moved ahead of the events of other roots
+        assert_eq!(queue.pop(), Some(request(4)));
+        assert_eq!(queue.pop(), Some(complete));
+        assert!(queue.pop().is_none());
+        assert!(queue.order.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_removes_only_its_events() {
+        let mut mgr = QueryManager::default();
+        // every provider is asked right away
+        mgr.set_provider_batch(100);
+        let providers = gen_peers(100);
+        let roots = (0..100u32)
+            .map(|i| {
+                let cid = Cid::new_v1(0x55, Code::Blake3_256.digest(&i.to_be_bytes()));
+                mgr.get(None, cid, providers.clone().into_iter())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(mgr.events.order.len(), 10_000);
+        for root in roots.iter().step_by(10) {
+            assert!(mgr.cancel(*root));
+        }
+        // one key is left per pending event
+        assert_eq!(mgr.events.order.len(), 9_000);
+        assert_eq!(
+            mgr.events
+                .events
+                .values()
+                .map(|events| events.len())
+                .sum::<usize>(),
+            9_000
+        );
+        let mut n = 0;
+        while mgr.next().is_some() {
+            n += 1;
+        }
+        assert_eq!(n, 9_000);
+    }
+
+    #[test]
+    fn test_sync_options() {
+        let mut mgr = QueryManager::default();
+        let cid = gen_cid();
+        let options = SyncOptions::default().with_strict(true);
+        let id = mgr.sync(cid, vec![], std::iter::empty(), options);
+        assert_eq!(mgr.sync_options(id), Some(&options));
+        let id1 = assert_request(mgr.next(), Request::MissingBlocks(cid));
+        assert_eq!(mgr.sync_options(id1), None);
+    }
 }
@@ -0,0 +1,370 @@
+use crate::query::{QueryEvent, QueryId, Request, Response, SyncOptions};
+use instant::Instant;
+use libipld::Cid;
+use libp2p::PeerId;
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub(crate) const GET: u8 = 0;
+pub(crate) const SYNC: u8 = 1;
+pub(crate) const CANCEL: u8 = 2;
+pub(crate) const RESPONSE: u8 = 3;
+pub(crate) const UNSUPPORTED: u8 = 4;
+pub(crate) const OBSERVE_BLOCK: u8 = 5;
+pub(crate) const EVENT: u8 = 6;
+pub(crate) const PROVIDER_BATCH: u8 = 7;
+pub(crate) const PROVIDERS: u8 = 8;
+pub(crate) const CIRCUIT_OPEN: u8 = 9;
+pub(crate) const DISCOVERY_ROUNDS: u8 = 10;
+pub(crate) const PREFETCH: u8 = 11;
+pub(crate) const COALESCE: u8 = 12;
+pub(crate) const PARK: u8 = 13;
+pub(crate) const TIMEOUT: u8 = 14;
+pub(crate) const MAX_SYNC_GETS: u8 = 15;
+pub(crate) const LATENCY: u8 = 16;
+pub(crate) const PRIORITY: u8 = 17;
+pub(crate) const FORGET_ESTIMATE: u8 = 18;
+
+/// An input to a `QueryManager` or an event it emitted.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Record {
+    /// Only holds the providers pulled when the query started.
+    Get {
+        id: QueryId,
+        cid: Cid,
+        providers: Vec<PeerId>,
+        probes: usize,
+    },
+    Sync {
+        id: QueryId,
+        cid: Cid,
+        providers: Vec<PeerId>,
+        missing: Vec<Cid>,
+        options: SyncOptions,
+    },
+    Cancel(QueryId),
+    Response(QueryId, Response),
+    Unsupported(PeerId, bool),
+    ObserveBlock(QueryId, usize),
+    /// The eta of progress events depends on the wall clock and isn't recorded.
+    Event(QueryEvent),
+    ProviderBatch(usize),
+    /// Providers pulled by a get after it started.
+    Providers(QueryId, Vec<PeerId>),
+    CircuitOpen(PeerId, bool),
+    DiscoveryRounds(usize),
+    Prefetch(QueryId, Vec<Cid>),
+    Coalesce(PeerId, usize),
+    Park(QueryId, Vec<PeerId>),
+    Timeout(QueryId),
+    MaxSyncGets(usize),
+    Latency(PeerId, Duration, usize),
+    Priority(QueryId, i32),
+    ForgetEstimate(PeerId),
+}
+
+/// Appends records to a writer, each prefixed with the time since recording started.
+pub(crate) struct Recorder {
+    writer: Box<dyn Write + Send>,
+    started: Instant,
+    pub(crate) buffer: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            started: Instant::now(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Appends a record with a single write, so that a failed write at most cuts the
+    /// last record short.
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        self.buffer.clear();
+        let mut enc = Encoder(&mut self.buffer);
+        enc.u64(self.started.elapsed().as_micros() as u64);
+        enc.record(record);
+        self.writer.write_all(&self.buffer)
+    }
+}
+
+struct Encoder<'a>(&'a mut Vec<u8>);
+
+impl<'a> Encoder<'a> {
+    fn u8(&mut self, n: u8) {
+        self.0.push(n);
+    }
+
+    fn u64(&mut self, n: u64) {
+        let mut buf = unsigned_varint::encode::u64_buffer();
+        self.0
+            .extend_from_slice(unsigned_varint::encode::u64(n, &mut buf));
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    /// The epoch is the same for every id of a log and isn't recorded, replays assign
+    /// ids with epoch zero.
+    fn id(&mut self, id: QueryId) {
+        self.u64(id.seq);
+    }
+
+    fn cid(&mut self, cid: &Cid) {
+        self.bytes(&cid.to_bytes());
+    }
+
+    fn cids(&mut self, cids: &[Cid]) {
+        self.u64(cids.len() as u64);
+        for cid in cids {
+            self.cid(cid);
+        }
+    }
+
+    fn peer(&mut self, peer_id: &PeerId) {
+        self.bytes(&peer_id.to_bytes());
+    }
+
+    fn peers(&mut self, peers: &[PeerId]) {
+        self.u64(peers.len() as u64);
+        for peer_id in peers {
+            self.peer(peer_id);
+        }
+    }
+
+    fn record(&mut self, record: &Record) {
+        match record {
+            Record::Get {
+                id,
+                cid,
+                providers,
+                probes,
+            } => {
+                self.u8(GET);
+                self.id(*id);
+                self.cid(cid);
+                self.peers(providers);
+                self.u64(*probes as u64);
+            }
+            Record::Sync {
+                id,
+                cid,
+                providers,
+                missing,
+                options,
+            } => {
+                self.u8(SYNC);
+                self.id(*id);
+                self.cid(cid);
+                self.peers(providers);
+                self.cids(missing);
+                let limits = [
+                    options.max_depth.map(u64::from),
+                    options.max_blocks,
+                    options.max_bytes,
+                    options.ordered_window.map(|window| window as u64),
+                ];
+                let mut flags = options.strict as u8 | (options.partial_ok as u8) << 1;
+                for (i, limit) in limits.iter().enumerate() {
+                    flags |= (limit.is_some() as u8) << (i + 2);
+                }
+                self.u8(flags);
+                for limit in limits.iter().flatten() {
+                    self.u64(*limit);
+                }
+            }
+            Record::Cancel(id) => {
+                self.u8(CANCEL);
+                self.id(*id);
+            }
+            Record::Response(id, res) => {
+                self.u8(RESPONSE);
+                self.id(*id);
+                match res {
+                    Response::Have(peer_id, have) => {
+                        self.u8(0);
+                        self.peer(peer_id);
+                        self.u8(*have as u8);
+                    }
+                    Response::Block(peer_id, valid) => {
+                        self.u8(1);
+                        self.peer(peer_id);
+                        self.u8(*valid as u8);
+                    }
+                    Response::Unresponsive(peer_id) => {
+                        self.u8(2);
+                        self.peer(peer_id);
+                    }
+                    Response::MissingBlocks(missing) => {
+                        self.u8(3);
+                        self.cids(missing);
+                    }
+                    Response::Inline(valid) => {
+                        self.u8(4);
+                        self.u8(*valid as u8);
+                    }
+                    Response::Providers(providers) => {
+                        self.u8(5);
+                        self.peers(providers);
+                    }
+                    Response::Failed(peer_id) => {
+                        self.u8(6);
+                        self.peer(peer_id);
+                    }
+                    Response::Expired(peer_id) => {
+                        self.u8(7);
+                        self.peer(peer_id);
+                    }
+                }
+            }
+            Record::Unsupported(peer_id, unsupported) => {
+                self.u8(UNSUPPORTED);
+                self.peer(peer_id);
+                self.u8(*unsupported as u8);
+            }
+            Record::ObserveBlock(id, bytes) => {
+                self.u8(OBSERVE_BLOCK);
+                self.id(*id);
+                self.u64(*bytes as u64);
+            }
+            Record::Event(event) => {
+                self.u8(EVENT);
+                self.event(event);
+            }
+            Record::ProviderBatch(batch) => {
+                self.u8(PROVIDER_BATCH);
+                self.u64(*batch as u64);
+            }
+            Record::Providers(id, providers) => {
+                self.u8(PROVIDERS);
+                self.id(*id);
+                self.peers(providers);
+            }
+            Record::CircuitOpen(peer_id, open) => {
+                self.u8(CIRCUIT_OPEN);
+                self.peer(peer_id);
+                self.u8(*open as u8);
+            }
+            Record::DiscoveryRounds(rounds) => {
+                self.u8(DISCOVERY_ROUNDS);
+                self.u64(*rounds as u64);
+            }
+            Record::Prefetch(id, links) => {
+                self.u8(PREFETCH);
+                self.id(*id);
+                self.cids(links);
+            }
+            Record::Coalesce(peer_id, max) => {
+                self.u8(COALESCE);
+                self.peer(peer_id);
+                self.u64(*max as u64);
+            }
+            Record::Park(id, providers) => {
+                self.u8(PARK);
+                self.id(*id);
+                self.peers(providers);
+            }
+            Record::Timeout(id) => {
+                self.u8(TIMEOUT);
+                self.id(*id);
+            }
+            Record::MaxSyncGets(max) => {
+                self.u8(MAX_SYNC_GETS);
+                self.u64(*max as u64);
+            }
+            Record::Latency(peer_id, latency, bytes) => {
+                self.u8(LATENCY);
+                self.peer(peer_id);
+                self.u64(latency.as_micros() as u64);
+                self.u64(*bytes as u64);
+            }
+            Record::Priority(id, priority) => {
+                self.u8(PRIORITY);
+                self.id(*id);
+                self.u64(*priority as u32 as u64);
+            }
+            Record::ForgetEstimate(peer_id) => {
+                self.u8(FORGET_ESTIMATE);
+                self.peer(peer_id);
+            }
+        }
+    }
+
+    fn event(&mut self, event: &QueryEvent) {
+        match event {
+            QueryEvent::Request(id, req) => {
+                self.u8(0);
+                self.id(*id);
+                match req {
+                    Request::Have(peer_id, cid) => {
+                        self.u8(0);
+                        self.peer(peer_id);
+                        self.cid(cid);
+                    }
+                    Request::Block(peer_id, cid) => {
+                        self.u8(1);
+                        self.peer(peer_id);
+                        self.cid(cid);
+                    }
+                    Request::Probe(peer_id, cid) => {
+                        self.u8(2);
+                        self.peer(peer_id);
+                        self.cid(cid);
+                    }
+                    Request::MissingBlocks(cid) => {
+                        self.u8(3);
+                        self.cid(cid);
+                    }
+                    Request::Inline(cid) => {
+                        self.u8(4);
+                        self.cid(cid);
+                    }
+                    Request::Providers(cid) => {
+                        self.u8(5);
+                        self.cid(cid);
+                    }
+                }
+            }
+            QueryEvent::Progress(id, info) => {
+                self.u8(1);
+                self.id(*id);
+                self.u64(info.missing as u64);
+                self.u64(info.discovered);
+                self.u64(info.fetched);
+            }
+            QueryEvent::Complete(id, res, stats) => {
+                self.u8(3);
+                self.id(*id);
+                match res {
+                    Ok(()) => self.u8(0),
+                    Err(cid) => {
+                        self.u8(1);
+                        self.cid(cid);
+                    }
+                }
+                match &stats.partial {
+                    None => self.u8(0),
+                    Some(partial) => {
+                        self.u8(1);
+                        self.cids(&partial.missing);
+                        self.cids(&partial.truncated);
+                        self.u64(partial.fetched);
+                    }
+                }
+            }
+            QueryEvent::Timeout(id, _) => {
+                self.u8(4);
+                self.id(*id);
+            }
+            QueryEvent::SyncBlock(id, cid) => {
+                self.u8(5);
+                self.id(*id);
+                self.cid(cid);
+            }
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use crate::query::{
+    PartialSync, QueryEvent, QueryId, QueryInfo, QueryManager, QueryStats, Request, Response,
+    SyncOptions,
+};
+use crate::record::*;
+use fnv::FnvHashMap;
+use libipld::Cid;
+use libp2p::PeerId;
+use std::convert::TryFrom;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::time::Duration;
+use unsigned_varint::io::ReadError;
+
+/// Longest cid or peer id accepted in a log.
+const MAX_ID_SIZE: u64 = 1024;
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+struct Decoder<R>(R);
+
+impl<R: Read> Decoder<R> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(invalid_data(format!("invalid bool {}", b))),
+        }
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        unsigned_varint::io::read_u64(&mut self.0).map_err(|e| match e {
+            ReadError::Io(e) => e,
+            err => invalid_data(err),
+        })
+    }
+
+    fn usize(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(invalid_data)
+    }
+
+    /// Priorities are recorded as the bits of an `u32`.
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(u32::try_from(self.u64()?).map_err(invalid_data)? as i32)
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u64()?;
+        if len > MAX_ID_SIZE {
+            return Err(invalid_data(format!("id of {} bytes", len)));
+        }
+        let mut buf = vec![0; len as usize];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn id(&mut self) -> io::Result<QueryId> {
+        Ok(QueryId::from(self.u64()?))
+    }
+
+    fn cid(&mut self) -> io::Result<Cid> {
+        Cid::try_from(self.bytes()?).map_err(invalid_data)
+    }
+
+    fn cids(&mut self) -> io::Result<Vec<Cid>> {
+        (0..self.u64()?).map(|_| self.cid()).collect()
+    }
+
+    fn peer(&mut self) -> io::Result<PeerId> {
+        PeerId::from_bytes(&self.bytes()?).map_err(invalid_data)
+    }
+
+    fn peers(&mut self) -> io::Result<Vec<PeerId>> {
+        (0..self.u64()?).map(|_| self.peer()).collect()
+    }
+
+    fn record(&mut self) -> io::Result<Record> {
+        Ok(match self.u8()? {
+            GET => Record::Get {
+                id: self.id()?,
+                cid: self.cid()?,
+                providers: self.peers()?,
+                probes: self.usize()?,
+            },
+            SYNC => Record::Sync {
+                id: self.id()?,
+                cid: self.cid()?,
+                providers: self.peers()?,
+                missing: self.cids()?,
+                options: {
+                    let flags = self.u8()?;
+                    let mut limit = |bit: u8| -> io::Result<Option<u64>> {
+                        if flags & bit != 0 {
+                            Ok(Some(self.u64()?))
+                        } else {
+                            Ok(None)
+                        }
+                    };
+                    let max_depth = limit(4)?
+                        .map(u32::try_from)
+                        .transpose()
+                        .map_err(invalid_data)?;
+                    let ordered_window = limit(32)?
+                        .map(usize::try_from)
+                        .transpose()
+                        .map_err(invalid_data)?;
+                    SyncOptions {
+                        strict: flags & 1 != 0,
+                        partial_ok: flags & 2 != 0,
+                        max_depth,
+                        max_blocks: limit(8)?,
+                        max_bytes: limit(16)?,
+                        ordered_window,
+                    }
+                },
+            },
+            CANCEL => Record::Cancel(self.id()?),
+            RESPONSE => {
+                let id = self.id()?;
+                let res = match self.u8()? {
+                    0 => Response::Have(self.peer()?, self.bool()?),
+                    1 => Response::Block(self.peer()?, self.bool()?),
+                    2 => Response::Unresponsive(self.peer()?),
+                    3 => Response::MissingBlocks(self.cids()?),
+                    4 => Response::Inline(self.bool()?),
+                    5 => Response::Providers(self.peers()?),
+                    6 => Response::Failed(self.peer()?),
+                    7 => Response::Expired(self.peer()?),
+                    ty => return Err(invalid_data(format!("unknown response {}", ty))),
+                };
+                Record::Response(id, res)
+            }
+            UNSUPPORTED => Record::Unsupported(self.peer()?, self.bool()?),
+            OBSERVE_BLOCK => Record::ObserveBlock(self.id()?, self.usize()?),
+            EVENT => Record::Event(self.event()?),
+            PROVIDER_BATCH => Record::ProviderBatch(self.usize()?),
+            PROVIDERS => Record::Providers(self.id()?, self.peers()?),
+            CIRCUIT_OPEN => Record::CircuitOpen(self.peer()?, self.bool()?),
+            DISCOVERY_ROUNDS => Record::DiscoveryRounds(self.usize()?),
+            PREFETCH => Record::Prefetch(self.id()?, self.cids()?),
+            COALESCE => Record::Coalesce(self.peer()?, self.usize()?),
+            PARK => Record::Park(self.id()?, self.peers()?),
+            TIMEOUT => Record::Timeout(self.id()?),
+            MAX_SYNC_GETS => Record::MaxSyncGets(self.usize()?),
+            LATENCY => Record::Latency(
+                self.peer()?,
+                Duration::from_micros(self.u64()?),
+                self.usize()?,
+            ),
+            PRIORITY => Record::Priority(self.id()?, self.i32()?),
+            FORGET_ESTIMATE => Record::ForgetEstimate(self.peer()?),
+            ty => return Err(invalid_data(format!("unknown record {}", ty))),
+        })
+    }
+
+    fn event(&mut self) -> io::Result<QueryEvent> {
+        Ok(match self.u8()? {
+            0 => {
+                let id = self.id()?;
+                let req = match self.u8()? {
+                    0 => Request::Have(self.peer()?, self.cid()?),
+                    1 => Request::Block(self.peer()?, self.cid()?),
+                    2 => Request::Probe(self.peer()?, self.cid()?),
+                    3 => Request::MissingBlocks(self.cid()?),
+                    4 => Request::Inline(self.cid()?),
+                    5 => Request::Providers(self.cid()?),
+                    ty => return Err(invalid_data(format!("unknown request {}", ty))),
+                };
+                QueryEvent::Request(id, req)
+            }
+            1 => {
+                let id = self.id()?;
+                let info = QueryInfo {
+                    missing: self.usize()?,
+                    discovered: self.u64()?,
+                    fetched: self.u64()?,
+                    ..Default::default()
+                };
+                QueryEvent::Progress(id, info)
+            }
+            3 => {
+                let id = self.id()?;
+                let res = if self.bool()? {
+                    Err(self.cid()?)
+                } else {
+                    Ok(())
+                };
+                let partial = if self.bool()? {
+                    Some(PartialSync {
+                        missing: self.cids()?,
+                        truncated: self.cids()?,
+                        fetched: self.u64()?,
+                    })
+                } else {
+                    None
+                };
+                let stats = QueryStats {
+                    partial,
+                    ..Default::default()
+                };
+                QueryEvent::Complete(id, res, stats)
+            }
+            4 => QueryEvent::Timeout(self.id()?, QueryStats::default()),
+            5 => QueryEvent::SyncBlock(self.id()?, self.cid()?),
+            ty => return Err(invalid_data(format!("unknown event {}", ty))),
+        })
+    }
+
+    /// Reads the next record. A record cut short by a failed write ends the log.
+    fn next(&mut self) -> io::Result<Option<(Duration, Record)>> {
+        let res = self
+            .u64()
+            .and_then(|at| Ok((Duration::from_micros(at), self.record()?)));
+        match res {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Drops what isn't recorded: the eta of progress events, which depends on the wall
+/// clock, and the stats of complete and timeout events except for the partial sync.
+fn as_recorded(event: QueryEvent) -> QueryEvent {
+    match event {
+        QueryEvent::Progress(id, mut info) => {
+            info.eta = None;
+            QueryEvent::Progress(id, info)
+        }
+        QueryEvent::Complete(id, res, stats) => {
+            let stats = QueryStats {
+                partial: stats.partial,
+                ..Default::default()
+            };
+            QueryEvent::Complete(id, res, stats)
+        }
+        QueryEvent::Timeout(id, _) => QueryEvent::Timeout(id, QueryStats::default()),
+        event => event,
+    }
+}
+
+/// Feeds a log written by `Bitswap::record_queries` through a fresh query manager.
+///
+/// # Panics
+///
+/// Panics if the query manager emits an event or assigns a query id that differs from
+/// the recorded one, so a log reported from the field can be turned into a failing
+/// unit test.
+pub fn replay(reader: impl Read) -> io::Result<()> {
+    let mut log = Decoder(BufReader::new(reader));
+    let mut records = Vec::new();
+    while let Some(record) = log.next()? {
+        records.push(record);
+    }
+    // providers pulled by a get after it started are recorded when they are pulled, so
+    // they are handed to the get up front and pulled again in the same batches
+    let mut pulled: FnvHashMap<QueryId, Vec<PeerId>> = FnvHashMap::default();
+    for (_, record) in &records {
+        if let Record::Providers(id, providers) = record {
+            pulled.entry(*id).or_default().extend(providers);
+        }
+    }
+    let mut mgr = QueryManager::default();
+    mgr.set_epoch(0);
+    for (index, (at, record)) in records.into_iter().enumerate() {
+        match record {
+            Record::Get {
+                id,
+                cid,
+                mut providers,
+                probes,
+            } => {
+                providers.extend(pulled.remove(&id).unwrap_or_default());
+                let id2 = mgr.get_probing(None, cid, providers.into_iter(), probes);
+                assert_eq!(id2, id, "record {} at {:?}: get id", index, at);
+            }
+            Record::Sync {
+                id,
+                cid,
+                providers,
+                missing,
+                options,
+            } => {
+                let id2 = mgr.sync(cid, providers, missing.into_iter(), options);
+                assert_eq!(id2, id, "record {} at {:?}: sync id", index, at);
+            }
+            Record::Cancel(id) => {
+                mgr.cancel(id);
+            }
+            Record::Response(id, res) => mgr.inject_response(id, res),
+            Record::Unsupported(peer_id, unsupported) => mgr.set_unsupported(peer_id, unsupported),
+            Record::ObserveBlock(id, bytes) => mgr.observe_block(id, bytes),
+            Record::Event(event) => {
+                let actual = mgr.next().map(as_recorded);
+                assert_eq!(actual, Some(event), "record {} at {:?}", index, at);
+            }
+            Record::ProviderBatch(batch) => mgr.set_provider_batch(batch),
+            Record::Providers(_, _) => {}
+            Record::CircuitOpen(peer_id, open) => mgr.set_circuit_open(peer_id, open),
+            Record::DiscoveryRounds(rounds) => mgr.set_discovery_rounds(rounds),
+            Record::Prefetch(id, links) => mgr.prefetch(id, links),
+            Record::Coalesce(peer_id, max) => {
+                mgr.coalesce(peer_id, max);
+            }
+            Record::Park(id, providers) => mgr.park(id, providers),
+            Record::Timeout(id) => {
+                mgr.timeout(id);
+            }
+            Record::MaxSyncGets(max) => mgr.set_max_sync_gets(max),
+            Record::Latency(peer_id, latency, bytes) => {
+                mgr.observe_latency(peer_id, latency, bytes)
+            }
+            Record::ForgetEstimate(peer_id) => mgr.forget_estimate(&peer_id),
+            Record::Priority(id, priority) => {
+                mgr.set_priority(id, priority);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::multihash::{Code, Multihash, MultihashDigest};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn cid(n: u8) -> Cid {
+        Cid::new_v1(0x55, Code::Blake3_256.digest(&[n]))
+    }
+
+    fn record_session() -> Vec<u8> {
+        let log = Log::default();
+        let mut mgr = QueryManager::default();
+        mgr.set_recorder(Recorder::new(log.clone()));
+        mgr.set_provider_batch(1);
+        mgr.set_discovery_rounds(1);
+        mgr.set_max_sync_gets(1);
+        let peers = vec![PeerId::random(), PeerId::random()];
+        mgr.set_unsupported(peers[1], true);
+        mgr.observe_latency(peers[0], Duration::from_millis(20), 0);
+        let get = mgr.get(None, cid(0), peers.clone().into_iter());
+        let inline = Cid::new_v1(0x55, Multihash::wrap(0x00, b"inline").unwrap());
+        mgr.get(None, inline, std::iter::empty());
+        // discovers its providers
+        mgr.get(None, cid(3), std::iter::empty());
+        let sync = mgr.sync(
+            cid(1),
+            peers.clone(),
+            std::iter::once(cid(2)),
+            SyncOptions::default()
+                .with_max_depth(8)
+                .with_max_bytes(1 << 20)
+                .with_ordered_window(4),
+        );
+        // the gets overtake the sync
+        mgr.set_priority(sync, -1);
+        while let Some(event) = mgr.next() {
+            match event {
+                QueryEvent::Request(id, Request::Block(peer_id, _)) if peer_id == peers[0] => {
+                    mgr.inject_response(id, Response::Block(peer_id, false));
+                }
+                QueryEvent::Request(id, Request::Block(peer_id, _)) => {
+                    mgr.observe_block(sync, 64);
+                    mgr.inject_response(id, Response::Block(peer_id, true));
+                }
+                QueryEvent::Request(id, Request::Have(peer_id, _)) => {
+                    mgr.inject_response(id, Response::Failed(peer_id));
+                }
+                QueryEvent::Request(id, Request::MissingBlocks(_)) => {
+                    mgr.inject_response(id, Response::MissingBlocks(vec![]));
+                }
+                QueryEvent::Request(id, Request::Inline(_)) => {
+                    mgr.inject_response(id, Response::Inline(true));
+                }
+                QueryEvent::Request(id, Request::Providers(_)) => {
+                    mgr.inject_response(id, Response::Providers(peers.clone()));
+                }
+                _ => {}
+            }
+        }
+        assert!(!mgr.cancel(get));
+        let bytes = log.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[test]
+    fn test_replay() {
+        let log = record_session();
+        replay(&log[..]).unwrap();
+        // a record cut short ends the log
+        replay(&log[..log.len() - 1]).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "record 1")]
+    fn test_replay_mismatch() {
+        let mut log = Vec::new();
+        let mut recorder = Recorder::new(Vec::new());
+        let get = Record::Get {
+            id: QueryId::from(0),
+            cid: cid(0),
+            providers: vec![PeerId::random()],
+            probes: 0,
+        };
+        let stats = QueryStats::default();
+        let complete = Record::Event(QueryEvent::Complete(QueryId::from(0), Ok(()), stats));
+        for record in [get, complete].iter() {
+            recorder.record(record).unwrap();
+            log.extend_from_slice(&recorder.buffer);
+        }
+        replay(&log[..]).unwrap();
+    }
+
+    #[test]
+    fn test_replay_malformed() {
+        // recorded at 0us with an unknown tag
+        let err = replay(&[0u8, 42][..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,127 @@
+use libipld::{Cid, Ipld};
+
+/// Subset of an IPLD selector for syncing part of a dag, see `Bitswap::sync_selected`.
+///
+/// Follows a path of map keys and list indices from the root of the dag, crossing
+/// links on the way, and selects the dag below the node the path ends at up to a
+/// number of links deep. Blocks on the path are fetched, blocks beside it aren't.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Selector {
+    /// Map keys and list indices leading to the selected node. Dag-pb blocks decode
+    /// to a map of `Data` and `Links`, so `Links/0/Hash` follows their first link.
+    pub path: Vec<String>,
+    /// Links below the end of the path that are followed, `None` for the whole dag.
+    /// `Some(0)` only selects the block the path ends in.
+    pub depth: Option<u32>,
+}
+
+impl Selector {
+    /// Creates a selector following a path of segments separated by `/`, selecting
+    /// the whole dag below its end. Empty segments are skipped.
+    pub fn path(path: &str) -> Self {
+        Self {
+            path: path
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(String::from)
+                .collect(),
+            depth: None,
+        }
+    }
+
+    /// Limits the links followed below the end of the path.
+    pub fn depth(mut self, depth: u32) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Returns `true` if the selector selects every block below its block.
+    pub(crate) fn is_all(&self) -> bool {
+        self.path.is_empty() && self.depth.is_none()
+    }
+
+    /// Returns the links of a block the selector follows, each with the selector
+    /// applying to the block it links to.
+    pub(crate) fn links(&self, ipld: &Ipld) -> Vec<(Cid, Selector)> {
+        let mut node = ipld;
+        for (i, segment) in self.path.iter().enumerate() {
+            let next = match node {
+                Ipld::Map(map) => map.get(segment),
+                Ipld::List(list) => segment.parse().ok().and_then(|i: usize| list.get(i)),
+                Ipld::Link(cid) => {
+                    let rest = Self {
+                        path: self.path[i..].to_vec(),
+                        depth: self.depth,
+                    };
+                    return vec![(*cid, rest)];
+                }
+                _ => None,
+            };
+            node = if let Some(next) = next {
+                next
+            } else {
+                return vec![];
+            };
+        }
+        // the node at the end of the path is the linked block itself
+        if let Ipld::Link(cid) = node {
+            let rest = Self {
+                path: vec![],
+                depth: self.depth,
+            };
+            return vec![(*cid, rest)];
+        }
+        if self.depth == Some(0) {
+            return vec![];
+        }
+        let mut links = vec![];
+        node.references(&mut links);
+        let below = Self {
+            path: vec![],
+            depth: self.depth.map(|depth| depth - 1),
+        };
+        links.into_iter().map(|cid| (cid, below.clone())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::ipld;
+    use libipld::multihash::{Code, MultihashDigest};
+
+    fn cid(name: &[u8]) -> Cid {
+        Cid::new_v1(0x55, Code::Blake3_256.digest(name))
+    }
+
+    #[test]
+    fn test_selector_links() {
+        let (a, b, c) = (cid(b"a"), cid(b"b"), cid(b"c"));
+        let node = ipld!({
+            "files": [a, { "more": b }],
+            "meta": c,
+        });
+
+        let selector = Selector::path("files/1/more");
+        assert_eq!(selector.path, vec!["files", "1", "more"]);
+        assert_eq!(selector.links(&node), vec![(b, Selector::default())]);
+
+        // the rest of the path applies to the linked block
+        let selector = Selector::path("/files/0/name/").depth(2);
+        let rest = Selector::path("name").depth(2);
+        assert_eq!(selector.links(&node), vec![(a, rest)]);
+
+        // paths that don't exist select nothing
+        assert!(Selector::path("files/2").links(&node).is_empty());
+        let meta = ipld!({ "meta": 1 });
+        assert!(Selector::path("meta/x/y").links(&meta).is_empty());
+
+        // the links below the end of the path count down the depth
+        let links = Selector::path("files").depth(1).links(&node);
+        let below = Selector::default().depth(0);
+        assert_eq!(links, vec![(a, below.clone()), (b, below)]);
+        assert!(Selector::path("files").depth(0).links(&node).is_empty());
+        assert!(Selector::default().is_all());
+        assert!(!Selector::default().depth(3).is_all());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::query::QueryId;
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+
+/// Handle of a session of related gets, e.g. the blocks of a dag, returned by
+/// `Bitswap::new_session`.
+///
+/// A session remembers which of its peers delivered blocks. Gets started through the
+/// session ask those peers first and only fall back to the other peers if they fail.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SessionId(u64);
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Peers of a session in the order they were added, with the number of blocks they
+/// delivered to the session.
+#[derive(Debug, Default)]
+struct Session {
+    peers: Vec<(PeerId, u64)>,
+}
+
+impl Session {
+    /// Adds a peer. Returns `false` if it was already added.
+    fn add_peer(&mut self, peer_id: PeerId) -> bool {
+        if self.peers.iter().any(|(peer, _)| *peer == peer_id) {
+            return false;
+        }
+        self.peers.push((peer_id, 0));
+        true
+    }
+
+    fn block_received(&mut self, peer_id: PeerId) {
+        self.add_peer(peer_id);
+        if let Some((_, blocks)) = self.peers.iter_mut().find(|(peer, _)| *peer == peer_id) {
+            *blocks += 1;
+        }
+    }
+
+    /// Returns the peers ordered by the number of blocks they delivered, ties in the
+    /// order they were added.
+    fn ranked(&self) -> Vec<(PeerId, u64)> {
+        let mut peers = self.peers.clone();
+        peers.sort_by(|(_, a), (_, b)| b.cmp(a));
+        peers
+    }
+}
+
+/// Open sessions and the in progress gets started through them.
+#[derive(Debug, Default)]
+pub(crate) struct Sessions {
+    sessions: FnvHashMap<SessionId, Session>,
+    queries: FnvHashMap<QueryId, SessionId>,
+    next_id: u64,
+}
+
+impl Sessions {
+    pub fn open(&mut self, peers: impl IntoIterator<Item = PeerId>) -> SessionId {
+        let id = SessionId(self.next_id);
+        self.next_id += 1;
+        let mut session = Session::default();
+        for peer_id in peers {
+            session.add_peer(peer_id);
+        }
+        self.sessions.insert(id, session);
+        id
+    }
+
+    /// Closes a session. Returns `false` if it wasn't open.
+    pub fn close(&mut self, id: SessionId) -> bool {
+        self.queries.retain(|_, session| *session != id);
+        self.sessions.remove(&id).is_some()
+    }
+
+    /// Adds a peer to a session. Returns `false` if the session isn't open or already
+    /// has the peer.
+    pub fn add_peer(&mut self, id: SessionId, peer_id: PeerId) -> bool {
+        self.sessions
+            .get_mut(&id)
+            .map(|session| session.add_peer(peer_id))
+            .unwrap_or_default()
+    }
+
+    /// Returns the peers of a session ordered by the number of blocks they delivered.
+    pub fn peers(&self, id: SessionId) -> Option<Vec<PeerId>> {
+        let session = self.sessions.get(&id)?;
+        Some(session.ranked().into_iter().map(|(peer, _)| peer).collect())
+    }
+
+    /// Splits the peers of a session into the ones that delivered blocks and the
+    /// others, both ordered by rank.
+    pub fn providers(&self, id: SessionId) -> Option<(Vec<PeerId>, Vec<PeerId>)> {
+        let session = self.sessions.get(&id)?;
+        let (useful, rest): (Vec<_>, Vec<_>) = session
+            .ranked()
+            .into_iter()
+            .partition(|(_, blocks)| *blocks > 0);
+        let peers = |peers: Vec<(PeerId, u64)>| peers.into_iter().map(|(peer, _)| peer).collect();
+        Some((peers(useful), peers(rest)))
+    }
+
+    /// Tracks a get started through a session.
+    pub fn insert_query(&mut self, query: QueryId, id: SessionId) {
+        self.queries.insert(query, id);
+    }
+
+    /// Forgets a get once it completed or was canceled.
+    pub fn remove_query(&mut self, query: QueryId) {
+        self.queries.remove(&query);
+    }
+
+    /// Credits the peer that delivered the block of a get, adding it to the session
+    /// if it was found elsewhere.
+    pub fn block_received(&mut self, query: QueryId, peer_id: PeerId) {
+        let sessions = &mut self.sessions;
+        if let Some(session) = self.queries.get(&query).and_then(|id| sessions.get_mut(id)) {
+            session.block_received(peer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_rank_peers() {
+        let peers: Vec<_> = (0..4).map(|_| PeerId::random()).collect();
+        let mut sessions = Sessions::default();
+        let id = sessions.open(peers[..3].iter().copied());
+        assert_eq!(sessions.providers(id), Some((vec![], peers[..3].to_vec())));
+
+        let query = QueryId::from(0);
+        sessions.insert_query(query, id);
+        sessions.block_received(query, peers[2]);
+        sessions.block_received(query, peers[2]);
+        // peers found elsewhere join the session
+        sessions.block_received(query, peers[3]);
+        assert_eq!(
+            sessions.providers(id),
+            Some((vec![peers[2], peers[3]], vec![peers[0], peers[1]]))
+        );
+        assert_eq!(
+            sessions.peers(id),
+            Some(vec![peers[2], peers[3], peers[0], peers[1]])
+        );
+
+        // blocks of completed queries aren't credited
+        sessions.remove_query(query);
+        sessions.block_received(query, peers[0]);
+        assert_eq!(sessions.providers(id).unwrap().0, vec![peers[2], peers[3]]);
+        assert!(!sessions.add_peer(id, peers[0]));
+
+        assert!(sessions.close(id));
+        assert!(!sessions.close(id));
+        assert_eq!(sessions.peers(id), None);
+        assert!(!sessions.add_peer(id, peers[0]));
+    }
+}
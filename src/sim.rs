@@ -0,0 +1,544 @@
+use crate::query::{
+    PartialSync, QueryEvent, QueryId, QueryInfo, QueryManager, Request, Response, SyncOptions,
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::multihash::Multihash;
+use libipld::Cid;
+use libp2p::PeerId;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Codec of the synthetic blocks.
+const RAW: u64 = 0x55;
+/// Multihash code of the synthetic blocks, from the private use range. Blocks with an
+/// identity hash would be inlined instead of fetched.
+const BLOCK_NUMBER: u64 = 0x30_0000;
+
+/// Shape of a synthetic dag: a complete tree in which every block above `depth` links
+/// to `branching` children.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DagModel {
+    /// Number of levels below the root.
+    pub depth: u32,
+    /// Number of links of every block that isn't a leaf.
+    pub branching: u32,
+}
+
+impl DagModel {
+    /// Returns the number of blocks in the dag.
+    pub fn blocks(&self) -> u64 {
+        let mut blocks = 0u64;
+        let mut level = 1u64;
+        for _ in 0..=self.depth {
+            blocks = blocks.saturating_add(level);
+            level = level.saturating_mul(self.branching as u64);
+        }
+        blocks
+    }
+
+    /// Returns the cid of a block. Blocks are numbered breadth first starting with the
+    /// root, and the digest of the cid is the number.
+    pub fn cid(index: u64) -> Cid {
+        let hash = Multihash::wrap(BLOCK_NUMBER, &index.to_be_bytes()).unwrap();
+        Cid::new_v1(RAW, hash)
+    }
+
+    /// Returns the number of a block.
+    fn index(cid: &Cid) -> Option<u64> {
+        Some(u64::from_be_bytes(cid.hash().digest().try_into().ok()?))
+    }
+
+    /// Returns the children of a block.
+    fn children(&self, index: u64) -> impl Iterator<Item = u64> {
+        let branching = self.branching as u64;
+        let first = index.saturating_mul(branching).saturating_add(1);
+        let end = first.saturating_add(branching).min(self.blocks());
+        first..end.max(first)
+    }
+}
+
+/// Behaviour of a simulated provider.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProviderModel {
+    /// Smallest latency of a response.
+    pub min_latency: Duration,
+    /// Largest latency of a response. Latencies are uniformly distributed.
+    pub max_latency: Duration,
+    /// Probability that a request is lost. Lost requests fail after the request
+    /// timeout of the scenario.
+    pub loss: f64,
+    /// Probability that the provider has a block.
+    pub have_probability: f64,
+}
+
+impl Default for ProviderModel {
+    fn default() -> Self {
+        Self {
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(50),
+            loss: 0.0,
+            have_probability: 1.0,
+        }
+    }
+}
+
+/// Setup of a simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scenario {
+    /// Dag that is synced.
+    pub dag: DagModel,
+    /// Providers passed to the sync.
+    pub providers: Vec<ProviderModel>,
+    /// Time after which a lost request fails.
+    pub request_timeout: Duration,
+    /// Time the store takes to answer a missing blocks request.
+    pub store_latency: Duration,
+    /// Options of the sync.
+    pub options: SyncOptions,
+    /// Seed of the random decisions. The same scenario always produces the same report.
+    pub seed: u64,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            dag: DagModel {
+                depth: 3,
+                branching: 4,
+            },
+            providers: vec![ProviderModel::default()],
+            request_timeout: Duration::from_secs(10),
+            store_latency: Duration::from_millis(0),
+            options: SyncOptions::default(),
+            seed: 0,
+        }
+    }
+}
+
+/// Outcome of a simulation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct SimReport {
+    /// Result of the sync.
+    pub result: Result<(), Cid>,
+    /// Reported by a sync with `SyncOptions::partial_ok` that couldn't fetch every block.
+    pub partial: Option<PartialSync>,
+    /// Number of have requests.
+    pub have_requests: u64,
+    /// Number of block requests.
+    pub block_requests: u64,
+    /// Number of probes.
+    pub probe_requests: u64,
+    /// Number of missing blocks requests.
+    pub missing_blocks_requests: u64,
+    /// Number of requests that were lost.
+    pub lost_requests: u64,
+    /// Number of blocks received.
+    pub fetched: u64,
+    /// Number of blocks received more than once.
+    pub duplicate_blocks: u64,
+    /// Virtual time until the sync completed.
+    pub duration: Duration,
+    /// Largest number of requests in flight at the same time.
+    pub peak_in_flight: usize,
+    /// Number of query events emitted.
+    pub events: u64,
+    /// Number of progress events emitted.
+    pub progress_events: u64,
+}
+
+/// Error returned by a simulation that detected a bug in the query manager.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("invariant violated at {at:?}: {reason}")]
+pub struct InvariantViolation {
+    /// Virtual time of the violation.
+    pub at: Duration,
+    /// Description of the violation.
+    pub reason: String,
+}
+
+/// Response that is delivered at a point in virtual time.
+struct Pending {
+    at: Duration,
+    seq: u64,
+    id: QueryId,
+    response: Response,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// Scrambles a number (splitmix64).
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Maps a random number to `[0, 1)`.
+fn unit(x: u64) -> f64 {
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Small deterministic random number generator.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(1);
+        mix(self.0)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        unit(self.next()) < p
+    }
+
+    fn duration(&mut self, min: Duration, max: Duration) -> Duration {
+        if max <= min {
+            return min;
+        }
+        min + (max - min).mul_f64(unit(self.next()))
+    }
+}
+
+/// Drives a `QueryManager` syncing a synthetic dag from simulated providers in virtual
+/// time, so that the behaviour of the query logic can be measured and checked without
+/// a network. Only available with the `sim` feature.
+pub struct Simulator {
+    scenario: Scenario,
+    rng: Rng,
+    now: Duration,
+    seq: u64,
+    pending: BinaryHeap<Reverse<Pending>>,
+    peers: FnvHashMap<PeerId, usize>,
+    stored: FnvHashSet<u64>,
+    report: SimReport,
+}
+
+impl Simulator {
+    /// Creates a simulator for a scenario. Panics if the scenario has no providers.
+    pub fn new(scenario: Scenario) -> Self {
+        assert!(!scenario.providers.is_empty());
+        Self {
+            rng: Rng(mix(scenario.seed)),
+            scenario,
+            now: Duration::default(),
+            seq: 0,
+            pending: BinaryHeap::new(),
+            peers: FnvHashMap::default(),
+            stored: FnvHashSet::default(),
+            report: SimReport {
+                result: Ok(()),
+                partial: None,
+                have_requests: 0,
+                block_requests: 0,
+                probe_requests: 0,
+                missing_blocks_requests: 0,
+                lost_requests: 0,
+                fetched: 0,
+                duplicate_blocks: 0,
+                duration: Duration::default(),
+                peak_in_flight: 0,
+                events: 0,
+                progress_events: 0,
+            },
+        }
+    }
+
+    fn violation(&self, reason: impl Into<String>) -> InvariantViolation {
+        InvariantViolation {
+            at: self.now,
+            reason: reason.into(),
+        }
+    }
+
+    /// Returns `true` if the provider has the block. The decision only depends on the
+    /// seed, so asking again gives the same answer.
+    fn has(&self, provider: usize, index: u64) -> bool {
+        let p = self.scenario.providers[provider].have_probability;
+        unit(mix(
+            self.scenario.seed ^ mix((provider as u64) << 48 ^ mix(index))
+        )) < p
+    }
+
+    /// Schedules the response to a request.
+    fn schedule(&mut self, delay: Duration, id: QueryId, response: Response) {
+        self.seq += 1;
+        self.pending.push(Reverse(Pending {
+            at: self.now + delay,
+            seq: self.seq,
+            id,
+            response,
+        }));
+        self.report.peak_in_flight = self.report.peak_in_flight.max(self.pending.len());
+    }
+
+    /// Answers a request of the query manager.
+    fn dispatch(&mut self, id: QueryId, req: Request) -> Result<(), InvariantViolation> {
+        let (peer, cid) = match req {
+            Request::MissingBlocks(cid) => {
+                self.report.missing_blocks_requests += 1;
+                let index = DagModel::index(&cid)
+                    .ok_or_else(|| self.violation(format!("unknown block {}", cid)))?;
+                let missing = self
+                    .scenario
+                    .dag
+                    .children(index)
+                    .filter(|child| !self.stored.contains(child))
+                    .map(DagModel::cid)
+                    .collect();
+                let latency = self.scenario.store_latency;
+                self.schedule(latency, id, Response::MissingBlocks(missing));
+                return Ok(());
+            }
+            Request::Have(peer, cid) => {
+                self.report.have_requests += 1;
+                (peer, cid)
+            }
+            Request::Block(peer, cid) => {
+                self.report.block_requests += 1;
+                (peer, cid)
+            }
+            Request::Probe(peer, cid) => {
+                self.report.probe_requests += 1;
+                (peer, cid)
+            }
+            Request::Inline(cid) => {
+                return Err(self.violation(format!("inline request of dag block {}", cid)));
+            }
+            Request::Providers(cid) => {
+                return Err(self.violation(format!("provider discovery of {}", cid)));
+            }
+        };
+        let provider = *self
+            .peers
+            .get(&peer)
+            .ok_or_else(|| self.violation(format!("request to unknown peer {}", peer)))?;
+        let index = DagModel::index(&cid)
+            .filter(|index| *index < self.scenario.dag.blocks())
+            .ok_or_else(|| self.violation(format!("unknown block {}", cid)))?;
+        let model = self.scenario.providers[provider];
+        if self.rng.chance(model.loss) {
+            self.report.lost_requests += 1;
+            let response = if let Request::Probe(_, _) = req {
+                Response::Unresponsive(peer)
+            } else {
+                Response::Have(peer, false)
+            };
+            let timeout = self.scenario.request_timeout;
+            self.schedule(timeout, id, response);
+            return Ok(());
+        }
+        let have = self.has(provider, index);
+        let response = if let Request::Block(_, _) = req {
+            Response::Block(peer, have)
+        } else {
+            Response::Have(peer, have)
+        };
+        let latency = self.rng.duration(model.min_latency, model.max_latency);
+        self.schedule(latency, id, response);
+        Ok(())
+    }
+
+    /// Runs the sync to completion. Returns an error if the query manager violated an
+    /// invariant: the sync must complete exactly once, no event may follow its
+    /// completion and no query may be left once every response was delivered.
+    pub fn run(mut self) -> Result<SimReport, InvariantViolation> {
+        let mut mgr = QueryManager::default();
+        let mut peers = Vec::with_capacity(self.scenario.providers.len());
+        for provider in 0..self.scenario.providers.len() {
+            let peer = PeerId::random();
+            self.peers.insert(peer, provider);
+            peers.push(peer);
+        }
+        let root = DagModel::cid(0);
+        let id = mgr.sync(root, peers, std::iter::once(root), self.scenario.options);
+        let mut result = None;
+        let mut progress = QueryInfo::default();
+        loop {
+            while let Some(event) = mgr.next() {
+                self.report.events += 1;
+                if result.is_some() {
+                    return Err(self.violation(format!("event after completion: {:?}", event)));
+                }
+                match event {
+                    QueryEvent::Request(id, req) => self.dispatch(id, req)?,
+                    QueryEvent::Progress(_, info) => {
+                        self.report.progress_events += 1;
+                        if info.discovered < progress.discovered
+                            || info.fetched < progress.fetched
+                            || info.fetched > info.discovered
+                        {
+                            return Err(
+                                self.violation(format!("progress {:?} after {:?}", info, progress))
+                            );
+                        }
+                        progress = info;
+                    }
+                    QueryEvent::SyncBlock(_, _) => {}
+                    QueryEvent::Complete(query, res, stats) => {
+                        if query != id {
+                            return Err(self.violation(format!("{} completed", query)));
+                        }
+                        self.report.duration = self.now;
+                        self.report.partial = stats.partial;
+                        result = Some(res);
+                    }
+                    QueryEvent::Timeout(query, _) => {
+                        return Err(self.violation(format!("{} timed out", query)));
+                    }
+                }
+            }
+            let pending = if let Some(Reverse(pending)) = self.pending.pop() {
+                pending
+            } else {
+                break;
+            };
+            self.now = pending.at;
+            if let Response::Block(_, true) = pending.response {
+                let cid = mgr.query_info(pending.id).map(|hdr| hdr.cid);
+                if let Some(index) = cid.as_ref().and_then(DagModel::index) {
+                    self.report.fetched += 1;
+                    if !self.stored.insert(index) {
+                        self.report.duplicate_blocks += 1;
+                    }
+                }
+            }
+            mgr.inject_response(pending.id, pending.response);
+        }
+        self.report.result = result.ok_or_else(|| self.violation("sync didn't complete"))?;
+        if !mgr.is_idle() {
+            return Err(self.violation("queries left after completion"));
+        }
+        match (&self.report.result, &self.report.partial) {
+            (Ok(()), None) => {
+                if self.stored.len() as u64 != self.scenario.dag.blocks() {
+                    return Err(self.violation("sync completed without fetching every block"));
+                }
+                if progress.fetched != progress.discovered
+                    || progress.fetched != self.stored.len() as u64
+                {
+                    return Err(self.violation(format!("final progress {:?}", progress)));
+                }
+            }
+            (Ok(()), Some(partial)) => {
+                if partial.fetched != self.stored.len() as u64 {
+                    return Err(self.violation("partial sync miscounted the fetched blocks"));
+                }
+                if partial
+                    .missing
+                    .iter()
+                    .filter_map(DagModel::index)
+                    .any(|index| self.stored.contains(&index))
+                {
+                    return Err(self.violation("partial sync reported a fetched block"));
+                }
+            }
+            (Err(_), _) if self.scenario.options.partial_ok => {
+                return Err(self.violation("partial sync failed"));
+            }
+            _ => {}
+        }
+        Ok(self.report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sim_reliable_providers() {
+        let latency = Duration::from_millis(10);
+        let provider = ProviderModel {
+            min_latency: latency,
+            max_latency: latency,
+            ..Default::default()
+        };
+        let report = Simulator::new(Scenario {
+            dag: DagModel {
+                depth: 3,
+                branching: 3,
+            },
+            providers: vec![provider; 2],
+            ..Default::default()
+        })
+        .run()
+        .unwrap();
+        assert_eq!(report.result, Ok(()));
+        assert_eq!(report.fetched, 40);
+        assert_eq!(report.duplicate_blocks, 0);
+        assert_eq!(report.block_requests, 40);
+        assert_eq!(report.have_requests, 40);
+        assert_eq!(report.missing_blocks_requests, 40);
+        assert_eq!(report.lost_requests, 0);
+        // one round trip per level
+        assert_eq!(report.duration, latency * 4);
+        // the leaves are requested from both providers at once
+        assert!(report.peak_in_flight >= 54);
+    }
+
+    #[test]
+    fn test_sim_missing_block() {
+        let report = Simulator::new(Scenario {
+            providers: vec![ProviderModel {
+                have_probability: 0.0,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .run()
+        .unwrap();
+        assert_eq!(report.result, Err(DagModel::cid(0)));
+        assert_eq!(report.fetched, 0);
+    }
+
+    #[test]
+    fn test_sim_invariants() {
+        for seed in 0..200 {
+            let mut rng = Rng(mix(seed));
+            let providers = (0..1 + rng.next() % 3)
+                .map(|_| ProviderModel {
+                    loss: unit(rng.next()) * 0.3,
+                    have_probability: 0.8 + unit(rng.next()) * 0.2,
+                    ..Default::default()
+                })
+                .collect();
+            let scenario = Scenario {
+                dag: DagModel {
+                    depth: (rng.next() % 4) as u32,
+                    branching: 1 + (rng.next() % 4) as u32,
+                },
+                providers,
+                options: SyncOptions::default().with_partial_ok(rng.chance(0.5)),
+                seed,
+                ..Default::default()
+            };
+            let report = Simulator::new(scenario.clone())
+                .run()
+                .unwrap_or_else(|err| panic!("{:?}: {}", scenario, err));
+            assert_eq!(report, Simulator::new(scenario).run().unwrap());
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use crate::error::Error;
+use crate::stats::*;
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use libipld::{Cid, Result};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Storage for received blocks that don't fit into the insert backlog. Blocks are
+/// drained into the `BitswapStore` when it catches up and verified again on the way.
+pub trait SpillStore: Send + 'static {
+    /// Appends a block.
+    fn put(&mut self, cid: &Cid, data: &[u8]) -> Result<()>;
+    /// Calls `f` with the blocks put since the last `clear` in insertion order, starting
+    /// at `offset` and stopping after the block that reaches `max_bytes`. Returns the
+    /// offset of the next block, or `None` once all blocks were read.
+    fn read(
+        &mut self,
+        offset: u64,
+        max_bytes: usize,
+        f: &mut dyn FnMut(Cid, Vec<u8>),
+    ) -> Result<Option<u64>>;
+    /// Removes all blocks.
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// Spill store backed by an append-only file. The file is truncated when opened, so
+/// blocks spilled before a crash are fetched again instead of being trusted.
+#[derive(Debug)]
+pub struct FileSpillStore {
+    file: File,
+}
+
+impl FileSpillStore {
+    /// Creates or truncates the spill file at `path`.
+    pub fn new(path: impl AsRef<Path>) -> std::result::Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self { file })
+    }
+}
+
+fn read_record(reader: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf)),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl SpillStore for FileSpillStore {
+    fn put(&mut self, cid: &Cid, data: &[u8]) -> Result<()> {
+        let cid = cid.to_bytes();
+        let mut record = Vec::with_capacity(cid.len() + data.len() + 8);
+        for part in [&cid[..], data] {
+            record.extend_from_slice(&(part.len() as u32).to_le_bytes());
+            record.extend_from_slice(part);
+        }
+        let len = self.file.seek(SeekFrom::End(0))?;
+        if let Err(err) = self.file.write_all(&record) {
+            // drop the partial record so that later ones can be read
+            self.file.set_len(len)?;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        mut offset: u64,
+        max_bytes: usize,
+        f: &mut dyn FnMut(Cid, Vec<u8>),
+    ) -> Result<Option<u64>> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(&mut self.file);
+        let mut bytes = 0;
+        loop {
+            // a record cut short by a failed write ends the file
+            let (cid, data) = match (read_record(&mut reader)?, read_record(&mut reader)?) {
+                (Some(cid), Some(data)) => (cid, data),
+                _ => return Ok(None),
+            };
+            offset += (cid.len() + data.len() + 8) as u64;
+            bytes += data.len();
+            f(Cid::try_from(cid)?, data);
+            if bytes >= max_bytes {
+                return Ok(Some(offset));
+            }
+        }
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        Ok(())
+    }
+}
+
+/// Spill store together with the offset of the next block to drain.
+#[derive(Default)]
+struct SpillFile {
+    store: Option<Box<dyn SpillStore>>,
+    offset: u64,
+}
+
+/// Insert backlog shared between the behaviour and the db thread.
+///
+/// The behaviour hands spilled blocks to a writer, so that the swarm never waits for the
+/// spill store.
+pub(crate) struct Spill {
+    file: Mutex<SpillFile>,
+    /// Spilled blocks the writer didn't put into the spill store yet.
+    unwritten: Mutex<VecDeque<(Cid, Vec<u8>)>>,
+    /// Wakes the writer.
+    writer: mpsc::UnboundedSender<()>,
+    /// Whether a spill store is set.
+    enabled: AtomicBool,
+    /// Bytes of blocks queued for insertion.
+    pending: AtomicUsize,
+    /// Bytes of spilled blocks that weren't drained yet.
+    spilled: AtomicUsize,
+}
+
+impl Spill {
+    /// Creates the insert backlog together with the future of its writer, which
+    /// completes once the backlog is dropped.
+    pub fn new() -> (Arc<Self>, BoxFuture<'static, ()>) {
+        let (writer, mut wakeups) = mpsc::unbounded();
+        let spill = Arc::new(Self {
+            file: Default::default(),
+            unwritten: Default::default(),
+            writer,
+            enabled: Default::default(),
+            pending: Default::default(),
+            spilled: Default::default(),
+        });
+        let weak = Arc::downgrade(&spill);
+        let writer = async move {
+            while wakeups.next().await.is_some() {
+                match weak.upgrade() {
+                    Some(spill) => spill.write(),
+                    None => break,
+                }
+            }
+        };
+        (spill, writer.boxed())
+    }
+
+    /// Sets the store blocks are spilled to.
+    pub fn set_store(&self, store: Box<dyn SpillStore>) {
+        self.file.lock().unwrap().store = Some(store);
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Hands a block to the writer if the queued blocks exceed `limit` bytes. Returns
+    /// `false` if the block needs to be queued.
+    pub fn put(&self, cid: &Cid, data: &[u8], limit: usize) -> bool {
+        if !self.enabled.load(Ordering::Relaxed)
+            || self.pending.load(Ordering::Relaxed) + data.len() <= limit
+        {
+            return false;
+        }
+        {
+            let mut unwritten = self.unwritten.lock().unwrap();
+            unwritten.push_back((*cid, data.to_vec()));
+            self.spilled.fetch_add(data.len(), Ordering::Relaxed);
+        }
+        SPILLED_BYTES.inc_by(data.len() as u64);
+        self.writer.unbounded_send(()).ok();
+        true
+    }
+
+    /// Puts the blocks handed to the writer into the spill store. A block that can't be
+    /// written stays in memory until it is drained.
+    fn write(&self) {
+        loop {
+            let mut file = self.file.lock().unwrap();
+            let (cid, data) = match self.unwritten.lock().unwrap().pop_front() {
+                Some(block) => block,
+                None => return,
+            };
+            let res = match file.store.as_mut() {
+                Some(store) => store.put(&cid, &data),
+                None => return,
+            };
+            if let Err(err) = res {
+                tracing::error!("error spilling block: {}", err);
+                self.unwritten.lock().unwrap().push_front((cid, data));
+                return;
+            }
+        }
+    }
+
+    /// Accounts a block queued for insertion.
+    pub fn queued(&self, len: usize) {
+        self.pending.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Accounts a queued block that was inserted.
+    pub fn inserted(&self, len: usize) {
+        self.pending.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if blocks were spilled.
+    pub fn is_spilled(&self) -> bool {
+        self.spilled.load(Ordering::Relaxed) > 0
+    }
+
+    /// Takes the next spilled blocks, adding up to about `max_bytes`. The spill store
+    /// is cleared once all of its blocks were taken, after which the blocks the writer
+    /// didn't write yet are taken. Blocks spilled in the meantime are taken by a later
+    /// call.
+    pub fn drain(&self, max_bytes: usize) -> Vec<(Cid, Vec<u8>)> {
+        let mut blocks = vec![];
+        if !self.is_spilled() {
+            return blocks;
+        }
+        // the writer doesn't take blocks while the spill store is read
+        let mut file = self.file.lock().unwrap();
+        let SpillFile { store, offset } = &mut *file;
+        let mut bytes = 0;
+        if let Some(store) = store.as_mut() {
+            let res = store.read(*offset, max_bytes, &mut |cid, data| {
+                bytes += data.len();
+                blocks.push((cid, data));
+            });
+            match res {
+                Ok(Some(next)) => {
+                    *offset = next;
+                    self.drained(bytes);
+                    return blocks;
+                }
+                Ok(None) => {}
+                Err(err) => tracing::error!("error draining spilled blocks: {}", err),
+            }
+            if let Err(err) = store.clear() {
+                tracing::error!("error clearing spilled blocks: {}", err);
+            }
+        }
+        *offset = 0;
+        let mut unwritten = self.unwritten.lock().unwrap();
+        while bytes < max_bytes {
+            match unwritten.pop_front() {
+                Some((cid, data)) => {
+                    bytes += data.len();
+                    blocks.push((cid, data));
+                }
+                None => break,
+            }
+        }
+        if unwritten.is_empty() {
+            // blocks that failed to be read are fetched again
+            self.spilled.store(0, Ordering::Relaxed);
+            DRAINED_BYTES.inc_by(bytes as u64);
+        } else {
+            self.drained(bytes);
+        }
+        blocks
+    }
+
+    /// Accounts spilled blocks that were drained.
+    fn drained(&self, bytes: usize) {
+        self.spilled.fetch_sub(bytes, Ordering::Relaxed);
+        DRAINED_BYTES.inc_by(bytes as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::multihash::{Code, MultihashDigest};
+
+    #[test]
+    fn test_file_spill_store() {
+        let path = std::env::temp_dir().join(format!("bitswap-spill-{}", std::process::id()));
+        let mut spill = FileSpillStore::new(&path).unwrap();
+        let blocks: Vec<_> = (0..3u8)
+            .map(|i| {
+                let data = vec![i; i as usize * 100];
+                (Cid::new_v1(0x55, Code::Sha2_256.digest(&data)), data)
+            })
+            .collect();
+        for (cid, data) in &blocks {
+            spill.put(cid, data).unwrap();
+        }
+        // a torn write is ignored
+        spill.file.write_all(&[5, 0, 0, 0, 1]).unwrap();
+
+        // the first two blocks reach 100 bytes
+        let mut drained = vec![];
+        let offset = spill
+            .read(0, 100, &mut |cid, data| drained.push((cid, data)))
+            .unwrap();
+        assert_eq!(drained, blocks[..2]);
+        let offset = spill
+            .read(offset.unwrap(), 100, &mut |cid, data| {
+                drained.push((cid, data))
+            })
+            .unwrap();
+        assert_eq!(drained, blocks);
+        assert!(offset.is_some());
+        let offset = spill.read(offset.unwrap(), 100, &mut |_, _| {}).unwrap();
+        assert!(offset.is_none());
+
+        spill.clear().unwrap();
+        let mut drained = 0;
+        spill.read(0, usize::MAX, &mut |_, _| drained += 1).unwrap();
+        assert_eq!(drained, 0);
+
+        spill.put(&blocks[1].0, &blocks[1].1).unwrap();
+        drop(spill);
+        let mut spill = FileSpillStore::new(&path).unwrap();
+        spill.read(0, usize::MAX, &mut |_, _| drained += 1).unwrap();
+        assert_eq!(drained, 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_spill_writer() {
+        let path = std::env::temp_dir().join(format!("bitswap-writer-{}", std::process::id()));
+        let (spill, mut writer) = Spill::new();
+        let blocks: Vec<_> = (0..3u8)
+            .map(|i| {
+                let data = vec![i; 100];
+                (Cid::new_v1(0x55, Code::Sha2_256.digest(&data)), data)
+            })
+            .collect();
+        assert!(!spill.put(&blocks[0].0, &blocks[0].1, 0));
+        spill.set_store(Box::new(FileSpillStore::new(&path).unwrap()));
+        assert!(!spill.put(&blocks[0].0, &blocks[0].1, 100));
+        for (cid, data) in &blocks[..2] {
+            assert!(spill.put(cid, data, 0));
+        }
+        // the first two blocks are written, the last one is still in memory
+        assert!(writer.as_mut().now_or_never().is_none());
+        assert!(spill.put(&blocks[2].0, &blocks[2].1, 0));
+        assert_eq!(spill.unwritten.lock().unwrap().len(), 1);
+
+        assert_eq!(spill.drain(100), blocks[..1]);
+        assert_eq!(spill.drain(150), blocks[1..]);
+        assert!(!spill.is_spilled());
+        assert!(spill.drain(100).is_empty());
+
+        drop(spill);
+        assert!(writer.now_or_never().is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+}
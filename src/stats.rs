@@ -1,6 +1,31 @@
 
+use fnv::FnvHashMap;
 use lazy_static::lazy_static;
-use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts};
+use libp2p::PeerId;
+use prometheus::core::Collector;
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    Result,
+};
+use std::time::Duration;
+
+/// Number of updates after which `LocalCounters` are flushed.
+pub const FLUSH_UPDATES: u32 = 1024;
+
+/// Longest time updates stay in `LocalCounters` while the behaviour is polled.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Labels of the query types.
+const QUERY_TYPES: [&str; 8] = [
+    "get",
+    "sync",
+    "have",
+    "block",
+    "probe",
+    "missing-blocks",
+    "inline",
+    "providers",
+];
 
 lazy_static! {
     pub static ref REQUESTS_TOTAL: IntCounterVec = IntCounterVec::new(
@@ -19,6 +44,22 @@ lazy_static! {
         &["type"],
     )
     .unwrap();
+    pub static ref TIME_TO_FIRST_REQUEST_SECONDS: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "bitswap_time_to_first_request_seconds",
+            "Time from starting a query until its first request was sent labelled by query type.",
+        ),
+        &["type"],
+    )
+    .unwrap();
+    pub static ref REQUEST_BATCH_ENTRIES: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "bitswap_request_batch_entries",
+            "Number of wants sent together in a batched request",
+        )
+        .buckets(vec![2.0, 4.0, 8.0, 16.0, 32.0]),
+    )
+    .unwrap();
     pub static ref REQUESTS_CANCELED: IntCounter = IntCounter::new(
         "bitswap_requests_canceled_total",
         "Number of canceled requests",
@@ -41,13 +82,34 @@ lazy_static! {
         average number of missing blocks per request can be computed."#
     )
     .unwrap();
-    pub static ref RECEIVED_BLOCK_BYTES: IntCounter =
-        IntCounter::new("bitswap_received_block_bytes", "Number of received bytes.",).unwrap();
+    pub static ref RECEIVED_BLOCK_BYTES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_received_block_bytes",
+            "Number of received bytes labelled by whether the hash was verified.",
+        ),
+        &["verified"],
+    )
+    .unwrap();
     pub static ref RECEIVED_INVALID_BLOCK_BYTES: IntCounter = IntCounter::new(
         "bitswap_received_invalid_block_bytes",
         "Number of received bytes that didn't match the hash.",
     )
     .unwrap();
+    pub static ref BROADCAST_GETS: IntCounter = IntCounter::new(
+        "bitswap_broadcast_gets_total",
+        "Number of gets started without providers that probed the connected peers.",
+    )
+    .unwrap();
+    pub static ref JOINED_GETS: IntCounter = IntCounter::new(
+        "bitswap_joined_gets_total",
+        "Number of gets that joined a get of the same block in progress.",
+    )
+    .unwrap();
+    pub static ref DUPLICATE_BLOCKS: IntCounter = IntCounter::new(
+        "bitswap_duplicate_blocks_total",
+        "Number of blocks received after their query had the block or was cancelled.",
+    )
+    .unwrap();
     pub static ref SENT_BLOCK_BYTES: IntCounter =
         IntCounter::new("bitswap_sent_block_bytes", "Number of sent block bytes.",).unwrap();
     pub static ref RESPONSES_TOTAL: IntCounterVec = IntCounterVec::new(
@@ -84,4 +146,629 @@ lazy_static! {
         &["type"],
     )
     .unwrap();
-}
\ No newline at end of file
+    pub static ref RESPONSE_DELIVERY_FAILED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_response_delivery_failed_total",
+            "Number of computed responses that couldn't be delivered to the peer.",
+        ),
+        &["type"],
+    )
+    .unwrap();
+    pub static ref REQUEST_EXPIRATIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_request_expirations_total",
+            "Number of expired outbound requests labelled by the timeout that expired them.",
+        ),
+        &["type"],
+    )
+    .unwrap();
+    pub static ref PROVIDER_PROBES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_provider_probes_total",
+            "Number of provider probes labelled by outcome.",
+        ),
+        &["result"],
+    )
+    .unwrap();
+    pub static ref DIAL_RETRIES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_dial_retries_total",
+            "Number of retried dial failures labelled by outcome.",
+        ),
+        &["result"],
+    )
+    .unwrap();
+    pub static ref REQUESTS_QUEUED: IntCounter = IntCounter::new(
+        "bitswap_requests_queued_total",
+        "Number of outbound requests queued because the peer had too many requests in flight.",
+    )
+    .unwrap();
+    pub static ref TIMEOUT_RETRIES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_timeout_retries_total",
+            "Number of retried request timeouts labelled by outcome.",
+        ),
+        &["result"],
+    )
+    .unwrap();
+    pub static ref FETCHING_WANTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_fetching_wants_total",
+            "Number of inbound wants for blocks we were fetching labelled by outcome.",
+        ),
+        &["result"],
+    )
+    .unwrap();
+    pub static ref SPILLED_BYTES: IntCounter = IntCounter::new(
+        "bitswap_spilled_bytes_total",
+        "Number of received block bytes put into the spill store.",
+    )
+    .unwrap();
+    pub static ref DRAINED_BYTES: IntCounter = IntCounter::new(
+        "bitswap_drained_bytes_total",
+        "Number of spilled block bytes drained into the store.",
+    )
+    .unwrap();
+    pub static ref COMPRESSION_RAW_BYTES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_compression_raw_bytes_total",
+            "Number of block bytes exchanged on compressed streams before compression labelled by direction.",
+        ),
+        &["direction"],
+    )
+    .unwrap();
+    pub static ref COMPRESSION_WIRE_BYTES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_compression_wire_bytes_total",
+            "Number of block bytes exchanged on compressed streams as sent, compressed or not, labelled by direction.",
+        ),
+        &["direction"],
+    )
+    .unwrap();
+    pub static ref COMPAT_UNSERVEABLE_BLOCKS: IntCounter = IntCounter::new(
+        "bitswap_compat_unserveable_blocks_total",
+        "Number of blocks not served to compat peers because they exceed the message size.",
+    )
+    .unwrap();
+    pub static ref COMPAT_FALLBACKS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_compat_fallbacks_total",
+            "Number of requests retried over the compat protocol labelled by outcome.",
+        ),
+        &["result"],
+    )
+    .unwrap();
+    pub static ref COMPAT_RESPONSE_MESSAGES: IntCounter = IntCounter::new(
+        "bitswap_compat_response_messages_total",
+        "Number of compat messages sent with the responses to an inbound wantlist.",
+    )
+    .unwrap();
+    pub static ref COMPAT_CANCELLED_WANTS: IntCounter = IntCounter::new(
+        "bitswap_compat_cancelled_wants_total",
+        "Number of inbound compat wants cancelled by the peer before they were answered.",
+    )
+    .unwrap();
+    pub static ref COMPAT_NOTIFIED_WANTS: IntCounter = IntCounter::new(
+        "bitswap_compat_notified_wants_total",
+        "Number of earlier compat wants answered because the block became available.",
+    )
+    .unwrap();
+    pub static ref MISMATCHED_RESPONSES: IntCounter = IntCounter::new(
+        "bitswap_mismatched_responses_total",
+        "Number of responses dropped because they came from a peer the request wasn't sent to.",
+    )
+    .unwrap();
+    pub static ref STALE_REQUESTS_SKIPPED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_stale_requests_skipped_total",
+            "Number of inbound requests skipped because they exceeded the response budget.",
+        ),
+        &["type"],
+    )
+    .unwrap();
+    pub static ref UNSUPPORTED_PROVIDERS_AVOIDED: IntCounter = IntCounter::new(
+        "bitswap_unsupported_providers_avoided_total",
+        "Number of requests avoided because the provider is known not to support bitswap.",
+    )
+    .unwrap();
+    pub static ref POLICY_BLOCKED_SERVES: IntCounter = IntCounter::new(
+        "bitswap_policy_blocked_serves_total",
+        "Number of requests answered with dont have because the store can't serve the block.",
+    )
+    .unwrap();
+    pub static ref POLICY_IGNORED_REQUESTS: IntCounter = IntCounter::new(
+        "bitswap_policy_ignored_requests_total",
+        "Number of inbound requests dropped without an answer by the server policy.",
+    )
+    .unwrap();
+    pub static ref DEBT_THROTTLED_REQUESTS: IntCounter = IntCounter::new(
+        "bitswap_debt_throttled_requests_total",
+        "Number of block requests deferred because the peer exceeded the maximum debt ratio.",
+    )
+    .unwrap();
+    pub static ref DIALS_LIMITED: IntCounter = IntCounter::new(
+        "bitswap_dials_limited_total",
+        "Number of dials rejected by the connection limits of the swarm.",
+    )
+    .unwrap();
+    pub static ref HAVE_CACHE_HITS: IntCounter = IntCounter::new(
+        "bitswap_have_cache_hits_total",
+        "Number of have requests answered from the have cache without asking the store.",
+    )
+    .unwrap();
+    pub static ref THROTTLED_BLOCK_RESPONSES: IntGauge = IntGauge::new(
+        "bitswap_throttled_block_responses",
+        "Number of block responses waiting for the upload budget of max_upload_rate.",
+    )
+    .unwrap();
+    pub static ref REFUSED_PEER_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_refused_peer_requests_total",
+            "Number of requests from and to blocked or not allowed peers labelled by direction.",
+        ),
+        &["direction"],
+    )
+    .unwrap();
+    pub static ref CIRCUIT_BREAKERS_OPEN: IntGauge = IntGauge::new(
+        "bitswap_circuit_breakers_open",
+        "Number of peers whose circuit breaker is open or half open.",
+    )
+    .unwrap();
+    pub static ref DB_QUEUE_WAIT_SECONDS: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "bitswap_db_queue_wait_seconds",
+            "Time requests wait for the db thread labelled by request kind.",
+        ),
+        &["kind"],
+    )
+    .unwrap();
+    pub static ref PROVIDER_DISCOVERIES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_provider_discoveries_total",
+            "Number of provider discovery rounds labelled by whether providers were found.",
+        ),
+        &["result"],
+    )
+    .unwrap();
+    pub static ref ACTIVE_QUERIES: IntGauge = IntGauge::new(
+        "bitswap_active_queries",
+        "Number of get and sync queries in progress, not counting those started by a sync.",
+    )
+    .unwrap();
+    pub static ref QUERIES_REJECTED: IntCounter = IntCounter::new(
+        "bitswap_queries_rejected_total",
+        "Number of get and sync queries rejected because too many were in progress.",
+    )
+    .unwrap();
+    pub static ref QUERIES_TIMED_OUT: IntCounter = IntCounter::new(
+        "bitswap_queries_timed_out_total",
+        "Number of get and sync queries canceled because their deadline passed.",
+    )
+    .unwrap();
+    /// Only registered with `BitswapConfig::per_peer_failure_metrics`, see `PeerFailures`.
+    pub static ref PEER_FAILURES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_peer_failures_total",
+            "Number of inbound and outbound failures labelled by peer.",
+        ),
+        &["peer", "direction", "type"],
+    )
+    .unwrap();
+    pub static ref CODEC_BUFFER_BYTES: IntGauge = IntGauge::new(
+        "bitswap_codec_buffer_bytes",
+        "Approximate number of bytes allocated by codec buffers.",
+    )
+    .unwrap();
+}
+
+/// Children of the metrics labelled by query type.
+pub struct QueryTypeMetrics {
+    /// Child of `REQUESTS_TOTAL`.
+    pub requests: IntCounter,
+    /// Child of `REQUEST_DURATION_SECONDS`.
+    pub duration: Histogram,
+}
+
+/// Children of the labelled counters updated for every served or received block.
+struct BlockCounters {
+    have: IntCounter,
+    block: IntCounter,
+    dont_have: IntCounter,
+    verified_bytes: IntCounter,
+    unverified_bytes: IntCounter,
+}
+
+lazy_static! {
+    static ref QUERY_TYPE_METRICS: Vec<QueryTypeMetrics> = QUERY_TYPES
+        .iter()
+        .map(|ty| QueryTypeMetrics {
+            requests: REQUESTS_TOTAL.with_label_values(&[ty]),
+            duration: REQUEST_DURATION_SECONDS.with_label_values(&[ty]),
+        })
+        .collect();
+    static ref BLOCK_COUNTERS: BlockCounters = BlockCounters {
+        have: RESPONSES_TOTAL.with_label_values(&["have"]),
+        block: RESPONSES_TOTAL.with_label_values(&["block"]),
+        dont_have: RESPONSES_TOTAL.with_label_values(&["dont_have"]),
+        verified_bytes: RECEIVED_BLOCK_BYTES.with_label_values(&["true"]),
+        unverified_bytes: RECEIVED_BLOCK_BYTES.with_label_values(&["false"]),
+    };
+}
+
+/// Returns the metrics of a query type without looking up the label.
+///
+/// # Panics
+///
+/// Panics if the label isn't a query type.
+pub fn query_type_metrics(label: &str) -> &'static QueryTypeMetrics {
+    let index = QUERY_TYPES
+        .iter()
+        .position(|ty| *ty == label)
+        .expect("unknown query type");
+    &QUERY_TYPE_METRICS[index]
+}
+
+/// Counters updated for every served or received block, accumulated in plain integers
+/// and added to the prometheus metrics by `flush`. Dropping them flushes too, so the
+/// totals are exact once the owner shut down.
+#[derive(Debug, Default)]
+pub struct LocalCounters {
+    have: u64,
+    block: u64,
+    dont_have: u64,
+    sent_block_bytes: u64,
+    verified_bytes: u64,
+    unverified_bytes: u64,
+    invalid_bytes: u64,
+    updates: u32,
+}
+
+impl LocalCounters {
+    /// Counts a have or dont have response.
+    pub fn sent_have(&mut self, have: bool) {
+        if have {
+            self.have += 1;
+        } else {
+            self.dont_have += 1;
+        }
+        self.updates += 1;
+    }
+
+    /// Counts a block response.
+    pub fn sent_block(&mut self, len: usize) {
+        self.block += 1;
+        self.sent_block_bytes += len as u64;
+        self.updates += 1;
+    }
+
+    /// Counts the bytes of a received block.
+    pub fn received_block(&mut self, len: usize, verified: bool) {
+        if verified {
+            self.verified_bytes += len as u64;
+        } else {
+            self.unverified_bytes += len as u64;
+        }
+        self.updates += 1;
+    }
+
+    /// Counts the bytes of a received block that didn't match its hash.
+    pub fn received_invalid_block(&mut self, len: usize) {
+        self.invalid_bytes += len as u64;
+        self.updates += 1;
+    }
+
+    /// Returns `true` if no updates are waiting to be flushed.
+    pub fn is_empty(&self) -> bool {
+        self.updates == 0
+    }
+
+    /// Returns `true` once `FLUSH_UPDATES` updates are waiting to be flushed.
+    pub fn is_due(&self) -> bool {
+        self.updates >= FLUSH_UPDATES
+    }
+
+    /// Adds the accumulated updates to the prometheus metrics.
+    pub fn flush(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let counters = &*BLOCK_COUNTERS;
+        for (counter, value) in [
+            (&counters.have, self.have),
+            (&counters.block, self.block),
+            (&counters.dont_have, self.dont_have),
+            (&*SENT_BLOCK_BYTES, self.sent_block_bytes),
+            (&counters.verified_bytes, self.verified_bytes),
+            (&counters.unverified_bytes, self.unverified_bytes),
+            (&*RECEIVED_INVALID_BLOCK_BYTES, self.invalid_bytes),
+        ] {
+            if value > 0 {
+                counter.inc_by(value);
+            }
+        }
+        // the replaced counters are empty, so dropping them doesn't flush again
+        self.updates = 0;
+        *self = Self::default();
+    }
+}
+
+impl Drop for LocalCounters {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Label of the failures of peers that aren't tracked individually.
+const OTHER_PEERS: &str = "other";
+
+/// Number of trailing characters of the base58 peer id used as its label. The leading
+/// ones are shared by every peer with the same key type.
+const PEER_LABEL_LEN: usize = 12;
+
+/// Directions and types of the failures counted per peer.
+const PEER_FAILURE_TYPES: [(&str, &str); 8] = [
+    ("outbound", "dial_failure"),
+    ("outbound", "timeout"),
+    ("outbound", "connection_closed"),
+    ("outbound", "unsupported_protocols"),
+    ("inbound", "timeout"),
+    ("inbound", "connection_closed"),
+    ("inbound", "unsupported_protocols"),
+    ("inbound", "response_omission"),
+];
+
+/// Returns the label of a peer in `PEER_FAILURES`.
+fn peer_label(peer_id: &PeerId) -> String {
+    let id = peer_id.to_base58();
+    id[id.len().saturating_sub(PEER_LABEL_LEN)..].to_string()
+}
+
+/// Counts the failures of up to `capacity` peers in `PEER_FAILURES`. Once the capacity
+/// is reached the peer with the fewest failures is evicted and its counts fold into the
+/// `other` label, so the cardinality of the metric stays bounded.
+#[derive(Debug)]
+pub(crate) struct PeerFailures {
+    capacity: usize,
+    /// Label and number of failures of each tracked peer.
+    peers: FnvHashMap<PeerId, (String, u64)>,
+}
+
+impl PeerFailures {
+    /// Creates a tracker labelling up to `capacity` peers.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            peers: Default::default(),
+        }
+    }
+
+    /// Counts a failure of a request to or from `peer_id`.
+    pub fn record(&mut self, peer_id: &PeerId, direction: &str, ty: &str) {
+        if !self.peers.contains_key(peer_id) {
+            if self.capacity == 0 {
+                PEER_FAILURES
+                    .with_label_values(&[OTHER_PEERS, direction, ty])
+                    .inc();
+                return;
+            }
+            if self.peers.len() >= self.capacity {
+                self.evict();
+            }
+            self.peers.insert(*peer_id, (peer_label(peer_id), 0));
+        }
+        let (label, failures) = self.peers.get_mut(peer_id).unwrap();
+        *failures += 1;
+        PEER_FAILURES
+            .with_label_values(&[label.as_str(), direction, ty])
+            .inc();
+    }
+
+    /// Folds the counts of the peer with the fewest failures into `other`.
+    fn evict(&mut self) {
+        let coldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, (_, failures))| *failures)
+            .map(|(peer_id, _)| *peer_id);
+        let (label, _) = match coldest.and_then(|peer_id| self.peers.remove(&peer_id)) {
+            Some(peer) => peer,
+            None => return,
+        };
+        for (direction, ty) in PEER_FAILURE_TYPES.iter() {
+            let labels = [label.as_str(), direction, ty];
+            if let Ok(counter) = PEER_FAILURES.get_metric_with_label_values(&labels) {
+                if counter.get() > 0 {
+                    PEER_FAILURES
+                        .with_label_values(&[OTHER_PEERS, direction, ty])
+                        .inc_by(counter.get());
+                }
+                PEER_FAILURES.remove_label_values(&labels).ok();
+            }
+        }
+    }
+}
+
+/// Returns all collectors, including `PEER_FAILURES` if `per_peer` is set.
+fn collectors(per_peer: bool) -> Vec<Box<dyn Collector>> {
+    let mut collectors: Vec<Box<dyn Collector>> = vec![
+        Box::new(REQUESTS_TOTAL.clone()),
+        Box::new(REQUEST_DURATION_SECONDS.clone()),
+        Box::new(TIME_TO_FIRST_REQUEST_SECONDS.clone()),
+        Box::new(REQUEST_BATCH_ENTRIES.clone()),
+        Box::new(REQUESTS_CANCELED.clone()),
+        Box::new(BLOCK_NOT_FOUND.clone()),
+        Box::new(PROVIDERS_TOTAL.clone()),
+        Box::new(MISSING_BLOCKS_TOTAL.clone()),
+        Box::new(RECEIVED_BLOCK_BYTES.clone()),
+        Box::new(RECEIVED_INVALID_BLOCK_BYTES.clone()),
+        Box::new(DUPLICATE_BLOCKS.clone()),
+        Box::new(BROADCAST_GETS.clone()),
+        Box::new(JOINED_GETS.clone()),
+        Box::new(SENT_BLOCK_BYTES.clone()),
+        Box::new(RESPONSES_TOTAL.clone()),
+        Box::new(THROTTLED_INBOUND.clone()),
+        Box::new(THROTTLED_OUTBOUND.clone()),
+        Box::new(OUTBOUND_FAILURE.clone()),
+        Box::new(INBOUND_FAILURE.clone()),
+        Box::new(RESPONSE_DELIVERY_FAILED.clone()),
+        Box::new(REQUEST_EXPIRATIONS.clone()),
+        Box::new(PROVIDER_PROBES.clone()),
+        Box::new(DIAL_RETRIES.clone()),
+        Box::new(TIMEOUT_RETRIES.clone()),
+        Box::new(REQUESTS_QUEUED.clone()),
+        Box::new(MISMATCHED_RESPONSES.clone()),
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        Box::new(COMPAT_UNSERVEABLE_BLOCKS.clone()),
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        Box::new(COMPAT_FALLBACKS.clone()),
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        Box::new(COMPAT_RESPONSE_MESSAGES.clone()),
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        Box::new(COMPAT_CANCELLED_WANTS.clone()),
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        Box::new(COMPAT_NOTIFIED_WANTS.clone()),
+        Box::new(FETCHING_WANTS.clone()),
+        #[cfg(feature = "compression")]
+        Box::new(COMPRESSION_RAW_BYTES.clone()),
+        #[cfg(feature = "compression")]
+        Box::new(COMPRESSION_WIRE_BYTES.clone()),
+        #[cfg(feature = "spill")]
+        Box::new(SPILLED_BYTES.clone()),
+        #[cfg(feature = "spill")]
+        Box::new(DRAINED_BYTES.clone()),
+        Box::new(STALE_REQUESTS_SKIPPED.clone()),
+        Box::new(UNSUPPORTED_PROVIDERS_AVOIDED.clone()),
+        Box::new(POLICY_BLOCKED_SERVES.clone()),
+        Box::new(POLICY_IGNORED_REQUESTS.clone()),
+        Box::new(DEBT_THROTTLED_REQUESTS.clone()),
+        Box::new(DIALS_LIMITED.clone()),
+        Box::new(HAVE_CACHE_HITS.clone()),
+        Box::new(THROTTLED_BLOCK_RESPONSES.clone()),
+        Box::new(REFUSED_PEER_REQUESTS.clone()),
+        Box::new(CIRCUIT_BREAKERS_OPEN.clone()),
+        Box::new(DB_QUEUE_WAIT_SECONDS.clone()),
+        Box::new(CODEC_BUFFER_BYTES.clone()),
+        Box::new(PROVIDER_DISCOVERIES.clone()),
+        Box::new(ACTIVE_QUERIES.clone()),
+        Box::new(QUERIES_REJECTED.clone()),
+        Box::new(QUERIES_TIMED_OUT.clone()),
+    ];
+    if per_peer {
+        collectors.push(Box::new(PEER_FAILURES.clone()));
+    }
+    collectors
+}
+
+/// Registers all collectors, including `PEER_FAILURES` if `per_peer` is set. If one of
+/// them can't be registered, the ones registered before it are unregistered again so
+/// that the call can be retried.
+pub fn register(registry: &Registry, per_peer: bool) -> Result<()> {
+    for (i, collector) in collectors(per_peer).into_iter().enumerate() {
+        if let Err(err) = registry.register(collector) {
+            for collector in collectors(per_peer).into_iter().take(i) {
+                registry.unregister(collector).ok();
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Unregisters all collectors that are registered.
+pub fn unregister(registry: &Registry) {
+    for collector in collectors(true) {
+        registry.unregister(collector).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_is_transactional() {
+        let registry = Registry::new();
+        // registered by someone else under the same name
+        let conflict = IntGauge::new(
+            "bitswap_codec_buffer_bytes",
+            "Approximate number of bytes allocated by codec buffers.",
+        )
+        .unwrap();
+        registry.register(Box::new(conflict.clone())).unwrap();
+        assert!(register(&registry, false).is_err());
+        assert_eq!(registry.gather().len(), 1);
+
+        registry.unregister(Box::new(conflict)).unwrap();
+        register(&registry, false).unwrap();
+        assert!(register(&registry, false).is_err());
+        // the failed call didn't unregister the collectors of the first one
+        assert!(registry
+            .unregister(Box::new(REQUESTS_CANCELED.clone()))
+            .is_ok());
+
+        unregister(&registry);
+        assert!(registry.gather().is_empty());
+        register(&registry, false).unwrap();
+    }
+
+    #[test]
+    fn test_peer_failures() {
+        let count = |label: &str, ty| {
+            PEER_FAILURES
+                .with_label_values(&[label, "outbound", ty])
+                .get()
+        };
+        let labels = || {
+            PEER_FAILURES.collect()[0]
+                .get_metric()
+                .iter()
+                .flat_map(|metric| metric.get_label().to_vec())
+                .map(|pair| pair.get_value().to_string())
+                .collect::<Vec<_>>()
+        };
+        // no other test counts per peer failures
+        let other = count(OTHER_PEERS, "timeout");
+        let peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        let mut failures = PeerFailures::new(2);
+        failures.record(&peers[0], "outbound", "timeout");
+        failures.record(&peers[0], "outbound", "timeout");
+        failures.record(&peers[1], "outbound", "timeout");
+        assert!(labels().contains(&peer_label(&peers[1])));
+
+        // the peer with the fewest failures makes room
+        failures.record(&peers[2], "outbound", "dial_failure");
+        assert_eq!(count(OTHER_PEERS, "timeout"), other + 1);
+        assert!(!labels().contains(&peer_label(&peers[1])));
+        assert_eq!(count(&peer_label(&peers[0]), "timeout"), 2);
+        assert_eq!(count(&peer_label(&peers[2]), "dial_failure"), 1);
+        assert_eq!(peer_label(&peers[0]).len(), PEER_LABEL_LEN);
+
+        // without capacity every failure is folded
+        let mut failures = PeerFailures::new(0);
+        failures.record(&peers[0], "outbound", "timeout");
+        assert_eq!(count(OTHER_PEERS, "timeout"), other + 2);
+    }
+
+    #[test]
+    fn test_local_counters() {
+        // no other test receives invalid blocks
+        let invalid = RECEIVED_INVALID_BLOCK_BYTES.get();
+        let mut counters = LocalCounters::default();
+        counters.received_invalid_block(3);
+        assert!(!counters.is_empty());
+        assert_eq!(RECEIVED_INVALID_BLOCK_BYTES.get(), invalid);
+
+        counters.flush();
+        assert!(counters.is_empty());
+        assert_eq!(RECEIVED_INVALID_BLOCK_BYTES.get(), invalid + 3);
+
+        // the updates left on drop are flushed too
+        for _ in 0..FLUSH_UPDATES {
+            counters.received_invalid_block(1);
+        }
+        assert!(counters.is_due());
+        drop(counters);
+        let expected = invalid + 3 + u64::from(FLUSH_UPDATES);
+        assert_eq!(RECEIVED_INVALID_BLOCK_BYTES.get(), expected);
+    }
+}
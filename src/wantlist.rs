@@ -0,0 +1,318 @@
+use crate::protocol::RequestType;
+use fnv::FnvHashMap;
+use futures::channel::{mpsc, oneshot};
+use libipld::Cid;
+use libp2p::PeerId;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+/// Want registered through a `WantListHandle`.
+#[derive(Debug)]
+pub(crate) struct Want {
+    pub cid: Cid,
+    pub tx: oneshot::Sender<()>,
+}
+
+/// Message sent by a `WantListHandle` or a dropped `WantToken` to the behaviour.
+#[derive(Debug)]
+pub(crate) enum WantMessage {
+    /// A want was registered.
+    Register(Want),
+    /// A token for the cid was dropped before it resolved.
+    Unregister(Cid),
+}
+
+/// Registers wants with a `Bitswap` behaviour from outside of the swarm, so that
+/// application logic can wait for blocks that bitswap is fetching instead of starting
+/// duplicate gets. Registering a want doesn't cause any network traffic.
+#[derive(Clone, Debug)]
+pub struct WantListHandle {
+    tx: mpsc::UnboundedSender<WantMessage>,
+}
+
+impl WantListHandle {
+    pub(crate) fn new() -> (Self, mpsc::UnboundedReceiver<WantMessage>) {
+        let (tx, rx) = mpsc::unbounded();
+        (Self { tx }, rx)
+    }
+
+    /// Registers a want for `cid`. The returned token resolves once the block is in the
+    /// store, either because it was already there, bitswap received it or it was
+    /// announced with `Bitswap::notify_block_stored`. Dropping the token unregisters
+    /// the want.
+    pub fn register_want(&self, cid: Cid) -> WantToken {
+        let (tx, rx) = oneshot::channel();
+        // if the behaviour was dropped the token resolves with an error
+        self.tx
+            .unbounded_send(WantMessage::Register(Want { cid, tx }))
+            .ok();
+        WantToken {
+            cid,
+            rx,
+            handle: self.tx.clone(),
+        }
+    }
+}
+
+/// Future resolving once the block of a registered want is available locally.
+#[derive(Debug)]
+#[must_use = "dropping a token unregisters the want"]
+pub struct WantToken {
+    cid: Cid,
+    rx: oneshot::Receiver<()>,
+    handle: mpsc::UnboundedSender<WantMessage>,
+}
+
+impl WantToken {
+    /// Returns the cid of the wanted block.
+    pub fn cid(&self) -> &Cid {
+        &self.cid
+    }
+}
+
+impl Future for WantToken {
+    type Output = Result<(), WantListClosed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|res| res.map_err(|_| WantListClosed))
+    }
+}
+
+impl Drop for WantToken {
+    fn drop(&mut self) {
+        // a token that resolved was already removed from the want list
+        if let Ok(None) = self.rx.try_recv() {
+            self.rx.close();
+            self.handle
+                .unbounded_send(WantMessage::Unregister(self.cid))
+                .ok();
+        }
+    }
+}
+
+/// Error returned by a `WantToken` if the behaviour was dropped before the block
+/// became available.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+#[error("the bitswap behaviour was dropped")]
+pub struct WantListClosed;
+
+/// Registered wants by cid.
+#[derive(Debug, Default)]
+pub(crate) struct WantList {
+    wants: FnvHashMap<Cid, Vec<oneshot::Sender<()>>>,
+}
+
+impl WantList {
+    /// Adds a want. Returns `true` if it is the only want for the cid, in which case the
+    /// store needs to be checked for the block.
+    pub fn insert(&mut self, want: Want) -> bool {
+        let wants = self.wants.entry(want.cid).or_default();
+        wants.retain(|tx| !tx.is_canceled());
+        wants.push(want.tx);
+        wants.len() == 1
+    }
+
+    /// Returns `true` if a want for the cid is registered.
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.wants.contains_key(cid)
+    }
+
+    /// Resolves the wants for a block that is available.
+    pub fn resolve(&mut self, cid: &Cid) {
+        for tx in self.wants.remove(cid).into_iter().flatten() {
+            tx.send(()).ok();
+        }
+    }
+
+    /// Removes the wants for the cid whose token was dropped.
+    pub fn unregister(&mut self, cid: &Cid) {
+        if let Some(wants) = self.wants.get_mut(cid) {
+            wants.retain(|tx| !tx.is_canceled());
+            if wants.is_empty() {
+                self.wants.remove(cid);
+            }
+        }
+    }
+}
+
+/// Number of wants tracked per remote peer. Further wants are still served.
+const MAX_PEER_WANTS: usize = 1024;
+
+/// Type of a want of a remote peer and whether it was received over compat.
+#[derive(Clone, Copy, Debug)]
+struct PeerWant {
+    ty: RequestType,
+    compat: bool,
+}
+
+/// Wants of remote peers that we didn't answer yet, see `Bitswap::peer_wantlist`.
+#[derive(Debug, Default)]
+pub(crate) struct PeerWantlists {
+    peers: FnvHashMap<PeerId, FnvHashMap<Cid, PeerWant>>,
+}
+
+impl PeerWantlists {
+    /// Adds a want of a peer. A block want replaces a have want for the same cid.
+    pub fn insert(&mut self, peer_id: PeerId, cid: Cid, ty: RequestType, compat: bool) {
+        let wants = self.peers.entry(peer_id).or_default();
+        if wants.len() >= MAX_PEER_WANTS && !wants.contains_key(&cid) {
+            return;
+        }
+        let want = wants.entry(cid).or_insert(PeerWant { ty, compat });
+        if ty == RequestType::Block {
+            want.ty = ty;
+        }
+        want.compat |= compat;
+    }
+
+    /// Removes a want answered with a response of type `ty`. A have only answers a have
+    /// want, so a block want for the same cid remains.
+    pub fn answered(&mut self, peer_id: PeerId, cid: Cid, ty: RequestType) {
+        if let Some(wants) = self.peers.get_mut(&peer_id) {
+            let have_want = matches!(wants.get(&cid), Some(want) if want.ty == RequestType::Have);
+            if ty == RequestType::Block || have_want {
+                wants.remove(&cid);
+            }
+            if wants.is_empty() {
+                self.peers.remove(&peer_id);
+            }
+        }
+    }
+
+    /// Removes a want the peer cancelled.
+    pub fn cancel(&mut self, peer_id: PeerId, cid: Cid) {
+        self.answered(peer_id, cid, RequestType::Block);
+    }
+
+    /// Forgets the wants of a disconnected peer.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    /// Returns the wants of a peer.
+    pub fn wantlist(&self, peer_id: &PeerId) -> Vec<(Cid, RequestType)> {
+        self.peers
+            .get(peer_id)
+            .map(|wants| wants.iter().map(|(cid, want)| (*cid, want.ty)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the cids a peer wants over compat.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub fn compat_wantlist(&self, peer_id: &PeerId) -> Vec<Cid> {
+        self.peers
+            .get(peer_id)
+            .map(|wants| {
+                wants
+                    .iter()
+                    .filter(|(_, want)| want.compat)
+                    .map(|(cid, _)| *cid)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the peers with a compat want for a cid.
+    #[cfg(any(feature = "compat", feature = "compat-lite"))]
+    pub fn compat_wants(&self, cid: &Cid) -> Vec<(PeerId, RequestType)> {
+        self.peers
+            .iter()
+            .filter_map(|(peer_id, wants)| match wants.get(cid) {
+                Some(want) if want.compat => Some((*peer_id, want.ty)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::tests::create_cid;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_want_list() {
+        let (handle, mut rx) = WantListHandle::new();
+        let mut wants = WantList::default();
+        let cid = Cid::default();
+
+        let mut recv = |wants: &mut WantList| match block_on(rx.next()).unwrap() {
+            WantMessage::Register(want) => wants.insert(want),
+            WantMessage::Unregister(cid) => {
+                wants.unregister(&cid);
+                false
+            }
+        };
+
+        let token1 = handle.register_want(cid);
+        let token2 = handle.clone().register_want(cid);
+        assert_eq!(token1.cid(), &cid);
+        assert!(recv(&mut wants));
+        assert!(!recv(&mut wants));
+        assert!(wants.contains(&cid));
+
+        // dropping a token unregisters its want right away
+        drop(token2);
+        recv(&mut wants);
+        assert_eq!(wants.wants[&cid].len(), 1);
+        wants.resolve(&cid);
+        assert_eq!(block_on(token1), Ok(()));
+        assert!(!wants.contains(&cid));
+
+        let token = handle.register_want(cid);
+        recv(&mut wants);
+        drop(token);
+        recv(&mut wants);
+        assert!(!wants.contains(&cid));
+
+        let token = handle.register_want(cid);
+        drop(rx);
+        assert_eq!(block_on(token), Err(WantListClosed));
+    }
+
+    #[test]
+    fn test_peer_wantlists() {
+        let mut wantlists = PeerWantlists::default();
+        let peer = PeerId::random();
+        let (cid1, cid2) = (Cid::default(), create_cid(b"want"));
+        wantlists.insert(peer, cid1, RequestType::Have, false);
+        wantlists.insert(peer, cid2, RequestType::Block, true);
+        // block wants aren't downgraded by a have want, but replace one
+        wantlists.insert(peer, cid2, RequestType::Have, false);
+        wantlists.insert(peer, cid1, RequestType::Block, false);
+        let mut wantlist = wantlists.wantlist(&peer);
+        wantlist.sort_by_key(|(cid, _)| cid.to_bytes());
+        let mut expected = vec![(cid1, RequestType::Block), (cid2, RequestType::Block)];
+        expected.sort_by_key(|(cid, _)| cid.to_bytes());
+        assert_eq!(wantlist, expected);
+        #[cfg(any(feature = "compat", feature = "compat-lite"))]
+        {
+            assert!(wantlists.compat_wants(&cid1).is_empty());
+            assert_eq!(
+                wantlists.compat_wants(&cid2),
+                vec![(peer, RequestType::Block)]
+            );
+        }
+
+        wantlists.answered(peer, cid1, RequestType::Have);
+        assert_eq!(wantlists.wantlist(&peer).len(), 2);
+        wantlists.answered(peer, cid1, RequestType::Block);
+        wantlists.cancel(peer, cid2);
+        assert!(wantlists.wantlist(&peer).is_empty());
+        assert!(wantlists.peers.is_empty());
+
+        for i in 0..MAX_PEER_WANTS + 1 {
+            let cid = create_cid(&i.to_be_bytes());
+            wantlists.insert(peer, cid, RequestType::Have, false);
+        }
+        assert_eq!(wantlists.wantlist(&peer).len(), MAX_PEER_WANTS);
+        wantlists.remove_peer(&peer);
+        assert!(wantlists.wantlist(&peer).is_empty());
+    }
+}
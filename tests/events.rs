@@ -0,0 +1,50 @@
+//! Downstream crates build events with the exported constructors and match them with
+//! `..`, so adding variants or fields doesn't break them.
+use bytes::Bytes;
+use libipld::Cid;
+use libp2p::PeerId;
+use libp2p_bitswap::{BitswapEvent, PartialSync, QueryId, QueryInfo, QueryStats};
+
+fn describe(event: &BitswapEvent) -> String {
+    match event {
+        BitswapEvent::Progress { id, missing, .. } => format!("progress {} {}", id, missing),
+        BitswapEvent::Complete {
+            id, result, stats, ..
+        } => match &stats.partial {
+            Some(partial) => format!("partial {} {}", id, partial.missing.len()),
+            None => format!("complete {} {}", id, result.is_ok()),
+        },
+        BitswapEvent::Block { id, data, .. } => format!("block {} {}", id, data.len()),
+        BitswapEvent::MismatchedResponse { id, .. } => format!("mismatched {}", id),
+        _ => "unknown".into(),
+    }
+}
+
+#[test]
+fn test_events_are_constructible() {
+    let id = QueryId::from(1);
+    let mut partial = PartialSync::default();
+    partial.missing.push(Cid::default());
+    let mut stats = QueryStats::default();
+    stats.partial = Some(partial);
+    let mut info = QueryInfo::default();
+    info.missing = 2;
+    let events = [
+        BitswapEvent::progress(id, info),
+        BitswapEvent::complete(id, Ok(())),
+        BitswapEvent::block(id, Cid::default(), Bytes::from_static(b"abc")),
+        BitswapEvent::complete_with_stats(id, Ok(()), stats),
+        BitswapEvent::mismatched_response(id, PeerId::random()),
+    ];
+    let described: Vec<_> = events.iter().map(describe).collect();
+    assert_eq!(
+        described,
+        [
+            "progress 1 2",
+            "complete 1 true",
+            "block 1 3",
+            "partial 1 1",
+            "mismatched 1",
+        ]
+    );
+}